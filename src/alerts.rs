@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use macroquad::prelude::*;
+
+use crate::hud::{Anchor, Hud};
+
+use crate::clock::Instant;
+
+// Metrics are judged by their worst value over each of these
+const WINDOW: Duration = Duration::from_secs(1);
+
+// How long an alert stays on the HUD
+const TOAST_DURATION: Duration = Duration::from_secs(8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+	RollbackDepth,
+	RttMs,
+	// Late inputs the server dropped per second, of the worst player
+	LateInputs,
+}
+
+impl Metric {
+	fn label(self) -> &'static str {
+		match self {
+			Self::RollbackDepth => "rollback depth",
+			Self::RttMs => "rtt ms",
+			Self::LateInputs => "late inputs/s",
+		}
+	}
+
+	// Levels keep their last value through a second nobody measured them, a
+	// second without rollbacks has depth 0
+	fn holds(self) -> bool {
+		!matches!(self, Self::RollbackDepth)
+	}
+}
+
+/// What --alert-* allows, a metric past its limit for `secs` seconds in a
+/// row raises an alert. Metrics without a limit aren't watched.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+	pub rollback_depth: Option<u32>,
+	pub rtt_ms: Option<u32>,
+	pub late_inputs: Option<u32>,
+	pub secs: u32,
+}
+
+struct Watch {
+	metric: Metric,
+	limit: u32,
+	// Worst value of the current second, and of the one before
+	worst: Option<u32>,
+	last: u32,
+	// Seconds in a row past the limit
+	over: u32,
+}
+
+/// Net health alarms for long unattended sessions: logged, and shown as
+/// toasts in the top right corner.
+pub struct Alerts {
+	watches: Vec<Watch>,
+	secs: u32,
+	window_start: Instant,
+	toasts: Vec<(String, Instant)>,
+}
+
+impl Alerts {
+	pub fn new(limits: Limits) -> Self {
+		let watches = [
+			(Metric::RollbackDepth, limits.rollback_depth),
+			(Metric::RttMs, limits.rtt_ms),
+			(Metric::LateInputs, limits.late_inputs),
+		]
+		.into_iter()
+		.filter_map(|(metric, limit)| {
+			Some(Watch {
+				metric,
+				limit: limit?,
+				worst: None,
+				last: 0,
+				over: 0,
+			})
+		})
+		.collect();
+		Self {
+			watches,
+			secs: limits.secs.max(1),
+			window_start: Instant::now(),
+			toasts: Vec::new(),
+		}
+	}
+
+	pub fn observe(&mut self, metric: Metric, value: u32) {
+		for w in self.watches.iter_mut().filter(|w| w.metric == metric) {
+			w.worst = Some(w.worst.map_or(value, |v| v.max(value)));
+		}
+	}
+
+	// Closes the second once it's over, call it every frame
+	pub fn update(&mut self) {
+		let now = Instant::now();
+		self.toasts.retain(|&(_, until)| until > now);
+		if now.duration_since(self.window_start) < WINDOW {
+			return;
+		}
+		self.window_start = now;
+		for w in &mut self.watches {
+			let value = match w.worst.take() {
+				Some(v) => v,
+				None if w.metric.holds() => w.last,
+				None => 0,
+			};
+			w.last = value;
+			let label = w.metric.label();
+			if value <= w.limit {
+				if w.over >= self.secs {
+					info!("recovered: {} back to {}", label, value);
+				}
+				w.over = 0;
+				continue;
+			}
+			w.over += 1;
+			// Once per episode, the toast would otherwise never go away
+			if w.over == self.secs {
+				let msg = format!("{label} past {} for {}s, now {value}", w.limit, self.secs);
+				warn!("alert: {}", msg);
+				self.toasts.push((msg, now + TOAST_DURATION));
+			}
+		}
+	}
+
+	pub fn draw(&self, hud: &mut Hud) {
+		for (msg, _) in &self.toasts {
+			hud.text(Anchor::TopRight, msg, RED);
+		}
+	}
+}
@@ -0,0 +1,139 @@
+use std::{hint::black_box, time::Instant};
+
+use crate::{
+	env::Rng,
+	sim::{self, DEFAULT_PLAYERS, InputBits, SimState},
+	snapshot::{Chunked, SnapshotRing},
+};
+
+const HISTORY: usize = 2048;
+const TICKS: u32 = 20_000;
+
+// Synthetic kilobyte-sized state: many chunks, only a couple touched per tick
+const BIG_CHUNKS: usize = 32;
+const BIG_CHUNK_BYTES: usize = 256;
+const BIG_DIRTY_PER_TICK: usize = 2;
+
+#[derive(Clone)]
+struct BigState {
+	chunks: Vec<[u8; BIG_CHUNK_BYTES]>,
+}
+
+impl Default for BigState {
+	fn default() -> Self {
+		Self {
+			chunks: vec![[0; BIG_CHUNK_BYTES]; BIG_CHUNKS],
+		}
+	}
+}
+
+impl Chunked for BigState {
+	type Chunk = [u8; BIG_CHUNK_BYTES];
+	type Extra = ();
+
+	fn chunk_count(&self) -> usize {
+		self.chunks.len()
+	}
+
+	fn chunk(&self, i: usize) -> &Self::Chunk {
+		&self.chunks[i]
+	}
+
+	fn extra(&self) {}
+
+	fn assemble(chunks: Vec<Self::Chunk>, _: ()) -> Self {
+		Self { chunks }
+	}
+}
+
+struct Report {
+	ns_per_save: f64,
+	retained_bytes: usize,
+}
+
+fn bench_full_copy<T: Chunked + Clone>(
+	mut state: T,
+	mut advance: impl FnMut(&mut T, u32),
+) -> Report {
+	let mut ring: Vec<Option<(u32, T)>> = vec![None; HISTORY];
+	let mut spent = 0u128;
+	for tick in 0..TICKS {
+		advance(&mut state, tick);
+		let t0 = Instant::now();
+		ring[tick as usize % HISTORY] = Some((tick, state.clone()));
+		spent += t0.elapsed().as_nanos();
+	}
+	black_box(&ring);
+	Report {
+		ns_per_save: spent as f64 / TICKS as f64,
+		retained_bytes: ring
+			.iter()
+			.flatten()
+			.map(|(_, s)| s.chunk_count() * std::mem::size_of::<T::Chunk>())
+			.sum(),
+	}
+}
+
+fn bench_cow<T: Chunked>(mut state: T, mut advance: impl FnMut(&mut T, u32)) -> Report {
+	let mut ring = SnapshotRing::<T>::new(HISTORY);
+	let mut spent = 0u128;
+	for tick in 0..TICKS {
+		advance(&mut state, tick);
+		let t0 = Instant::now();
+		ring.save(tick, &state);
+		spent += t0.elapsed().as_nanos();
+	}
+	Report {
+		ns_per_save: spent as f64 / TICKS as f64,
+		retained_bytes: ring.retained_bytes(),
+	}
+}
+
+fn print_row(name: &str, r: &Report) {
+	println!(
+		"  {name:<10} {:>9.1} ns/save {:>10} KiB retained",
+		r.ns_per_save,
+		r.retained_bytes / 1024
+	);
+}
+
+pub fn run_bench() -> anyhow::Result<()> {
+	// Only player 0 moves, like a match where one player idles
+	let advance_sim = |s: &mut SimState, tick: u32| {
+		let moving = if (tick / 60).is_multiple_of(2) {
+			InputBits::RIGHT
+		} else {
+			InputBits::LEFT
+		};
+		let inputs: Vec<_> = (0..s.player_count())
+			.map(|i| if i == 0 { moving } else { InputBits::empty() }.into())
+			.collect();
+		sim::step(s, &inputs);
+	};
+	let start = SimState::new(DEFAULT_PLAYERS);
+	println!(
+		"SimState ({} bytes, {DEFAULT_PLAYERS} players), {TICKS} ticks",
+		start.to_bytes().len()
+	);
+	print_row("full copy", &bench_full_copy(start.clone(), advance_sim));
+	print_row("cow", &bench_cow(start, advance_sim));
+
+	let mut rng = Rng::new(7);
+	let mut advance_big = |s: &mut BigState, tick: u32| {
+		for _ in 0..BIG_DIRTY_PER_TICK {
+			let i = rng.next_u32() as usize % BIG_CHUNKS;
+			s.chunks[i][tick as usize % BIG_CHUNK_BYTES] = tick as u8;
+		}
+	};
+	println!(
+		"BigState ({} bytes, {BIG_DIRTY_PER_TICK}/{BIG_CHUNKS} chunks dirty per tick), {TICKS} ticks",
+		BIG_CHUNKS * BIG_CHUNK_BYTES
+	);
+	print_row(
+		"full copy",
+		&bench_full_copy(BigState::default(), &mut advance_big),
+	);
+	print_row("cow", &bench_cow(BigState::default(), &mut advance_big));
+
+	Ok(())
+}
@@ -0,0 +1,277 @@
+use std::{path::Path, sync::mpsc::RecvTimeoutError, thread, time::Duration};
+
+use anyhow::Context;
+use macroquad::prelude::info;
+
+use crate::{
+	bridge::Bridge,
+	clock::{self, Instant},
+	env::Rng,
+	net::{self, InputTransport, NetEvent},
+	protocol::{InputGrant, InputMsg, KickReason},
+	rollback::{is_before, later},
+	sim::{self, InputBits, PlayerInput},
+	simulate,
+	sockopt::SocketOptions,
+	stats,
+};
+
+// How often the aggregate progress line is printed
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Random bots hold each input for one in this many ticks on average
+const HOLD_TICKS: u32 = 12;
+
+// Gap between two bots connecting, so a crowd doesn't hit the accept loop at once
+const CONNECT_STAGGER: Duration = Duration::from_millis(20);
+
+// What a bot did over its connection
+#[derive(Default)]
+struct Summary {
+	player: Option<u8>,
+	inputs_sent: u64,
+	ticks_received: u64,
+	kicked: Option<KickReason>,
+}
+
+impl Summary {
+	fn line(&self) -> String {
+		format!(
+			"{}, {} inputs sent, {} ticks received{}",
+			self.player
+				.map_or("spectator".to_string(), |p| format!("P{p}")),
+			self.inputs_sent,
+			self.ticks_received,
+			self.kicked
+				.map_or(String::new(), |r| format!(", kicked ({r:?})"))
+		)
+	}
+}
+
+// Where a bot's inputs come from: another program over a bridge, the script's
+// line for the tick, the last line once it runs out, or random mashing like the
+// self-test's bots
+struct Inputs {
+	bridge: Option<Bridge>,
+	script: Option<Vec<PlayerInput>>,
+	rng: Rng,
+	held: PlayerInput,
+}
+
+impl Inputs {
+	fn at(&mut self, tick: u32) -> PlayerInput {
+		if let Some(bridge) = &mut self.bridge {
+			return bridge.input(tick);
+		}
+		if let Some(script) = &self.script {
+			return script
+				.get(tick as usize)
+				.or(script.last())
+				.copied()
+				.unwrap_or(InputBits::empty().into());
+		}
+		if self.rng.next_u32().is_multiple_of(HOLD_TICKS) {
+			let r = self.rng.next_u32();
+			self.held = PlayerInput::new(r as u8, (r >> 8) as u8);
+		}
+		self.held
+	}
+}
+
+// One bot's connection, until the server drops it
+fn run_bot(
+	addr: String,
+	room: Option<String>,
+	mut inputs: Inputs,
+	socket: SocketOptions,
+	transport: InputTransport,
+) -> anyhow::Result<Summary> {
+	let (rx_evt, tx_cmd) = net::spawn_client(addr, None, None, room, None, socket, transport)
+		.context("spawn_client")?;
+	let stat_inputs = stats::counter("bot.inputs_sent");
+	let stat_ticks = stats::counter("bot.ticks_received");
+	let stat_playing = stats::gauge("bot.playing");
+
+	let mut summary = Summary::default();
+	// Instant tick `.1` is due, None while spectating or before the match starts
+	let mut clock_at: Option<(Instant, u32)> = None;
+	// Next tick to send an input for, and the newest tick the server confirmed
+	let mut next_tick = 0u32;
+	let mut latest_server_tick = 0u32;
+	let mut input_grant: Option<InputGrant> = None;
+	let mut window = net::InputWindow::default();
+	loop {
+		let ev = rx_evt.recv_timeout(Duration::from_millis(1));
+		if let (Some(bridge), Ok(ev)) = (&mut inputs.bridge, &ev) {
+			bridge.observe(ev);
+		}
+		match ev {
+			Ok(NetEvent::AssignStart(a)) => {
+				if summary.player.is_none() {
+					stat_playing.add(1);
+				}
+				summary.player = Some(a.player_id);
+				let start_at = clock::now() + Duration::from_millis(a.start_after_ms as u64);
+				clock_at = Some((start_at, 0));
+				next_tick = 0;
+				latest_server_tick = 0;
+				input_grant = None;
+				window.clear();
+			}
+			// A match starting past tick 0 comes as a resume too
+			Ok(NetEvent::Resume(r)) => {
+				if summary.player.is_none() {
+					stat_playing.add(1);
+				}
+				summary.player = Some(r.player_id);
+				let start_at = clock::now() + Duration::from_millis(r.start_after_ms as u64);
+				clock_at = Some((start_at, r.tick));
+				next_tick = r.tick;
+				latest_server_tick = r.tick;
+				input_grant = None;
+				window.clear();
+			}
+			// Took over a dropped player, the server is about as far as its stream
+			Ok(NetEvent::Control(c)) => {
+				if summary.player.is_none() {
+					stat_playing.add(1);
+				}
+				summary.player = Some(c.player_id);
+				next_tick = later(c.tick, latest_server_tick.wrapping_add(1));
+				clock_at = Some((clock::now(), next_tick));
+				input_grant = None;
+				window.clear();
+			}
+			Ok(NetEvent::SpectateStart(_) | NetEvent::Searching) => clock_at = None,
+			Ok(NetEvent::TickInputs(t)) => {
+				latest_server_tick = later(latest_server_tick, t.msg.tick);
+				summary.ticks_received += 1;
+				stat_ticks.inc();
+			}
+			Ok(NetEvent::History(h)) => {
+				if let Some(t) = h.last() {
+					latest_server_tick = later(latest_server_tick, t.tick);
+				}
+			}
+			Ok(NetEvent::InputGrant(g)) => input_grant = Some(g),
+			Ok(NetEvent::Kicked(r)) => summary.kicked = Some(r),
+			Ok(NetEvent::Rejected(r)) => anyhow::bail!("refused, {r}"),
+			Ok(NetEvent::Disconnected) | Err(RecvTimeoutError::Disconnected) => break,
+			Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+		}
+
+		// Every tick the clock has reached gets one input, stamped for that tick
+		let Some((at, base)) = clock_at else {
+			continue;
+		};
+		let Some(elapsed) = clock::now().checked_duration_since(at) else {
+			continue;
+		};
+		let clock_tick = base.wrapping_add((elapsed.as_secs_f64() * sim::TPS as f64) as u32);
+		while !is_before(clock_tick, next_tick) {
+			let input = inputs.at(next_tick);
+			let tick = match input_grant {
+				Some(grant) => grant.clamp(next_tick),
+				None => next_tick,
+			};
+			let cmd = window.send(InputMsg {
+				tick,
+				bits: input.bits.as_u8(),
+				aim: input.aim,
+				ack_tick: latest_server_tick,
+				sent_us: clock::wall_us(),
+			});
+			if tx_cmd.send(cmd).is_err() {
+				break;
+			}
+			summary.inputs_sent += 1;
+			stat_inputs.inc();
+			next_tick = next_tick.wrapping_add(1);
+		}
+	}
+	if summary.player.is_some() {
+		stat_playing.add(-1);
+	}
+	Ok(summary)
+}
+
+/// Headless players for load and soak tests: `count` connections to `addr`,
+/// all joining `room` on a lobby server, each following the protocol and
+/// sending an input every tick. Inputs come
+/// from `script` (simulate's format) or are random, seeded per bot. Runs until
+/// the server has dropped every bot.
+pub fn run_bots(
+	addr: &str,
+	room: Option<String>,
+	count: u32,
+	script: Option<&Path>,
+	seed: u32,
+	socket: SocketOptions,
+	transport: InputTransport,
+) -> anyhow::Result<()> {
+	let script = script
+		.map(|p| {
+			let text =
+				std::fs::read_to_string(p).with_context(|| format!("read {}", p.display()))?;
+			simulate::parse_script(&text).with_context(|| p.display().to_string())
+		})
+		.transpose()?;
+	let stat_inputs = stats::counter("bot.inputs_sent");
+	let stat_ticks = stats::counter("bot.ticks_received");
+	let stat_playing = stats::gauge("bot.playing");
+
+	let mut bots = Vec::new();
+	for id in 0..count {
+		let inputs = Inputs {
+			bridge: None,
+			script: script.clone(),
+			rng: Rng::new(seed.wrapping_add(id.wrapping_mul(7919))),
+			held: InputBits::empty().into(),
+		};
+		let addr = addr.to_string();
+		let room = room.clone();
+		bots.push(thread::spawn(move || {
+			run_bot(addr, room, inputs, socket, transport)
+		}));
+		thread::sleep(CONNECT_STAGGER);
+	}
+
+	while bots.iter().any(|b| !b.is_finished()) {
+		thread::sleep(REPORT_INTERVAL);
+		println!(
+			"{}/{count} bots connected, {} playing, {} inputs sent, {} ticks received",
+			bots.iter().filter(|b| !b.is_finished()).count(),
+			stat_playing.get(),
+			stat_inputs.get(),
+			stat_ticks.get()
+		);
+	}
+	for (id, bot) in bots.into_iter().enumerate() {
+		match bot.join() {
+			Ok(Ok(s)) => println!("bot {id}: {}", s.line()),
+			Ok(Err(e)) => println!("bot {id}: {e:#}"),
+			Err(_) => println!("bot {id}: panicked"),
+		}
+	}
+	Ok(())
+}
+
+/// One bot played by another program over `bridge` (see Bridge::open), until
+/// the server drops it. Stdout may be the bridge, so the summary goes to stderr.
+pub fn run_bridged(
+	addr: &str,
+	room: Option<String>,
+	bridge: &str,
+	socket: SocketOptions,
+	transport: InputTransport,
+) -> anyhow::Result<()> {
+	let inputs = Inputs {
+		bridge: Some(Bridge::open(bridge)?),
+		script: None,
+		rng: Rng::new(0),
+		held: InputBits::empty().into(),
+	};
+	let summary = run_bot(addr.to_string(), room, inputs, socket, transport)?;
+	info!("bridged bot: {}", summary.line());
+	Ok(())
+}
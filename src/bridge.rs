@@ -0,0 +1,156 @@
+use std::{
+	collections::BTreeMap,
+	io::{self, BufRead, BufReader, Write},
+	net::TcpListener,
+	sync::{
+		Arc, Mutex,
+		atomic::{AtomicU32, Ordering},
+		mpsc,
+	},
+	thread,
+};
+
+use anyhow::Context;
+use macroquad::prelude::info;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{
+	net::NetEvent,
+	rpc::{self, Out},
+	sim::{InputBits, PlayerInput},
+};
+
+// JSON-RPC error code of ours: the tick's input already went to the server
+const TOO_LATE: i64 = 1;
+
+#[derive(Deserialize)]
+struct InputParams {
+	tick: u32,
+	bits: u8,
+	#[serde(default)]
+	aim: u8,
+}
+
+/// A bot's inputs from another program, so test harnesses and agents in any
+/// language can play without linking this crate. JSON-RPC 2.0, one message
+/// per line, over stdin and stdout or a local TCP connection.
+///
+/// The program calls `input` with `{"tick", "bits", "aim"}`: `bits` are
+/// sim::InputBits (1 left, 2 right, 4 jump, 8 fire, 16 ready, 32 attack),
+/// `aim` the quantized angle, 0 when left out. A tick without an input of its own
+/// repeats the last one before it. Inputs for ticks the bot already sent are refused.
+/// The bridge tells the program `start` (`{"player", "tick"}`) when it gets a
+/// slot, `tick` (`{"tick", "inputs", "aims"}`) for every tick the server
+/// confirms, and `spectate` and `end` when it has none.
+pub struct Bridge {
+	rx: mpsc::Receiver<(u32, PlayerInput)>,
+	out: Out,
+	// Inputs for ticks not sent yet, and the last one that was
+	queued: BTreeMap<u32, PlayerInput>,
+	held: PlayerInput,
+	// First tick the bot hasn't sent an input for, shared with the reader
+	next_tick: Arc<AtomicU32>,
+}
+
+impl Bridge {
+	/// `-` bridges stdin and stdout, anything else is an address to listen on
+	/// for the one connection.
+	pub fn open(source: &str) -> anyhow::Result<Self> {
+		let (read, write): (Box<dyn BufRead + Send>, Box<dyn Write + Send>) = match source {
+			"-" => (
+				Box::new(BufReader::new(io::stdin())),
+				Box::new(io::stdout()),
+			),
+			addr => {
+				let listener = TcpListener::bind(addr).with_context(|| format!("bind {addr}"))?;
+				info!("bridge waiting on {}", listener.local_addr()?);
+				let (stream, peer) = listener.accept().context("accept bridge")?;
+				info!("bridge connected to {}", peer);
+				let read = stream.try_clone().context("clone bridge stream")?;
+				(Box::new(BufReader::new(read)), Box::new(stream))
+			}
+		};
+		let out: Out = Arc::new(Mutex::new(write));
+		let next_tick = Arc::new(AtomicU32::new(0));
+		let (tx, rx) = mpsc::channel();
+		let (reader_out, reader_next) = (out.clone(), next_tick.clone());
+		thread::spawn(move || {
+			rpc::serve(read, &reader_out, |method, params| {
+				handle(method, params, &reader_next, &tx)
+			});
+		});
+		Ok(Self {
+			rx,
+			out,
+			queued: BTreeMap::new(),
+			held: InputBits::empty().into(),
+			next_tick,
+		})
+	}
+
+	// The input for `tick`, which the bot is about to send
+	pub fn input(&mut self, tick: u32) -> PlayerInput {
+		self.queued.extend(self.rx.try_iter());
+		let later = self.queued.split_off(&(tick + 1));
+		if let Some((_, &input)) = self.queued.last_key_value() {
+			self.held = input;
+		}
+		self.queued = later;
+		self.next_tick.store(tick + 1, Ordering::Relaxed);
+		self.held
+	}
+
+	// Passes what the bot hears on to the program
+	pub fn observe(&mut self, ev: &NetEvent) {
+		let start = match ev {
+			NetEvent::AssignStart(a) => Some((a.player_id, 0)),
+			NetEvent::Resume(r) => Some((r.player_id, r.tick)),
+			NetEvent::Control(c) => Some((c.player_id, c.tick)),
+			_ => None,
+		};
+		// Sending starts over at `tick`, inputs queued for before it mean nothing
+		if let Some((_, tick)) = start {
+			self.queued.extend(self.rx.try_iter());
+			self.queued.retain(|&t, _| t >= tick);
+			self.held = InputBits::empty().into();
+			self.next_tick.store(tick, Ordering::Relaxed);
+		}
+		let (method, params) = match (ev, start) {
+			(_, Some((player, tick))) => ("start", json!({"player": player, "tick": tick})),
+			(NetEvent::SpectateStart(s), _) => ("spectate", json!({"tick": s.tick})),
+			(NetEvent::TickInputs(t), _) => (
+				"tick",
+				json!({"tick": t.msg.tick, "inputs": t.msg.inputs, "aims": t.msg.aims}),
+			),
+			(NetEvent::Kicked(r), _) => ("end", json!({"reason": format!("{r:?}")})),
+			(NetEvent::Rejected(r), _) => ("end", json!({"reason": r.to_string()})),
+			(NetEvent::Disconnected, _) => ("end", json!({"reason": "disconnected"})),
+			_ => return,
+		};
+		rpc::notify(&self.out, method, params);
+	}
+}
+
+fn handle(
+	method: &str,
+	params: Value,
+	next_tick: &AtomicU32,
+	tx: &mpsc::Sender<(u32, PlayerInput)>,
+) -> Result<Value, rpc::Error> {
+	match method {
+		"input" => {
+			let p: InputParams = rpc::params(params)?;
+			let next = next_tick.load(Ordering::Relaxed);
+			if p.tick < next {
+				return Err((
+					TOO_LATE,
+					format!("tick {} was sent, next is {next}", p.tick),
+				));
+			}
+			let _ = tx.send((p.tick, PlayerInput::new(p.bits, p.aim)));
+			Ok(Value::Null)
+		}
+		m => Err(rpc::no_method(m)),
+	}
+}
@@ -0,0 +1,103 @@
+//! What the client needs from the page when it runs in a browser: a WebSocket
+//! to the server, its address and random bytes. web/repl_net.js provides them
+//! as a miniquad plugin. A browser's sockets never block, the page buffers
+//! what arrives and the frame loop takes it from there, see net::pump.
+
+// Bumped along with the plugin in web/repl_net.js, miniquad warns on a mismatch
+const PLUGIN_VERSION: u32 = 1;
+
+unsafe extern "C" {
+	// A socket id, the connection opens in the background
+	fn repl_ws_open(url: *const u8, len: usize) -> i32;
+	// 0 while connecting, 1 open, 2 closed or failed
+	fn repl_ws_state(id: i32) -> i32;
+	fn repl_ws_send(id: i32, data: *const u8, len: usize);
+	// Length of the oldest message, -1 when none is buffered
+	fn repl_ws_recv_len(id: i32) -> i32;
+	// Copies the oldest message out and drops it from the buffer
+	fn repl_ws_recv(id: i32, buf: *mut u8, len: usize);
+	fn repl_ws_close(id: i32);
+	// Length of the server address the page was opened for, written into `buf`
+	// if it fits
+	fn repl_server_addr(buf: *mut u8, len: usize) -> usize;
+	fn repl_random(buf: *mut u8, len: usize);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn repl_net_crate_version() -> u32 {
+	PLUGIN_VERSION
+}
+
+/// One of the page's WebSockets, each message one frame of the game protocol.
+pub struct Socket(i32);
+
+impl Socket {
+	pub fn open(url: &str) -> Self {
+		Self(unsafe { repl_ws_open(url.as_ptr(), url.len()) })
+	}
+
+	pub fn is_open(&self) -> bool {
+		unsafe { repl_ws_state(self.0) == 1 }
+	}
+
+	pub fn is_closed(&self) -> bool {
+		unsafe { repl_ws_state(self.0) == 2 }
+	}
+
+	// Sent once the socket is open, the page holds on to it until then
+	pub fn send(&self, frame: &[u8]) {
+		unsafe { repl_ws_send(self.0, frame.as_ptr(), frame.len()) }
+	}
+
+	pub fn recv(&self) -> Option<Vec<u8>> {
+		let len = usize::try_from(unsafe { repl_ws_recv_len(self.0) }).ok()?;
+		let mut buf = vec![0u8; len];
+		unsafe { repl_ws_recv(self.0, buf.as_mut_ptr(), len) };
+		Some(buf)
+	}
+
+	pub fn close(&self) {
+		unsafe { repl_ws_close(self.0) }
+	}
+}
+
+impl Drop for Socket {
+	fn drop(&mut self) {
+		self.close();
+	}
+}
+
+/// The `ws://` address of the server the page belongs to, `?server=` overrides
+/// it.
+pub fn server_addr() -> String {
+	let mut buf = vec![0u8; 256];
+	let len = unsafe { repl_server_addr(buf.as_mut_ptr(), buf.len()) };
+	if len > buf.len() {
+		buf.resize(len, 0);
+		unsafe { repl_server_addr(buf.as_mut_ptr(), buf.len()) };
+	}
+	buf.truncate(len);
+	String::from_utf8_lossy(&buf).into_owned()
+}
+
+fn fill_random(dest: &mut [u8]) {
+	unsafe { repl_random(dest.as_mut_ptr(), dest.len()) }
+}
+
+// Both getrandom versions in the tree look for a custom source on wasm32, see
+// .cargo/config.toml
+#[unsafe(no_mangle)]
+unsafe extern "Rust" fn __getrandom_v03_custom(
+	dest: *mut u8,
+	len: usize,
+) -> Result<(), getrandom::Error> {
+	fill_random(unsafe { std::slice::from_raw_parts_mut(dest, len) });
+	Ok(())
+}
+
+fn getrandom_v02(dest: &mut [u8]) -> Result<(), getrandom_v02::Error> {
+	fill_random(dest);
+	Ok(())
+}
+
+getrandom_v02::register_custom_getrandom!(getrandom_v02);
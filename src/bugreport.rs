@@ -0,0 +1,68 @@
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use macroquad::texture::Image;
+use serde::Serialize;
+
+use crate::{
+	clock,
+	sim::{self, SimState},
+};
+
+#[derive(Serialize)]
+struct Meta<'a> {
+	player_id: u8,
+	tick: u32,
+	local_tick: u32,
+	confirmed_tick: u32,
+	// Hex, JSON numbers lose precision past 2^53
+	local_checksum: String,
+	server_checksum: String,
+	local_state: &'a SimState,
+	server_state: &'a SimState,
+}
+
+/// A confirmed tick where our state and the server's snapshot disagree,
+/// written out with what was on screen so it can be attached to an issue.
+pub struct DesyncReport {
+	pub player_id: u8,
+	// The disagreeing tick
+	pub tick: u32,
+	pub local_tick: u32,
+	pub confirmed_tick: u32,
+	pub local: SimState,
+	pub server: SimState,
+}
+
+impl DesyncReport {
+	// `seen` is the last rendered frame, `confirmed` the server's state drawn the
+	// same way. Everything goes into a new folder under `dir`, which is returned
+	pub fn write(&self, dir: &Path, seen: &Image, confirmed: &Image) -> anyhow::Result<PathBuf> {
+		let folder = dir.join(format!("desync-t{}-{}", self.tick, clock::wall_us() / 1000));
+		fs::create_dir_all(&folder).with_context(|| format!("create {}", folder.display()))?;
+
+		let meta = Meta {
+			player_id: self.player_id,
+			tick: self.tick,
+			local_tick: self.local_tick,
+			confirmed_tick: self.confirmed_tick,
+			local_checksum: format!("{:016x}", sim::checksum(&self.local)),
+			server_checksum: format!("{:016x}", sim::checksum(&self.server)),
+			local_state: &self.local,
+			server_state: &self.server,
+		};
+		fs::write(
+			folder.join("meta.json"),
+			serde_json::to_string_pretty(&meta)?,
+		)
+		.context("write meta.json")?;
+		for (name, image) in [("seen.png", seen), ("confirmed.png", confirmed)] {
+			let path = folder.join(name);
+			image.export_png(path.to_str().context("non-utf8 bug report path")?);
+		}
+		Ok(folder)
+	}
+}
@@ -0,0 +1,123 @@
+use std::{
+	collections::BTreeMap,
+	fs,
+	io::ErrorKind,
+	path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PlayerRecord {
+	pub matches: u32,
+	pub wins: u32,
+	pub losses: u32,
+	// Inputs that arrived after their tick ran
+	pub late_inputs: u64,
+	// One-way input latency, averaged over every sample the server took
+	pub latency_samples: u64,
+	pub mean_latency_ms: f64,
+}
+
+impl PlayerRecord {
+	pub fn add_latency_samples(&mut self, samples_us: &[u32]) {
+		for &us in samples_us {
+			self.latency_samples += 1;
+			let ms = us as f64 / 1000.0;
+			self.mean_latency_ms += (ms - self.mean_latency_ms) / self.latency_samples as f64;
+		}
+	}
+
+	// Folds a finished match into the career record
+	pub fn merge_match(&mut self, m: &PlayerRecord) {
+		self.matches += m.matches;
+		self.wins += m.wins;
+		self.losses += m.losses;
+		self.late_inputs += m.late_inputs;
+		let samples = self.latency_samples + m.latency_samples;
+		if samples > 0 {
+			self.mean_latency_ms = (self.mean_latency_ms * self.latency_samples as f64
+				+ m.mean_latency_ms * m.latency_samples as f64)
+				/ samples as f64;
+		}
+		self.latency_samples = samples;
+	}
+}
+
+/// Career records across matches and server runs, keyed by the player's slot
+/// token. Only reserved slots keep their token between runs, everyone else
+/// starts a fresh record each time.
+pub struct CareerStore {
+	path: PathBuf,
+	// Tokens as written on the command line, JSON object keys have to be strings
+	players: BTreeMap<String, PlayerRecord>,
+}
+
+pub fn token_key(token: u64) -> String {
+	token.to_string()
+}
+
+impl CareerStore {
+	// A missing file is an empty store
+	pub fn open(path: &Path) -> anyhow::Result<Self> {
+		let players = match fs::read_to_string(path) {
+			Ok(text) => serde_json::from_str(&text).context("parse player stats")?,
+			Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+			Err(e) => return Err(e).context("read player stats"),
+		};
+		Ok(Self {
+			path: path.to_path_buf(),
+			players,
+		})
+	}
+
+	pub fn get(&self, token: u64) -> Option<&PlayerRecord> {
+		self.players.get(&token_key(token))
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (&String, &PlayerRecord)> {
+		self.players.iter()
+	}
+
+	pub fn record_match(&mut self, token: u64, m: &PlayerRecord) {
+		self.players
+			.entry(token_key(token))
+			.or_default()
+			.merge_match(m);
+	}
+
+	pub fn save(&self) -> anyhow::Result<()> {
+		// Write then rename, like the match save
+		let tmp = self.path.with_extension("tmp");
+		fs::write(&tmp, serde_json::to_string_pretty(&self.players)?)
+			.context("write player stats")?;
+		fs::rename(&tmp, &self.path).context("replace player stats")?;
+		Ok(())
+	}
+}
+
+/// `stats <player>`: print one player's record, or everyone's without a token.
+pub fn run_player_stats(path: &Path, player: Option<u64>) -> anyhow::Result<()> {
+	let store = CareerStore::open(path)?;
+	let print = |key: &str, r: &PlayerRecord| {
+		println!(
+			"{key}: {} matches, {}-{} (W-L), {:.1}ms mean input latency, {} late inputs",
+			r.matches, r.wins, r.losses, r.mean_latency_ms, r.late_inputs
+		);
+	};
+	match player {
+		Some(token) => {
+			let r = store
+				.get(token)
+				.with_context(|| format!("no record for {token}"))?;
+			print(&token_key(token), r);
+		}
+		None => {
+			for (key, r) in store.iter() {
+				print(key, r);
+			}
+		}
+	}
+	Ok(())
+}
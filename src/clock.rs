@@ -0,0 +1,303 @@
+use std::{collections::VecDeque, sync::OnceLock, time::Duration};
+
+// Samples used to establish the initial offset between the two timelines
+const BASELINE_SAMPLES: u32 = 120;
+
+// EMA factor for the offset, small so jitter averages out and only drift remains
+const DRIFT_SMOOTHING: f64 = 0.002;
+
+// Offsets below this many ticks are left alone
+const DEADBAND_TICKS: f64 = 0.5;
+
+// Max correction speed, in ticks per second of wall time
+const SLEW_TICKS_PER_SEC: f64 = 0.25;
+
+/// Tracks how the client's start_at-based timeline slowly diverges from the
+/// server's, and slews a tick correction towards it.
+///
+/// Samples are `client_tick - server_tick` taken whenever server ticks arrive.
+/// The first samples fix the baseline (lead, latency), anything beyond it is drift.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DriftEstimator {
+	samples: u32,
+	baseline: f64,
+	offset: f64,
+	correction: f64,
+	first_sample_tick: f64,
+	last_sample_tick: f64,
+}
+
+impl DriftEstimator {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn observe(&mut self, client_tick: f64, server_tick: u32) {
+		let sample = client_tick - server_tick as f64;
+		self.last_sample_tick = client_tick;
+		if self.samples < BASELINE_SAMPLES {
+			if self.samples == 0 {
+				self.first_sample_tick = client_tick;
+			}
+			self.samples += 1;
+			self.baseline += (sample - self.baseline) / self.samples as f64;
+			return;
+		}
+		self.offset += (sample - self.baseline - self.offset) * DRIFT_SMOOTHING;
+	}
+
+	pub fn slew(&mut self, dt: f32) {
+		let err = self.offset - self.correction;
+		if err.abs() < DEADBAND_TICKS {
+			return;
+		}
+		let max_step = SLEW_TICKS_PER_SEC * dt as f64;
+		self.correction += err.clamp(-max_step, max_step);
+	}
+
+	// Ticks to subtract from the local clock tick
+	pub fn correction(&self) -> f64 {
+		self.correction
+	}
+
+	// Measured drift in ticks, positive when the local clock runs fast
+	pub fn drift_ticks(&self) -> f64 {
+		self.offset
+	}
+
+	// Measured drift rate in parts per million
+	pub fn drift_ppm(&self) -> f64 {
+		let span = self.last_sample_tick - self.first_sample_tick;
+		if span <= 0.0 {
+			return 0.0;
+		}
+		self.offset / span * 1_000_000.0
+	}
+}
+
+// Wall clock in µs, comparable across machines once the offset is known
+pub fn wall_us() -> u64 {
+	match RATE.get() {
+		Some(r) => r.wall_us + (r.real.elapsed().as_micros() as f64 * r.rate) as u64,
+		None => real_wall_us(),
+	}
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn real_wall_us() -> u64 {
+	use std::time::{SystemTime, UNIX_EPOCH};
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_micros() as u64
+}
+
+#[cfg(target_arch = "wasm32")]
+fn real_wall_us() -> u64 {
+	(macroquad::miniquad::date::now() * 1e6) as u64
+}
+
+// A process running faster than real time, from the moment it was set
+struct Rate {
+	rate: f64,
+	real: Instant,
+	wall_us: u64,
+}
+
+static RATE: OnceLock<Rate> = OnceLock::new();
+
+// How late the runtime's timer may wake a task, it rounds up to whole
+// milliseconds and then has to be scheduled
+const TIMER_RESOLUTION: Duration = Duration::from_millis(2);
+
+/// Runs the clock of the server and bots `rate` times faster than real time,
+/// now, sleep and wall_us all follow it. Set once before either starts, a
+/// stress run compresses hours of play into minutes with it.
+pub fn set_rate(rate: f64) {
+	let _ = RATE.set(Rate {
+		rate,
+		real: Instant::now(),
+		wall_us: real_wall_us(),
+	});
+}
+
+pub fn now() -> Instant {
+	match RATE.get() {
+		Some(r) => r.real + r.real.elapsed().mul_f64(r.rate),
+		None => Instant::now(),
+	}
+}
+
+// Async, the server's tasks mustn't hold up the thread they run on
+pub async fn sleep(d: Duration) {
+	let Some(r) = RATE.get() else {
+		tokio::time::sleep(d).await;
+		return;
+	};
+	// Sped up, waits go below the runtime timer's millisecond. It sleeps the
+	// whole ones and yields through the rest
+	let d = d.div_f64(r.rate);
+	let until = Instant::now() + d;
+	if d > TIMER_RESOLUTION {
+		tokio::time::sleep(d - TIMER_RESOLUTION).await;
+	}
+	while Instant::now() < until {
+		tokio::task::yield_now().await;
+	}
+}
+
+/// Monotonic time for everything the client runs. The browser has no std
+/// clock, there it's the page's clock as miniquad reads it.
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::Instant;
+
+#[cfg(target_arch = "wasm32")]
+pub use browser_clock::Instant;
+
+#[cfg(target_arch = "wasm32")]
+mod browser_clock {
+	use std::{
+		cmp::Ordering,
+		ops::{Add, AddAssign, Sub, SubAssign},
+		time::Duration,
+	};
+
+	// Seconds since the epoch
+	#[derive(Debug, Clone, Copy, PartialEq)]
+	pub struct Instant(f64);
+
+	impl Eq for Instant {}
+
+	impl PartialOrd for Instant {
+		fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+			Some(self.cmp(other))
+		}
+	}
+
+	impl Ord for Instant {
+		fn cmp(&self, other: &Self) -> Ordering {
+			self.0.total_cmp(&other.0)
+		}
+	}
+
+	impl Instant {
+		pub fn now() -> Self {
+			Self(macroquad::miniquad::date::now())
+		}
+
+		pub fn elapsed(&self) -> Duration {
+			Self::now().saturating_duration_since(*self)
+		}
+
+		pub fn duration_since(&self, earlier: Self) -> Duration {
+			self.saturating_duration_since(earlier)
+		}
+
+		pub fn saturating_duration_since(&self, earlier: Self) -> Duration {
+			self.checked_duration_since(earlier).unwrap_or_default()
+		}
+
+		pub fn checked_duration_since(&self, earlier: Self) -> Option<Duration> {
+			Duration::try_from_secs_f64(self.0 - earlier.0).ok()
+		}
+
+		pub fn checked_sub(&self, d: Duration) -> Option<Self> {
+			Some(Self(self.0 - d.as_secs_f64()))
+		}
+	}
+
+	impl Add<Duration> for Instant {
+		type Output = Self;
+
+		fn add(self, d: Duration) -> Self {
+			Self(self.0 + d.as_secs_f64())
+		}
+	}
+
+	impl AddAssign<Duration> for Instant {
+		fn add_assign(&mut self, d: Duration) {
+			self.0 += d.as_secs_f64();
+		}
+	}
+
+	impl Sub<Duration> for Instant {
+		type Output = Self;
+
+		fn sub(self, d: Duration) -> Self {
+			Self(self.0 - d.as_secs_f64())
+		}
+	}
+
+	impl SubAssign<Duration> for Instant {
+		fn sub_assign(&mut self, d: Duration) {
+			self.0 -= d.as_secs_f64();
+		}
+	}
+
+	impl Sub for Instant {
+		type Output = Duration;
+
+		fn sub(self, earlier: Self) -> Duration {
+			self.saturating_duration_since(earlier)
+		}
+	}
+}
+
+// Ping samples kept, the one with the shortest round trip wins
+const OFFSET_SAMPLES: usize = 16;
+
+// Gain of the jitter estimate, RTP's 1/16
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// NTP-style estimate of the server's wall clock relative to ours.
+///
+/// The shortest round trip is the least likely to have queued in one
+/// direction only, so its offset is trusted over the rest.
+#[derive(Debug, Clone, Default)]
+pub struct ClockOffset {
+	// (round trip, server minus client), µs
+	samples: VecDeque<(i64, i64)>,
+	// Smoothed difference between consecutive round trips, µs
+	jitter: Option<f64>,
+}
+
+impl ClockOffset {
+	// t0 ping sent, t1 server received, t2 server replied, t3 pong received
+	pub fn observe(&mut self, t0: u64, t1: u64, t2: u64, t3: u64) {
+		let (t0, t1, t2, t3) = (t0 as i64, t1 as i64, t2 as i64, t3 as i64);
+		let rtt = (t3 - t0) - (t2 - t1);
+		let offset = ((t1 - t0) + (t2 - t3)) / 2;
+		if let Some(&(last, _)) = self.samples.back() {
+			let diff = (rtt.max(0) - last).abs() as f64;
+			let jitter = self.jitter.unwrap_or(diff);
+			self.jitter = Some(jitter + (diff - jitter) * JITTER_GAIN);
+		}
+		self.samples.push_back((rtt.max(0), offset));
+		if self.samples.len() > OFFSET_SAMPLES {
+			self.samples.pop_front();
+		}
+	}
+
+	// Round trip of the newest pong, µs
+	pub fn last_rtt_us(&self) -> Option<i64> {
+		self.samples.back().map(|s| s.0)
+	}
+
+	// Median round trip of the kept samples, µs. Steadier than the newest, and
+	// follows a changed latency sooner than the shortest
+	pub fn rtt_us(&self) -> Option<i64> {
+		let mut rtts: Vec<i64> = self.samples.iter().map(|s| s.0).collect();
+		rtts.sort_unstable();
+		rtts.get(rtts.len() / 2).copied()
+	}
+
+	// How much round trips vary, µs, None before the second pong
+	pub fn jitter_us(&self) -> Option<i64> {
+		self.jitter.map(|j| j.round() as i64)
+	}
+
+	// Server minus client clock, None before the first pong
+	pub fn offset_us(&self) -> Option<i64> {
+		self.samples.iter().min_by_key(|(rtt, _)| *rtt).map(|s| s.1)
+	}
+}
@@ -0,0 +1,114 @@
+use std::{
+	io::BufReader,
+	net::TcpListener,
+	sync::{Arc, Mutex, mpsc},
+	thread,
+	time::Duration,
+};
+
+use anyhow::Context;
+use macroquad::prelude::info;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+
+use crate::{rpc, stats};
+
+// How long a call waits for the loop it's for, which looks once a frame or tick
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(2);
+
+// JSON-RPC error codes of ours: the loop didn't answer in time, or can't do it
+const UNANSWERED: i64 = 1;
+const REFUSED: i64 = 2;
+
+/// What an orchestrator asks of a running client or server loop.
+#[derive(Debug, Clone)]
+pub enum Command {
+	// Artificial latency like the arrow keys set, clients only
+	SetDelay(u32),
+	// A client drops its connection and reconnects like after any drop, a
+	// server drops the player's
+	Disconnect(Option<usize>),
+	// The current tick and state
+	Dump,
+}
+
+/// A command for the loop, which answers with `reply`.
+#[derive(Debug, Clone)]
+pub struct Request {
+	pub command: Command,
+	reply: mpsc::Sender<Result<Value, String>>,
+}
+
+impl Request {
+	pub fn reply(self, result: Result<Value, String>) {
+		let _ = self.reply.send(result);
+	}
+}
+
+#[derive(Deserialize)]
+struct DelayParams {
+	ms: u32,
+}
+
+#[derive(Deserialize)]
+struct DisconnectParams {
+	player: Option<usize>,
+}
+
+/// Listens on `addr` for orchestrators scripting end-to-end tests, JSON-RPC
+/// lines like the input bridge. `stats` answers with every stat, `set_delay`
+/// (`{"ms"}`), `disconnect` (`{"player"}` on a server) and `dump` go to the
+/// loop through `forward`. Anyone who can reach `addr` controls the process.
+pub fn spawn(addr: &str, forward: impl Fn(Request) + Clone + Send + 'static) -> anyhow::Result<()> {
+	let listener = TcpListener::bind(addr).with_context(|| format!("bind control {addr}"))?;
+	info!("control on {}", listener.local_addr()?);
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			let Ok(stream) = stream else { continue };
+			let Ok(read) = stream.try_clone() else {
+				continue;
+			};
+			let forward = forward.clone();
+			thread::spawn(move || {
+				let out: rpc::Out = Arc::new(Mutex::new(Box::new(stream)));
+				rpc::serve(BufReader::new(read), &out, |method, params| {
+					handle(method, params, &forward)
+				});
+			});
+		}
+	});
+	Ok(())
+}
+
+fn handle(method: &str, params: Value, forward: &impl Fn(Request)) -> Result<Value, rpc::Error> {
+	let command = match method {
+		"stats" => {
+			let stats: Map<String, Value> = stats::snapshot()
+				.into_iter()
+				.map(|(name, v)| (name.to_string(), v.into()))
+				.collect();
+			return Ok(stats.into());
+		}
+		"set_delay" => Command::SetDelay(rpc::params::<DelayParams>(params)?.ms),
+		"disconnect" if params.is_null() => Command::Disconnect(None),
+		"disconnect" => Command::Disconnect(rpc::params::<DisconnectParams>(params)?.player),
+		"dump" => Command::Dump,
+		m => return Err(rpc::no_method(m)),
+	};
+	let (reply, answer) = mpsc::channel();
+	forward(Request { command, reply });
+	match answer.recv_timeout(ANSWER_TIMEOUT) {
+		Ok(Ok(v)) => Ok(v),
+		Ok(Err(msg)) => Err((REFUSED, msg)),
+		Err(_) => Err((UNANSWERED, "the loop didn't answer".to_string())),
+	}
+}
+
+// What `dump` answers with
+pub fn dump(tick: u32, state: &crate::sim::SimState) -> Value {
+	json!({
+		"tick": tick,
+		"checksum": format!("{:016x}", crate::sim::checksum(state)),
+		"state": state,
+	})
+}
@@ -0,0 +1,324 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	protocol::TickInputs,
+	replay::Replay,
+	sim::{self, SimState},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpTick {
+	// Inputs the client simulated this tick with, `tick` is the dumped tick
+	pub inputs: TickInputs,
+	// Checksum of the client's state after the tick
+	pub checksum: u64,
+}
+
+/// A client's view of the confirmed part of a match, written when a player
+/// suspects a desync so it can be checked against the server's recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DesyncDump {
+	pub player_id: u8,
+	// State right before the first dumped tick
+	pub start_state: SimState,
+	pub ticks: Vec<DumpTick>,
+}
+
+impl DesyncDump {
+	pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+		fs::write(path, bincode::serialize(self)?).context("write dump")?;
+		Ok(())
+	}
+
+	pub fn read(path: &Path) -> anyhow::Result<Self> {
+		let bytes = fs::read(path).context("read dump")?;
+		Ok(bincode::deserialize(&bytes)?)
+	}
+}
+
+// Whether a match's ticks include `first..=last`. Ticks wrap, a match's are
+// counted from its first
+fn covers(ticks: &[TickInputs], first: u32, last: u32) -> bool {
+	ticks.first().is_some_and(|start| {
+		let (from, to) = (
+			first.wrapping_sub(start.tick),
+			last.wrapping_sub(start.tick),
+		);
+		from <= to && (to as usize) < ticks.len()
+	})
+}
+
+// The last match covering ticks `first..=last` of the dump
+fn server_match(replay: &Replay, first: u32, last: u32) -> Option<&[TickInputs]> {
+	replay
+		.matches()
+		.into_iter()
+		.rev()
+		.find(|ticks| covers(ticks, first, last))
+}
+
+// The server's inputs for `tick`, a match's ticks run on from its first
+fn server_tick(ticks: &[TickInputs], tick: u32) -> anyhow::Result<TickInputs> {
+	let Some(start) = ticks.first() else {
+		bail!("the server's match has no ticks");
+	};
+	match ticks.get(tick.wrapping_sub(start.tick) as usize) {
+		Some(t) if t.tick == tick => Ok(t.clone()),
+		_ => bail!("the server's match has no tick {tick}"),
+	}
+}
+
+/// Re-simulate the server's authoritative stream and the client's claimed one
+/// side by side and report where they part ways and why.
+pub fn run_dispute(replay_path: &Path, dump_path: &Path) -> anyhow::Result<()> {
+	let (replay, _) = Replay::read(replay_path)?;
+	let dump = DesyncDump::read(dump_path)?;
+	let (Some(first), Some(last)) = (
+		dump.ticks.first().map(|d| &d.inputs),
+		dump.ticks.last().map(|d| &d.inputs),
+	) else {
+		bail!("dump has no ticks");
+	};
+	let Some(server_ticks) = server_match(&replay, first.tick, last.tick) else {
+		bail!(
+			"replay doesn't cover ticks {}..={} of any match",
+			first.tick,
+			last.tick
+		);
+	};
+	println!(
+		"P{} dump: ticks {}..={} ({} ticks)",
+		dump.player_id,
+		first.tick,
+		last.tick,
+		dump.ticks.len()
+	);
+
+	// Server side, from the match start up to the dump
+	let mut server = replay.start_state();
+	let before = first.tick.wrapping_sub(server_ticks[0].tick) as usize;
+	for t in &server_ticks[..before] {
+		sim::step(&mut server, &t.sim_inputs());
+	}
+	if sim::checksum(&server) != sim::checksum(&dump.start_state) {
+		println!(
+			"start state already differs at tick {}: the desync predates the dump",
+			first.tick
+		);
+	}
+
+	// Client side, replaying its own claims from its own start state
+	let mut client = dump.start_state.clone();
+	let mut input_mismatches = vec![0u32; client.player_count()];
+	let mut first_divergence = None;
+	let mut self_inconsistent = None;
+	for d in &dump.ticks {
+		let tick = d.inputs.tick;
+		let auth = server_tick(server_ticks, tick)?;
+		let auth_inputs = auth.sim_inputs();
+		let claimed = d.inputs.sim_inputs();
+		for (n, (a, c)) in input_mismatches
+			.iter_mut()
+			.zip(auth_inputs.iter().zip(&claimed))
+		{
+			if a != c {
+				*n += 1;
+			}
+		}
+
+		sim::step(&mut client, &claimed);
+		if self_inconsistent.is_none() && sim::checksum(&client) != d.checksum {
+			self_inconsistent = Some(tick);
+		}
+
+		sim::step(&mut server, &auth_inputs);
+		if first_divergence.is_none() && sim::checksum(&server) != d.checksum {
+			first_divergence = Some((tick, auth_inputs, claimed));
+		}
+	}
+
+	for (p, n) in input_mismatches.iter().enumerate().filter(|(_, n)| **n > 0) {
+		println!("P{p}: {n} ticks simulated with inputs the server never confirmed");
+	}
+	if let Some(tick) = self_inconsistent {
+		println!(
+			"computation mismatch at tick {tick}: the client's own inputs don't reproduce its checksum (nondeterminism or a tampered client)"
+		);
+	}
+	match first_divergence {
+		None => println!("no divergence, the client agrees with the server"),
+		Some((tick, auth, claimed)) if auth == claimed => println!(
+			"first divergence at tick {tick}: computation mismatch, same inputs {auth:?} gave a different state"
+		),
+		Some((tick, auth, claimed)) => println!(
+			"first divergence at tick {tick}: input mismatch, server {auth:?} vs client {claimed:?}"
+		),
+	}
+	Ok(())
+}
+
+// One side of a bisection: a match as a replay recorded it or as a client dumped it
+struct Timeline {
+	// State right before the first tick
+	start: SimState,
+	ticks: Vec<TickInputs>,
+	// What the client saw after each tick, a replay's are computed here instead
+	checksums: Option<Vec<u64>>,
+}
+
+// Every match of a replay, or the one stretch of a dump
+fn timelines(path: &Path) -> anyhow::Result<Vec<Timeline>> {
+	if let Ok((replay, _)) = Replay::read(path) {
+		let start = replay.start_state();
+		return Ok(replay
+			.matches()
+			.into_iter()
+			.map(|ticks| Timeline {
+				start: start.clone(),
+				ticks: ticks.to_vec(),
+				checksums: None,
+			})
+			.collect());
+	}
+	let dump = DesyncDump::read(path)
+		.with_context(|| format!("{} is neither a replay nor a dump", path.display()))?;
+	Ok(vec![Timeline {
+		start: dump.start_state,
+		ticks: dump.ticks.iter().map(|d| d.inputs.clone()).collect(),
+		checksums: Some(dump.ticks.iter().map(|d| d.checksum).collect()),
+	}])
+}
+
+// Each tick of a timeline with the state before it and the checksum after it
+struct Step {
+	inputs: TickInputs,
+	before: SimState,
+	after: u64,
+}
+
+fn steps(t: &Timeline) -> Vec<Step> {
+	let mut state = t.start.clone();
+	t.ticks
+		.iter()
+		.enumerate()
+		.map(|(i, inputs)| {
+			let before = state.clone();
+			sim::step(&mut state, &inputs.sim_inputs());
+			let after = match &t.checksums {
+				Some(sums) => sums[i],
+				None => sim::checksum(&state),
+			};
+			Step {
+				inputs: inputs.clone(),
+				before,
+				after,
+			}
+		})
+		.collect()
+}
+
+#[derive(Serialize)]
+struct Side {
+	inputs: TickInputs,
+	before: SimState,
+	// Recomputed here, a dump's own state may differ if the desync is nondeterminism
+	after: SimState,
+	// Hex, JSON numbers lose precision past 2^53
+	checksum: String,
+}
+
+impl Side {
+	fn of(step: &Step) -> Self {
+		let mut after = step.before.clone();
+		sim::step(&mut after, &step.inputs.sim_inputs());
+		Self {
+			inputs: step.inputs.clone(),
+			before: step.before.clone(),
+			after,
+			checksum: format!("{:016x}", step.after),
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct Divergence {
+	tick: u32,
+	// "inputs" when the two sides simulated different inputs, else "computation"
+	cause: &'static str,
+	a: Side,
+	b: Side,
+}
+
+// First tick both cover whose resulting states differ
+fn bisect(a: &Timeline, b: &Timeline) -> Option<Divergence> {
+	let (a, b) = (steps(a), steps(b));
+	let b_first = b.first()?.inputs.tick;
+	let a_first = a.first()?.inputs.tick;
+	// Ticks wrap, whichever starts later is ahead by less than half the range
+	let ahead = b_first.wrapping_sub(a_first) as i32;
+	let (a, b) = if ahead >= 0 {
+		(a.get(ahead as usize..)?, &b[..])
+	} else {
+		(&a[..], b.get(ahead.unsigned_abs() as usize..)?)
+	};
+	let (x, y) = a.iter().zip(b).find(|(x, y)| x.after != y.after)?;
+	let cause = if x.inputs.sim_inputs() == y.inputs.sim_inputs() {
+		"computation"
+	} else {
+		"inputs"
+	};
+	Some(Divergence {
+		tick: x.inputs.tick,
+		cause,
+		a: Side::of(x),
+		b: Side::of(y),
+	})
+}
+
+/// Find the first tick where two recordings of a match part ways, each a replay
+/// or a client's dump, and write both sides' states and inputs there as JSON.
+pub fn run_bisect(a_path: &Path, b_path: &Path, out: Option<&Path>) -> anyhow::Result<()> {
+	let (a, b) = (timelines(a_path)?, timelines(b_path)?);
+	// Replays pair up match by match, a dump goes with the last match covering it
+	let pairs: Vec<(&Timeline, &Timeline)> = match (a.len(), b.len()) {
+		(_, 1) if b[0].checksums.is_some() => covering(&a, &b[0])
+			.map(|m| (m, &b[0]))
+			.into_iter()
+			.collect(),
+		(1, _) if a[0].checksums.is_some() => covering(&b, &a[0])
+			.map(|m| (&a[0], m))
+			.into_iter()
+			.collect(),
+		_ => a.iter().zip(&b).collect(),
+	};
+	if pairs.is_empty() {
+		bail!("the two files share no ticks");
+	}
+	for (n, (x, y)) in pairs.into_iter().enumerate() {
+		let Some(d) = bisect(x, y) else {
+			continue;
+		};
+		println!(
+			"match {n}: first divergence at tick {} ({} mismatch)",
+			d.tick, d.cause
+		);
+		let json = serde_json::to_string_pretty(&d)?;
+		match out {
+			Some(path) => {
+				fs::write(path, json).with_context(|| format!("write {}", path.display()))?
+			}
+			None => println!("{json}"),
+		}
+		return Ok(());
+	}
+	println!("no divergence, both sides agree on every tick they share");
+	Ok(())
+}
+
+fn covering<'a>(matches: &'a [Timeline], dump: &Timeline) -> Option<&'a Timeline> {
+	let (first, last) = (dump.ticks.first()?.tick, dump.ticks.last()?.tick);
+	matches.iter().rev().find(|m| covers(&m.ticks, first, last))
+}
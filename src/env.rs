@@ -0,0 +1,181 @@
+use crate::{
+	num::to_f32,
+	sim::{self, BUFFER_H, BUFFER_W, InputBits, Player, SimState},
+};
+
+// Values per player in an observation: x, y, vx, vy
+pub const OBS_PER_PLAYER: usize = 4;
+
+// OBS_PER_PLAYER values of every player in turn
+pub type Observation = Vec<f32>;
+
+#[derive(Debug, Clone)]
+pub struct StepResult {
+	pub observation: Observation,
+	// One per player
+	pub rewards: Vec<f32>,
+	pub done: bool,
+}
+
+/// Gym-style wrapper around the deterministic sim.
+///
+/// Player 0 is the chaser and every other player an evader: rewards are the
+/// normalized distance between them, negated for the chaser.
+pub struct Env {
+	players: usize,
+	state: SimState,
+	tick: u32,
+	max_ticks: u32,
+}
+
+impl Env {
+	pub fn new(players: usize, max_ticks: u32) -> Self {
+		Self {
+			players,
+			state: SimState::new(players),
+			tick: 0,
+			max_ticks,
+		}
+	}
+
+	pub fn reset(&mut self) -> Observation {
+		self.state = SimState::new(self.players);
+		self.tick = 0;
+		self.observe()
+	}
+
+	// An action for every player
+	pub fn step(&mut self, actions: &[InputBits]) -> StepResult {
+		let inputs: Vec<_> = actions.iter().map(|&a| a.into()).collect();
+		sim::step(&mut self.state, &inputs);
+		self.tick = self.tick.wrapping_add(1);
+		StepResult {
+			observation: self.observe(),
+			rewards: self.reward(),
+			done: self.tick >= self.max_ticks,
+		}
+	}
+
+	pub fn observe(&self) -> Observation {
+		let mut obs = vec![0.0; OBS_PER_PLAYER * self.players];
+		for (i, p) in self.state.players.iter().enumerate() {
+			let o = &mut obs[i * OBS_PER_PLAYER..(i + 1) * OBS_PER_PLAYER];
+			o[0] = to_f32(p.x) / (BUFFER_W as f32 - to_f32(Player::W));
+			o[1] = to_f32(p.y) / (BUFFER_H as f32 - to_f32(Player::H));
+			o[2] = to_f32(p.vx) / 100.0;
+			o[3] = to_f32(p.vy) / 100.0;
+		}
+		obs
+	}
+
+	// Player 0 chases, everyone else runs from it. The chaser is rewarded by
+	// how close the nearest runner is
+	pub fn reward(&self) -> Vec<f32> {
+		let chaser = self.state.players[0];
+		let max_dist = ((BUFFER_W as f32).powi(2) + (BUFFER_H as f32).powi(2)).sqrt();
+		let mut rewards: Vec<f32> = self
+			.state
+			.players
+			.iter()
+			.map(|p| {
+				let (dx, dy) = (to_f32(p.x - chaser.x), to_f32(p.y - chaser.y));
+				(dx.powi(2) + dy.powi(2)).sqrt() / max_dist
+			})
+			.collect();
+		rewards[0] = -rewards[1..].iter().copied().fold(f32::INFINITY, f32::min);
+		rewards
+	}
+
+	pub fn tick(&self) -> u32 {
+		self.tick
+	}
+}
+
+// xorshift32, enough for reproducible exploration without a rand dependency
+#[derive(Debug, Clone)]
+pub struct Rng(u32);
+
+impl Rng {
+	pub fn new(seed: u32) -> Self {
+		Self(seed.max(1))
+	}
+
+	pub fn next_u32(&mut self) -> u32 {
+		let mut x = self.0;
+		x ^= x << 13;
+		x ^= x >> 17;
+		x ^= x << 5;
+		self.0 = x;
+		x
+	}
+}
+
+/// Built-in self-play policy: mostly follow (or flee from) the opponent,
+/// with random jumps and occasional random moves for exploration.
+pub fn self_play_policy(obs: &[f32], player: usize, rng: &mut Rng) -> InputBits {
+	let me = obs[player * OBS_PER_PLAYER];
+	// The chaser follows player 1, runners watch the chaser
+	let target = if player == 0 { 1 } else { 0 };
+	let other = obs[target * OBS_PER_PLAYER];
+	let mut bits = InputBits::empty();
+
+	if rng.next_u32().is_multiple_of(8) {
+		bits = InputBits::from_u8(rng.next_u32() as u8);
+	} else {
+		let towards_right = other > me;
+		let go_right = if player == 0 {
+			towards_right
+		} else {
+			!towards_right
+		};
+		bits |= if go_right {
+			InputBits::RIGHT
+		} else {
+			InputBits::LEFT
+		};
+	}
+	if rng.next_u32().is_multiple_of(30) {
+		bits |= InputBits::JUMP;
+	}
+	bits
+}
+
+pub fn run_self_play(
+	players: usize,
+	episodes: u32,
+	episode_ticks: u32,
+	seed: u32,
+) -> anyhow::Result<()> {
+	let mut env = Env::new(players, episode_ticks);
+	let mut rng = Rng::new(seed);
+
+	for episode in 0..episodes {
+		let mut obs = env.reset();
+		let mut returns = vec![0.0f32; players];
+		loop {
+			let actions: Vec<_> = (0..players)
+				.map(|p| self_play_policy(&obs, p, &mut rng))
+				.collect();
+			let r = env.step(&actions);
+			for (ret, reward) in returns.iter_mut().zip(r.rewards) {
+				*ret += reward;
+			}
+			obs = r.observation;
+			if r.done {
+				break;
+			}
+		}
+		let returns: Vec<String> = returns
+			.iter()
+			.enumerate()
+			.map(|(p, r)| format!("p{p}={r:.2}"))
+			.collect();
+		println!(
+			"episode {episode}: ticks={} return {}",
+			env.tick(),
+			returns.join(" ")
+		);
+	}
+
+	Ok(())
+}
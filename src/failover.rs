@@ -0,0 +1,131 @@
+use std::{sync::mpsc, time::Duration};
+
+use macroquad::prelude::warn;
+
+use crate::{
+	clock::Instant,
+	net::{self, InputTransport, NetCmd, NetEvent},
+	protocol::Reject,
+	queue,
+	sockopt::SocketOptions,
+};
+
+// How often a standby that failed or dropped is opened again
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+type Channels = (queue::Receiver<NetEvent>, mpsc::Sender<NetCmd>);
+
+/// --backup-addr: a standby connection to the same server over another
+/// address, held open next to the one we play over. When that one drops or
+/// goes quiet the standby claims our slot at once, no reconnect to wait for,
+/// and the server answers with a Resume from the same match and clock, so the
+/// timeline carries on where it is.
+pub struct Standby {
+	// Where the standby connects, the old primary's address after a failover
+	addr: String,
+	room: Option<String>,
+	signing_key: Option<[u8; 32]>,
+	socket: SocketOptions,
+	transport: InputTransport,
+	// The connection and the slot token it's for, it can take over once welcomed
+	conn: Option<(Channels, u64)>,
+	welcomed: bool,
+	last_attempt: Option<Instant>,
+	// The server doesn't know standbys, there's no use asking again
+	refused: bool,
+}
+
+impl Standby {
+	pub fn new(
+		addr: String,
+		room: Option<String>,
+		signing_key: Option<[u8; 32]>,
+		socket: SocketOptions,
+		transport: InputTransport,
+	) -> Self {
+		Self {
+			addr,
+			room,
+			signing_key,
+			socket,
+			transport,
+			conn: None,
+			welcomed: false,
+			last_attempt: None,
+			refused: false,
+		}
+	}
+
+	// Keeps a standby open for the slot `token` is for, none without a slot.
+	// Called every frame
+	pub fn maintain(&mut self, token: Option<u64>) {
+		if self.refused {
+			return;
+		}
+		// A new match's token, or no slot, leaves the one we have useless
+		if self.conn.as_ref().is_some_and(|&(_, t)| Some(t) != token) {
+			self.close();
+		}
+		let mut dropped = false;
+		if let Some(((rx, _), _)) = &self.conn {
+			while let Ok(ev) = rx.try_recv() {
+				match ev {
+					NetEvent::Welcome(_) => self.welcomed = true,
+					// The slot may be one the server hasn't got back to yet
+					NetEvent::Rejected(Reject::NoSlot) => {}
+					NetEvent::Rejected(r) => {
+						warn!("{} refused a standby: {r}", self.addr);
+						self.refused = true;
+					}
+					NetEvent::Disconnected => dropped = true,
+					_ => {}
+				}
+			}
+		}
+		if dropped || self.refused {
+			self.close();
+		}
+		let Some(token) = token.filter(|_| self.conn.is_none() && !self.refused) else {
+			return;
+		};
+		if self
+			.last_attempt
+			.is_some_and(|t| t.elapsed() < RETRY_INTERVAL)
+		{
+			return;
+		}
+		self.last_attempt = Some(Instant::now());
+		if let Ok(conn) = net::spawn_standby(
+			self.addr.clone(),
+			token,
+			self.room.clone(),
+			self.signing_key,
+			self.socket,
+			self.transport,
+		) {
+			self.conn = Some((conn, token));
+		}
+	}
+
+	/// Claims the slot over the standby and hands back its channels, none when
+	/// there's no standby ready. `addr` becomes the standby's address, and the
+	/// next standby goes to the one it had, in case it comes back.
+	pub fn take_over(&mut self, addr: &mut String) -> Option<Channels> {
+		if !self.welcomed {
+			return None;
+		}
+		let ((rx, tx), _) = self.conn.take()?;
+		self.welcomed = false;
+		let _ = tx.send(NetCmd::Failover);
+		std::mem::swap(&mut self.addr, addr);
+		self.last_attempt = Some(Instant::now());
+		Some((rx, tx))
+	}
+
+	fn close(&mut self) {
+		if let Some(((_, tx), _)) = self.conn.take() {
+			let _ = tx.send(NetCmd::Disconnect);
+		}
+		self.welcomed = false;
+	}
+}
@@ -0,0 +1,225 @@
+use macroquad::prelude::*;
+
+use crate::{
+	palette::Palette,
+	rollback::{self, Session},
+	sim::{self, Arena, MAX_PLAYERS, SimEvent, SimState},
+};
+
+// How long a shake or flash lasts
+const EFFECT_SECS: f32 = 0.25;
+
+// Ticks confirmed longer ago than an effect lasts fire nothing, a burst of
+// confirmations after a stall would only flash at once
+const EFFECT_TICKS: u32 = (EFFECT_SECS * sim::TPS as f32) as u32;
+
+// Landings slower than this are just walking off a ledge
+const MIN_LAND_SPEED: f32 = 120.0;
+
+// Shake amplitude in buffer pixels at full intensity
+const MAX_SHAKE_PX: f32 = 3.0;
+
+struct Effect {
+	event: SimEvent,
+	age: f32,
+}
+
+/// Camera shake and flashes driven by confirmed sim events.
+///
+/// Effects fire once the server has confirmed the tick that caused them: the
+/// session's checkpoint is followed on the server's inputs alone, so a
+/// misprediction never shakes the camera for a hit that didn't happen. They
+/// trail the prediction by about the round trip.
+#[derive(Default)]
+pub struct Feedback {
+	effects: Vec<Effect>,
+	// The confirmed state effects have fired up to, before its tick
+	heard: Option<(u32, SimState)>,
+}
+
+impl Feedback {
+	pub fn clear(&mut self) {
+		self.effects.clear();
+		self.heard = None;
+	}
+
+	// Fires the effects of every tick the session confirmed since the last call
+	pub fn confirm(&mut self, session: &Session<Arena>) {
+		let Some((tick, checkpoint)) = session.checkpoint() else {
+			return;
+		};
+		if let Some((mut t, mut state)) = self.heard.take()
+			&& rollback::is_before(t, tick)
+		{
+			// Stops short where the ring lost the inputs, after a resume jumped ahead
+			while t != tick
+				&& let Some(inputs) = session.authoritative(t)
+			{
+				let before = state.clone();
+				sim::step(&mut state, &inputs);
+				if tick.wrapping_sub(t) <= EFFECT_TICKS {
+					self.fire(&sim::events(&before, &state));
+				}
+				t = t.wrapping_add(1);
+			}
+		}
+		// The checkpoint's own from here on, a reseed may have replaced it
+		self.heard = Some((tick, checkpoint.clone()));
+	}
+
+	fn fire(&mut self, events: &[SimEvent]) {
+		for &event in events {
+			if let SimEvent::Land { speed, .. } = event
+				&& speed < MIN_LAND_SPEED
+			{
+				continue;
+			}
+			self.effects.push(Effect { event, age: 0.0 });
+		}
+	}
+
+	// Age effects, forgetting the ones that are over
+	pub fn update(&mut self, dt: f32) {
+		for e in &mut self.effects {
+			e.age += dt;
+		}
+		self.effects.retain(|e| e.age < EFFECT_SECS);
+	}
+
+	fn strength(e: &Effect) -> f32 {
+		(1.0 - e.age / EFFECT_SECS).max(0.0)
+	}
+
+	// Camera offset in buffer pixels
+	pub fn shake(&self) -> Vec2 {
+		let trauma: f32 = self
+			.effects
+			.iter()
+			.map(|e| match e.event {
+				SimEvent::Land { speed, .. } => Self::strength(e) * (speed / 400.0).min(1.0),
+				SimEvent::Hit { .. } => Self::strength(e) * 0.8,
+				SimEvent::HillTaken { .. } => 0.0,
+				SimEvent::ShotFired { .. } => Self::strength(e) * 0.15,
+			})
+			.sum::<f32>()
+			.min(1.0);
+		if trauma <= 0.0 {
+			return Vec2::ZERO;
+		}
+		// Squared trauma so small bumps stay subtle
+		let amp = trauma * trauma * MAX_SHAKE_PX;
+		vec2(
+			rand::gen_range(-amp, amp).round(),
+			rand::gen_range(-amp, amp).round(),
+		)
+	}
+
+	// Flash opacity over each player
+	pub fn flashes(&self) -> [f32; MAX_PLAYERS] {
+		let mut out = [0.0f32; MAX_PLAYERS];
+		for e in &self.effects {
+			let (SimEvent::HillTaken { player } | SimEvent::Hit { player }) = e.event else {
+				continue;
+			};
+			let a = &mut out[player as usize];
+			*a = a.max(Self::strength(e));
+		}
+		out
+	}
+
+	// Muzzle flash opacity of each player
+	pub fn muzzle_flashes(&self) -> [f32; MAX_PLAYERS] {
+		let mut out = [0.0f32; MAX_PLAYERS];
+		for e in &self.effects {
+			if let SimEvent::ShotFired { player, .. } = e.event {
+				let a = &mut out[player as usize];
+				*a = a.max(Self::strength(e));
+			}
+		}
+		out
+	}
+}
+
+// Rollback depth in ticks at which the border flash is at full strength
+const CUE_FULL_DEPTH: u32 = 12;
+
+// Fastest the rollback beep repeats, a burst of rollbacks is one beep
+const BEEP_INTERVAL_SECS: f32 = 0.5;
+
+/// Opt-in cue for noticing netcode activity: a border flash on every rollback,
+/// stronger the deeper it went, and a beep on rollbacks of at least
+/// `beep_depth` ticks. There's no audio backend, the beep is the terminal bell.
+///
+/// The flash blames whoever's input was mispredicted: it takes the color of
+/// that player, who is outlined in it too, so it shows whose connection each
+/// correction came from. A rollback with nobody to blame, a desync corrected
+/// from a snapshot, flashes orange.
+pub struct RollbackCue {
+	beep_depth: Option<u32>,
+	flash: f32,
+	since_beep: f32,
+	// How strongly each player is outlined, and who the border flash is for
+	blame: [f32; MAX_PLAYERS],
+	culprit: Option<usize>,
+}
+
+impl RollbackCue {
+	pub fn new(beep_depth: Option<u32>) -> Self {
+		Self {
+			beep_depth,
+			flash: 0.0,
+			since_beep: BEEP_INTERVAL_SECS,
+			blame: [0.0; MAX_PLAYERS],
+			culprit: None,
+		}
+	}
+
+	// A rollback `depth` ticks deep, for the players whose input was mispredicted
+	pub fn rollback(&mut self, depth: u32, mispredicted: &[bool]) {
+		let strength = (depth as f32 / CUE_FULL_DEPTH as f32).min(1.0);
+		if strength >= self.flash {
+			self.culprit = mispredicted.iter().position(|&m| m);
+		}
+		self.flash = self.flash.max(strength);
+		for (b, _) in self.blame.iter_mut().zip(mispredicted).filter(|(_, m)| **m) {
+			*b = b.max(strength);
+		}
+		if self.beep_depth.is_some_and(|d| depth >= d) && self.since_beep >= BEEP_INTERVAL_SECS {
+			self.since_beep = 0.0;
+			eprint!("\x07");
+		}
+	}
+
+	pub fn update(&mut self, dt: f32) {
+		self.flash = (self.flash - dt / EFFECT_SECS).max(0.0);
+		for b in &mut self.blame {
+			*b = (*b - dt / EFFECT_SECS).max(0.0);
+		}
+		self.since_beep += dt;
+	}
+
+	// Outline opacity of each player
+	pub fn blame(&self) -> [f32; MAX_PLAYERS] {
+		self.blame
+	}
+
+	// The color a player is blamed in, its slot's in the palette whatever its team
+	pub fn blame_color(palette: Palette, player: usize, alpha: f32) -> Color {
+		Color {
+			a: alpha,
+			..palette.team(player as u8)
+		}
+	}
+
+	// Screen space, draw after the buffer is blitted
+	pub fn draw(&self, palette: Palette) {
+		if self.flash <= 0.0 {
+			return;
+		}
+		let color = match self.culprit {
+			Some(p) => Self::blame_color(palette, p, self.flash * 0.6),
+			None => Color::new(1.0, 0.55, 0.1, self.flash * 0.6),
+		};
+		draw_rectangle_lines(0.0, 0.0, screen_width(), screen_height(), 6.0, color);
+	}
+}
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+
+use macroquad::prelude::*;
+
+use crate::hud::{Anchor, Hud};
+
+use crate::clock::Instant;
+
+// Frames in the graph, newest on the right
+const FRAMES: usize = 120;
+
+// The budget at 60 fps, the graph is two of them high
+const BUDGET_MS: f32 = 1000.0 / 60.0;
+
+// Graph size in HUD units, per frame and in total
+const BAR_W: f32 = 2.0;
+const GRAPH_H: f32 = 80.0;
+const LEGEND_H: f32 = 20.0;
+
+/// Where a client frame goes, in the order the loop runs them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+	// Reading events and delivering the due ones
+	Net,
+	// Rolling back and resimulating, hybrid corrections included
+	Rollback,
+	// Stepping forward to the target tick
+	Sim,
+	// Building the frame's draw calls
+	Render,
+	// next_frame: the GPU and vsync, whatever the CPU waits for
+	Present,
+}
+
+const PHASES: [(Phase, &str, Color); 5] = [
+	(Phase::Net, "net", SKYBLUE),
+	(Phase::Rollback, "rollback", ORANGE),
+	(Phase::Sim, "sim", GREEN),
+	(Phase::Render, "render", VIOLET),
+	(Phase::Present, "present", DARKGRAY),
+];
+
+/// Per-frame time breakdown for the F3 overlay, so resimulation spikes show
+/// against the frame budget.
+pub struct FrameTimes {
+	// Milliseconds per phase of past frames, oldest first
+	frames: VecDeque<[f32; PHASES.len()]>,
+	current: [f32; PHASES.len()],
+	last_mark: Instant,
+}
+
+impl Default for FrameTimes {
+	fn default() -> Self {
+		Self {
+			frames: VecDeque::with_capacity(FRAMES),
+			current: [0.0; PHASES.len()],
+			last_mark: Instant::now(),
+		}
+	}
+}
+
+impl FrameTimes {
+	// Charges the time since the last mark to `phase`
+	pub fn mark(&mut self, phase: Phase) {
+		let now = Instant::now();
+		self.current[phase as usize] += now.duration_since(self.last_mark).as_secs_f32() * 1000.0;
+		self.last_mark = now;
+	}
+
+	// Call first thing every frame: what passed since the previous frame's last
+	// mark was spent presenting it
+	pub fn begin_frame(&mut self) {
+		self.mark(Phase::Present);
+		if self.frames.len() == FRAMES {
+			self.frames.pop_front();
+		}
+		self.frames.push_back(std::mem::take(&mut self.current));
+	}
+
+	pub fn draw(&self, hud: &mut Hud, anchor: Anchor) {
+		let s = hud.scale();
+		let font = 16.0 * s;
+		// Legend with each phase's mean over the graph, in ms
+		let n = self.frames.len().max(1) as f32;
+		let legend = PHASES.map(|(phase, label, color)| {
+			let mean = self.frames.iter().map(|f| f[phase as usize]).sum::<f32>() / n;
+			let text = format!("{label} {mean:.1} ");
+			let width = measure_text(&text, None, font as u16, 1.0).width;
+			(text, width, color)
+		});
+		let w = FRAMES as f32 * BAR_W;
+		let legend_w = legend.iter().map(|(_, width, _)| width).sum::<f32>() / s;
+		let at = hud.place(anchor, w.max(legend_w), LEGEND_H + GRAPH_H);
+
+		let mut x = at.x;
+		for (text, width, color) in &legend {
+			draw_text(text, x, at.y + font, font, *color);
+			x += width;
+		}
+
+		let top = at.y + LEGEND_H * s;
+		let bottom = top + GRAPH_H * s;
+		let px_per_ms = GRAPH_H * s / (2.0 * BUDGET_MS);
+		draw_rectangle(
+			at.x,
+			top,
+			w * s,
+			GRAPH_H * s,
+			Color::new(0.0, 0.0, 0.0, 0.5),
+		);
+		let skip = FRAMES - self.frames.len();
+		for (i, frame) in self.frames.iter().enumerate() {
+			let x = at.x + (skip + i) as f32 * BAR_W * s;
+			let mut y = bottom;
+			for (phase, _, color) in PHASES {
+				let h = (frame[phase as usize] * px_per_ms).min(y - top);
+				y -= h;
+				draw_rectangle(x, y, BAR_W * s, h, color);
+			}
+		}
+		let budget_y = bottom - BUDGET_MS * px_per_ms;
+		draw_line(at.x, budget_y, at.x + w * s, budget_y, 1.0, WHITE);
+	}
+}
@@ -0,0 +1,137 @@
+use std::{
+	fs::File,
+	io::Read,
+	sync::{Arc, Mutex},
+	thread,
+};
+
+use anyhow::{Context, bail};
+use macroquad::prelude::{error, vec2};
+
+use crate::sim::{self, InputBits, InputSource};
+
+// Linux joystick events, native endian: u32 ms timestamp, i16 value, u8 type,
+// u8 number
+const EVENT_LEN: usize = 8;
+const JS_EVENT_BUTTON: u8 = 0x01;
+const JS_EVENT_AXIS: u8 = 0x02;
+// Or'ed into the type of the events describing the state on open
+const JS_EVENT_INIT: u8 = 0x80;
+
+// Layout of the xpad driver, which most other pads' drivers follow too
+const AXIS_LEFT_X: u8 = 0;
+const AXIS_RIGHT_X: u8 = 3;
+const AXIS_RIGHT_Y: u8 = 4;
+const AXIS_DPAD_X: u8 = 6;
+const BUTTON_A: u8 = 0;
+const BUTTON_X: u8 = 2;
+const BUTTON_RB: u8 = 5;
+const BUTTON_START: u8 = 7;
+const AXES: usize = 8;
+
+// How far a stick has to lean, out of i16::MAX, to move or to aim
+const MOVE_DEADZONE: i32 = i16::MAX as i32 / 2;
+const AIM_DEADZONE: i32 = i16::MAX as i32 / 3;
+
+struct Pad {
+	axes: [i16; AXES],
+	buttons: u32,
+	// Went down since the last `pressed`
+	taps: InputBits,
+	aim: u8,
+}
+
+impl Pad {
+	const IDLE: Self = Self {
+		axes: [0; AXES],
+		buttons: 0,
+		taps: InputBits::empty(),
+		aim: 0,
+	};
+
+	fn bits(&self) -> InputBits {
+		let x = self.axes[AXIS_LEFT_X as usize] as i32 + self.axes[AXIS_DPAD_X as usize] as i32;
+		let down = |button: u8| self.buttons & (1 << button) != 0;
+		let mut b = InputBits::empty();
+		b.set(InputBits::LEFT, x < -MOVE_DEADZONE);
+		b.set(InputBits::RIGHT, x > MOVE_DEADZONE);
+		b.set(InputBits::JUMP, down(BUTTON_A));
+		b.set(InputBits::FIRE, down(BUTTON_RB));
+		b.set(InputBits::ATTACK, down(BUTTON_X));
+		b.set(InputBits::READY, down(BUTTON_START));
+		b
+	}
+
+	fn apply(&mut self, kind: u8, number: u8, value: i16) {
+		let before = self.bits();
+		match kind & !JS_EVENT_INIT {
+			JS_EVENT_BUTTON if number < 32 => {
+				self.buttons &= !(1 << number);
+				self.buttons |= ((value != 0) as u32) << number;
+			}
+			JS_EVENT_AXIS if (number as usize) < AXES => self.axes[number as usize] = value,
+			_ => return,
+		}
+		let after = self.bits();
+		self.taps |= after - before;
+
+		// The right stick aims where it leans and stays there when let go, until
+		// it's first used the pad aims the way it last moved
+		let (rx, ry) = (
+			self.axes[AXIS_RIGHT_X as usize] as i32,
+			self.axes[AXIS_RIGHT_Y as usize] as i32,
+		);
+		if rx * rx + ry * ry > AIM_DEADZONE * AIM_DEADZONE {
+			self.aim = sim::quantize_aim(vec2(rx as f32, ry as f32));
+		} else if after.contains(InputBits::LEFT) {
+			self.aim = 128;
+		} else if after.contains(InputBits::RIGHT) {
+			self.aim = 0;
+		}
+	}
+}
+
+/// A game controller read from /dev/input/jsN: the left stick or d-pad
+/// moves, A jumps, the right bumper fires, the right stick aims and Start
+/// ends the warm-up. Linux only, other systems would need a backend of their
+/// own.
+pub struct Gamepad {
+	pad: Arc<Mutex<Pad>>,
+}
+
+impl Gamepad {
+	pub fn open(index: u32) -> anyhow::Result<Self> {
+		if !cfg!(target_os = "linux") {
+			bail!("gamepads are only supported on Linux");
+		}
+		let path = format!("/dev/input/js{index}");
+		let mut file = File::open(&path).with_context(|| format!("open {path}"))?;
+		let pad = Arc::new(Mutex::new(Pad::IDLE));
+		let reader = pad.clone();
+		thread::spawn(move || {
+			let mut event = [0; EVENT_LEN];
+			while file.read_exact(&mut event).is_ok() {
+				let value = i16::from_ne_bytes([event[4], event[5]]);
+				reader.lock().unwrap().apply(event[6], event[7], value);
+			}
+			// Unplugged: let go of everything rather than hold it forever
+			error!("lost gamepad {}", path);
+			*reader.lock().unwrap() = Pad::IDLE;
+		});
+		Ok(Self { pad })
+	}
+}
+
+impl InputSource for Gamepad {
+	fn held(&self) -> InputBits {
+		self.pad.lock().unwrap().bits()
+	}
+
+	fn pressed(&mut self) -> InputBits {
+		std::mem::replace(&mut self.pad.lock().unwrap().taps, InputBits::empty())
+	}
+
+	fn aim(&self) -> Option<u8> {
+		Some(self.pad.lock().unwrap().aim)
+	}
+}
@@ -0,0 +1,65 @@
+use macroquad::prelude::*;
+
+// Gap between a widget and the window edge, in HUD units
+const MARGIN: f32 = 10.0;
+const FONT: f32 = 16.0;
+const LINE: f32 = 20.0;
+
+// The layout is made for the default window, three buffer pixels per pixel
+const DESIGN_SCALE: f32 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+	TopLeft,
+	TopRight,
+	BottomLeft,
+	BottomRight,
+}
+
+/// Screen-space overlays of one frame. Widgets stack away from the corner
+/// they're anchored to in the order they're placed, so none overlap, and
+/// everything scales with the window like the gameplay buffer does.
+pub struct Hud {
+	scale: f32,
+	// HUD units taken from the top or bottom edge so far, per anchor
+	used: [f32; 4],
+}
+
+impl Hud {
+	// Starts a new frame's layout, `buffer_scale` is how large the buffer is drawn
+	pub fn new(buffer_scale: f32) -> Self {
+		Self {
+			scale: (buffer_scale / DESIGN_SCALE).clamp(0.5, 4.0),
+			used: [0.0; 4],
+		}
+	}
+
+	// Screen pixels per HUD unit, widgets multiply their sizes by it
+	pub fn scale(&self) -> f32 {
+		self.scale
+	}
+
+	// Room for a `w` by `h` widget in HUD units, returns its top left in screen pixels
+	pub fn place(&mut self, anchor: Anchor, w: f32, h: f32) -> Vec2 {
+		let used = &mut self.used[anchor as usize];
+		let x = match anchor {
+			Anchor::TopLeft | Anchor::BottomLeft => MARGIN,
+			Anchor::TopRight | Anchor::BottomRight => screen_width() / self.scale - MARGIN - w,
+		};
+		let y = match anchor {
+			Anchor::TopLeft | Anchor::TopRight => MARGIN + *used,
+			Anchor::BottomLeft | Anchor::BottomRight => {
+				screen_height() / self.scale - MARGIN - *used - h
+			}
+		};
+		*used += h;
+		vec2(x, y) * self.scale
+	}
+
+	pub fn text(&mut self, anchor: Anchor, text: &str, color: Color) {
+		let size = FONT * self.scale;
+		let w = measure_text(text, None, size as u16, 1.0).width / self.scale;
+		let at = self.place(anchor, w, LINE);
+		draw_text(text, at.x, at.y + size, size, color);
+	}
+}
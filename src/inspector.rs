@@ -0,0 +1,123 @@
+use macroquad::prelude::*;
+
+use crate::{
+	hud::{Anchor, Hud},
+	rollback::Session,
+	sim::{self, Arena, InputBits, PlayerInput},
+};
+
+// One letter per input bit, in bit order
+const BIT_LETTERS: [(InputBits, char); 5] = [
+	(InputBits::LEFT, 'L'),
+	(InputBits::RIGHT, 'R'),
+	(InputBits::JUMP, 'J'),
+	(InputBits::FIRE, 'F'),
+	(InputBits::ATTACK, 'A'),
+];
+
+fn input_label(input: &PlayerInput) -> String {
+	let bits: String = BIT_LETTERS
+		.iter()
+		.map(|&(bit, c)| if input.bits.contains(bit) { c } else { '-' })
+		.collect();
+	format!("{bits} aim {:>3}", input.aim)
+}
+
+fn inputs_label(inputs: &[PlayerInput]) -> String {
+	inputs
+		.iter()
+		.enumerate()
+		.map(|(pid, i)| format!("P{pid} {}", input_label(i)))
+		.collect::<Vec<_>>()
+		.join("  ")
+}
+
+/// F4 opens a panel on any tick still in the rollback history: the state
+/// before it ran, and the inputs it last ran with next to the server's.
+/// `[` and `]` step a tick, a second with Shift.
+#[derive(Debug, Default)]
+pub struct Inspector {
+	tick: Option<u32>,
+}
+
+impl Inspector {
+	// Keys, once per frame. Opens on the newest confirmed tick, steps no further
+	// than the newest simulated one
+	pub fn update(&mut self, confirmed: u32, local_tick: u32) {
+		let newest = local_tick.saturating_sub(1);
+		if is_key_pressed(KeyCode::F4) {
+			self.tick = match self.tick {
+				Some(_) => None,
+				None => Some(confirmed.min(newest)),
+			};
+		}
+		let Some(tick) = self.tick.as_mut() else {
+			return;
+		};
+		let step = if is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift) {
+			sim::TPS
+		} else {
+			1
+		};
+		if is_key_pressed(KeyCode::LeftBracket) {
+			*tick = tick.saturating_sub(step);
+		}
+		if is_key_pressed(KeyCode::RightBracket) {
+			*tick = tick.saturating_add(step).min(newest);
+		}
+	}
+
+	pub fn draw(&self, hud: &mut Hud, session: &Session<Arena>) {
+		let Some(tick) = self.tick else {
+			return;
+		};
+		let anchor = Anchor::TopRight;
+		hud.text(
+			anchor,
+			&format!("inspecting tick {tick}  [ ] step, shift 1s, F4 close"),
+			WHITE,
+		);
+		match session.load(tick) {
+			Some(state) => {
+				for (pid, p) in state.players.iter().enumerate() {
+					hud.text(
+						anchor,
+						&format!(
+							"P{pid} x={:.2} y={:.2} vx={:.2} vy={:.2} score={} hits={} health={} cooldown={} fired={}",
+							p.x, p.y, p.vx, p.vy, p.score, p.hits, p.health, p.cooldown, p.fired
+						),
+						LIGHTGRAY,
+					);
+				}
+				hud.text(
+					anchor,
+					&format!(
+						"team hill {:?}  checksum {:016x}",
+						state.team_scores,
+						sim::checksum(&state)
+					),
+					LIGHTGRAY,
+				);
+			}
+			None => hud.text(anchor, "state no longer in history", GRAY),
+		}
+		let used = session.used(tick);
+		let auth = session.authoritative(tick);
+		match &used {
+			Some(used) => hud.text(anchor, &format!("ran  {}", inputs_label(used)), LIGHTGRAY),
+			None => hud.text(anchor, "ran  -", GRAY),
+		}
+		match auth {
+			Some(auth) => {
+				// Only until the rollback that fixes it
+				let color = if used.is_some_and(|u| u != auth) {
+					ORANGE
+				} else {
+					LIGHTGRAY
+				};
+				hud.text(anchor, &format!("srv  {}", inputs_label(&auth)), color);
+			}
+			None => hud.text(anchor, "srv  not confirmed yet", GRAY),
+		}
+	}
+}
@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+
+use crate::{
+	num::num,
+	protocol::{Roster, StateSnapshot},
+	sim::{self, SimState, lerp},
+};
+
+// Snapshots kept for interpolation
+const CAPACITY: usize = 32;
+
+// Render this far behind the newest snapshot, two snapshot intervals
+// absorb one late snapshot without extrapolating
+pub const INTERP_DELAY_TICKS: f64 = 12.0;
+
+// Dead reckoning stops this far past the newest snapshot rather than drift forever
+pub const MAX_EXTRAPOLATE_TICKS: f64 = 15.0;
+
+/// Server snapshots for the state-sync baseline: render between the two
+/// snapshots around the render time, or dead-reckon from the newest one
+/// with its velocities when the next snapshot is late.
+#[derive(Default)]
+pub struct SnapshotBuffer {
+	snaps: VecDeque<StateSnapshot>,
+}
+
+impl SnapshotBuffer {
+	pub fn clear(&mut self) {
+		self.snaps.clear();
+	}
+
+	// Out of order or duplicate snapshots are ignored
+	pub fn push(&mut self, s: StateSnapshot) {
+		if self.snaps.back().is_some_and(|b| b.tick >= s.tick) {
+			return;
+		}
+		self.snaps.push_back(s);
+		if self.snaps.len() > CAPACITY {
+			self.snaps.pop_front();
+		}
+	}
+
+	pub fn newest_tick(&self) -> Option<u32> {
+		self.snaps.back().map(|s| s.tick)
+	}
+
+	// State at fractional server tick `t`, and whether it had to be extrapolated
+	pub fn sample(&self, t: f64) -> Option<(SimState, bool)> {
+		let newest = self.snaps.back()?;
+		if t >= newest.tick as f64 {
+			let ahead = num((t - newest.tick as f64).min(MAX_EXTRAPOLATE_TICKS) as f32 * sim::DT);
+			let mut state = newest.state.clone();
+			for p in state.players.iter_mut() {
+				p.x += p.vx * ahead;
+				p.y = (p.y + p.vy * ahead).min(num(sim::BUFFER_H as f32) - sim::Player::H);
+				for s in p.shots.iter_mut().filter(|s| s.ttl > 0) {
+					s.x += s.vx * ahead;
+					s.y += s.vy * ahead;
+				}
+			}
+			return Some((state, t > newest.tick as f64));
+		}
+
+		let Some(i) = self.snaps.iter().rposition(|s| s.tick as f64 <= t) else {
+			return Some((self.snaps[0].state.clone(), false));
+		};
+		let (a, b) = (&self.snaps[i], &self.snaps[i + 1]);
+		let alpha = ((t - a.tick as f64) / (b.tick - a.tick) as f64) as f32;
+		let mut state = a.state.clone();
+		for (p, q) in state.players.iter_mut().zip(&b.state.players) {
+			p.x = num(lerp(p.x, q.x, alpha));
+			p.y = num(lerp(p.y, q.y, alpha));
+			for (s, r) in p.shots.iter_mut().zip(&q.shots) {
+				if s.ttl > 0 && r.ttl > 0 {
+					s.x = num(lerp(s.x, r.x, alpha));
+					s.y = num(lerp(s.y, r.y, alpha));
+				}
+			}
+		}
+		Some((state, false))
+	}
+}
+
+/// What the snapshot client shows. Nothing until a start says who plays, then
+/// the start's state until snapshots of the match come in.
+#[derive(Default)]
+pub struct SnapshotView {
+	pub my_id: usize,
+	pub roster: Option<Roster>,
+	pub shown: Option<SimState>,
+	pub snaps: SnapshotBuffer,
+}
+
+impl SnapshotView {
+	// A match starting from `state`, from the top or resumed
+	pub fn start(&mut self, player_id: u8, roster: Roster, state: SimState) {
+		self.my_id = player_id as usize;
+		self.roster = Some(roster);
+		self.shown = Some(state);
+		self.snaps.clear();
+	}
+
+	// Another match's snapshots, or ones from before the start, are dropped
+	pub fn push(&mut self, s: StateSnapshot) {
+		if self
+			.roster
+			.as_ref()
+			.is_some_and(|r| r.players() == s.state.player_count())
+		{
+			self.snaps.push(s);
+		}
+	}
+
+	// Shows the state at fractional server tick `t`, returns whether it had
+	// to be extrapolated
+	pub fn sample(&mut self, t: f64) -> bool {
+		match self.snaps.sample(t) {
+			Some((state, extrapolated)) => {
+				self.shown = Some(state);
+				extrapolated
+			}
+			None => false,
+		}
+	}
+}
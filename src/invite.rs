@@ -0,0 +1,51 @@
+use std::{fmt, net::SocketAddr};
+
+const SCHEME: &str = "replnet://";
+
+/// Everything needed to join a match as one string to paste:
+/// `replnet://host:port/code`, the code being a reserved slot's token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+	pub addr: String,
+	pub code: Option<u64>,
+}
+
+impl fmt::Display for Invite {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{SCHEME}{}", self.addr)?;
+		if let Some(code) = self.code {
+			write!(f, "/{code}")?;
+		}
+		Ok(())
+	}
+}
+
+impl Invite {
+	// A server bound to every interface can't tell which address reaches it
+	pub fn needs_host(&self) -> bool {
+		self.addr
+			.parse::<SocketAddr>()
+			.is_ok_and(|a| a.ip().is_unspecified())
+	}
+}
+
+pub fn parse_invite(s: &str) -> Result<Invite, String> {
+	let rest = s
+		.strip_prefix(SCHEME)
+		.ok_or_else(|| format!("invite links start with {SCHEME}"))?;
+	let (addr, code) = match rest.split_once('/') {
+		Some((addr, "")) => (addr, None),
+		Some((addr, code)) => (
+			addr,
+			Some(code.parse().map_err(|e| format!("invite code: {e}"))?),
+		),
+		None => (rest, None),
+	};
+	if !addr.contains(':') {
+		return Err("invite needs host:port".to_string());
+	}
+	Ok(Invite {
+		addr: addr.to_string(),
+		code,
+	})
+}
@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+
+use macroquad::prelude::*;
+
+use crate::hud::{Anchor, Hud};
+
+const BUCKET_US: u32 = 5_000;
+const BUCKETS: usize = 40;
+
+// Samples the distribution is computed over, older ones fall out
+const WINDOW: usize = 600;
+
+/// Rolling latency distribution, 5 ms buckets up to 200 ms, the last bucket
+/// holds everything slower.
+pub struct Histogram {
+	samples: VecDeque<u32>,
+	buckets: [u32; BUCKETS],
+	sum: u64,
+}
+
+impl Default for Histogram {
+	fn default() -> Self {
+		Self {
+			samples: VecDeque::with_capacity(WINDOW),
+			buckets: [0; BUCKETS],
+			sum: 0,
+		}
+	}
+}
+
+fn bucket(us: u32) -> usize {
+	((us / BUCKET_US) as usize).min(BUCKETS - 1)
+}
+
+impl Histogram {
+	pub fn push(&mut self, us: u32) {
+		if self.samples.len() == WINDOW
+			&& let Some(old) = self.samples.pop_front()
+		{
+			self.buckets[bucket(old)] -= 1;
+			self.sum -= old as u64;
+		}
+		self.samples.push_back(us);
+		self.buckets[bucket(us)] += 1;
+		self.sum += us as u64;
+	}
+
+	pub fn mean_ms(&self) -> Option<f32> {
+		if self.samples.is_empty() {
+			return None;
+		}
+		Some(self.sum as f32 / self.samples.len() as f32 / 1000.0)
+	}
+
+	// Upper edge of the bucket holding the q-th quantile, ms
+	pub fn quantile_ms(&self, q: f32) -> Option<u32> {
+		let target = (self.samples.len() as f32 * q).ceil().max(1.0) as u32;
+		let mut seen = 0;
+		for (i, &n) in self.buckets.iter().enumerate() {
+			seen += n;
+			if seen >= target {
+				return Some((i as u32 + 1) * BUCKET_US / 1000);
+			}
+		}
+		None
+	}
+
+	// Bars plus a p50/p95 label
+	pub fn draw(&self, label: &str, hud: &mut Hud, anchor: Anchor) {
+		const BAR_W: f32 = 3.0;
+		const H: f32 = 32.0;
+		let text = match (self.quantile_ms(0.5), self.quantile_ms(0.95)) {
+			(Some(p50), Some(p95)) => format!("{label} p50<{p50}ms p95<{p95}ms"),
+			_ => format!("{label} no samples"),
+		};
+		// The label goes under the bars, and bottom anchors stack upwards
+		if matches!(anchor, Anchor::BottomLeft | Anchor::BottomRight) {
+			hud.text(anchor, &text, WHITE);
+		}
+		let s = hud.scale();
+		let w = BAR_W * BUCKETS as f32;
+		let at = hud.place(anchor, w, H);
+		draw_rectangle(at.x, at.y, w * s, H * s, Color::new(0.0, 0.0, 0.0, 0.5));
+		draw_rectangle_lines(at.x, at.y, w * s, H * s, 1.0, GRAY);
+		let max = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+		for (i, &n) in self.buckets.iter().enumerate() {
+			let h = H * s * n as f32 / max as f32;
+			let x = at.x + i as f32 * BAR_W * s;
+			draw_rectangle(x, at.y + H * s - h, (BAR_W - 1.0) * s, h, SKYBLUE);
+		}
+		if matches!(anchor, Anchor::TopLeft | Anchor::TopRight) {
+			hud.text(anchor, &text, WHITE);
+		}
+	}
+}
@@ -0,0 +1,255 @@
+use std::{
+	collections::HashMap,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use macroquad::prelude::info;
+use tokio::{
+	runtime::{Builder, Runtime},
+	sync::mpsc as bounded,
+	task::JoinHandle,
+	time::timeout,
+};
+
+use crate::{
+	net::{self, ARRIVAL_QUEUE, Arrival, Listeners, RoomStatus, ServerConfig, ServerRender},
+	protocol::{C2S, Capabilities, Frame},
+	queue, stats,
+	transport::{self, Conn, ConnReader, Listener},
+};
+
+// Longer join codes get the connection dropped
+const MAX_CODE_LEN: usize = 32;
+
+// A room still missing players this long after it opened closes, whoever
+// waits in it can try again
+const ROOM_FILL_TIMEOUT: Duration = Duration::from_secs(120);
+
+// How often rooms are looked in on while nobody joins
+const ROOM_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often the scheduler line goes to the log, its stats update every sweep
+const SCHEDULER_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Room {
+	id: u32,
+	tx: bounded::Sender<Arrival>,
+	// Connections handed over for a player's place so far, watchers take none
+	joined: usize,
+	// Players it seats, every room plays the server's count
+	players: usize,
+	status: Arc<RoomStatus>,
+	opened: Instant,
+	done: JoinHandle<()>,
+}
+
+impl Room {
+	// Whether its players and the ones on their way fill it
+	fn full(&self) -> bool {
+		self.status.expected(self.joined) >= self.players
+	}
+
+	// Given up on, a full room closes when its series is over
+	fn expired(&self) -> bool {
+		!self.full() && self.opened.elapsed() >= ROOM_FILL_TIMEOUT
+	}
+
+	// Over, and why, or none while it's still open
+	fn closed(&self) -> Option<&'static str> {
+		if self.done.is_finished() {
+			Some("closed")
+		} else if self.expired() {
+			Some("closed, nobody filled it")
+		} else {
+			None
+		}
+	}
+}
+
+// The pool every room's tick loop runs on, however many rooms there are, and
+// what it reported last
+struct Scheduler {
+	pool: Runtime,
+	busy: Duration,
+	sampled: Instant,
+	logged: Instant,
+}
+
+impl Scheduler {
+	fn new(workers: usize) -> Self {
+		#[cfg(not(target_arch = "wasm32"))]
+		let mut builder = Builder::new_multi_thread();
+		// A page never gets to run a lobby, see transport::runtime
+		#[cfg(target_arch = "wasm32")]
+		let mut builder = Builder::new_current_thread();
+		let pool = builder
+			.worker_threads(workers)
+			.thread_name("room")
+			.enable_all()
+			.build()
+			.expect("start the room pool");
+		Self {
+			pool,
+			busy: Duration::ZERO,
+			sampled: Instant::now(),
+			logged: Instant::now(),
+		}
+	}
+
+	// How busy the workers were since the last sample, how many tasks they
+	// have and how many wait for one, and the worst a room ran late in its
+	// last second. Logged once a SCHEDULER_LOG_INTERVAL
+	fn sample<'a>(&mut self, rooms: impl Iterator<Item = &'a Room>) {
+		let metrics = self.pool.metrics();
+		let workers = metrics.num_workers();
+		let busy: Duration = (0..workers)
+			.map(|w| metrics.worker_total_busy_duration(w))
+			.sum();
+		let window = self.sampled.elapsed() * workers as u32;
+		let busy_pct = (busy - self.busy).as_secs_f64() * 100.0 / window.as_secs_f64();
+		self.busy = busy;
+		self.sampled = Instant::now();
+		let (mut count, mut lateness) = (0, Duration::ZERO);
+		for r in rooms {
+			count += 1;
+			lateness = lateness.max(r.status.lateness());
+		}
+		stats::gauge("lobby.rooms").set(count);
+		stats::gauge("lobby.workers").set(workers as i64);
+		stats::gauge("lobby.busy_pct").set(busy_pct as i64);
+		stats::gauge("lobby.tasks").set(metrics.num_alive_tasks() as i64);
+		stats::gauge("lobby.queued_tasks").set(metrics.global_queue_depth() as i64);
+		stats::gauge("lobby.tick_lateness_max_us").set(lateness.as_micros() as i64);
+		if self.logged.elapsed() >= SCHEDULER_LOG_INTERVAL {
+			self.logged = Instant::now();
+			info!(
+				"{count} rooms on {workers} workers, {busy_pct:.0}% busy, {} tasks queued, \
+				 worst tick {:.1}ms late",
+				metrics.global_queue_depth(),
+				lateness.as_secs_f64() * 1000.0
+			);
+		}
+	}
+}
+
+// A connection for a room, the code it asked for, and whether it only watches,
+// a referee or a spectator, which takes no player's place
+type Join = (Arrival, Option<String>, bool);
+
+// Hello and the JoinRoom after it, the room answers the Hello. None when the
+// client hung up, was refused or sent something else
+async fn read_join(conn: Conn, reader: ConnReader) -> Option<Join> {
+	let mut arrival = net::greet(conn, reader).await?;
+	let watcher = Capabilities::negotiate(arrival.hello.capabilities).watches_only();
+	let Ok(C2S::JoinRoom(code)) = C2S::decode(&arrival.reader.recv().await.ok()?) else {
+		return None;
+	};
+	if code.as_ref().is_some_and(|c| c.len() > MAX_CODE_LEN) {
+		return None;
+	}
+	Some((arrival, code, watcher))
+}
+
+/// A server running any number of matches at once. The player port keeps
+/// accepting, connections with the same join code share a room, the rest pair
+/// up first come, and every room runs its own match like a server would.
+/// Latecomers to a full room spectate it, a room its players never filled
+/// closes after ROOM_FILL_TIMEOUT. Renders of every room come out of the
+/// receiver, told apart by `room`. The rooms' tick loops share `workers`
+/// threads, each sleeping until its next tick is due.
+pub fn spawn_lobby(cfg: ServerConfig, workers: usize) -> queue::Receiver<ServerRender> {
+	let (tx_render, rx_render) = net::render_queue();
+	let players = net::bind_players(&cfg.addr, cfg.ws_addr.as_deref(), cfg.socket);
+	let scheduler = Scheduler::new(workers);
+	transport::runtime().spawn(run_lobby(cfg, players, scheduler, tx_render));
+	rx_render
+}
+
+async fn run_lobby(
+	cfg: ServerConfig,
+	players: Vec<Listener>,
+	mut scheduler: Scheduler,
+	tx_render: queue::Sender<ServerRender>,
+) {
+	let (tx_join, mut rx_join) = bounded::channel::<Join>(ARRIVAL_QUEUE);
+	net::accept(players, tx_join, read_join);
+
+	let mut next_id = 1;
+	let pool = scheduler.pool.handle().clone();
+	let mut open_room = |code: Option<&str>| {
+		let (tx, arrivals) = bounded::channel(ARRIVAL_QUEUE);
+		let status = Arc::new(RoomStatus::default());
+		let cfg = ServerConfig {
+			room_status: Some(status.clone()),
+			..cfg.clone()
+		};
+		let tx_render = tx_render.clone();
+		let id = next_id;
+		next_id += 1;
+		match code {
+			Some(code) => info!("room {} opened for {:?}", id, code),
+			None => info!("room {} opened", id),
+		}
+		Room {
+			id,
+			tx,
+			joined: 0,
+			players: cfg.roster.players(),
+			status,
+			opened: Instant::now(),
+			done: pool.spawn(net::serve(
+				cfg,
+				arrivals,
+				Listeners::default(),
+				id,
+				tx_render,
+			)),
+		}
+	};
+	// The room whoever comes without a code joins, until it has its players
+	let mut pairing: Option<Room> = None;
+	let mut rooms: HashMap<String, Room> = HashMap::new();
+	loop {
+		let join = match timeout(ROOM_SWEEP_INTERVAL, rx_join.recv()).await {
+			Ok(Some(join)) => Some(join),
+			Ok(None) => break,
+			Err(_) => None,
+		};
+		// Dropping a room that's still running hangs up on it
+		rooms.retain(|code, r| {
+			let closed = r.closed();
+			if let Some(why) = closed {
+				info!("room {} for {code:?} {why}", r.id);
+			}
+			closed.is_none()
+		});
+		if let Some(why) = pairing.as_ref().and_then(Room::closed) {
+			info!("room {} {why}", pairing.take().expect("pairing room").id);
+		}
+		if scheduler.sampled.elapsed() >= ROOM_SWEEP_INTERVAL {
+			scheduler.sample(rooms.values().chain(&pairing));
+		}
+		let Some((arrival, code, watcher)) = join else {
+			continue;
+		};
+		let room = match code {
+			Some(code) => rooms
+				.entry(code)
+				.or_insert_with_key(|code| open_room(Some(code))),
+			None => {
+				if pairing.as_ref().is_none_or(Room::full) {
+					pairing = Some(open_room(None));
+				}
+				pairing.as_mut().expect("pairing room")
+			}
+		};
+		// A room too far behind to take it is as good as full, the client can
+		// try again
+		if room.tx.try_send(arrival).is_ok() && !watcher {
+			room.joined += 1;
+		}
+	}
+	// Dropping it would wait for the rooms from a task of another runtime
+	scheduler.pool.shutdown_background();
+}
@@ -1,14 +1,63 @@
+mod alerts;
+mod bench;
+mod bot;
+mod bridge;
+#[cfg(target_arch = "wasm32")]
+mod browser;
+mod bugreport;
+mod career;
+mod clock;
+mod control;
+mod dispute;
+mod env;
+mod failover;
+mod feedback;
+mod frametime;
+mod gamepad;
+mod hud;
+mod inspector;
+mod interp;
+mod invite;
+mod latency;
+mod lobby;
 mod net;
+mod netsim;
+mod netstats;
+mod num;
+mod palette;
+mod physics;
+mod playback;
 mod protocol;
+mod quality;
+mod queue;
+mod referee;
+mod replay;
+mod rollback;
+mod rpc;
+mod savegame;
+mod selftest;
+mod series;
+mod session;
+mod signing;
 mod sim;
+mod simulate;
+mod smoothing;
+mod snapshot;
+mod sockopt;
+mod stats;
+mod transport;
+mod websocket;
+mod wirestats;
 
 use std::{
 	collections::VecDeque,
+	path::{Path, PathBuf},
 	sync::{
 		Arc,
 		atomic::{AtomicU32, Ordering},
+		mpsc,
 	},
-	time::{Duration, Instant},
+	time::Duration,
 };
 
 use anyhow::Context;
@@ -16,8 +65,17 @@ use clap::{Parser, ValueEnum};
 use macroquad::{miniquad::window::set_window_size, prelude::*};
 
 use crate::{
+	alerts::Metric,
+	clock::Instant,
+	hud::{Anchor, Hud},
 	net::{NetCmd, NetEvent},
-	sim::{InputBits, SimState, lerp},
+	palette::Palette,
+	protocol::{
+		Capabilities, InputGrant, InputMsg, KickReason, MatchSetup, Ping, Reject, ResumeState,
+		Roster, SeriesState, Stamped, StateHash, StateSnapshot, TickInputs,
+	},
+	savegame::SaveGame,
+	sim::{InputBits, PlayerInput, SimState, lerp},
 };
 
 // Server intentionally runs behind clock time by this many ticks
@@ -32,11 +90,49 @@ const HISTORY: usize = 2048;
 // Max ticks we simulate in one rendered frame
 const CATCHUP_BUDGET_TICKS: u32 = 2000;
 
+// How often a client pings the server to estimate the clock offset
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+// After connecting or resuming: ping this often for this long to measure the server's
+// clock and latency. A resume also stamps inputs extra ticks ahead meanwhile, the
+// margin then shrinks a tick at a time
+const RESUME_PING_INTERVAL: Duration = Duration::from_millis(100);
+const REMEASURE_DURATION: Duration = Duration::from_secs(2);
+const RESUME_STAMP_MARGIN_TICKS: u32 = 6;
+const MARGIN_DECAY_TICKS: u32 = 15;
+
+// How often a disconnected client retries the server
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// With --backup-addr, a connection this quiet mid-match is given up for the standby
+const FAILOVER_SILENCE: Duration = Duration::from_secs(1);
+
+// --bug-reports: at most one report this often, a real desync fails every snapshot after it
+const BUG_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// --delay-control: cap for the delay of control messages, so a large artificial
+// delay can't hold a match start back indefinitely
+const MAX_CONTROL_DELAY_MS: u32 = 1000;
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum Runtime {
 	Server,
 	Client,
 	Malicious,
+	SelfPlay,
+	Bench,
+	MigrateReplay,
+	VerifyReplay,
+	Dispute,
+	Spectator,
+	SnapshotClient,
+	Simulate,
+	PlayerStats,
+	Replay,
+	Bisect,
+	Bot,
+	Dual,
+	Referee,
 }
 
 #[derive(Debug, Parser)]
@@ -50,16 +146,402 @@ struct Args {
 
 	#[arg(long, default_value = "127.0.0.1:4000")]
 	addr: String,
+
+	// Clients: servers to choose from, the one with the lowest round trip is used
+	// instead of --addr
+	#[arg(long, value_delimiter = ',')]
+	servers: Vec<String>,
+
+	// Clients: use this entry of --servers instead of measuring them
+	#[arg(long)]
+	server_index: Option<usize>,
+
+	// Client only: a second address of the server for a standby connection, which
+	// takes over mid-match when the first one drops
+	#[arg(long)]
+	backup_addr: Option<String>,
+
+	// Server only: hand input delay to the faster client to equalize confirmation delay
+	#[arg(long)]
+	fairness: bool,
+
+	// Server only: periodically save the match here
+	#[arg(long)]
+	save: Option<PathBuf>,
+
+	// Server only: resume the match saved in this file
+	#[arg(long)]
+	resume: Option<PathBuf>,
+
+	// Server only: record the authoritative input stream to this replay file
+	#[arg(long)]
+	record: Option<PathBuf>,
+
+	// Server only: play a best-of-N series instead of a single match
+	#[arg(long, default_value_t = 1)]
+	best_of: u8,
+
+	// Server only: end each match after this long, the team ahead wins. A tie
+	// plays on until one team is ahead
+	#[arg(long)]
+	time_limit_secs: Option<u64>,
+
+	// Server only: start every match with free play that counts for nothing,
+	// until every player presses ready
+	#[arg(long)]
+	warmup: bool,
+
+	// Server only: run without a window, logging to the console instead
+	#[arg(long)]
+	headless: bool,
+
+	// Server only: run a lobby instead of a single match. Players pick a room with
+	// --room or pair up first come, and every room plays its own match. No window,
+	// it logs like --headless
+	#[arg(long)]
+	rooms: bool,
+
+	// Server only: threads the --rooms lobby runs its rooms' ticks on, however
+	// many rooms there are. One per core by default
+	#[arg(long)]
+	room_workers: Option<usize>,
+
+	// Server only: never finish. A new match starts whenever every slot is taken and
+	// none is running, and one that loses a player ends for a new opponent
+	#[arg(long)]
+	watch: bool,
+
+	// Server only: seconds of neutral input before an AFK warning, 0 disables
+	#[arg(long, default_value_t = 0)]
+	afk_secs: u64,
+
+	// Server only: accept read-only spectators on this address instead of the player port
+	#[arg(long)]
+	observe_addr: Option<String>,
+
+	// Server only: also accept WebSocket connections on this address. Clients reach
+	// it with a ws://host:port --addr, the browser client (make wasm) on port 4001
+	// of the page's host unless the page has ?server=
+	#[arg(long)]
+	ws_addr: Option<String>,
+
+	// Server only: hold a slot for the player with this token, as slot:token
+	#[arg(long, value_parser = parse_reservation)]
+	reserve: Vec<(usize, u64)>,
+
+	// Server only: pair players only when their latency to each other through the
+	// server is at most this, others wait for a closer opponent
+	#[arg(long)]
+	max_pair_latency_ms: Option<u64>,
+
+	// Server: keep players' career records in this JSON file. Player stats: the file to read
+	#[arg(long)]
+	player_stats: Option<PathBuf>,
+
+	// Player stats only: token of the player to show, everyone without
+	#[arg(long)]
+	player: Option<u64>,
+
+	// Server, self-play and simulate: players in a match, 2 by default. A resumed
+	// match keeps its own
+	#[arg(long, value_parser = parse_players)]
+	players: Option<usize>,
+
+	// Server only: team of every slot, everyone on their own team without it
+	#[arg(long, value_delimiter = ',')]
+	teams: Vec<u8>,
+
+	// Clients: how inputs travel, udp falls back to tcp when the server can't take it
+	#[arg(long, value_enum, default_value_t = net::InputTransport::Udp)]
+	transport: net::InputTransport,
+
+	// Client and snapshot client: play with the gamepad at /dev/input/jsN instead
+	// of the keyboard and mouse
+	#[arg(long)]
+	gamepad: Option<u32>,
+
+	// Disable TCP_NODELAY, letting the OS batch small frames
+	#[arg(long)]
+	no_nodelay: bool,
+
+	// Socket send and receive buffer sizes in bytes, the OS default without
+	#[arg(long)]
+	send_buffer: Option<usize>,
+
+	#[arg(long)]
+	recv_buffer: Option<usize>,
+
+	// Enable TCP keepalive, probing after this many idle seconds
+	#[arg(long)]
+	keepalive_secs: Option<u64>,
+
+	// Spectator only: --addr is a server's observer port
+	#[arg(long)]
+	observer: bool,
+
+	// Client only: token of the slot the server reserved for us
+	#[arg(long)]
+	reservation: Option<u64>,
+
+	// Clients: room to join on a --rooms server, the next open one without
+	#[arg(long)]
+	room: Option<String>,
+
+	// Clients: a replnet://host:port/code invite, in place of --addr and --reservation
+	#[arg(long, value_parser = invite::parse_invite)]
+	join: Option<invite::Invite>,
+
+	// Client only: keep a JSON network quality report of the session here
+	#[arg(long)]
+	quality_report: Option<PathBuf>,
+
+	// Windowed runtimes: colors to start with, F6 cycles through them
+	#[arg(long, value_enum, default_value_t = palette::Palette::Classic)]
+	palette: palette::Palette,
+
+	// Client only: flash the window border on rollbacks, brighter the deeper they go,
+	// in the color of the player whose input was mispredicted and outlining them
+	#[arg(long)]
+	rollback_flash: bool,
+
+	// Client only: beep on rollbacks at least this many ticks deep
+	#[arg(long)]
+	rollback_beep: Option<u32>,
+
+	// Client only: sign our confirmed inputs so recorded replays can be verified
+	#[arg(long)]
+	sign: bool,
+
+	// Client only: corrupt the predicted remote input every k ticks to force rollbacks
+	#[arg(long)]
+	force_mispredict: Option<u32>,
+
+	// Client: where F9 writes a desync dump. Dispute: the client dump to check
+	#[arg(long)]
+	dump: Option<PathBuf>,
+
+	// Client only: measure key press to local apply and to server confirmation
+	#[arg(long)]
+	measure_latency: bool,
+
+	// Client only: correct desyncs from server snapshots, blending errors up to this many pixels
+	#[arg(long)]
+	hybrid: Option<f32>,
+
+	// Client only: of the confirmed ticks' states keep only those server snapshots
+	// and state hashes are for. F9 dumps need every one
+	#[arg(long)]
+	compact_history: bool,
+
+	// Client only: with --hybrid, save screenshots and checksums of every desync
+	// a snapshot reveals into a folder under this directory
+	#[arg(long)]
+	bug_reports: Option<PathBuf>,
+
+	// Client only: log operator actions like delay changes to this file, as JSON lines
+	#[arg(long)]
+	record_session: Option<PathBuf>,
+
+	// Client only: redo the actions of a recorded session at the ticks they happened
+	#[arg(long)]
+	replay_session: Option<PathBuf>,
+
+	// Client only: loss, delay, jitter, reordering and duplication for one message
+	// type, as kind:key=value,... e.g. tick-inputs:loss=0.1,jitter=20,reorder=0.05.
+	// Inputs also take an MTU their datagrams are fragmented at, or dropped over
+	// with df=1, e.g. input:mtu=48,loss=0.05. Repeat for more types
+	#[arg(long, value_parser = netsim::parse_policy)]
+	netsim: Vec<(netsim::Kind, netsim::Policy)>,
+
+	// Client only: put match start, resume, series and AFK messages through the
+	// artificial delay too, not just the per-tick traffic
+	#[arg(long)]
+	delay_control: bool,
+
+	// Run a loopback session on a mock clock and exit with its result
+	#[arg(long)]
+	self_test: bool,
+
+	// Self-test for this many hours of session time instead, across the tick
+	// wrap, then as long with the real server and bots
+	#[arg(long)]
+	stress_hours: Option<f32>,
+
+	// Stress only: session time per unit of real time for the server and bots
+	#[arg(long, default_value_t = 10.0)]
+	sim_rate: f32,
+
+	// Replay tools: input replay file. Bisect: a replay or dump
+	#[arg(long)]
+	file: Option<PathBuf>,
+
+	// Bisect only: the replay or dump to compare --file with
+	#[arg(long)]
+	against: Option<PathBuf>,
+
+	// Replay tools: output file. Simulate and bisect: JSON results, stdout without it
+	#[arg(long)]
+	out: Option<PathBuf>,
+
+	// Simulate only: ticks to run
+	#[arg(long, default_value_t = 60 * sim::TPS)]
+	ticks: u32,
+
+	// Simulate only: input script of each player in turn, the rest stay idle
+	#[arg(long)]
+	inputs: Vec<PathBuf>,
+
+	// Self-play only
+	#[arg(long, default_value_t = 10)]
+	episodes: u32,
+
+	#[arg(long, default_value_t = 60 * sim::TPS)]
+	episode_ticks: u32,
+
+	// Self-play, bots and the client's --netsim
+	#[arg(long, default_value_t = 1)]
+	seed: u32,
+
+	// Alert, in the log and on the HUD, when a metric stays past its limit for
+	// --alert-secs seconds in a row. Clients watch rollback depth and round trip,
+	// servers late inputs per second of the worst player
+	#[arg(long)]
+	alert_rollback_depth: Option<u32>,
+
+	#[arg(long)]
+	alert_rtt_ms: Option<u32>,
+
+	#[arg(long)]
+	alert_late_inputs: Option<u32>,
+
+	#[arg(long, default_value_t = 5)]
+	alert_secs: u32,
+
+	// Bot only: connections to open
+	#[arg(long, default_value_t = 1)]
+	bots: u32,
+
+	// Bot only: input script in simulate's format, random inputs without it
+	#[arg(long)]
+	bot_script: Option<PathBuf>,
+
+	// Bot only: one bot whose inputs come from another program as JSON-RPC
+	// lines, `-` for stdin and stdout or an address to listen on for it
+	#[arg(long)]
+	bridge: Option<String>,
+
+	// Client and server: take commands from a test orchestrator on this address,
+	// JSON-RPC lines for stats, set_delay, disconnect and dump. Anyone who can
+	// reach it controls the process, bind a local one unless the network is yours
+	#[arg(long)]
+	control: Option<String>,
+
+	// Anything networked: print what each kind of message costs in bytes and
+	// codec time every second, to stderr
+	#[arg(long)]
+	wire_stats: bool,
+}
+
+// A change of keyboard state followed from detection to the server's confirmation
+struct PressProbe {
+	detected_at: Instant,
+	bits: InputBits,
+	// Tick the input was stamped for once applied locally
+	stamped_tick: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+struct ClientConfig {
+	addr: String,
+	backup_addr: Option<String>,
+	malicious: bool,
+	sign: bool,
+	force_mispredict: Option<u32>,
+	dump_path: Option<PathBuf>,
+	measure_latency: bool,
+	hybrid: Option<f32>,
+	compact_history: bool,
+	reservation: Option<u64>,
+	room: Option<String>,
+	delay_control: bool,
+	netsim: netsim::NetSim,
+	bug_report_dir: Option<PathBuf>,
+	record_session: Option<PathBuf>,
+	replay_session: Option<PathBuf>,
+	socket: sockopt::SocketOptions,
+	rollback_flash: bool,
+	rollback_beep: Option<u32>,
+	quality_report: Option<PathBuf>,
+	transport: net::InputTransport,
+	palette: Palette,
+	alerts: alerts::Limits,
+	control: Option<String>,
+	gamepad: Option<u32>,
 }
 
-fn draw_buffer_to_screen(buffer: &RenderTarget) {
+fn parse_reservation(s: &str) -> Result<(usize, u64), String> {
+	let (slot, token) = s.split_once(':').ok_or("expected slot:token")?;
+	let slot: usize = slot.parse().map_err(|e| format!("slot: {e}"))?;
+	if slot >= sim::MAX_PLAYERS {
+		return Err(format!("slot must be below {}", sim::MAX_PLAYERS));
+	}
+	let token = token.parse().map_err(|e| format!("token: {e}"))?;
+	Ok((slot, token))
+}
+
+fn parse_players(s: &str) -> Result<usize, String> {
+	let players: usize = s.parse().map_err(|e| format!("{e}"))?;
+	if !(2..=sim::MAX_PLAYERS).contains(&players) {
+		return Err(format!("2 to {} players", sim::MAX_PLAYERS));
+	}
+	Ok(players)
+}
+
+// Per-player numbers as shown in the HUD, e.g. 3/0
+fn slash_list<T: std::fmt::Display>(values: &[T]) -> String {
+	values
+		.iter()
+		.map(ToString::to_string)
+		.collect::<Vec<_>>()
+		.join("/")
+}
+
+// Top left corner and scale of the letterboxed buffer on screen
+fn buffer_placement() -> (Vec2, f32) {
 	let sw = screen_width();
 	let sh = screen_height();
 	let scale = (sw / sim::BUFFER_W as f32).min(sh / sim::BUFFER_H as f32);
 	let draw_w = sim::BUFFER_W as f32 * scale;
 	let draw_h = sim::BUFFER_H as f32 * scale;
-	let offset_x = ((sw - draw_w) / 2.0).round();
-	let offset_y = ((sh - draw_h) / 2.0).round();
+	let offset = vec2(((sw - draw_w) / 2.0).round(), ((sh - draw_h) / 2.0).round());
+	(offset, scale)
+}
+
+// Layout for this frame's overlays, scaled along with the buffer
+fn new_hud() -> Hud {
+	Hud::new(buffer_placement().1)
+}
+
+fn screen_to_buffer(p: Vec2) -> Vec2 {
+	let (offset, scale) = buffer_placement();
+	(p - offset) / scale
+}
+
+// The mouse, or a gamepad's aim this far from the player's centre
+const CROSSHAIR_REACH: f32 = 24.0;
+
+fn crosshair(taps: &sim::TapLatch, center: Vec2) -> Vec2 {
+	match taps.aim() {
+		Some(aim) => center + sim::aim_dir(aim).to_vec2() * CROSSHAIR_REACH,
+		None => screen_to_buffer(mouse_position().into()),
+	}
+}
+
+fn draw_buffer_to_screen(buffer: &RenderTarget) {
+	let (offset, scale) = buffer_placement();
+	let draw_w = sim::BUFFER_W as f32 * scale;
+	let draw_h = sim::BUFFER_H as f32 * scale;
+	let (offset_x, offset_y) = (offset.x, offset.y);
 
 	draw_texture_ex(
 		&buffer.texture,
@@ -73,234 +555,1495 @@ fn draw_buffer_to_screen(buffer: &RenderTarget) {
 	);
 }
 
-fn schedule_with_delay<T>(
-	queue: &mut VecDeque<(Instant, T)>,
-	last_scheduled_at: &mut Option<Instant>,
-	msg: T,
-	delay_ms: u32,
-) {
-	let now = Instant::now();
-	let desired = now + Duration::from_millis(delay_ms as u64);
-	let scheduled = match *last_scheduled_at {
-		Some(prev) if prev > desired => prev,
-		_ => desired,
+// Zoom of a camera following one player
+const FOLLOW_ZOOM: f32 = 2.0;
+
+// What the server and spectator views look at, Tab cycles through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Perspective {
+	Arena,
+	Follow(usize),
+}
+
+impl Perspective {
+	// The view after this one in a match of `players`
+	fn next(self, players: usize) -> Self {
+		match self {
+			Self::Arena => Self::Follow(0),
+			Self::Follow(p) if p + 1 < players => Self::Follow(p + 1),
+			Self::Follow(_) => Self::Arena,
+		}
+	}
+
+	fn label(self) -> String {
+		match self {
+			Self::Arena => "arena".to_string(),
+			Self::Follow(p) => format!("following p{p}"),
+		}
+	}
+
+	// Camera into the low-res buffer. A followed player stays centred unless that
+	// would show past the arena's edges
+	fn camera(self, state: &SimState) -> Camera2D {
+		let mut cam = sim::camera_for_buffer();
+		if let Self::Follow(p) = self {
+			let half = vec2(sim::BUFFER_W as f32, sim::BUFFER_H as f32) / (2.0 * FOLLOW_ZOOM);
+			let max = vec2(sim::BUFFER_W as f32, sim::BUFFER_H as f32) - half;
+			cam.zoom *= FOLLOW_ZOOM;
+			cam.target = state.players[p].center().to_vec2().clamp(half, max);
+		}
+		cam
+	}
+}
+
+// Enough of the state's checksum to compare the server's window with a spectator's
+// by eye. The server shows the state after its tick and a spectator the state
+// before its own, so the sums match with the spectator one tick ahead
+fn short_checksum(state: &SimState) -> String {
+	format!("{:04x}", sim::checksum(state) & 0xffff)
+}
+
+fn draw_hill(palette: Palette) {
+	let y = sim::BUFFER_H as f32 - 3.0;
+	let (x, w) = (num::to_f32(sim::HILL_X), num::to_f32(sim::HILL_W));
+	draw_rectangle(x, y, w, 3.0, palette.hill());
+}
+
+// A health bar over a player drawn at `x`, `y`, and the box its attack hits
+// with while it's out
+fn draw_combat(p: &sim::Player, x: f32, y: f32) {
+	let w = num::to_f32(sim::Player::W);
+	let left = p.health as f32 / sim::Player::MAX_HEALTH as f32;
+	draw_rectangle(x, y - 4.0, w, 2.0, DARKGRAY);
+	draw_rectangle(
+		x,
+		y - 4.0,
+		w * left,
+		2.0,
+		if left > 0.3 { GREEN } else { RED },
+	);
+	if let Some(b) = p.swing_box() {
+		let bx = x + num::to_f32(b.x - p.x);
+		let by = y + num::to_f32(b.y - p.y);
+		let (bw, bh) = (num::to_f32(b.w), num::to_f32(b.h));
+		draw_rectangle_lines(bx, by, bw, bh, 1.0, WHITE);
+	}
+}
+
+fn draw_shots(state: &SimState) {
+	let size = num::to_f32(sim::Shot::SIZE);
+	for p in &state.players {
+		for s in p.shots.iter().filter(|s| s.ttl > 0) {
+			draw_rectangle(num::to_f32(s.x), num::to_f32(s.y), size, size, WHITE);
+		}
+	}
+}
+
+// Shots partway from one state to the next. They're matched by id, so a shot
+// that moved to another slot in a rollback doesn't jump or show up twice
+fn draw_shots_between(prev: &SimState, cur: &SimState, alpha: f32) {
+	let size = num::to_f32(sim::Shot::SIZE);
+	for (a, b) in prev.players.iter().zip(&cur.players) {
+		for s in b.shots.iter().filter(|s| s.ttl > 0) {
+			let from = a
+				.shots
+				.iter()
+				.find(|p| p.ttl > 0 && p.id == s.id)
+				.unwrap_or(s);
+			let x = lerp(from.x, s.x, alpha);
+			let y = lerp(from.y, s.y, alpha);
+			draw_rectangle(x, y, size, size, WHITE);
+		}
+	}
+}
+
+fn draw_players(state: &SimState, roster: &Roster, palette: Palette) {
+	draw_hill(palette);
+	for (p, &team) in state.players.iter().zip(&roster.teams) {
+		let (x, y) = (num::to_f32(p.x), num::to_f32(p.y));
+		palette.draw_player(x, y, team);
+		draw_combat(p, x, y);
+	}
+	draw_shots(state);
+}
+
+// Everything in the stats registry, one metric per line
+fn draw_stats(hud: &mut Hud, anchor: Anchor) {
+	for (name, v) in stats::snapshot() {
+		hud.text(anchor, &format!("{name} {v}"), LIGHTGRAY);
+	}
+}
+
+// One row per player: buffered inputs by tick offset from the next tick, and a
+// red cell in front while inputs show up after their tick already ran
+fn draw_input_windows(r: &net::ServerRender, hud: &mut Hud) {
+	const CELL: f32 = 8.0;
+	const ROW: f32 = 16.0;
+	let s = hud.scale();
+	let end_x = 24.0 + (r.window_len + 1) as f32 * CELL + 12.0;
+	let players = r.late_per_sec.len();
+	let at = hud.place(Anchor::BottomLeft, end_x + 64.0, ROW * players as f32);
+	let cell = |x: f32, y: f32, color| {
+		draw_rectangle(at.x + x * s, y, (CELL - 1.0) * s, (CELL - 1.0) * s, color);
+	};
+	for pid in 0..players {
+		let row_y = at.y + pid as f32 * ROW * s;
+		let text_y = row_y + CELL * s;
+		draw_text(&format!("p{pid}"), at.x, text_y, 16.0 * s, WHITE);
+		let late = r.late_per_sec[pid];
+		cell(24.0, row_y, if late > 0 { RED } else { DARKGRAY });
+		for i in 0..r.window_len {
+			let filled = r.input_windows[pid] & (1 << i) != 0;
+			let color = if filled { GREEN } else { DARKGRAY };
+			cell(24.0 + (i + 1) as f32 * CELL + 4.0, row_y, color);
+		}
+		if late > 0 {
+			let text = format!("{late} late/s");
+			draw_text(&text, at.x + end_x * s, text_y, 16.0 * s, RED);
+		}
+	}
+}
+
+// Why we can't play a match a start describes, if we can't: a roster of a
+// count we don't play, a state or count that disagrees with it, or a slot
+// outside it
+fn check_start(ev: &NetEvent) -> anyhow::Result<()> {
+	let (roster, players, player_id) = match ev {
+		NetEvent::AssignStart(a) => (&a.roster, a.players as usize, a.player_id),
+		NetEvent::Resume(r) => (&r.roster, r.state.player_count(), r.player_id),
+		NetEvent::SpectateStart(s) => (&s.roster, s.state.player_count(), 0),
+		_ => return Ok(()),
 	};
-	*last_scheduled_at = Some(scheduled);
-	queue.push_back((scheduled, msg));
+	roster.check()?;
+	if let NetEvent::Resume(ResumeState { state, .. })
+	| NetEvent::SpectateStart(protocol::SpectateStart { state, .. }) = ev
+	{
+		state.check()?;
+	}
+	anyhow::ensure!(
+		players == roster.players(),
+		"{players} players with a roster of {}",
+		roster.players()
+	);
+	anyhow::ensure!(
+		(player_id as usize) < players,
+		"player {player_id} of {players}"
+	);
+	Ok(())
+}
+
+// Why we can't play the rules a server sent, if we can't
+fn check_setup(s: &MatchSetup) -> Result<(), &'static str> {
+	if s.fingerprint != sim::fingerprint() {
+		return Err("this build simulates differently from the server's");
+	}
+	if s.rules.win_score != sim::WIN_SCORE {
+		return Err("the server plays to a score this build doesn't");
+	}
+	if s.rules.best_of == 0 {
+		return Err("the server sent a series of no matches");
+	}
+	if !(1..=sim::MAX_PLAYERS).contains(&(s.players as usize)) {
+		return Err("the server plays a player count this build doesn't");
+	}
+	Ok(())
+}
+
+fn series_banner(s: &SeriesState) -> String {
+	let who = if s.finished { "series" } else { "match" };
+	let wins: Vec<_> = s.wins.iter().map(ToString::to_string).collect();
+	let wins = wins.join("-");
+	format!(
+		"team {} wins the {who}! series {wins} (best of {})",
+		s.last_winner, s.best_of
+	)
+}
+
+// While the match warms up, who's ready and how to be
+fn warmup_banner(state: &SimState, key: &str) -> Option<String> {
+	state.warmup.then(|| {
+		let ready = state.players.iter().filter(|p| p.ready).count();
+		format!(
+			"warm-up, nothing counts: {ready}/{} ready, {key} when you are",
+			state.player_count()
+		)
+	})
 }
 
-#[macroquad::main("Demo")]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
 	let args = Args::parse();
+	if args.wire_stats {
+		wirestats::report_every_second();
+	}
+	if args.self_test || args.stress_hours.is_some() {
+		let stress = args.stress_hours.map(|hours| selftest::Stress {
+			hours,
+			rate: args.sim_rate,
+		});
+		return selftest::run_self_test(args.seed, stress);
+	}
+
+	// Headless runtimes never open a window
+	match args.runtime {
+		Runtime::SelfPlay => {
+			let players = args.players.unwrap_or(sim::DEFAULT_PLAYERS);
+			return env::run_self_play(players, args.episodes, args.episode_ticks, args.seed);
+		}
+		Runtime::Bench => return bench::run_bench(),
+		Runtime::MigrateReplay => {
+			let input = args.file.context("--file is required")?;
+			let output = args.out.context("--out is required")?;
+			return replay::run_migrate(&input, &output);
+		}
+		Runtime::VerifyReplay => {
+			let input = args.file.context("--file is required")?;
+			return replay::run_verify(&input);
+		}
+		Runtime::Dispute => {
+			let input = args.file.context("--file is required")?;
+			let dump = args.dump.context("--dump is required")?;
+			return dispute::run_dispute(&input, &dump);
+		}
+		Runtime::Simulate => {
+			if args.inputs.is_empty() {
+				anyhow::bail!("--inputs is required");
+			}
+			let players = args.players.unwrap_or(sim::DEFAULT_PLAYERS);
+			let scripts: Vec<&Path> = args.inputs.iter().map(PathBuf::as_path).collect();
+			return simulate::run_simulate(players, args.ticks, &scripts, args.out.as_deref());
+		}
+		Runtime::PlayerStats => {
+			let path = args.player_stats.context("--player-stats is required")?;
+			return career::run_player_stats(&path, args.player);
+		}
+		Runtime::Bisect => {
+			let a = args.file.context("--file is required")?;
+			let b = args.against.context("--against is required")?;
+			return dispute::run_bisect(&a, &b, args.out.as_deref());
+		}
+		Runtime::Bot if let Some(bridge) = &args.bridge => {
+			if args.bots != 1 || args.bot_script.is_some() {
+				anyhow::bail!("--bridge drives a single bot, without --bots or --bot-script");
+			}
+			return bot::run_bridged(
+				&args.addr,
+				args.room.clone(),
+				bridge,
+				socket_options(&args),
+				args.transport,
+			);
+		}
+		Runtime::Bot => {
+			return bot::run_bots(
+				&args.addr,
+				args.room.clone(),
+				args.bots,
+				args.bot_script.as_deref(),
+				args.seed,
+				socket_options(&args),
+				args.transport,
+			);
+		}
+		Runtime::Referee => {
+			return referee::run_referee(&args.addr, args.room.clone(), socket_options(&args));
+		}
+		Runtime::Server if args.headless || args.rooms => {
+			let socket = socket_options(&args);
+			let limits = alert_limits(&args);
+			let rooms = args.rooms.then(|| {
+				args.room_workers
+					.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+			});
+			return run_headless_server(server_config(args, socket)?, limits, rooms);
+		}
+		_ if args.headless || args.rooms => {
+			anyhow::bail!("--headless and --rooms only apply to the server")
+		}
+		_ => {}
+	}
+
+	// The window's future can't hand the error back out, the exit code has to say it
+	macroquad::Window::new("Demo", async move {
+		if let Err(err) = run_windowed(args).await {
+			error!("Error: {:?}", err);
+			std::process::exit(1);
+		}
+	});
+	Ok(())
+}
+
+async fn run_windowed(mut args: Args) -> anyhow::Result<()> {
+	// A page can only reach the server it names
+	#[cfg(target_arch = "wasm32")]
+	{
+		args.addr = browser::server_addr();
+	}
+	if let Some(invite) = args.join.take() {
+		args.addr = invite.addr;
+		args.reservation = invite.code.or(args.reservation);
+	}
+	// Client runtimes connect to the closest of --servers when given
+	if !matches!(args.runtime, Runtime::Server | Runtime::Replay) && !args.servers.is_empty() {
+		args.addr = match args.server_index {
+			Some(i) => args
+				.servers
+				.get(i)
+				.with_context(|| {
+					format!("--server-index {i} but only {} servers", args.servers.len())
+				})?
+				.clone(),
+			None => net::pick_server(&args.servers)?,
+		};
+		info!("connecting to {}", args.addr);
+	}
 
 	let buffer = render_target(sim::BUFFER_W, sim::BUFFER_H);
 	buffer.texture.set_filter(FilterMode::Nearest);
 	set_window_size(sim::BUFFER_W * 3, sim::BUFFER_H * 3);
+	let socket = socket_options(&args);
 
 	match args.runtime {
-		Runtime::Server => run_server(args.addr, buffer).await,
-		Runtime::Client => run_client(args.addr, buffer, false).await,
-		Runtime::Malicious => run_client(args.addr, buffer, true).await,
+		Runtime::Server => {
+			let palette = args.palette;
+			let limits = alert_limits(&args);
+			run_server(server_config(args, socket)?, limits, palette, buffer).await
+		}
+		Runtime::Client | Runtime::Malicious => {
+			let limits = alert_limits(&args);
+			let cfg = ClientConfig {
+				addr: args.addr,
+				backup_addr: args.backup_addr,
+				malicious: matches!(args.runtime, Runtime::Malicious),
+				sign: args.sign,
+				force_mispredict: args.force_mispredict,
+				dump_path: args.dump,
+				measure_latency: args.measure_latency,
+				hybrid: args.hybrid,
+				compact_history: args.compact_history,
+				reservation: args.reservation,
+				room: args.room,
+				delay_control: args.delay_control,
+				netsim: netsim::NetSim::new(args.netsim, args.seed),
+				bug_report_dir: args.bug_reports,
+				record_session: args.record_session,
+				replay_session: args.replay_session,
+				socket,
+				rollback_flash: args.rollback_flash,
+				rollback_beep: args.rollback_beep,
+				quality_report: args.quality_report,
+				transport: args.transport,
+				palette: args.palette,
+				alerts: limits,
+				control: args.control,
+				gamepad: args.gamepad,
+			};
+			run_client(cfg, buffer).await
+		}
+		Runtime::Spectator => {
+			run_spectator(
+				args.addr,
+				args.room,
+				args.observer,
+				socket,
+				args.palette,
+				buffer,
+			)
+			.await
+		}
+		Runtime::Dual => {
+			run_dual_client(
+				args.addr,
+				args.room,
+				socket,
+				args.transport,
+				args.palette,
+				buffer,
+			)
+			.await
+		}
+		Runtime::SnapshotClient => {
+			run_snapshot_client(
+				args.addr,
+				args.room,
+				socket,
+				args.transport,
+				args.gamepad,
+				args.palette,
+				buffer,
+			)
+			.await
+		}
+		Runtime::Replay => {
+			let path = args.file.context("--file is required")?;
+			run_replay(&path, args.palette, buffer).await
+		}
+		Runtime::SelfPlay
+		| Runtime::Bench
+		| Runtime::MigrateReplay
+		| Runtime::VerifyReplay
+		| Runtime::Dispute
+		| Runtime::Simulate
+		| Runtime::PlayerStats
+		| Runtime::Bisect
+		| Runtime::Bot
+		| Runtime::Referee => {
+			unreachable!("headless runtime")
+		}
+	}
+}
+
+// The keyboard and mouse, or the gamepad asked for
+fn input_source(gamepad: Option<u32>) -> anyhow::Result<sim::TapLatch> {
+	Ok(match gamepad {
+		Some(index) => sim::TapLatch::with_source(Box::new(gamepad::Gamepad::open(index)?)),
+		None => sim::TapLatch::with_keys(sim::KeyMap::MOUSE),
+	})
+}
+
+fn alert_limits(args: &Args) -> alerts::Limits {
+	alerts::Limits {
+		rollback_depth: args.alert_rollback_depth,
+		rtt_ms: args.alert_rtt_ms,
+		late_inputs: args.alert_late_inputs,
+		secs: args.alert_secs,
+	}
+}
+
+fn socket_options(args: &Args) -> sockopt::SocketOptions {
+	sockopt::SocketOptions {
+		nodelay: !args.no_nodelay,
+		send_buffer: args.send_buffer,
+		recv_buffer: args.recv_buffer,
+		keepalive: args.keepalive_secs.map(Duration::from_secs),
+	}
+}
+
+// Server settings from the command line, and the invites to hand out printed
+fn server_config(args: Args, socket: sockopt::SocketOptions) -> anyhow::Result<net::ServerConfig> {
+	let resume = args.resume.as_deref().map(SaveGame::read).transpose()?;
+	let saved = resume.as_ref().map(|s| s.state.player_count());
+	let players = args.players.or(saved).unwrap_or(sim::DEFAULT_PLAYERS);
+	if let Some(saved) = saved
+		&& saved != players
+	{
+		anyhow::bail!("the saved match has {saved} players, not {players}");
+	}
+	let mut reserved = vec![None; players];
+	for (slot, token) in args.reserve {
+		if slot >= players {
+			anyhow::bail!("--reserve slot {slot} of {players} players");
+		}
+		reserved[slot] = Some(token);
+	}
+	let roster = match args.teams {
+		teams if teams.is_empty() => Roster::new(players),
+		teams if teams.len() != players => {
+			anyhow::bail!("--teams needs {players} entries, got {}", teams.len())
+		}
+		teams => Roster { teams },
+	};
+	roster.check().context("--teams")?;
+	if args.max_pair_latency_ms.is_some()
+		&& (resume.is_some() || reserved.iter().any(Option::is_some))
+	{
+		anyhow::bail!("--max-pair-latency-ms only applies to fresh matches without reservations");
+	}
+	// Rooms would share these files and ports, and pairing is the lobby's job
+	if args.rooms
+		&& (resume.is_some()
+			|| args.save.is_some()
+			|| args.record.is_some()
+			|| args.player_stats.is_some()
+			|| args.observe_addr.is_some()
+			|| args.control.is_some()
+			|| reserved.iter().any(Option::is_some)
+			|| args.max_pair_latency_ms.is_some())
+	{
+		anyhow::bail!(
+			"--rooms doesn't combine with --resume, --save, --record, --player-stats, \
+			 --observe-addr, --control, --reserve or --max-pair-latency-ms"
+		);
+	}
+	match args.room_workers {
+		Some(_) if !args.rooms => anyhow::bail!("--room-workers only applies to --rooms"),
+		Some(0) => anyhow::bail!("--room-workers needs at least 1"),
+		_ => {}
+	}
+	let cfg = net::ServerConfig {
+		addr: args.addr,
+		start_delay: Duration::from_millis(800),
+		lead_ticks: LEAD_TICKS,
+		d_max: D_MAX,
+		fairness: args.fairness,
+		save_path: args.save,
+		resume,
+		record_path: args.record,
+		best_of: args.best_of,
+		time_limit: args.time_limit_secs.map(Duration::from_secs),
+		first_tick: 0,
+		warmup: args.warmup,
+		watch: args.watch,
+		afk_after: (args.afk_secs > 0).then(|| Duration::from_secs(args.afk_secs)),
+		observe_addr: args.observe_addr,
+		reserved: reserved.clone(),
+		roster,
+		socket,
+		max_pair_latency: args.max_pair_latency_ms.map(Duration::from_millis),
+		career_path: args.player_stats,
+		ws_addr: args.ws_addr,
+		control_addr: args.control,
+		room_status: None,
+	};
+	// Reserved slots get an invite each, otherwise anyone may use the plain one
+	let mut codes: Vec<Option<u64>> = reserved.iter().copied().filter(Option::is_some).collect();
+	if codes.is_empty() {
+		codes.push(None);
+	}
+	let invites: Vec<invite::Invite> = codes
+		.into_iter()
+		.map(|code| invite::Invite {
+			addr: cfg.addr.clone(),
+			code,
+		})
+		.collect();
+	for invite in &invites {
+		println!("invite: {invite}");
+	}
+	if invites.iter().any(invite::Invite::needs_host) {
+		warn!("replace the unspecified address with one players can reach");
 	}
+	Ok(cfg)
 }
 
-async fn run_server(addr: String, buffer: RenderTarget) -> anyhow::Result<()> {
-	let rx_render = net::spawn_server(addr, Duration::from_millis(800), LEAD_TICKS, D_MAX);
+// The server without graphics, for machines with no display. Logs its tick once a
+// second and exits when the match or series is over. A lobby, with `rooms` for its
+// worker count, never does
+fn run_headless_server(
+	cfg: net::ServerConfig,
+	limits: alerts::Limits,
+	rooms: Option<usize>,
+) -> anyhow::Result<()> {
+	let rx_render = match rooms {
+		Some(workers) => lobby::spawn_lobby(cfg, workers),
+		None => net::spawn_server(cfg),
+	};
+	let mut alerts = alerts::Alerts::new(limits);
+	while let Ok(r) = rx_render.recv() {
+		alerts.observe(
+			Metric::LateInputs,
+			r.late_per_sec.iter().copied().max().unwrap_or(0),
+		);
+		alerts.update();
+		if r.tick.is_multiple_of(sim::TPS) {
+			let room = match r.room {
+				0 => String::new(),
+				id => format!("room {id} "),
+			};
+			println!(
+				"{room}tick {} sum={} in_delay={} late/s={}",
+				r.tick,
+				short_checksum(&r.state),
+				slash_list(&r.input_delays),
+				slash_list(&r.late_per_sec)
+			);
+		}
+	}
+	println!("server finished");
+	Ok(())
+}
+
+async fn run_server(
+	cfg: net::ServerConfig,
+	limits: alerts::Limits,
+	mut palette: Palette,
+	buffer: RenderTarget,
+) -> anyhow::Result<()> {
+	let roster = cfg.roster.clone();
+	let players = roster.players();
+	let rx_render = net::spawn_server(cfg);
+	let mut alerts = alerts::Alerts::new(limits);
+	let mut show_stats = false;
+	let mut perspective = Perspective::Arena;
 	let mut latest = net::ServerRender {
 		tick: 0,
-		state: SimState::new(),
+		state: SimState::new(players),
+		input_delays: vec![0; players],
+		input_windows: vec![0; players],
+		window_len: 0,
+		late_per_sec: vec![0; players],
+		room: 0,
 	};
 
 	loop {
 		while let Ok(r) = rx_render.try_recv() {
+			alerts.observe(
+				Metric::LateInputs,
+				r.late_per_sec.iter().copied().max().unwrap_or(0),
+			);
 			latest = r;
 		}
+		alerts.update();
+		if is_key_pressed(KeyCode::F3) {
+			show_stats = !show_stats;
+		}
+		if is_key_pressed(KeyCode::Tab) {
+			perspective = perspective.next(players);
+		}
+		if is_key_pressed(KeyCode::F6) {
+			palette = palette.next();
+		}
 
 		// Draw gameplay into the low-res buffer
-		let mut cam = sim::camera_for_buffer();
+		let mut cam = perspective.camera(&latest.state);
 		cam.render_target = Some(buffer.clone());
 		set_camera(&cam);
 		clear_background(BLACK);
-		for (i, p) in latest.state.players.iter().enumerate() {
-			let color = if i == 0 { BLUE } else { RED };
-			draw_rectangle(p.x, p.y, sim::Player::W, sim::Player::H, color);
-		}
+		draw_players(&latest.state, &roster, palette);
 
 		// Blit buffer to screen
 		set_default_camera();
 		clear_background(BLACK);
 		draw_buffer_to_screen(&buffer);
-		draw_text(
-			&format!("server tick {}", latest.tick),
-			10.0,
-			24.0,
-			16.0,
+		let mut hud = new_hud();
+		hud.text(
+			Anchor::TopLeft,
+			&format!(
+				"server tick {} sum={} in_delay={} [tab] {} [F6] {}",
+				latest.tick,
+				short_checksum(&latest.state),
+				slash_list(&latest.input_delays),
+				perspective.label(),
+				palette.label()
+			),
 			WHITE,
 		);
+		draw_input_windows(&latest, &mut hud);
+		if show_stats {
+			draw_stats(&mut hud, Anchor::TopLeft);
+		}
+		alerts.draw(&mut hud);
 
 		next_frame().await;
 	}
 }
 
-async fn run_client(addr: String, buffer: RenderTarget, malicious: bool) -> anyhow::Result<()> {
+async fn run_client(cfg: ClientConfig, buffer: RenderTarget) -> anyhow::Result<()> {
+	let ClientConfig {
+		mut addr,
+		backup_addr,
+		malicious,
+		sign,
+		force_mispredict,
+		dump_path,
+		measure_latency,
+		hybrid,
+		compact_history,
+		reservation,
+		room,
+		delay_control,
+		mut netsim,
+		bug_report_dir,
+		record_session,
+		replay_session,
+		socket,
+		rollback_flash,
+		rollback_beep,
+		quality_report,
+		transport,
+		mut palette,
+		alerts: alert_limits,
+		control,
+		gamepad,
+	} = cfg;
+	let mut taps = input_source(gamepad)?;
+	let mut quality = quality_report.map(quality::QualityReport::new);
+	let mut alerts = alerts::Alerts::new(alert_limits);
+	let mut session_recorder = record_session
+		.as_deref()
+		.map(session::SessionRecorder::create)
+		.transpose()?;
+	let mut session_script = replay_session
+		.as_deref()
+		.map(session::SessionScript::load)
+		.transpose()?;
 	let artificial_delay_ms = Arc::new(AtomicU32::new(0));
-	let (rx_evt, tx_cmd) = net::spawn_client(addr).context("spawn_client")?;
+	let (tx_control, rx_control) = mpsc::channel::<control::Request>();
+	if let Some(addr) = &control {
+		control::spawn(addr, move |req| {
+			let _ = tx_control.send(req);
+		})?;
+	}
+	let mut signer = sign.then(signing::InputSigner::generate).transpose()?;
+	let signing_key = signer.as_ref().map(|s| s.public_key());
+	let (mut rx_evt, mut tx_cmd) = net::spawn_client(
+		addr.clone(),
+		None,
+		reservation,
+		room.clone(),
+		signing_key,
+		socket,
+		transport,
+	)
+	.context("spawn_client")?;
+	// Negotiated in the handshake, --hybrid needs SNAPSHOTS
+	let mut server_caps = Capabilities::empty();
+	// The setup of the coming or current match, and why we refused the last one.
+	// A server with MATCH_SETUP doesn't get to start us without one we accept
+	let mut setup: Option<MatchSetup> = None;
+	let mut setup_refused: Option<&'static str> = None;
+	// Newest tick whose state went to the server to compare, and the tick it
+	// said we diverged at
+	let mut last_hashed: Option<u32> = None;
+	let mut desynced_at: Option<u32> = None;
+
+	// Slot token from the server, used to reclaim our slot after a server restart
+	let mut token: Option<u64> = None;
+	let mut disconnected = false;
+	let mut last_reconnect_attempt = Instant::now();
+	let mut standby = backup_addr
+		.map(|backup| failover::Standby::new(backup, room.clone(), signing_key, socket, transport));
+	// When the connection last said anything, and whether the standby claimed our
+	// slot and the Resume that answers it is still to come
+	let mut last_heard = Instant::now();
+	let mut failing_over = false;
 
 	// Delay queues
-	let mut in_q: VecDeque<(Instant, NetEvent)> = VecDeque::new();
-	let mut out_q: VecDeque<(Instant, NetCmd)> = VecDeque::new();
-	let mut in_last: Option<Instant> = None;
-	let mut out_last: Option<Instant> = None;
+	let mut in_q = netsim::DelayQueue::<NetEvent>::new();
+	let mut out_q = netsim::DelayQueue::<NetCmd>::new();
 
 	// Rolling history for rollback
-	let mut auth_inputs: Vec<Option<(u32, [InputBits; sim::PLAYER_COUNT])>> = vec![None; HISTORY];
-	let mut used_inputs: Vec<Option<(u32, [InputBits; sim::PLAYER_COUNT])>> = vec![None; HISTORY];
-	let mut state_history: Vec<Option<(u32, SimState)>> = vec![None; HISTORY];
+	let mut session = rollback::Session::new(sim::Arena::new(HISTORY), HISTORY);
+	if compact_history {
+		session = session.compacting(net::SNAPSHOT_INTERVAL_TICKS);
+	}
 
 	let mut my_id: usize = 0;
+	// How many play is the server's word, this holds until its start says
+	let mut roster = Roster::new(sim::DEFAULT_PLAYERS);
+	// Following the match without a slot, until the server hands one over
+	let mut spectating = false;
+	// Matchmaking hasn't found us an opponent close enough yet
+	let mut searching = false;
 	let mut sim_start_at: Option<Instant> = None;
 
-	let mut state = SimState::new();
-	let mut render_prev_state = state;
+	let mut state = SimState::new(roster.players());
+	let mut render_prev_state = state.clone();
 	let mut local_tick: u32 = 0;
 
-	let mut last_remote: [InputBits; sim::PLAYER_COUNT] = [InputBits::empty(), InputBits::empty()];
+	let mut last_remote = vec![PlayerInput::from(InputBits::empty()); roster.players()];
 	let mut latest_server_tick: u32 = 0;
-	let mut pending_rollback: Option<u32> = None;
+	let mut last_rollback_depth: u32 = 0;
+	// Players whose confirmed input differed from what we ran, since the last rollback
+	let mut mispredicted = vec![false; roster.players()];
+
+	// Fairness input delay assigned by the server, local inputs wait in a delay line
+	let mut input_delays = vec![0u8; roster.players()];
+	let mut local_delay_line: VecDeque<PlayerInput> = VecDeque::new();
+
+	let mut drift = clock::DriftEstimator::new();
+
+	// Set once the server reports the match result, cleared by the next AssignStart
+	let mut match_over: Option<SeriesState> = None;
+
+	// AFK kick deadline while warned, cleared as soon as we touch the keyboard
+	let mut afk_kick_at: Option<Instant> = None;
+	let mut kicked: Option<KickReason> = None;
+	// Why the server wouldn't have us, reconnecting won't change its mind
+	let mut rejected: Option<Reject> = None;
+
+	let mut feedback = feedback::Feedback::default();
+	let mut rollback_cue = feedback::RollbackCue::new(rollback_beep);
+
+	// Ping-derived clock offset and one-way latencies per direction
+	let mut clock_offset = clock::ClockOffset::default();
+	let mut last_ping: Option<Instant> = None;
+	// Measurement after connecting or a resume, and the extra stamp ticks a resume still adds
+	let mut remeasure_until = Some(Instant::now() + REMEASURE_DURATION);
+	let mut stamp_margin: u32 = 0;
+	// Newest window the server accepts our inputs for, stamps come from our clock until one arrives
+	let mut input_grant: Option<InputGrant> = None;
+	let mut sent_inputs = net::InputWindow::default();
+	let mut input_latency = latency::Histogram::default();
+	let mut tick_latency = latency::Histogram::default();
+
+	// --measure-latency: key change -> applied locally -> confirmed by the server
+	let mut probes: VecDeque<PressProbe> = VecDeque::new();
+	let mut last_keys = InputBits::empty();
+	let mut last_applied_keys = InputBits::empty();
+	let mut apply_latency = latency::Histogram::default();
+	let mut confirm_latency = latency::Histogram::default();
+
+	// Newest unchecked server snapshot, and how its corrections were shown. Every
+	// second one comes anyway, --hybrid subscribes to all of them
+	let mut correction: Option<StateSnapshot> = None;
+	let mut correcting = false;
+	let mut smoothing = smoothing::VisualOffsets::default();
+	let mut blended_corrections: u32 = 0;
+	let mut snapped_corrections: u32 = 0;
+	// --bug-reports: the server's state is drawn here to sit next to what we showed
+	let confirmed_buffer = bug_report_dir.as_ref().map(|_| {
+		let rt = render_target(sim::BUFFER_W, sim::BUFFER_H);
+		rt.texture.set_filter(FilterMode::Nearest);
+		rt
+	});
+	let mut last_bug_report: Option<Instant> = None;
+
+	// F3 lists the stats registry, net.rs feeds it too
+	let mut show_stats = false;
+	let mut net_stats = netstats::NetStats::default();
+	let mut inspector = inspector::Inspector::default();
+	let stat_tick = stats::gauge("client.tick");
+	let stat_rollbacks = stats::counter("client.rollbacks");
+	let stat_resimulated = stats::counter("client.resimulated_ticks");
+	let stat_rollback_depth = stats::gauge("client.rollback_depth");
+	let stat_corrections = stats::counter("client.corrections");
+	let stat_stamp_margin = stats::gauge("client.stamp_margin");
+	let stat_sample_offset = stats::gauge("client.sample_offset_us");
 
 	let mut accumulator: f32 = 0.0;
+	let mut frame_times = frametime::FrameTimes::default();
 
 	loop {
+		frame_times.begin_frame();
+		if is_key_pressed(KeyCode::F3) {
+			show_stats = !show_stats;
+		}
+		if is_key_pressed(KeyCode::F6) {
+			palette = palette.next();
+		}
+		let cur_delay = artificial_delay_ms.load(Ordering::Relaxed);
+		let mut delay = cur_delay;
 		if is_key_pressed(KeyCode::Left) {
-			let cur = artificial_delay_ms.load(Ordering::Relaxed);
-			artificial_delay_ms.store(cur.saturating_sub(10), Ordering::Relaxed);
+			delay = delay.saturating_sub(10);
 		}
-
 		if is_key_pressed(KeyCode::Right) {
-			let cur = artificial_delay_ms.load(Ordering::Relaxed);
-			artificial_delay_ms.store(cur.saturating_add(10), Ordering::Relaxed);
+			delay = delay.saturating_add(10);
 		}
-
-		// Pull raw network events and schedule inbound delay
-		while let Ok(ev) = rx_evt.try_recv() {
-			match ev {
-				NetEvent::AssignStart(_) => in_q.push_back((Instant::now(), ev)),
-				NetEvent::TickInputs(_) => schedule_with_delay(
-					&mut in_q,
-					&mut in_last,
-					ev,
-					artificial_delay_ms.load(Ordering::Relaxed),
-				),
+		if let Some(script) = session_script.as_mut() {
+			for action in script.due(local_tick) {
+				if let session::Action::Delay { ms } = action {
+					delay = ms;
+				}
 			}
 		}
-
-		// Flush outbound delayed commands
-		let now = Instant::now();
-		while let Some((send_at, _)) = out_q.front() {
-			if *send_at > now {
-				break;
+		while let Ok(req) = rx_control.try_recv() {
+			let result = match req.command {
+				control::Command::SetDelay(ms) => {
+					delay = ms;
+					Ok(serde_json::Value::Null)
+				}
+				control::Command::Disconnect(None) => {
+					let _ = tx_cmd.send(NetCmd::Disconnect);
+					Ok(serde_json::Value::Null)
+				}
+				control::Command::Disconnect(Some(_)) => {
+					Err("a client drops its own connection, no player".to_string())
+				}
+				control::Command::Dump => Ok(control::dump(local_tick, &state)),
+			};
+			req.reply(result);
+		}
+		if delay != cur_delay {
+			artificial_delay_ms.store(delay, Ordering::Relaxed);
+			if let Some(rec) = session_recorder.as_mut()
+				&& let Err(e) = rec.record(local_tick, session::Action::Delay { ms: delay })
+			{
+				error!("session recording failed: {:?}", e);
+			}
+		}
+
+		taps.poll();
+		let keys = taps.held();
+		if !keys.is_empty() {
+			afk_kick_at = None;
+		}
+		if measure_latency && keys != last_keys {
+			last_keys = keys;
+			if probes.len() == 64 {
+				probes.pop_front();
+			}
+			probes.push_back(PressProbe {
+				detected_at: Instant::now(),
+				bits: keys,
+				stamped_tick: None,
+			});
+		}
+
+		// Dump every confirmed tick still in the rollback history
+		if is_key_pressed(KeyCode::F9)
+			&& let Some(path) = &dump_path
+		{
+			// Ticks wrap, walk them as offsets from the oldest kept
+			let confirmed = latest_server_tick.wrapping_add(1);
+			let end = if rollback::is_before(confirmed, local_tick) {
+				confirmed
+			} else {
+				local_tick
+			};
+			let mut start = end.wrapping_sub(HISTORY as u32 - 1);
+			while start != end && session.load(start).is_none() {
+				start = start.wrapping_add(1);
 			}
-			let (_, cmd) = out_q.pop_front().unwrap();
+			let mut ticks = Vec::new();
+			for k in 0..end.wrapping_sub(start) {
+				let t = start.wrapping_add(k);
+				let Some(used) = session.used(t) else {
+					break;
+				};
+				let after = if t.wrapping_add(1) == local_tick {
+					Some(state.clone())
+				} else {
+					session.load(t.wrapping_add(1))
+				};
+				let Some(after) = after else {
+					break;
+				};
+				ticks.push(dispute::DumpTick {
+					inputs: TickInputs {
+						tick: t,
+						inputs: used.iter().map(|i| i.bits.as_u8()).collect(),
+						aims: used.iter().map(|i| i.aim).collect(),
+					},
+					checksum: sim::checksum(&after),
+				});
+			}
+			if let Some(start_state) = session.load(start) {
+				let dump = dispute::DesyncDump {
+					player_id: my_id as u8,
+					start_state,
+					ticks,
+				};
+				match dump.write(path) {
+					Ok(()) => info!("wrote desync dump to {}", path.display()),
+					Err(e) => error!("desync dump failed: {:?}", e),
+				}
+			}
+		}
+
+		if let Some(standby) = standby.as_mut() {
+			standby.maintain(token.filter(|_| !spectating && kicked.is_none()));
+			let in_play = !spectating
+				&& !searching
+				&& match_over.is_none()
+				&& sim_start_at.is_some_and(|t| Instant::now() >= t);
+			let silent = in_play && last_heard.elapsed() >= FAILOVER_SILENCE;
+			if (disconnected || silent)
+				&& kicked.is_none()
+				&& rejected.is_none()
+				&& let Some((rx, tx)) = standby.take_over(&mut addr)
+			{
+				info!("connection lost, failing over to {}", addr);
+				let _ = tx_cmd.send(NetCmd::Disconnect);
+				rx_evt = rx;
+				tx_cmd = tx;
+				disconnected = false;
+				failing_over = true;
+				last_heard = Instant::now();
+			}
+		}
+
+		if disconnected
+			&& kicked.is_none()
+			&& rejected.is_none()
+			&& let Some(token) = token
+			&& last_reconnect_attempt.elapsed() >= RECONNECT_INTERVAL
+		{
+			last_reconnect_attempt = Instant::now();
+			if let Ok((rx, tx)) = net::spawn_client(
+				addr.clone(),
+				Some(token),
+				reservation,
+				room.clone(),
+				signing_key,
+				socket,
+				transport,
+			) {
+				rx_evt = rx;
+				tx_cmd = tx;
+				disconnected = false;
+			}
+		}
+
+		// Pull raw network events and schedule inbound delay
+		net::pump();
+		while let Ok(ev) = rx_evt.try_recv() {
+			last_heard = Instant::now();
+			let Some(kind) = netsim::Kind::of_event(&ev) else {
+				// The handshake and a dropped connection are local, never simulated
+				match ev {
+					NetEvent::Welcome(caps) => {
+						server_caps = caps;
+						if hybrid.is_some() && caps.contains(Capabilities::SNAPSHOTS) {
+							let _ = tx_cmd.send(NetCmd::SubscribeSnapshots);
+						}
+					}
+					_ => in_q.push(ev),
+				}
+				continue;
+			};
+			if kind.is_control() && !delay_control && !netsim.has_policy(kind) {
+				in_q.push(ev);
+				continue;
+			}
+			let mut base_ms = artificial_delay_ms.load(Ordering::Relaxed);
+			if kind.is_control() {
+				base_ms = base_ms.min(MAX_CONTROL_DELAY_MS);
+			}
+			let deliveries = netsim.deliveries(kind, base_ms, None);
+			if deliveries.is_empty() {
+				continue;
+			}
+			// Sample clock offset on raw receipt, the artificial delay would read as drift
+			if let (NetEvent::TickInputs(m), Some(start_at)) = (&ev, sim_start_at) {
+				let clock_tick = Instant::now()
+					.saturating_duration_since(start_at)
+					.as_secs_f64() * sim::TPS as f64;
+				drift.observe(clock_tick, m.msg.tick);
+			}
+			in_q.schedule(ev, &deliveries);
+		}
+
+		if remeasure_until.is_some_and(|t| Instant::now() >= t) {
+			remeasure_until = None;
+		}
+		let ping_interval = match remeasure_until {
+			Some(_) => RESUME_PING_INTERVAL,
+			None => PING_INTERVAL,
+		};
+		if !disconnected && !spectating && last_ping.is_none_or(|t| t.elapsed() >= ping_interval) {
+			last_ping = Some(Instant::now());
+			let ping = Ping {
+				client_us: clock::wall_us(),
+				offset_us: clock_offset.offset_us(),
+			};
+			let base_ms = artificial_delay_ms.load(Ordering::Relaxed);
+			let deliveries = netsim.deliveries(netsim::Kind::Ping, base_ms, None);
+			out_q.schedule(NetCmd::Ping(ping), &deliveries);
+		}
+
+		// Flush outbound delayed commands
+		while let Some(cmd) = out_q.pop_due(Instant::now()) {
 			let _ = tx_cmd.send(cmd);
 		}
 
 		// Deliver inbound events whose delay has elapsed
-		let now = Instant::now();
-		while let Some((deliver_at, _)) = in_q.front() {
-			if *deliver_at > now {
-				break;
-			}
-			let (_, ev) = in_q.pop_front().unwrap();
+		while let Some(ev) = in_q.pop_due(Instant::now()) {
 			match ev {
-				NetEvent::AssignStart(a) => {
-					my_id = a.player_id as usize;
-					sim_start_at =
-						Some(Instant::now() + Duration::from_millis(a.start_after_ms as u64));
-
-					state = SimState::new();
-					render_prev_state = state;
-					local_tick = 0;
-					latest_server_tick = 0;
-					pending_rollback = None;
+				NetEvent::MatchSetup(s) => match check_setup(&s) {
+					Ok(()) => {
+						setup = Some(s);
+						setup_refused = None;
+					}
+					Err(reason) => {
+						error!("refusing the match setup: {}", reason);
+						setup = None;
+						setup_refused = Some(reason);
+					}
+				},
+				// The standby has our slot now. Same server, same match: the timeline
+				// carries on, only what went down with the old connection is made up for
+				NetEvent::Resume(r)
+					if failing_over
+						&& !spectating && token == Some(r.token)
+						&& r.player_id as usize == my_id =>
+				{
+					failing_over = false;
+					input_grant = None;
+					latest_server_tick = rollback::later(latest_server_tick, r.tick);
+					// The server's state covers whatever ticks the old connection lost
+					if !rollback::is_before(local_tick, r.tick) {
+						session.reseed(r.tick, &r.state);
+					} else {
+						state = r.state;
+						render_prev_state = state.clone();
+						local_tick = r.tick;
+						session.clear();
+					}
+					if let Some(cmd) = sent_inputs.resend() {
+						let _ = tx_cmd.send(cmd);
+					}
+					if hybrid.is_some() && server_caps.contains(Capabilities::SNAPSHOTS) {
+						let _ = tx_cmd.send(NetCmd::SubscribeSnapshots);
+					}
+				}
+				NetEvent::AssignStart(_) | NetEvent::Resume(_) | NetEvent::SpectateStart(_)
+					if server_caps.contains(Capabilities::MATCH_SETUP) && setup.is_none() => {}
+				NetEvent::AssignStart(_) | NetEvent::Resume(_) | NetEvent::SpectateStart(_) => {
+					if let Err(e) = check_start(&ev) {
+						error!("ignoring the match start: {:#}", e);
+						continue;
+					}
+					// A fresh match is a resume from tick 0, following one is a resume
+					// without a slot until the server hands us one
+					spectating = matches!(ev, NetEvent::SpectateStart(_));
+					searching = false;
+					input_grant = None;
+					sent_inputs.clear();
+					// A restarted server has a new clock, our offset and stamps are stale
+					if matches!(ev, NetEvent::Resume(_)) {
+						clock_offset = clock::ClockOffset::default();
+						last_ping = None;
+						remeasure_until = Some(Instant::now() + REMEASURE_DURATION);
+						stamp_margin = RESUME_STAMP_MARGIN_TICKS;
+					} else {
+						stamp_margin = 0;
+					}
+					let r = match ev {
+						NetEvent::Resume(r) => r,
+						NetEvent::AssignStart(a) => ResumeState {
+							player_id: a.player_id,
+							token: a.token,
+							tick: 0,
+							start_after_ms: a.start_after_ms,
+							state: SimState::with_teams(a.roster.teams.clone())
+								.warming_up(setup.is_some_and(|s| s.rules.warmup)),
+							roster: a.roster,
+						},
+						NetEvent::SpectateStart(s) => ResumeState {
+							player_id: 0,
+							token: token.unwrap_or_default(),
+							tick: s.tick,
+							start_after_ms: 0,
+							state: s.state,
+							roster: s.roster,
+						},
+						_ => unreachable!(),
+					};
+					my_id = r.player_id as usize;
+					roster = r.roster;
+					if !spectating {
+						token = Some(r.token);
+					}
+					let start_at = Instant::now() + Duration::from_millis(r.start_after_ms as u64);
+					sim_start_at = start_at
+						.checked_sub(Duration::from_secs_f64(r.tick as f64 * sim::DT as f64));
+
+					let players = roster.players();
+					state = r.state;
+					render_prev_state = state.clone();
+					local_tick = r.tick;
+					latest_server_tick = r.tick;
+					last_rollback_depth = 0;
+					mispredicted = vec![false; players];
 					accumulator = 0.0;
-					in_q.clear();
-					out_q.clear();
-					in_last = None;
-					out_last = None;
-					last_remote = [InputBits::empty(), InputBits::empty()];
-					for s in auth_inputs.iter_mut() {
-						*s = None;
+					// The history a spectator catches up with is already queued behind it, and
+					// so is the new match's traffic when the start went through the delay
+					if !spectating {
+						if !delay_control {
+							in_q.clear();
+						}
+						out_q.clear();
+					}
+					last_remote = vec![InputBits::empty().into(); players];
+					input_delays = vec![0; players];
+					local_delay_line.clear();
+					drift = clock::DriftEstimator::new();
+					match_over = None;
+					session.clear();
+					if let Some(signer) = signer.as_mut() {
+						signer.reset();
+					}
+					feedback.clear();
+					probes.clear();
+					correction = None;
+					correcting = false;
+					smoothing.clear();
+				}
+				// Another match's, whatever a server sends has to fit the one we play
+				NetEvent::TickInputs(Stamped { msg: m, .. })
+					if m.inputs.len() != roster.players() => {}
+				NetEvent::TickInputs(Stamped { msg: m, sent_us }) => {
+					if let Some(offset) = clock_offset.offset_us() {
+						let sent = sent_us as i64 - offset;
+						tick_latency.push((clock::wall_us() as i64 - sent).max(0) as u32);
+					}
+					latest_server_tick = rollback::later(latest_server_tick, m.tick);
+					// A probe is confirmed by the first authoritative tick carrying it,
+					// the player may have moved on before a late one made it
+					probes.retain(|p| match p.stamped_tick {
+						Some(t)
+							if !rollback::is_before(m.tick, t)
+								&& m.inputs[my_id] == p.bits.as_u8() =>
+						{
+							confirm_latency.push(p.detected_at.elapsed().as_micros() as u32);
+							false
+						}
+						Some(t) => !rollback::is_before(t.wrapping_add(D_MAX), m.tick),
+						None => true,
+					});
+					if let Some(sig) = signer
+						.as_mut()
+						.filter(|_| !spectating)
+						.and_then(|s| s.push(my_id as u8, m.tick, m.inputs[my_id], m.aims[my_id]))
+					{
+						let _ = tx_cmd.send(NetCmd::SendSignature(sig));
 					}
-					for s in used_inputs.iter_mut() {
-						*s = None;
+					let inputs = m.sim_inputs();
+					if let Some(used) = session.used(m.tick) {
+						for (pid, (u, i)) in used.iter().zip(&inputs).enumerate() {
+							mispredicted[pid] |= u != i;
+						}
+					}
+					session.confirm(m.tick, inputs.clone());
+					last_remote = inputs;
+				}
+				NetEvent::InputDelay(d) if d.delays.len() == roster.players() => {
+					input_delays = d.delays
+				}
+				NetEvent::InputDelay(_) => {}
+				NetEvent::Disconnected => {
+					disconnected = true;
+					failing_over = false;
+					last_reconnect_attempt = Instant::now();
+				}
+				NetEvent::Series(s) => match_over = Some(s),
+				NetEvent::AfkWarning(w) => {
+					afk_kick_at = Some(Instant::now() + Duration::from_millis(w.kick_in_ms as u64));
+				}
+				NetEvent::Kicked(r) => kicked = Some(r),
+				NetEvent::Rejected(r) => {
+					error!("the server refused us: {}", r);
+					rejected = Some(r);
+				}
+				NetEvent::DesyncDetected(tick) => {
+					error!("the server's state differs from ours at tick {}", tick);
+					desynced_at = Some(tick);
+				}
+				NetEvent::Pong(p) => {
+					clock_offset.observe(
+						p.client_us,
+						p.server_recv_us,
+						p.server_send_us,
+						clock::wall_us(),
+					);
+					if let Some(rtt) = clock_offset.last_rtt_us() {
+						alerts.observe(Metric::RttMs, (rtt / 1000).max(0) as u32);
+						if let Some(q) = quality.as_mut() {
+							q.ping(rtt);
+						}
 					}
-					for s in state_history.iter_mut() {
-						*s = None;
+					for us in p.input_latency_us {
+						input_latency.push(us);
 					}
 				}
-				NetEvent::TickInputs(m) => {
-					latest_server_tick = latest_server_tick.max(m.tick);
-					let idx = (m.tick as usize) % HISTORY;
-					let inputs = [
-						InputBits::from_u8(m.inputs[0]),
-						InputBits::from_u8(m.inputs[1]),
-					];
-					auth_inputs[idx] = Some((m.tick, inputs));
-					last_remote[0] = inputs[0];
-					last_remote[1] = inputs[1];
-
-					if let Some((t_used, used)) = used_inputs[idx] {
-						if t_used == m.tick && used != inputs {
-							pending_rollback = Some(match pending_rollback {
-								Some(t0) => t0.min(m.tick),
-								None => m.tick,
-							});
+				// Without --hybrid only a desync is corrected, past the confirmed tick
+				// a difference is a misprediction input rollback fixes
+				NetEvent::Snapshot(s) if s.state.player_count() != roster.players() => {}
+				NetEvent::Snapshot(s)
+					if hybrid.is_some() || !rollback::is_before(latest_server_tick, s.tick) =>
+				{
+					correction = Some(s)
+				}
+				NetEvent::History(h) if spectating => {
+					// Catch up on the match so far at once, it may be longer than the rollback ring
+					for t in &h {
+						if rollback::is_before(t.tick, local_tick) {
+							continue;
+						}
+						if t.tick != local_tick || t.inputs.len() != roster.players() {
+							break;
 						}
+						let inputs = t.sim_inputs();
+						session.confirm(local_tick, inputs.clone());
+						session.step(local_tick, &mut state, inputs.clone());
+						last_remote = inputs;
+						latest_server_tick = local_tick;
+						local_tick = local_tick.wrapping_add(1);
 					}
+					render_prev_state = state.clone();
+					// The server is about as far as its history, the drift estimate takes it from here
+					sim_start_at = Instant::now().checked_sub(Duration::from_secs_f64(
+						local_tick.wrapping_add(LEAD_TICKS) as f64 * sim::DT as f64,
+					));
 				}
+				NetEvent::Control(c) => {
+					// Same timeline, from c.tick on our keyboard drives c.player_id
+					my_id = c.player_id as usize;
+					token = Some(c.token);
+					spectating = false;
+					input_grant = None;
+					sent_inputs.clear();
+					local_delay_line.clear();
+					probes.clear();
+					last_applied_keys = InputBits::empty();
+					if let Some(signer) = signer.as_mut() {
+						signer.reset();
+					}
+					if hybrid.is_some() && server_caps.contains(Capabilities::SNAPSHOTS) {
+						let _ = tx_cmd.send(NetCmd::SubscribeSnapshots);
+					}
+					info!("now controlling P{my_id} from tick {}", c.tick);
+				}
+				NetEvent::Searching => {
+					// Outside matchmaking it means our opponent left a watch-mode server
+					searching = true;
+					sim_start_at = None;
+				}
+				NetEvent::InputGrant(g) => input_grant = Some(g),
+				// Only for referees
+				NetEvent::StateHash(_) => {}
+				NetEvent::History(_) | NetEvent::Snapshot(_) | NetEvent::Welcome(_) => {}
 			}
 		}
 
+		frame_times.mark(frametime::Phase::Net);
+
 		// Wait for start
 		let Some(start_at) = sim_start_at else {
 			set_default_camera();
 			clear_background(BLACK);
-			draw_text("connecting...", 20.0, 30.0, 16.0, WHITE);
+			let text = if let Some(r) = rejected {
+				format!("refused by the server: {r}")
+			} else if let Some(reason) = setup_refused {
+				reason.to_string()
+			} else if searching {
+				"waiting for an opponent...".to_string()
+			} else {
+				"connecting...".to_string()
+			};
+			new_hud().text(Anchor::TopLeft, &text, WHITE);
 			next_frame().await;
 			continue;
 		};
 		if Instant::now() < start_at {
 			set_default_camera();
 			clear_background(BLACK);
-			draw_text("waiting for start...", 20.0, 30.0, 16.0, WHITE);
+			new_hud().text(Anchor::TopLeft, "waiting for start...", WHITE);
 			next_frame().await;
 			continue;
 		}
 
+		// A snapshot disagreeing with our history re-seeds it and rolls back from
+		// there, even when the difference goes back further than inputs could. An
+		// earlier input rollback goes first, it may already fix the state
+		if let Some(snap) = correction.take_if(|s| {
+			rollback::is_before(s.tick, local_tick)
+				&& session
+					.pending_rollback()
+					.is_none_or(|t| !rollback::is_before(t, s.tick))
+		}) && let Some(ours) = session.load(snap.tick)
+			&& sim::checksum(&ours) != sim::checksum(&snap.state)
+		{
+			// Past the confirmed tick it may just be a misprediction, before it's a desync
+			if let (Some(dir), Some(confirmed_buffer)) = (&bug_report_dir, &confirmed_buffer)
+				&& !rollback::is_before(latest_server_tick, snap.tick)
+				&& last_bug_report.is_none_or(|t| t.elapsed() >= BUG_REPORT_INTERVAL)
+			{
+				last_bug_report = Some(Instant::now());
+				let seen = buffer.texture.get_texture_data();
+				let mut cam = sim::camera_for_buffer();
+				cam.render_target = Some(confirmed_buffer.clone());
+				set_camera(&cam);
+				clear_background(BLACK);
+				draw_players(&snap.state, &roster, palette);
+				set_default_camera();
+				let confirmed = confirmed_buffer.texture.get_texture_data();
+				let report = bugreport::DesyncReport {
+					player_id: my_id as u8,
+					tick: snap.tick,
+					local_tick,
+					confirmed_tick: latest_server_tick,
+					local: ours,
+					server: snap.state.clone(),
+				};
+				match report.write(dir, &seen, &confirmed) {
+					Ok(folder) => info!("wrote desync report to {}", folder.display()),
+					Err(e) => error!("desync report failed: {:?}", e),
+				}
+			}
+			if hybrid.is_none() {
+				error!(
+					"corrected a desync from the server's state at tick {}",
+					snap.tick
+				);
+			}
+			session.reseed(snap.tick, &snap.state);
+			correcting = true;
+		}
+
 		// If we detected an authoritative mismatch, rewind to that tick and replay
-		if let Some(t_rb) = pending_rollback {
-			let idx = (t_rb as usize) % HISTORY;
-			if let Some((t_saved, saved)) = state_history[idx] {
-				if t_saved == t_rb {
-					state = saved;
-					render_prev_state = state;
-					local_tick = t_rb;
-					pending_rollback = None;
+		// Authoritative inputs where known, otherwise our own sent input and re-predicted remotes
+		if let Some(rb) = session.rollback(local_tick, |_, used| {
+			let mut inputs = last_remote.clone();
+			if let Some(used) = used {
+				inputs[my_id] = used[my_id];
+			}
+			inputs
+		}) {
+			let mut before = rb.start;
+			for after in rb.states {
+				render_prev_state = before;
+				before = after;
+			}
+			last_rollback_depth = local_tick.wrapping_sub(rb.from);
+			stat_rollbacks.inc();
+			stat_resimulated.add(last_rollback_depth as u64);
+			stat_rollback_depth.set(last_rollback_depth as i64);
+			rollback_cue.rollback(last_rollback_depth, &mispredicted);
+			mispredicted.fill(false);
+			alerts.observe(Metric::RollbackDepth, last_rollback_depth);
+			if let Some(q) = quality.as_mut() {
+				q.rollback(last_rollback_depth);
+			}
+			// Players glide from where they were drawn to the corrected position, a
+			// hybrid correction uses its own threshold
+			let max_px = match hybrid {
+				Some(px) if correcting => px,
+				_ => smoothing::MAX_SMOOTH_PX,
+			};
+			let snapped = smoothing.absorb(&state, &before, max_px);
+			if correcting {
+				stat_corrections.inc();
+				if snapped == 0 {
+					blended_corrections += 1;
+				} else {
+					snapped_corrections += 1;
 				}
 			}
+			correcting = false;
+			state = before;
 		}
+		frame_times.mark(frametime::Phase::Rollback);
 
-		// Determine where we should be by clock time
-		let time_tick = (Instant::now()
+		// Every so often a state all of whose inputs were the server's goes for
+		// comparison, the newest on the interval if it's still in our history
+		let hash_tick = latest_server_tick.wrapping_add(1) / net::STATE_HASH_INTERVAL_TICKS
+			* net::STATE_HASH_INTERVAL_TICKS;
+		if server_caps.contains(Capabilities::STATE_HASHES)
+			&& !spectating
+			&& rollback::is_before(hash_tick, local_tick)
+			&& local_tick.wrapping_sub(hash_tick) <= HISTORY as u32
+			&& last_hashed != Some(hash_tick)
+			&& session.pending_rollback().is_none()
+			&& let Some(confirmed) = session.load(hash_tick)
+		{
+			last_hashed = Some(hash_tick);
+			let _ = tx_cmd.send(NetCmd::SendStateHash(StateHash {
+				tick: hash_tick,
+				hash: sim::checksum(&confirmed),
+			}));
+		}
+
+		// Determine where we should be by clock time, minus the slewed drift correction
+		drift.slew(get_frame_time());
+		let clock_tick = Instant::now()
 			.saturating_duration_since(start_at)
-			.as_secs_f32()
-			* sim::TPS as f32)
-			.floor() as u32;
-		let target_tick = time_tick;
+			.as_secs_f64()
+			* sim::TPS as f64;
+		let time_tick = (clock_tick - drift.correction()).max(0.0).floor() as u32;
 
-		// Estimated latency from the artificial delay
+		// One way to the server, half the measured round trip. Until the first pong
+		// the artificial delay is the only guess
 		let delay_ms = artificial_delay_ms.load(Ordering::Relaxed);
-		let latency_ticks = ((delay_ms as f32 / 1000.0) * sim::TPS as f32).floor() as u32;
+		let latency_ticks = match clock_offset.rtt_us() {
+			Some(rtt) => (rtt as f64 / 2e6 * sim::TPS as f64).ceil() as u32,
+			None => ((delay_ms as f32 / 1000.0) * sim::TPS as f32).floor() as u32,
+		};
+
+		// Ahead of the server by the lead plus the trip there, so an input stamped
+		// with the tick it was simulated on arrives before the server runs it
+		let lead = LEAD_TICKS + latency_ticks;
+		let target_tick = time_tick + latency_ticks;
 
 		// Estimated current server tick from shared time
 		let server_tick_est = time_tick.saturating_sub(LEAD_TICKS);
@@ -309,104 +2052,1074 @@ async fn run_client(addr: String, buffer: RenderTarget, malicious: bool) -> anyh
 		// Time dilation to keep a healthy lead relative to the server timeline
 		let expected_server_tick = time_tick.saturating_sub(LEAD_TICKS);
 		let ahead = local_tick as i64 - expected_server_tick as i64;
-		let sim_rate = if ahead < (LEAD_TICKS as i64 - 1) {
+		let sim_rate = if ahead < (lead as i64 - 1) {
 			1.03
-		} else if ahead > (LEAD_TICKS as i64 + 2) {
+		} else if ahead > (lead as i64 + 2) {
 			0.98
 		} else {
 			1.0
 		};
 		accumulator += get_frame_time() * sim_rate;
+		if let Some(q) = quality.as_mut()
+			&& let Err(e) = q.update(ahead)
+		{
+			error!("quality report failed: {:?}", e);
+		}
+
+		// Where on its timeline the client is as it samples this frame's inputs,
+		// in ticks. Each input's offset from its tick's start goes to telemetry
+		let sample_tick = clock_tick - drift.correction() + latency_ticks as f64;
 
 		// Simulate forward (catch up if behind)
 		let mut steps_this_frame: u32 = 0;
-		while local_tick < target_tick
+		while match_over.is_none()
+			&& local_tick < target_tick
 			&& steps_this_frame < CATCHUP_BUDGET_TICKS
 			&& (accumulator >= sim::DT || local_tick + 1 < target_tick)
 		{
 			if accumulator < sim::DT {
 				accumulator = sim::DT;
 			}
-			render_prev_state = state;
+			render_prev_state = state.clone();
 
-			let idx = (local_tick as usize) % HISTORY;
-			state_history[idx] = Some((local_tick, state));
+			// Aim from our predicted centre to the mouse, quantized before the sim
+			// sees it, unless a gamepad aims
+			let aim = taps.aim().unwrap_or_else(|| {
+				let mouse = screen_to_buffer(mouse_position().into());
+				sim::quantize_aim(mouse - state.players[my_id].center().to_vec2())
+			});
 
-			let mut inputs = [InputBits::empty(), InputBits::empty()];
-			let mut have_auth = false;
-			if let Some((t, auth)) = auth_inputs[idx] {
-				if t == local_tick {
-					inputs = auth;
-					have_auth = true;
-				}
+			// Fairness delay: keyboard state only takes effect input_delay ticks later
+			local_delay_line.push_back(PlayerInput {
+				bits: taps.sample(),
+				aim,
+			});
+			let mut local_input = PlayerInput::from(InputBits::empty());
+			while local_delay_line.len() > input_delays[my_id] as usize {
+				local_input = local_delay_line.pop_front().unwrap();
 			}
-			if !have_auth {
-				for pid in 0..sim::PLAYER_COUNT {
-					if pid == my_id {
-						inputs[pid] = InputBits::from_keyboard();
+
+			let mut inputs = vec![PlayerInput::from(InputBits::empty()); roster.players()];
+			let auth = session.authoritative(local_tick);
+			if let Some(auth) = auth {
+				inputs = auth;
+			} else {
+				// Flipping every remote bit guarantees the prediction is wrong
+				let corrupt =
+					force_mispredict.is_some_and(|k| k > 0 && local_tick.is_multiple_of(k));
+				for pid in 0..roster.players() {
+					if pid == my_id && !spectating {
+						inputs[pid] = local_input;
+					} else if corrupt {
+						inputs[pid] = PlayerInput {
+							bits: last_remote[pid].bits.complement(),
+							..last_remote[pid]
+						};
 					} else {
 						inputs[pid] = last_remote[pid];
 					}
 				}
 			}
-			used_inputs[idx] = Some((local_tick, inputs));
 
-			// Delay input submission by the same ms
-			let stamped_tick = local_tick.saturating_add(latency_ticks).min(max_stamp_tick);
-			schedule_with_delay(
-				&mut out_q,
-				&mut out_last,
-				NetCmd::SendInput {
+			// The lead already covers the latency, only a resume's margin goes on top
+			if remeasure_until.is_none()
+				&& stamp_margin > 0
+				&& local_tick.is_multiple_of(MARGIN_DECAY_TICKS)
+			{
+				stamp_margin -= 1;
+			}
+			stat_stamp_margin.set(stamp_margin as i64);
+			let wanted_tick = local_tick.saturating_add(stamp_margin);
+			let stamped_tick = match input_grant {
+				Some(grant) => grant.clamp(wanted_tick),
+				None => wanted_tick.min(max_stamp_tick),
+			};
+
+			// A keyboard change reached the sim, match it to the oldest probe carrying it
+			if local_input.bits != last_applied_keys
+				&& let Some(i) = probes
+					.iter()
+					.position(|p| p.stamped_tick.is_none() && p.bits == local_input.bits)
+			{
+				// Older changes never reached a tick (pressed and released within one)
+				let mut rest = probes.split_off(i);
+				probes.retain(|p| p.stamped_tick.is_some());
+				rest[0].stamped_tick = Some(stamped_tick);
+				apply_latency.push(rest[0].detected_at.elapsed().as_micros() as u32);
+				probes.append(&mut rest);
+			}
+			last_applied_keys = local_input.bits;
+			if !spectating {
+				let offset_us = ((sample_tick - local_tick as f64) * sim::DT as f64 * 1e6) as i32;
+				stat_sample_offset.set(offset_us as i64);
+				if let Some(q) = quality.as_mut() {
+					q.sampled(offset_us);
+				}
+				// Windowed before the netsim, so the next message covers a dropped one
+				let cmd = sent_inputs.send(InputMsg {
 					tick: stamped_tick,
-					bits: inputs[my_id].as_u8(),
-				},
-				delay_ms,
-			);
+					bits: inputs[my_id].bits.as_u8(),
+					aim: inputs[my_id].aim,
+					ack_tick: latest_server_tick,
+					sent_us: clock::wall_us(),
+				});
+				let deliveries =
+					netsim.deliveries(netsim::Kind::Input, delay_ms, cmd.datagram_len());
+				out_q.schedule(cmd, &deliveries);
+			}
 
-			sim::step(&mut state, inputs);
+			session.step(local_tick, &mut state, inputs);
 
 			if malicious {
-				state.players[my_id].y -= 20.0;
-				state.players[my_id].vy = 0.0;
+				state.players[my_id].y -= num::num(20.0);
+				state.players[my_id].vy = num::ZERO;
 			}
 
 			local_tick = local_tick.wrapping_add(1);
 			accumulator -= sim::DT;
 			steps_this_frame += 1;
 		}
+		frame_times.mark(frametime::Phase::Sim);
+
+		stat_tick.set(local_tick as i64);
+		feedback.confirm(&session);
+		feedback.update(get_frame_time());
+		rollback_cue.update(get_frame_time());
+		smoothing.decay(get_frame_time());
 
 		// Render interpolation
 		let alpha = (accumulator / sim::DT).clamp(0.0, 1.0);
 
 		// Draw gameplay into low-res buffer
 		let mut cam = sim::camera_for_buffer();
+		cam.target += feedback.shake();
 		cam.render_target = Some(buffer.clone());
 		set_camera(&cam);
 		clear_background(BLACK);
-		for i in 0..sim::PLAYER_COUNT {
+		draw_hill(palette);
+		let muzzle = feedback.muzzle_flashes();
+		let blame = rollback_cue.blame();
+		let (w, h) = (num::to_f32(sim::Player::W), num::to_f32(sim::Player::H));
+		for (i, flash) in feedback.flashes().into_iter().enumerate() {
 			let cur = state.players[i];
 			let prev = render_prev_state.players[i];
-			let x = lerp(prev.x, cur.x, alpha);
-			let y = lerp(prev.y, cur.y, alpha);
-			let color = if i == 0 { BLUE } else { RED };
-			draw_rectangle(x, y, sim::Player::W, sim::Player::H, color);
+			let offset = smoothing.get(i);
+			let x = lerp(prev.x, cur.x, alpha) + offset.x;
+			let y = lerp(prev.y, cur.y, alpha) + offset.y;
+			palette.draw_player(x, y, roster.teams[i]);
+			draw_combat(&cur, x, y);
+			if rollback_flash && blame[i] > 0.0 {
+				let color = feedback::RollbackCue::blame_color(palette, i, blame[i]);
+				draw_rectangle_lines(x - 2.0, y - 2.0, w + 4.0, h + 4.0, 1.0, color);
+			}
+			if flash > 0.0 {
+				let tint = Color::new(1.0, 0.85, 0.3, flash);
+				draw_rectangle(x, y, w, h, tint);
+			}
+			if muzzle[i] > 0.0 {
+				let (cx, cy) = (x + w / 2.0, y + h / 2.0);
+				draw_circle(cx, cy, 6.0, Color::new(1.0, 1.0, 0.8, muzzle[i]));
+			}
 		}
+		draw_shots_between(&render_prev_state, &state, alpha);
+		let crosshair = crosshair(&taps, state.players[my_id].center().to_vec2());
+		draw_rectangle_lines(crosshair.x - 2.0, crosshair.y - 2.0, 5.0, 5.0, 1.0, WHITE);
 
 		// Blit buffer
 		set_default_camera();
 		clear_background(BLACK);
 		draw_buffer_to_screen(&buffer);
+		if rollback_flash {
+			rollback_cue.draw(palette);
+		}
+		let mut hud = new_hud();
 		let title = if malicious { "malicious" } else { "client" };
 		let delay = delay_ms;
-		draw_text(
+		hud.text(
+			Anchor::TopLeft,
+			&format!(
+				"{title} id={my_id} tick={local_tick} srv={latest_server_tick} delay={delay}ms latency_ticks={latency_ticks} in_delay={} drift={:+.2}t ({:+.0}ppm) rollback={last_rollback_depth}",
+				slash_list(&input_delays),
+				drift.drift_ticks(),
+				drift.drift_ppm()
+			),
+			WHITE,
+		);
+		net_stats.update();
+		hud.text(
+			Anchor::TopLeft,
+			&net_stats.line(clock_offset.last_rtt_us(), clock_offset.jitter_us()),
+			WHITE,
+		);
+		hud.text(
+			Anchor::TopLeft,
+			&format!(
+				"team hill {} of {}, you're on team {} ({} yourself)",
+				slash_list(&state.team_scores),
+				sim::WIN_SCORE,
+				roster.teams[my_id],
+				state.players[my_id].score
+			),
+			palette.team(roster.teams[my_id]),
+		);
+		if let Some(rules) = setup.map(|s| s.rules) {
+			let mut text = format!("best of {}", rules.best_of);
+			if let Some(left) = rules.ticks_left(local_tick, &state) {
+				let secs = left / sim::TPS;
+				text += &format!(", {}:{:02} left", secs / 60, secs % 60);
+			}
+			hud.text(Anchor::TopLeft, &text, LIGHTGRAY);
+		}
+		if let Some(text) = warmup_banner(&state, "R or Start") {
+			hud.text(Anchor::TopLeft, &text, YELLOW);
+		}
+		if let Some(s) = &match_over {
+			hud.text(Anchor::TopLeft, &series_banner(s), YELLOW);
+		}
+		if let Some(kick_at) = afk_kick_at {
+			let secs = kick_at
+				.saturating_duration_since(Instant::now())
+				.as_secs_f32();
+			hud.text(
+				Anchor::TopLeft,
+				&format!("you are idle, move or forfeit in {secs:.0}s"),
+				ORANGE,
+			);
+		}
+		if let Some(tick) = desynced_at {
+			hud.text(
+				Anchor::TopLeft,
+				&format!("desync: the server's state differs from ours at tick {tick}"),
+				RED,
+			);
+		}
+		alerts.update();
+		alerts.draw(&mut hud);
+		if let Some(reason) = kicked {
+			let text = match reason {
+				KickReason::Afk => "kicked for being idle",
+				KickReason::BuildMismatch => "kicked, this build simulates differently",
+			};
+			hud.text(Anchor::TopLeft, text, RED);
+		} else if let Some(r) = rejected {
+			hud.text(
+				Anchor::TopLeft,
+				&format!("connection lost, refused on reconnecting: {r}"),
+				RED,
+			);
+		} else if disconnected {
+			hud.text(Anchor::TopLeft, "connection lost, reconnecting...", YELLOW);
+		}
+		if measure_latency {
+			let ms = |h: &latency::Histogram| {
+				h.mean_ms()
+					.map_or("-".to_string(), |ms| format!("{ms:.1}ms"))
+			};
+			hud.text(
+				Anchor::TopLeft,
+				&format!(
+					"press->applied {} press->confirmed {}",
+					ms(&apply_latency),
+					ms(&confirm_latency)
+				),
+				SKYBLUE,
+			);
+		}
+		if let Some(max_px) = hybrid {
+			let text = if server_caps.contains(Capabilities::SNAPSHOTS) {
+				format!(
+					"corrections blended={blended_corrections} snapped={snapped_corrections} (<={max_px}px)"
+				)
+			} else {
+				"hybrid off, the server doesn't send snapshots".to_string()
+			};
+			hud.text(Anchor::TopLeft, &text, SKYBLUE);
+		}
+
+		if show_stats {
+			draw_stats(&mut hud, Anchor::TopRight);
+			frame_times.draw(&mut hud, Anchor::TopRight);
+		}
+		inspector.update(latest_server_tick, local_tick);
+		inspector.draw(&mut hud, &session);
+
+		// One-way latency per direction, needs the ping offset
+		input_latency.draw("Input C2S", &mut hud, Anchor::BottomLeft);
+		tick_latency.draw("TickInputs S2C", &mut hud, Anchor::BottomRight);
+
+		frame_times.mark(frametime::Phase::Render);
+		next_frame().await;
+	}
+}
+
+// One player of --runtime dual: a connection and rollback session of its own,
+// sharing the keyboard and window with the other
+struct Seat {
+	keys: sim::TapLatch,
+	rx_evt: queue::Receiver<NetEvent>,
+	tx_cmd: mpsc::Sender<NetCmd>,
+	session: rollback::Session<sim::Arena>,
+	my_id: usize,
+	roster: Roster,
+	// When tick 0 was due, None until the match starts
+	start_at: Option<Instant>,
+	state: SimState,
+	local_tick: u32,
+	latest_server_tick: u32,
+	last_remote: Vec<PlayerInput>,
+	input_grant: Option<InputGrant>,
+	sent_inputs: net::InputWindow,
+	match_over: Option<SeriesState>,
+	last_rollback_depth: u32,
+	// Why the seat isn't playing, if it isn't
+	status: Option<String>,
+	// Matches start with a warm-up, from the server's MatchSetup
+	warmup: bool,
+}
+
+impl Seat {
+	fn connect(
+		addr: &str,
+		room: Option<String>,
+		keys: sim::KeyMap,
+		socket: sockopt::SocketOptions,
+		transport: net::InputTransport,
+	) -> anyhow::Result<Self> {
+		let (rx_evt, tx_cmd) =
+			net::spawn_client(addr.to_string(), None, None, room, None, socket, transport)
+				.context("spawn_client")?;
+		Ok(Self {
+			keys: sim::TapLatch::with_keys(keys),
+			rx_evt,
+			tx_cmd,
+			session: rollback::Session::new(sim::Arena::new(HISTORY), HISTORY),
+			my_id: 0,
+			roster: Roster::new(sim::DEFAULT_PLAYERS),
+			start_at: None,
+			state: SimState::new(sim::DEFAULT_PLAYERS),
+			local_tick: 0,
+			latest_server_tick: 0,
+			last_remote: vec![InputBits::empty().into(); sim::DEFAULT_PLAYERS],
+			input_grant: None,
+			sent_inputs: net::InputWindow::default(),
+			match_over: None,
+			last_rollback_depth: 0,
+			status: Some("connecting...".to_string()),
+			warmup: false,
+		})
+	}
+
+	fn receive(&mut self) {
+		net::pump();
+		while let Ok(ev) = self.rx_evt.try_recv() {
+			match ev {
+				NetEvent::AssignStart(_) | NetEvent::Resume(_) => {
+					if let Err(e) = check_start(&ev) {
+						self.status = Some(format!("can't play the server's match: {e:#}"));
+						continue;
+					}
+					let r = match ev {
+						NetEvent::Resume(r) => r,
+						NetEvent::AssignStart(a) => ResumeState {
+							player_id: a.player_id,
+							token: a.token,
+							tick: 0,
+							start_after_ms: a.start_after_ms,
+							state: SimState::with_teams(a.roster.teams.clone())
+								.warming_up(self.warmup),
+							roster: a.roster,
+						},
+						_ => unreachable!(),
+					};
+					self.my_id = r.player_id as usize;
+					self.roster = r.roster;
+					let start_at = Instant::now() + Duration::from_millis(r.start_after_ms as u64);
+					self.start_at = start_at
+						.checked_sub(Duration::from_secs_f64(r.tick as f64 * sim::DT as f64));
+					self.state = r.state;
+					self.local_tick = r.tick;
+					self.latest_server_tick = r.tick;
+					self.last_remote = vec![InputBits::empty().into(); self.roster.players()];
+					self.input_grant = None;
+					self.sent_inputs.clear();
+					self.match_over = None;
+					self.last_rollback_depth = 0;
+					self.session.clear();
+					self.status = None;
+				}
+				NetEvent::TickInputs(Stamped { msg: m, .. })
+					if m.inputs.len() == self.roster.players() =>
+				{
+					self.latest_server_tick = rollback::later(self.latest_server_tick, m.tick);
+					let inputs = m.sim_inputs();
+					self.session.confirm(m.tick, inputs.clone());
+					self.last_remote = inputs;
+				}
+				NetEvent::Control(c) => {
+					self.my_id = c.player_id as usize;
+					self.input_grant = None;
+					self.sent_inputs.clear();
+				}
+				NetEvent::InputGrant(g) => self.input_grant = Some(g),
+				NetEvent::MatchSetup(s) => self.warmup = s.rules.warmup,
+				NetEvent::Series(s) => self.match_over = Some(s),
+				// Both seats are ours, a full server has nothing for one of them
+				NetEvent::SpectateStart(_) => self.status = Some("no free slot".to_string()),
+				NetEvent::Searching => {
+					self.start_at = None;
+					self.status = Some("waiting for an opponent...".to_string());
+				}
+				NetEvent::Kicked(_) => self.status = Some("kicked".to_string()),
+				NetEvent::Rejected(r) => self.status = Some(format!("refused by the server: {r}")),
+				// A refusal says more
+				NetEvent::Disconnected
+					if self
+						.status
+						.as_ref()
+						.is_some_and(|s| s.starts_with("refused")) => {}
+				NetEvent::Disconnected => self.status = Some("connection lost".to_string()),
+				_ => {}
+			}
+		}
+	}
+
+	// Rolls back what the server's inputs corrected, then runs to where the clock is
+	fn advance(&mut self) {
+		let my_id = self.my_id;
+		let last_remote = &self.last_remote;
+		if let Some(rb) = self.session.rollback(self.local_tick, |_, used| {
+			let mut inputs = last_remote.clone();
+			if let Some(used) = used {
+				inputs[my_id] = used[my_id];
+			}
+			inputs
+		}) {
+			self.last_rollback_depth = self.local_tick.wrapping_sub(rb.from);
+			self.state = rb.states.into_iter().last().unwrap_or(rb.start);
+		}
+
+		let Some(start_at) = self.start_at.filter(|_| self.status.is_none()) else {
+			return;
+		};
+		let time_tick = (Instant::now()
+			.saturating_duration_since(start_at)
+			.as_secs_f64()
+			* sim::TPS as f64) as u32;
+		let max_stamp_tick = time_tick.saturating_sub(LEAD_TICKS).saturating_add(D_MAX);
+		let mut steps = 0;
+		while self.match_over.is_none()
+			&& self.local_tick < time_tick
+			&& steps < CATCHUP_BUDGET_TICKS
+		{
+			// No mouse to spare, shots go at the nearest opponent's predicted centre
+			let me = self.state.players[my_id].center().to_vec2();
+			let target = (0..self.roster.players())
+				.filter(|&pid| self.roster.teams[pid] != self.roster.teams[my_id])
+				.map(|pid| self.state.players[pid].center().to_vec2())
+				.min_by(|a, b| a.distance(me).total_cmp(&b.distance(me)))
+				.unwrap_or(me);
+			let local_input = PlayerInput {
+				bits: self.keys.sample(),
+				aim: sim::quantize_aim(target - me),
+			};
+			let inputs = self
+				.session
+				.authoritative(self.local_tick)
+				.unwrap_or_else(|| {
+					let mut inputs = self.last_remote.clone();
+					inputs[my_id] = local_input;
+					inputs
+				});
+			let tick = match self.input_grant {
+				Some(grant) => grant.clamp(self.local_tick),
+				None => self.local_tick.min(max_stamp_tick),
+			};
+			let cmd = self.sent_inputs.send(InputMsg {
+				tick,
+				bits: inputs[my_id].bits.as_u8(),
+				aim: inputs[my_id].aim,
+				ack_tick: self.latest_server_tick,
+				sent_us: clock::wall_us(),
+			});
+			let _ = self.tx_cmd.send(cmd);
+			self.session.step(self.local_tick, &mut self.state, inputs);
+			self.local_tick = self.local_tick.wrapping_add(1);
+			steps += 1;
+		}
+	}
+}
+
+// The buffer letterboxed into the band of the screen `top` pixels down and
+// `height` tall, returns its scale
+fn draw_buffer_in_band(buffer: &RenderTarget, top: f32, height: f32) -> f32 {
+	let scale = (screen_width() / sim::BUFFER_W as f32).min(height / sim::BUFFER_H as f32);
+	let size = vec2(sim::BUFFER_W as f32, sim::BUFFER_H as f32) * scale;
+	draw_texture_ex(
+		&buffer.texture,
+		((screen_width() - size.x) / 2.0).round(),
+		(top + (height - size.y) / 2.0).round(),
+		WHITE,
+		DrawTextureParams {
+			dest_size: Some(size),
+			..Default::default()
+		},
+	);
+	scale
+}
+
+// Both players from one keyboard for demos on a single machine, WASD and Space
+// for the first seat, the arrows and Enter for the second. Each seat is a full
+// client of the server, and each half of the window shows what its seat predicts
+async fn run_dual_client(
+	addr: String,
+	room: Option<String>,
+	socket: sockopt::SocketOptions,
+	transport: net::InputTransport,
+	mut palette: Palette,
+	buffer: RenderTarget,
+) -> anyhow::Result<()> {
+	set_window_size(sim::BUFFER_W * 3, sim::BUFFER_H * 6);
+	let second = render_target(sim::BUFFER_W, sim::BUFFER_H);
+	second.texture.set_filter(FilterMode::Nearest);
+	let buffers = [buffer, second];
+	// In this order, so a fresh server gives the WASD seat P0
+	let mut seats = Vec::new();
+	for keys in [sim::KeyMap::WASD, sim::KeyMap::ARROWS] {
+		seats.push(Seat::connect(&addr, room.clone(), keys, socket, transport)?);
+	}
+
+	loop {
+		if is_key_pressed(KeyCode::F6) {
+			palette = palette.next();
+		}
+		for seat in &mut seats {
+			seat.keys.poll();
+			seat.receive();
+			seat.advance();
+		}
+
+		for (seat, buffer) in seats.iter().zip(&buffers) {
+			let mut cam = sim::camera_for_buffer();
+			cam.render_target = Some(buffer.clone());
+			set_camera(&cam);
+			clear_background(BLACK);
+			if seat.start_at.is_some() {
+				draw_players(&seat.state, &seat.roster, palette);
+			}
+		}
+
+		set_default_camera();
+		clear_background(BLACK);
+		let band = screen_height() / 2.0;
+		let mut scale = f32::MAX;
+		for (i, buffer) in buffers.iter().enumerate() {
+			scale = scale.min(draw_buffer_in_band(buffer, i as f32 * band, band));
+		}
+		let mut hud = Hud::new(scale);
+		// Each with the ready key of its KeyMap
+		let places = [(Anchor::TopLeft, "Q"), (Anchor::BottomLeft, "right shift")];
+		for (seat, (anchor, ready_key)) in seats.iter().zip(places) {
+			let team = seat.roster.teams[seat.my_id];
+			let line = match &seat.status {
+				Some(status) => status.clone(),
+				None => format!(
+					"P{} tick={} srv={} rollback={} team hill {}",
+					seat.my_id,
+					seat.local_tick,
+					seat.latest_server_tick,
+					seat.last_rollback_depth,
+					slash_list(&seat.state.team_scores)
+				),
+			};
+			hud.text(anchor, &line, palette.team(team));
+			if let Some(text) = warmup_banner(&seat.state, ready_key) {
+				hud.text(anchor, &text, YELLOW);
+			}
+			if let Some(s) = &seat.match_over {
+				hud.text(anchor, &series_banner(s), YELLOW);
+			}
+		}
+
+		next_frame().await;
+	}
+}
+
+async fn run_spectator(
+	addr: String,
+	room: Option<String>,
+	observer: bool,
+	socket: sockopt::SocketOptions,
+	mut palette: Palette,
+	buffer: RenderTarget,
+) -> anyhow::Result<()> {
+	// An observer connection never writes, a spectator on the player port says
+	// Hello as a watcher, so it never takes a slot, not even in an unfilled room
+	let (rx_evt, _tx_cmd) = if observer {
+		(
+			net::spawn_observer(addr, socket).context("spawn_observer")?,
+			None,
+		)
+	} else {
+		let (rx, tx) = net::spawn_watcher(addr, room, socket).context("spawn_watcher")?;
+		(rx, Some(tx))
+	};
+
+	let mut playback: Option<playback::Playback> = None;
+	let mut roster = Roster::new(sim::DEFAULT_PLAYERS);
+	let mut match_over: Option<SeriesState> = None;
+	let mut live = true;
+	let mut paused = false;
+	let mut accumulator: f32 = 0.0;
+	let mut perspective = Perspective::Arena;
+
+	loop {
+		net::pump();
+		while let Ok(ev) = rx_evt.try_recv() {
+			if let Err(e) = check_start(&ev) {
+				error!("ignoring the match start: {:#}", e);
+				continue;
+			}
+			match ev {
+				NetEvent::SpectateStart(s) => {
+					playback = Some(playback::Playback::new(s.tick, s.state));
+					roster = s.roster;
+					match_over = None;
+				}
+				NetEvent::Series(s) => match_over = Some(s),
+				NetEvent::History(ticks) => {
+					if let Some(pb) = playback.as_mut() {
+						ticks.iter().for_each(|t| pb.push(t));
+					}
+				}
+				NetEvent::TickInputs(t) => {
+					if let Some(pb) = playback.as_mut() {
+						pb.push(&t.msg);
+					}
+				}
+				_ => {}
+			}
+		}
+
+		let Some(pb) = playback.as_mut() else {
+			set_default_camera();
+			clear_background(BLACK);
+			new_hud().text(Anchor::TopLeft, "connecting...", WHITE);
+			next_frame().await;
+			continue;
+		};
+
+		// DVR controls: pause, skip a second either way, jump back to live
+		if is_key_pressed(KeyCode::Space) {
+			paused = !paused;
+			live = false;
+		}
+		if is_key_pressed(KeyCode::Left) {
+			pb.seek(pb.tick().wrapping_sub(sim::TPS));
+			live = false;
+		}
+		if is_key_pressed(KeyCode::Right) {
+			pb.seek(pb.tick().wrapping_add(sim::TPS));
+		}
+		if is_key_pressed(KeyCode::L) {
+			live = true;
+			paused = false;
+		}
+		if is_key_pressed(KeyCode::Tab) {
+			perspective = perspective.next(roster.players());
+		}
+		if is_key_pressed(KeyCode::F6) {
+			palette = palette.next();
+		}
+
+		if live {
+			pb.seek(pb.end_tick());
+			accumulator = 0.0;
+		} else if !paused {
+			accumulator += get_frame_time();
+			while accumulator >= sim::DT {
+				accumulator -= sim::DT;
+				if !pb.step() {
+					// Caught up with the stream
+					live = true;
+					break;
+				}
+			}
+		}
+
+		let mut cam = perspective.camera(pb.state());
+		cam.render_target = Some(buffer.clone());
+		set_camera(&cam);
+		clear_background(BLACK);
+		draw_players(pb.state(), &roster, palette);
+
+		set_default_camera();
+		clear_background(BLACK);
+		draw_buffer_to_screen(&buffer);
+		let mut hud = new_hud();
+		let behind = pb.end_tick().wrapping_sub(pb.tick()) as f32 / sim::TPS as f32;
+		let mode = if live {
+			"LIVE".to_string()
+		} else if paused {
+			format!("PAUSED -{behind:.1}s")
+		} else {
+			format!("-{behind:.1}s")
+		};
+		hud.text(
+			Anchor::TopLeft,
+			&format!(
+				"spectator tick={} sum={} live={} {mode}  [space] pause [<-/->] 1s [L] live [tab] {} [F6] {}",
+				pb.tick(),
+				short_checksum(pb.state()),
+				pb.end_tick(),
+				perspective.label(),
+				palette.label()
+			),
+			WHITE,
+		);
+		if let Some(s) = &match_over {
+			hud.text(Anchor::TopLeft, &series_banner(s), YELLOW);
+		}
+
+		next_frame().await;
+	}
+}
+
+// Plays a recorded replay back with the spectator's controls, matches of a
+// series one after another
+async fn run_replay(
+	path: &std::path::Path,
+	mut palette: Palette,
+	buffer: RenderTarget,
+) -> anyhow::Result<()> {
+	let (replay, _) = replay::Replay::read(path)?;
+	let matches = replay.matches();
+	if matches.is_empty() {
+		anyhow::bail!("replay has no match starting at tick 0");
+	}
+	let roster = replay.roster.clone();
+	let open = |m: usize| {
+		let mut pb = playback::Playback::new(0, replay.start_state());
+		matches[m].iter().for_each(|t| pb.push(t));
+		pb
+	};
+
+	let mut current = 0;
+	let mut pb = open(current);
+	let mut paused = false;
+	let mut accumulator: f32 = 0.0;
+	let mut perspective = Perspective::Arena;
+	// Digits typed after G, Enter jumps to that tick
+	let mut goto: Option<String> = None;
+
+	loop {
+		// While a tick is being typed every other key waits
+		if let Some(typed) = goto.as_mut() {
+			// The queue hands out the newest char first
+			let mut chars: Vec<char> = std::iter::from_fn(get_char_pressed).collect();
+			chars.reverse();
+			typed.extend(chars.into_iter().filter(char::is_ascii_digit));
+			if is_key_pressed(KeyCode::Enter) {
+				if let Ok(tick) = typed.parse() {
+					pb.seek(tick);
+					paused = true;
+				}
+				goto = None;
+			} else if is_key_pressed(KeyCode::Escape) {
+				goto = None;
+			}
+		} else {
+			if is_key_pressed(KeyCode::G) {
+				// The G itself isn't part of the tick
+				clear_input_queue();
+				goto = Some(String::new());
+			}
+			if is_key_pressed(KeyCode::Space) {
+				paused = !paused;
+			}
+			if is_key_pressed(KeyCode::Left) {
+				pb.seek(pb.tick().wrapping_sub(sim::TPS));
+			}
+			if is_key_pressed(KeyCode::Right) {
+				pb.seek(pb.tick().wrapping_add(sim::TPS));
+			}
+			// Single ticks, stepping back restores the closest keyframe and resimulates
+			if is_key_pressed(KeyCode::Comma) {
+				pb.seek(pb.tick().wrapping_sub(1));
+				paused = true;
+			}
+			if is_key_pressed(KeyCode::Period) {
+				pb.step();
+				paused = true;
+			}
+			if is_key_pressed(KeyCode::Home) {
+				pb.seek(0);
+			}
+			if is_key_pressed(KeyCode::End) {
+				pb.seek(pb.end_tick());
+			}
+			if is_key_pressed(KeyCode::N) {
+				current = (current + 1) % matches.len();
+				pb = open(current);
+				accumulator = 0.0;
+			}
+			if is_key_pressed(KeyCode::Tab) {
+				perspective = perspective.next(roster.players());
+			}
+			if is_key_pressed(KeyCode::F6) {
+				palette = palette.next();
+			}
+		}
+
+		if !paused {
+			accumulator += get_frame_time();
+			while accumulator >= sim::DT {
+				accumulator -= sim::DT;
+				if !pb.step() {
+					// The end of the match holds until there's a key press
+					accumulator = 0.0;
+					break;
+				}
+			}
+		}
+
+		let mut cam = perspective.camera(pb.state());
+		cam.render_target = Some(buffer.clone());
+		set_camera(&cam);
+		clear_background(BLACK);
+		draw_players(pb.state(), &roster, palette);
+
+		set_default_camera();
+		clear_background(BLACK);
+		draw_buffer_to_screen(&buffer);
+		let mut hud = new_hud();
+		hud.text(
+			Anchor::TopLeft,
 			&format!(
-				"{title} id={my_id} tick={local_tick} srv={latest_server_tick} delay={delay}ms latency_ticks={latency_ticks}"
+				"replay match {}/{} tick={}/{} sum={}{}  [space] pause [<-/->] 1s [,/.] 1 tick [G] go to tick [N] next match [tab] {} [F6] {}",
+				current + 1,
+				matches.len(),
+				pb.tick(),
+				pb.end_tick(),
+				short_checksum(pb.state()),
+				if paused { " PAUSED" } else { "" },
+				perspective.label(),
+				palette.label()
 			),
-			10.0,
-			24.0,
-			16.0,
 			WHITE,
 		);
+		if let Some(typed) = &goto {
+			hud.text(Anchor::TopLeft, &format!("go to tick: {typed}_"), SKYBLUE);
+		}
+		if pb.tick() == pb.end_tick() {
+			let result = match sim::winner(pb.state()) {
+				Some(team) => format!("team {team} won"),
+				None => "the recording ends here".to_string(),
+			};
+			hud.text(Anchor::TopLeft, &result, YELLOW);
+		}
+
+		next_frame().await;
+	}
+}
+
+// State-sync baseline to compare against: no prediction and no rollback, the
+// server's 10 Hz snapshots are shown a little in the past, interpolated, and
+// dead-reckoned from the last one when the next is late.
+async fn run_snapshot_client(
+	addr: String,
+	room: Option<String>,
+	socket: sockopt::SocketOptions,
+	transport: net::InputTransport,
+	gamepad: Option<u32>,
+	mut palette: Palette,
+	buffer: RenderTarget,
+) -> anyhow::Result<()> {
+	let (rx_evt, tx_cmd) = net::spawn_client(addr, None, None, room, None, socket, transport)
+		.context("spawn_client")?;
+	let mut taps = input_source(gamepad)?;
+	let mut no_snapshots = false;
+
+	let mut delay_ms: u32 = 0;
+	let mut in_q = netsim::DelayQueue::<NetEvent>::new();
+	let mut out_q = netsim::DelayQueue::<NetCmd>::new();
+
+	let mut sim_start_at: Option<Instant> = None;
+	let mut next_input_tick: u32 = 0;
+	let mut input_grant: Option<InputGrant> = None;
+	let mut sent_inputs = net::InputWindow::default();
+	let mut view = interp::SnapshotView::default();
+	let mut render_tick: f64 = 0.0;
+	let mut match_over: Option<SeriesState> = None;
+	let mut disconnected = false;
+	let mut rejected: Option<Reject> = None;
+
+	loop {
+		if is_key_pressed(KeyCode::F6) {
+			palette = palette.next();
+		}
+		if is_key_pressed(KeyCode::Left) {
+			delay_ms = delay_ms.saturating_sub(10);
+		}
+		if is_key_pressed(KeyCode::Right) {
+			delay_ms = delay_ms.saturating_add(10);
+		}
+
+		net::pump();
+		while let Ok(ev) = rx_evt.try_recv() {
+			match ev {
+				NetEvent::AssignStart(_)
+				| NetEvent::Resume(_)
+				| NetEvent::Series(_)
+				| NetEvent::Disconnected => in_q.push(ev),
+				NetEvent::Snapshot(_) => in_q.schedule(ev, &[netsim::Delivery::in_order(delay_ms)]),
+				NetEvent::Welcome(caps) => {
+					no_snapshots = !caps.contains(Capabilities::SNAPSHOTS);
+					if !no_snapshots {
+						let _ = tx_cmd.send(NetCmd::SubscribeSnapshots);
+					}
+				}
+				NetEvent::Rejected(r) => rejected = Some(r),
+				_ => {}
+			}
+		}
+
+		let now = Instant::now();
+		while let Some(cmd) = out_q.pop_due(now) {
+			let _ = tx_cmd.send(cmd);
+		}
+		while let Some(ev) = in_q.pop_due(now) {
+			if let Err(e) = check_start(&ev) {
+				error!("ignoring the match start: {:#}", e);
+				continue;
+			}
+			match ev {
+				NetEvent::AssignStart(a) => {
+					let state = SimState::with_teams(a.roster.teams.clone());
+					view.start(a.player_id, a.roster, state);
+					sim_start_at = Some(now + Duration::from_millis(a.start_after_ms as u64));
+					next_input_tick = 0;
+					match_over = None;
+					input_grant = None;
+					sent_inputs.clear();
+				}
+				NetEvent::Resume(r) => {
+					view.start(r.player_id, r.roster, r.state);
+					let start_at = now + Duration::from_millis(r.start_after_ms as u64);
+					sim_start_at = start_at
+						.checked_sub(Duration::from_secs_f64(r.tick as f64 * sim::DT as f64));
+					next_input_tick = r.tick;
+					input_grant = None;
+					sent_inputs.clear();
+				}
+				NetEvent::InputGrant(g) => input_grant = Some(g),
+				NetEvent::Snapshot(s) => view.push(s),
+				NetEvent::Series(s) => match_over = Some(s),
+				NetEvent::Disconnected => disconnected = true,
+				_ => {}
+			}
+		}
+
+		let Some(start_at) = sim_start_at.filter(|s| now >= *s && view.shown.is_some()) else {
+			set_default_camera();
+			clear_background(BLACK);
+			let text = if let Some(r) = rejected {
+				format!("refused by the server: {r}")
+			} else if no_snapshots {
+				"the server doesn't send snapshots".to_string()
+			} else {
+				"waiting for start...".to_string()
+			};
+			new_hud().text(Anchor::TopLeft, &text, WHITE);
+			next_frame().await;
+			continue;
+		};
+
+		// Inputs go out once per tick on the same clock as the rollback client,
+		// they just aren't simulated locally
+		let clock_tick = now.saturating_duration_since(start_at).as_secs_f64() * sim::TPS as f64;
+		let latency_ticks = ((delay_ms as f32 / 1000.0) * sim::TPS as f32).floor() as u32;
+		let max_stamp_tick = (clock_tick as u32).saturating_sub(LEAD_TICKS) + D_MAX;
+		let center = view
+			.shown
+			.as_ref()
+			.and_then(|s| s.players.get(view.my_id))
+			.map(|p| p.center().to_vec2());
+		let aim = taps.aim().unwrap_or_else(|| {
+			let mouse = screen_to_buffer(mouse_position().into());
+			center.map_or(0, |c| sim::quantize_aim(mouse - c))
+		});
+		taps.poll();
+		next_input_tick = next_input_tick.max((clock_tick as u32).saturating_sub(1));
+		while match_over.is_none() && next_input_tick < clock_tick as u32 {
+			let cmd = sent_inputs.send(InputMsg {
+				tick: match input_grant {
+					Some(grant) => grant.clamp(next_input_tick + latency_ticks),
+					None => (next_input_tick + latency_ticks).min(max_stamp_tick),
+				},
+				bits: taps.sample().as_u8(),
+				aim,
+				ack_tick: view.snaps.newest_tick().unwrap_or(0),
+				sent_us: clock::wall_us(),
+			});
+			out_q.schedule(cmd, &[netsim::Delivery::in_order(delay_ms)]);
+			next_input_tick += 1;
+		}
+
+		// Render clock runs in real time and is nudged towards a fixed distance
+		// behind the newest snapshot, a big gap (start, resume) snaps it
+		let mut extrapolating = false;
+		if let Some(newest) = view.snaps.newest_tick() {
+			let target = newest as f64 - interp::INTERP_DELAY_TICKS;
+			render_tick += get_frame_time() as f64 * sim::TPS as f64;
+			if (render_tick - target).abs() > 2.0 * sim::TPS as f64 {
+				render_tick = target;
+			}
+			render_tick += (target - render_tick) * 0.05;
+			extrapolating = view.sample(render_tick);
+		}
+
+		let mut cam = sim::camera_for_buffer();
+		cam.render_target = Some(buffer.clone());
+		set_camera(&cam);
+		clear_background(BLACK);
+		if let (Some(roster), Some(shown)) = (&view.roster, &view.shown) {
+			draw_players(shown, roster, palette);
+			if let Some(p) = shown.players.get(view.my_id) {
+				let crosshair = crosshair(&taps, p.center().to_vec2());
+				draw_rectangle_lines(crosshair.x - 2.0, crosshair.y - 2.0, 5.0, 5.0, 1.0, WHITE);
+			}
+		}
+
+		set_default_camera();
+		clear_background(BLACK);
+		draw_buffer_to_screen(&buffer);
+		let mut hud = new_hud();
+		let behind = view
+			.snaps
+			.newest_tick()
+			.map_or(0.0, |n| n as f64 - render_tick);
+		hud.text(
+			Anchor::TopLeft,
+			&format!(
+				"snapshot client id={} delay={delay_ms}ms render={render_tick:.1} ({behind:+.1}t behind newest){}",
+				view.my_id,
+				if extrapolating { " EXTRAPOLATING" } else { "" }
+			),
+			WHITE,
+		);
+		if let Some(text) = view
+			.shown
+			.as_ref()
+			.and_then(|s| warmup_banner(s, "R or Start"))
+		{
+			hud.text(Anchor::TopLeft, &text, YELLOW);
+		}
+		if let Some(s) = &match_over {
+			hud.text(Anchor::TopLeft, &series_banner(s), YELLOW);
+		}
+		if disconnected {
+			hud.text(Anchor::TopLeft, "connection lost", YELLOW);
+		}
 
 		next_frame().await;
 	}
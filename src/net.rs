@@ -1,36 +1,198 @@
 use std::{
-	io::{Read, Write},
-	net::{TcpListener, TcpStream},
-	sync::mpsc,
+	collections::{HashMap, VecDeque},
+	io,
+	net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+	path::PathBuf,
+	sync::{
+		Arc, Mutex,
+		atomic::{AtomicU64, AtomicUsize, Ordering},
+		mpsc,
+	},
 	thread,
-	time::{Duration, Instant},
+	time::Duration,
 };
 
 use anyhow::Context;
+use clap::ValueEnum;
+use macroquad::prelude::{error, info, warn};
+use serde_json::Value;
+use tokio::{
+	sync::{Semaphore, mpsc as bounded},
+	time::timeout,
+};
+
+use crate::{
+	career::{CareerStore, PlayerRecord},
+	clock::{self, Instant},
+	control::{self, Command},
+	protocol::{
+		self, AfkWarning, AssignStart, C2S, Capabilities, ControlChange, Frame,
+		HISTORY_CHUNK_TICKS, Hello, InputDelay, InputGrant, InputMsg, InputSignature, KickReason,
+		MIN_PROTOCOL_VERSION, MatchSetup, PROTOCOL_VERSION, Ping, Pong, Reject, ResumeState,
+		Roster, Ruleset, S2C, SeriesState, SpectateStart, Stamped, StateHash, StateSnapshot,
+		TickInputs, UdpDatagram, UdpOffer, Welcome,
+	},
+	queue::{self, Overflow, Policy},
+	replay::ReplayWriter,
+	rollback::is_before,
+	savegame::{self, RECENT_INPUTS, SAVE_INTERVAL_TICKS, SaveGame},
+	series::Series,
+	sim::SimState,
+	sockopt::SocketOptions,
+	stats,
+	transport::{self, Backoff, Conn, ConnReader, Listener, Transport},
+	wirestats::{self, Dir},
+};
+
+// Inputs repeated in every input message or datagram, a loss only hurts when
+// this many in a row go
+const INPUT_REDUNDANCY: usize = 4;
+
+// Frames a player may send per RATE_WINDOW, one input a tick with plenty to
+// spare for catching up. The rest are dropped
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+const MAX_FRAMES_PER_WINDOW: u32 = 4 * crate::sim::TPS;
+
+// Ticks back from the newest a player's reader remembers inputs for. Redundancy
+// sends each one INPUT_REDUNDANCY times, more than that are dropped as floods
+const SEEN_TICKS: u32 = 128;
+
+// Inputs per second stamped before the server's tick or past the player's
+// window after which they're dropped, half of what they send. An honest client
+// only strays this much when its clock is off, and reconnecting sets it right
+const MAX_STRAY_PER_SEC: u32 = crate::sim::TPS / 2;
+
+// Wait per UDP probe and how many to send before giving up on the upgrade
+#[cfg(not(target_arch = "wasm32"))]
+const UDP_PROBE_TIMEOUT: Duration = Duration::from_millis(250);
+#[cfg(not(target_arch = "wasm32"))]
+const UDP_PROBE_TRIES: u32 = 4;
+
+// Upper bound for the input delay handed out by fairness mode
+const MAX_FAIRNESS_DELAY: u8 = 12;
+
+// Clients get this long to send their handshake after connecting
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Round trips measured per player while matchmaking, the fastest counts
+const RTT_PROBES: u32 = 3;
+
+// Pause between two matches of a series
+const INTERMISSION: Duration = Duration::from_secs(3);
+
+// Longest the match loop sleeps, the time of one tick
+const TICK: Duration = Duration::from_nanos(1_000_000_000 / crate::sim::TPS as u64);
+
+// Ticks between two StateSnapshots, 10 Hz
+pub const SNAPSHOT_INTERVAL_TICKS: u32 = 6;
+
+// Ticks between two StateSnapshots for players who didn't subscribe, for them to
+// correct a desync their rollback history can't
+const AUTHORITY_INTERVAL_TICKS: u32 = crate::sim::TPS;
+
+// Input latency samples a reader holds between two pings
+const MAX_LATENCY_SAMPLES: usize = 512;
+
+// Idle players get this long after the AFK warning before they forfeit
+const AFK_GRACE: Duration = Duration::from_secs(10);
+
+// How often fairness mode re-evaluates the per-player delays
+const FAIRNESS_INTERVAL_TICKS: u32 = 30;
+
+// Events a connection's reader may queue for a stalled client loop, and renders
+// a stalled server window may fall behind by. See NetEvent's and ServerRender's
+// Overflow for what happens past that
+const EVENT_CAPACITY: usize = 256;
+const RENDER_CAPACITY: usize = 64;
+
+const EVENT_QUEUE: queue::Stats = queue::Stats {
+	depth: "client.event_queue",
+	dropped: "client.events_dropped",
+	coalesced: "client.events_coalesced",
+};
+const RENDER_QUEUE: queue::Stats = queue::Stats {
+	depth: "server.render_queue",
+	dropped: "server.renders_dropped",
+	coalesced: "server.renders_coalesced",
+};
+
+// Messages readers may queue for the tick loop before they wait, which stops
+// them reading and lets TCP push back on a client flooding the server
+const INBOUND_CAPACITY: usize = 1024;
+
+// Greeted connections waiting for their match to take them, and spectators
+// for the tick loop to let in. Past that their tasks wait
+pub const ARRIVAL_QUEUE: usize = 16;
+
+// Handshakes under way at once, more connections wait in the listen backlog
+const MAX_HANDSHAKES: usize = 256;
 
-use crate::protocol::{AssignStart, C2S, PLAYER_COUNT, S2C, TickInputs};
+// How often players are granted a new window of ticks to stamp inputs for
+const GRANT_INTERVAL_TICKS: u32 = 6;
 
-fn write_frame(stream: &mut TcpStream, msg: &impl serde::Serialize) -> anyhow::Result<()> {
-	let bytes = bincode::serialize(msg)?;
-	let len = bytes.len() as u32;
-	stream.write_all(&len.to_le_bytes())?;
-	stream.write_all(&bytes)?;
+// Ticks between two states whose hashes clients and the server compare
+pub const STATE_HASH_INTERVAL_TICKS: u32 = crate::sim::TPS;
+
+// Server state hashes kept for clients to be compared against, the newest last
+const STATE_HASHES_KEPT: usize = 32;
+
+fn write_frame(conn: &mut dyn Transport, msg: &impl Frame) -> anyhow::Result<()> {
+	let started = wirestats::start();
+	let frame = msg.encode()?;
+	conn.send_frame(&frame)?;
+	wirestats::record(started, Dir::Sent, msg.kind(), frame.len());
 	Ok(())
 }
 
-fn read_frame<T: for<'de> serde::Deserialize<'de>>(stream: &mut TcpStream) -> anyhow::Result<T> {
-	let mut lenb = [0u8; 4];
-	stream.read_exact(&mut lenb)?;
-	let len = u32::from_le_bytes(lenb) as usize;
-	let mut buf = vec![0u8; len];
-	stream.read_exact(&mut buf)?;
-	Ok(bincode::deserialize(&buf)?)
+fn read_frame<T: Frame>(conn: &mut dyn Transport) -> anyhow::Result<T> {
+	let frame = conn.recv_frame()?;
+	let started = wirestats::start();
+	let msg = T::decode(&frame)?;
+	wirestats::record(started, Dir::Received, msg.kind(), frame.len());
+	Ok(msg)
 }
 
-#[derive(Debug, Clone, Copy)]
+// The server's side of the two above, sending only queues
+fn send(conn: &Conn, msg: &impl Frame) -> anyhow::Result<()> {
+	let started = wirestats::start();
+	let frame = msg.encode()?;
+	let len = frame.len();
+	conn.send(frame)?;
+	wirestats::record(started, Dir::Sent, msg.kind(), len);
+	Ok(())
+}
+
+async fn recv<T: Frame>(reader: &mut ConnReader) -> anyhow::Result<T> {
+	let frame = reader.recv().await?;
+	let started = wirestats::start();
+	let msg = T::decode(&frame)?;
+	wirestats::record(started, Dir::Received, msg.kind(), frame.len());
+	Ok(msg)
+}
+
+#[derive(Debug, Clone)]
 pub struct ServerRender {
 	pub tick: u32,
 	pub state: crate::sim::SimState,
+	pub input_delays: Vec<u8>,
+	// Buffered inputs per player, bit i for tick + 1 + i up to d_max
+	pub input_windows: Vec<u64>,
+	pub window_len: u32,
+	// Inputs that arrived after their tick ran, per player over the last second
+	pub late_per_sec: Vec<u32>,
+	// Lobby room the match runs in, 0 without a lobby
+	pub room: u32,
+}
+
+// A window only draws the newest render
+impl Overflow for ServerRender {
+	fn overflow(&self) -> Policy {
+		Policy::DropOldest
+	}
+}
+
+pub fn render_queue() -> (queue::Sender<ServerRender>, queue::Receiver<ServerRender>) {
+	queue::bounded(RENDER_CAPACITY, RENDER_QUEUE)
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -38,168 +200,2032 @@ pub struct InboundInput {
 	pub player_id: usize,
 	pub tick: u32,
 	pub bits: u8,
+	pub aim: u8,
+	pub ack_tick: u32,
 }
 
-pub fn spawn_server(
-	addr: String,
-	start_delay: Duration,
-	lead_ticks: u32,
-	d_max: u32,
-) -> mpsc::Receiver<ServerRender> {
-	let (tx_render, rx_render) = mpsc::channel::<ServerRender>();
+// Everything a player's reader task forwards to the tick loop
+#[derive(Debug, Clone)]
+pub enum Inbound {
+	Input(InboundInput),
+	SigningKey {
+		player_id: usize,
+		key: [u8; 32],
+	},
+	Signature {
+		player_id: usize,
+		sig: InputSignature,
+	},
+	Ping {
+		player_id: usize,
+		client_us: u64,
+		recv_us: u64,
+		input_latency_us: Vec<u32>,
+	},
+	SubscribeSnapshots {
+		player_id: usize,
+	},
+	UdpUpgrade {
+		player_id: usize,
+		mask: u8,
+	},
+	StateHash {
+		player_id: usize,
+		hash: StateHash,
+	},
+	// Answered once the match runs
+	Control(control::Request),
+}
 
-	thread::spawn(move || {
-		let listener = TcpListener::bind(&addr).expect("bind server");
-
-		let mut conns: Vec<TcpStream> = Vec::new();
-		let (tx_in, rx_in) = mpsc::channel::<InboundInput>();
-
-		for assigned_id in 0u8..2u8 {
-			let (stream, _) = listener.accept().expect("accept");
-			stream.set_nodelay(true).ok();
-			let mut read_stream = stream.try_clone().expect("clone stream");
-
-			let tx_in = tx_in.clone();
-			let pid = assigned_id as usize;
-			thread::spawn(move || {
-				loop {
-					let msg: anyhow::Result<C2S> = read_frame(&mut read_stream);
-					let Ok(C2S::Input(i)) = msg else { break };
-					let _ = tx_in.send(InboundInput {
-						player_id: pid, // don't trust client
-						tick: i.tick,
-						bits: i.bits,
-					});
-				}
-			});
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+	pub addr: String,
+	pub start_delay: Duration,
+	pub lead_ticks: u32,
+	pub d_max: u32,
+	// Equalize confirmation delay by handing input delay to the faster client
+	pub fairness: bool,
+	// Periodically persist the match here for crash recovery
+	pub save_path: Option<PathBuf>,
+	// Continue this saved match instead of starting a new one
+	pub resume: Option<SaveGame>,
+	// Record the authoritative input stream to this replay file
+	pub record_path: Option<PathBuf>,
+	// Matches in the series, the first to win the majority takes it
+	pub best_of: u8,
+	// Matches running this long go to the team ahead
+	pub time_limit: Option<Duration>,
+	// Tick every match starts at, the stress run starts them just short of the
+	// wrap. Players learn it from a Resume instead of an AssignStart
+	pub first_tick: u32,
+	// Start every match with a warm-up that ends once everyone is ready
+	pub warmup: bool,
+	// Keep starting matches for whoever is connected instead of finishing
+	pub watch: bool,
+	// Warn players sending only neutral inputs for this long, then forfeit them
+	pub afk_after: Option<Duration>,
+	// Spectators connect here instead of the player port
+	pub observe_addr: Option<String>,
+	// Slots only the player presenting this token may take, one per player
+	pub reserved: Vec<Option<u64>>,
+	// A team for every player, so also how many play
+	pub roster: Roster,
+	pub socket: SocketOptions,
+	// Only start players whose latency to each other, through the server, is at
+	// most this. The rest wait for a closer opponent. Fresh unreserved matches only
+	pub max_pair_latency: Option<Duration>,
+	// Career records of players by token, updated after every match
+	pub career_path: Option<PathBuf>,
+	// Also take players and spectators over WebSocket here, for browser clients
+	pub ws_addr: Option<String>,
+	// Take commands from test orchestrators here, see control::spawn
+	pub control_addr: Option<String>,
+	// A lobby room's report of how it fills and keeps time, see RoomStatus
+	pub room_status: Option<Arc<RoomStatus>>,
+}
+
+/// How far a lobby room got filling its slots, and how late it runs. The
+/// lobby hands connections over, the room counts the ones it got to and the
+/// players it seated, so a connection that hung up or was refused doesn't take
+/// a player's place.
+#[derive(Debug, Default)]
+pub struct RoomStatus {
+	// Connections handed over for a player's place that the room dealt with
+	taken: AtomicUsize,
+	seated: AtomicUsize,
+	// The worst tick lateness of the room's last second, in microseconds
+	lateness_us: AtomicU64,
+}
+
+impl RoomStatus {
+	fn report(&self, taken: usize, seated: usize) {
+		self.seated.store(seated, Ordering::Release);
+		self.taken.store(taken, Ordering::Release);
+	}
+
+	fn report_lateness(&self, lateness: Duration) {
+		self.lateness_us
+			.store(lateness.as_micros() as u64, Ordering::Relaxed);
+	}
+
+	pub fn lateness(&self) -> Duration {
+		Duration::from_micros(self.lateness_us.load(Ordering::Relaxed))
+	}
+
+	// Players seated, with the ones still on their way out of `joined` handed
+	// over so far
+	pub fn expected(&self, joined: usize) -> usize {
+		let taken = self.taken.load(Ordering::Acquire);
+		self.seated.load(Ordering::Acquire) + joined.saturating_sub(taken)
+	}
+}
 
-			conns.push(stream);
+// Extra input delay per player so everyone confirms as late as the laggiest player
+fn fairness_delays(lag: &[f32]) -> Vec<u8> {
+	let worst = lag.iter().copied().fold(0.0f32, f32::max);
+	lag.iter()
+		.map(|l| ((worst - l).round() as u8).min(MAX_FAIRNESS_DELAY))
+		.collect()
+}
+
+// Send to every connected player, forgetting the ones whose connection failed
+fn broadcast(conns: &mut [Option<Conn>], msg: &S2C) {
+	for c in conns.iter_mut() {
+		if let Some(s) = c
+			&& send(s, msg).is_err()
+		{
+			*c = None;
 		}
+	}
+}
 
-		// Shared start instant, then notify everyone
-		let start_at = Instant::now() + start_delay;
-		for (i, s) in conns.iter_mut().enumerate() {
-			let start_after_ms = start_at
-				.saturating_duration_since(Instant::now())
-				.as_millis()
-				.min(u128::from(u32::MAX)) as u32;
-			let msg = S2C::AssignStart(AssignStart {
+// The match setup ahead of a start, for clients that take it
+fn send_setup(conn: &Conn, caps: Capabilities, setup: MatchSetup) -> bool {
+	!caps.contains(Capabilities::MATCH_SETUP) || send(conn, &S2C::MatchSetup(setup)).is_ok()
+}
+
+// Tell every player when the match starts, `resume` continues a saved match
+fn send_start(
+	conns: &[Option<Conn>],
+	caps: &[Capabilities],
+	setup: MatchSetup,
+	tokens: &[u64],
+	roster: &Roster,
+	start_at: Instant,
+	resume: Option<(u32, &SimState)>,
+) {
+	for (i, c) in conns.iter().enumerate() {
+		let Some(s) = c else { continue };
+		if !send_setup(s, caps[i], setup) {
+			continue;
+		}
+		let start_after_ms = start_at
+			.saturating_duration_since(clock::now())
+			.as_millis()
+			.min(u128::from(u32::MAX)) as u32;
+		let msg = match resume {
+			Some((tick, state)) => S2C::Resume(ResumeState {
 				player_id: i as u8,
+				token: tokens[i],
+				tick,
 				start_after_ms,
-			});
-			let _ = write_frame(s, &msg);
+				state: state.clone(),
+				roster: roster.clone(),
+			}),
+			None => S2C::AssignStart(AssignStart {
+				player_id: i as u8,
+				start_after_ms,
+				token: tokens[i],
+				players: roster.players() as u8,
+				roster: roster.clone(),
+			}),
+		};
+		let _ = send(s, &msg);
+	}
+}
+
+// Its frames queue on its connection like a player's, so its catch-up or a
+// slow connection never holds up the match
+struct Spectator {
+	conn: Conn,
+	// Left unread until it takes over a slot, an observer's is read elsewhere
+	// only to hang up on it
+	reader: Option<ConnReader>,
+	// Protocol version of a spectator that may take over a dropped player, none for observers
+	version: Option<u16>,
+	caps: Capabilities,
+	// Slot of a player reconnecting mid-match, it goes back to them and nobody else
+	rejoin: Option<usize>,
+}
+
+impl Spectator {
+	// False once the connection failed or fell too far behind. Then it's closed
+	// and should be dropped
+	fn send(&self, msg: &S2C) -> bool {
+		send(&self.conn, msg).is_ok()
+	}
+
+	// The match setup ahead of a start, for clients that take it
+	fn send_setup(&self, setup: MatchSetup) -> bool {
+		send_setup(&self.conn, self.caps, setup)
+	}
+}
+
+fn welcome(conn: &Conn, caps: Capabilities) -> anyhow::Result<()> {
+	send(
+		conn,
+		&S2C::Welcome(Welcome {
+			capabilities: caps.bits(),
+			fingerprint: crate::sim::fingerprint(),
+			version: PROTOCOL_VERSION,
+		}),
+	)
+}
+
+/// The Hello in a new connection's first frame and whether it came as a
+/// Standby, or why we won't serve it.
+pub fn check_hello(frame: &[u8]) -> Result<(Hello, bool), Reject> {
+	let (hello, standby) = match C2S::decode(frame) {
+		Ok(C2S::Hello(hello)) => (hello, false),
+		Ok(C2S::Standby(hello)) => (hello, true),
+		_ => return Err(Reject::Unreadable),
+	};
+	if hello.version < MIN_PROTOCOL_VERSION {
+		return Err(Reject::Version {
+			peer: hello.version,
+			min: MIN_PROTOCOL_VERSION,
+		});
+	}
+	if hello.fingerprint != crate::sim::fingerprint() {
+		return Err(Reject::Build);
+	}
+	Ok((hello, standby))
+}
+
+/// Tells a connection why it's refused, for the caller to drop it.
+pub fn reject(conn: &Conn, reject: Reject) {
+	info!("refusing {:?}: {reject}", conn.peer_addr());
+	let _ = send(conn, &S2C::Reject(reject));
+}
+
+/// A connection that said Hello, on its way to the match it's for.
+pub struct Arrival {
+	pub conn: Conn,
+	pub reader: ConnReader,
+	pub hello: Hello,
+	// Came as a Standby, for a slot once the match runs
+	pub standby: bool,
+}
+
+/// A new connection's Hello, none when it hangs up or we refuse it.
+pub async fn greet(conn: Conn, mut reader: ConnReader) -> Option<Arrival> {
+	let frame = reader.recv().await.ok()?;
+	match check_hello(&frame) {
+		Ok((hello, standby)) => Some(Arrival {
+			conn,
+			reader,
+			hello,
+			standby,
+		}),
+		Err(r) => {
+			reject(&conn, r);
+			None
 		}
-		while Instant::now() < start_at {
-			thread::sleep(Duration::from_millis(5));
+	}
+}
+
+/// Accepts on every listener until `tx` closes, each connection opened and put
+/// through `handshake` on a task of its own so a slow one holds up nobody.
+/// What the handshake makes of it goes to `tx`. It gets HANDSHAKE_TIMEOUT for
+/// all of that, and past MAX_HANDSHAKES at once the rest wait to be accepted.
+pub fn accept<T, F, Fut>(listeners: Vec<Listener>, tx: bounded::Sender<T>, handshake: F)
+where
+	T: Send + 'static,
+	F: Fn(Conn, ConnReader) -> Fut + Clone + Send + 'static,
+	Fut: Future<Output = Option<T>> + Send,
+{
+	let handshakes = Arc::new(Semaphore::new(MAX_HANDSHAKES));
+	for mut listener in listeners {
+		let (tx, handshake, handshakes) = (tx.clone(), handshake.clone(), handshakes.clone());
+		transport::runtime().spawn(async move {
+			let mut backoff = Backoff::default();
+			loop {
+				let Ok(permit) = handshakes.clone().acquire_owned().await else {
+					break;
+				};
+				let incoming = tokio::select! {
+					incoming = listener.accept() => incoming,
+					_ = tx.closed() => break,
+				};
+				let Ok(incoming) = incoming else {
+					backoff.failed().await;
+					continue;
+				};
+				backoff.succeeded();
+				let (tx, handshake) = (tx.clone(), handshake.clone());
+				transport::runtime().spawn(async move {
+					let greeted = async move {
+						let (conn, reader) = incoming.open().await.ok()?;
+						handshake(conn, reader).await
+					};
+					if let Ok(Some(t)) = timeout(HANDSHAKE_TIMEOUT, greeted).await {
+						let _ = tx.send(t).await;
+					}
+					drop(permit);
+				});
+			}
+		});
+	}
+}
+
+/// Connections to `listeners` that said Hello, for `serve`.
+pub fn accept_players(listeners: Vec<Listener>) -> bounded::Receiver<Arrival> {
+	let (tx, arrivals) = bounded::channel(ARRIVAL_QUEUE);
+	accept(listeners, tx, greet);
+	arrivals
+}
+
+// Late connections on the player port become spectators, players rejoining
+// with one of `tokens` get their slot back
+async fn take_spectators(
+	mut arrivals: bounded::Receiver<Arrival>,
+	tokens: Vec<u64>,
+	tx_spec: bounded::Sender<Spectator>,
+) {
+	loop {
+		let arrival = tokio::select! {
+			arrival = arrivals.recv() => arrival,
+			_ = tx_spec.closed() => None,
+		};
+		let Some(Arrival {
+			conn,
+			reader,
+			hello,
+			standby,
+		}) = arrival
+		else {
+			break;
+		};
+		let rejoin = hello
+			.rejoin
+			.and_then(|t| tokens.iter().position(|&k| k == t));
+		// A standby is for a slot, not to watch
+		if standby && rejoin.is_none() {
+			reject(&conn, Reject::NoSlot);
+			continue;
+		}
+		let caps = Capabilities::negotiate(hello.capabilities);
+		if welcome(&conn, caps).is_err() {
+			continue;
+		}
+		// A watcher never plays, not even for a player that dropped
+		let spectator = Spectator {
+			conn,
+			reader: Some(reader),
+			version: Some(hello.version).filter(|_| !caps.watches_only()),
+			caps,
+			rejoin,
+		};
+		if standby {
+			transport::runtime().spawn(hold_standby(spectator, tx_spec.clone()));
+		} else if tx_spec.send(spectator).await.is_err() {
+			break;
+		}
+	}
+}
+
+// Keeps a standby connection aside until it claims its slot, then it rejoins
+// like a reconnecting player would. Until then it has nothing to say, what
+// else it sends before is dropped
+async fn hold_standby(mut spectator: Spectator, tx_spec: bounded::Sender<Spectator>) {
+	let Some(reader) = spectator.reader.as_mut() else {
+		return;
+	};
+	loop {
+		let msg = tokio::select! {
+			msg = recv(reader) => msg,
+			_ = tx_spec.closed() => return,
+		};
+		match msg {
+			Ok(C2S::Failover) => break,
+			Ok(_) => continue,
+			Err(_) => return,
+		}
+	}
+	let _ = tx_spec.send(spectator).await;
+}
+
+// Observers are read-only: no handshake, and anything they send gets them dropped
+async fn observe(conn: Conn, mut reader: ConnReader) -> Option<Spectator> {
+	transport::runtime().spawn(async move {
+		let _ = reader.recv().await;
+		reader.close();
+	});
+	Some(Spectator {
+		conn,
+		reader: None,
+		version: None,
+		caps: Capabilities::empty(),
+		rejoin: None,
+	})
+}
+
+// Forwards a player's messages to the tick loop, `pid` is decided by the server
+// `early` holds frames the server already read off the connection during the handshake
+async fn read_player(
+	mut reader: ConnReader,
+	pid: usize,
+	mask: u8,
+	caps: Capabilities,
+	early: Vec<C2S>,
+	tx_in: bounded::Sender<Inbound>,
+) {
+	// Input latency is measured on receipt, using the offset from the client's last ping
+	let mut offset_us: Option<i64> = None;
+	let mut input_latency_us = Vec::new();
+	let mut early = VecDeque::from(early);
+	// Times each recent tick's input came, the first goes on
+	let mut seen: HashMap<u32, usize> = HashMap::new();
+	let stat_rate_limited = stats::counter("server.rate_limited_frames");
+	let stat_floods = stats::counter("server.flooded_inputs");
+	let mut window_start = clock::now();
+	let mut window_frames = 0;
+	let (mut rate_limited, mut flooded) = (0, 0);
+	loop {
+		let msg: anyhow::Result<C2S> = match early.pop_front() {
+			Some(msg) => Ok(msg),
+			None => recv(&mut reader).await,
+		};
+		let recv_us = clock::wall_us();
+		let now = clock::now();
+		if now.duration_since(window_start) >= RATE_WINDOW {
+			if rate_limited + flooded > 0 {
+				warn!(
+					"p{} sent {} frames in a second, dropped {} \
+					 over the limit and {} inputs repeated too often",
+					pid, window_frames, rate_limited, flooded
+				);
+			}
+			window_start = now;
+			window_frames = 0;
+			(rate_limited, flooded) = (0, 0);
+		}
+		window_frames += 1;
+		if msg.is_ok() && window_frames > MAX_FRAMES_PER_WINDOW {
+			rate_limited += 1;
+			stat_rate_limited.inc();
+			continue;
 		}
+		let inbound = match msg {
+			// Nothing honest sends more, or the same tick twice
+			Ok(C2S::Input(inputs))
+				if inputs.len() > INPUT_REDUNDANCY
+					|| inputs
+						.windows(2)
+						.any(|w| w[1].tick.wrapping_sub(w[0].tick) as i32 <= 0) =>
+			{
+				flooded += inputs.len();
+				stat_floods.add(inputs.len() as u64);
+				continue;
+			}
+			Ok(C2S::Input(inputs)) => {
+				if let Some(offset) = offset_us
+					&& let Some(i) = inputs.last()
+					&& input_latency_us.len() < MAX_LATENCY_SAMPLES
+				{
+					let sent = i.sent_us as i64 + offset;
+					input_latency_us.push((recv_us as i64 - sent).max(0) as u32);
+				}
+				// Repeats of what already came are left out, the tick loop keeps
+				// the first input per tick anyway but would count them late
+				for i in &inputs {
+					let times = seen.entry(i.tick).or_insert(0);
+					*times += 1;
+					if *times > INPUT_REDUNDANCY {
+						flooded += 1;
+						stat_floods.inc();
+					}
+					if *times > 1 {
+						continue;
+					}
+					let _ = tx_in
+						.send(Inbound::Input(InboundInput {
+							player_id: pid, // don't trust client
+							tick: i.tick,
+							bits: i.bits & mask,
+							aim: i.aim,
+							ack_tick: i.ack_tick,
+						}))
+						.await;
+				}
+				if let Some(newest) = inputs.last() {
+					seen.retain(|&t, _| newest.tick.wrapping_sub(t) <= SEEN_TICKS);
+				}
+				continue;
+			}
+			Ok(C2S::SubscribeSnapshots) if caps.contains(Capabilities::SNAPSHOTS) => {
+				Inbound::SubscribeSnapshots { player_id: pid }
+			}
+			Ok(C2S::UdpUpgrade) if caps.contains(Capabilities::UDP_UPGRADE) => {
+				Inbound::UdpUpgrade {
+					player_id: pid,
+					mask,
+				}
+			}
+			Ok(C2S::Ping(p)) => {
+				offset_us = p.offset_us;
+				Inbound::Ping {
+					player_id: pid,
+					client_us: p.client_us,
+					recv_us,
+					input_latency_us: std::mem::take(&mut input_latency_us),
+				}
+			}
+			Ok(C2S::SigningKey(key)) => Inbound::SigningKey {
+				player_id: pid,
+				key,
+			},
+			Ok(C2S::InputSignature(sig)) => Inbound::Signature {
+				player_id: pid,
+				sig,
+			},
+			Ok(C2S::StateHash(hash)) if caps.contains(Capabilities::STATE_HASHES) => {
+				Inbound::StateHash {
+					player_id: pid,
+					hash,
+				}
+			}
+			Ok(_) => continue,
+			Err(_) => break,
+		};
+		let _ = tx_in.send(inbound).await;
+	}
+}
 
-		let mut tick: u32 = 0;
-		let mut state = crate::sim::SimState::new();
+// A player whose inputs come over UDP, keyed by the nonce of their UdpOffer
+struct UdpSession {
+	player_id: usize,
+	mask: u8,
+	// Datagrams repeat recent inputs, only newer ticks are forwarded
+	last_tick: Option<u32>,
+}
+
+type UdpSessions = Arc<Mutex<HashMap<u64, UdpSession>>>;
+
+// Waits `d` on the match's clock, or less when something comes in meanwhile,
+// kept in `woke`: a ping answered a timer tick late skews the client's clock
+// by as much
+async fn wait_inbound(
+	rx_in: &mut bounded::Receiver<Inbound>,
+	woke: &mut Option<Inbound>,
+	d: Duration,
+) {
+	if woke.is_none() {
+		*woke = tokio::select! {
+			inbound = rx_in.recv() => inbound,
+			_ = clock::sleep(d) => None,
+		};
+	}
+}
+
+// Forwards inputs arriving over UDP to the tick loop like a player's reader
+// would, and echoes probes so clients know the path works. One socket for the
+// whole match, read by a task that ends with the match's inbound channel
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_udp_reader(
+	socket: UdpSocket,
+	sessions: UdpSessions,
+	tx_in: bounded::Sender<Inbound>,
+) -> io::Result<()> {
+	socket.set_nonblocking(true)?;
+	let socket = tokio::net::UdpSocket::from_std(socket)?;
+	tokio::spawn(async move {
+		let stat_datagrams = stats::counter("server.udp_datagrams");
+		let mut backoff = Backoff::default();
+		let mut buf = [0u8; 1500];
+		loop {
+			let (n, from) = tokio::select! {
+				received = socket.recv_from(&mut buf) => match received {
+					Ok(received) => {
+						backoff.succeeded();
+						received
+					}
+					Err(_) => {
+						backoff.failed().await;
+						continue;
+					}
+				},
+				_ = tx_in.closed() => return,
+			};
+			let started = wirestats::start();
+			let Ok(datagram) = bincode::deserialize::<UdpDatagram>(&buf[..n]) else {
+				continue;
+			};
+			wirestats::record(started, Dir::Received, "UdpDatagram", n);
+			let s = transport::frame_stats();
+			s.received.inc();
+			s.received_bytes.add(n as u64);
+			let mut sessions = sessions.lock().unwrap();
+			let Some(session) = sessions.get_mut(&datagram.nonce) else {
+				continue;
+			};
+			stat_datagrams.inc();
+			if datagram.inputs.is_empty() {
+				// The client probes again if the echo doesn't make it
+				let _ = socket.try_send_to(&buf[..n], from);
+				continue;
+			}
+			for i in datagram.inputs {
+				if session.last_tick.is_some_and(|t| !is_before(t, i.tick)) {
+					continue;
+				}
+				session.last_tick = Some(i.tick);
+				// Datagrams can't push back, drop them while the tick loop is behind
+				let input = Inbound::Input(InboundInput {
+					player_id: session.player_id,
+					tick: i.tick,
+					bits: i.bits & session.mask,
+					aim: i.aim,
+					ack_tick: i.ack_tick,
+				});
+				if let Err(bounded::error::TrySendError::Closed(_)) = tx_in.try_send(input) {
+					return;
+				}
+			}
+		}
+	});
+	Ok(())
+}
 
-		let mut pending: [std::collections::HashMap<u32, u8>; PLAYER_COUNT] =
-			[Default::default(), Default::default()];
-		let mut last: [u8; PLAYER_COUNT] = [0, 0];
+// A page never binds one, see Listeners::bind
+#[cfg(target_arch = "wasm32")]
+fn spawn_udp_reader(
+	_socket: UdpSocket,
+	_sessions: UdpSessions,
+	_tx_in: bounded::Sender<Inbound>,
+) -> io::Result<()> {
+	Err(io::ErrorKind::Unsupported.into())
+}
 
-		let mut last_step = Instant::now();
-		let mut acc = 0.0f32;
+// A player connected while matchmaking, not in a slot yet
+struct Waiting {
+	conn: Conn,
+	reader: ConnReader,
+	version: u16,
+	caps: Capabilities,
+	rtt: Duration,
+	// Frames that came in between the probes
+	early: Vec<C2S>,
+	told_searching: bool,
+}
 
+// Round trip to a client in the handshake, the best of a few probes. Anything
+// else the client sends meanwhile goes to `early`
+async fn measure_rtt(
+	conn: &Conn,
+	reader: &mut ConnReader,
+	early: &mut Vec<C2S>,
+) -> Option<Duration> {
+	let mut best: Option<Duration> = None;
+	for seq in 0..RTT_PROBES {
+		let sent = clock::now();
+		send(conn, &S2C::RttProbe(seq)).ok()?;
 		loop {
-			let now = Instant::now();
-			acc += now.duration_since(last_step).as_secs_f32();
+			match timeout(HANDSHAKE_TIMEOUT, recv::<C2S>(reader))
+				.await
+				.ok()?
+				.ok()?
+			{
+				C2S::RttEcho(s) if s == seq => break,
+				msg => early.push(msg),
+			}
+		}
+		let rtt = clock::now().saturating_duration_since(sent);
+		best = Some(best.map_or(rtt, |b| b.min(rtt)));
+	}
+	best
+}
+
+// Indices of `players` waiting players who are all within `max_latency` of
+// each other, one way through the server. Earlier arrivals go first
+fn close_group(waiting: &[Waiting], players: usize, max_latency: Duration) -> Option<Vec<usize>> {
+	let close = |a: &Waiting, b: &Waiting| (a.rtt + b.rtt) / 2 <= max_latency;
+	(0..waiting.len()).find_map(|first| {
+		let mut group = vec![first];
+		for i in first + 1..waiting.len() {
+			if group.len() < players && group.iter().all(|&g| close(&waiting[g], &waiting[i])) {
+				group.push(i);
+			}
+		}
+		(group.len() == players).then_some(group)
+	})
+}
+
+// The player port, plus the WebSocket one when there is one
+pub fn bind_players(addr: &str, ws_addr: Option<&str>, socket: SocketOptions) -> Vec<Listener> {
+	let mut listeners = vec![Listener::bind(addr, socket, false).expect("bind server")];
+	if let Some(ws_addr) = ws_addr {
+		listeners.push(Listener::bind(ws_addr, socket, true).expect("bind websocket port"));
+	}
+	listeners
+}
+
+/// Sockets a match takes besides its players' connections, bound by whoever
+/// runs it. Without them spectators come in with the players and inputs stay
+/// on the connection.
+#[derive(Default)]
+pub struct Listeners {
+	pub observers: Option<Listener>,
+	pub udp: Option<UdpSocket>,
+}
+
+impl Listeners {
+	// The observer port from the config, and UDP on the player port's number.
+	// Without UDP upgrade requests go unanswered and clients stay on TCP
+	pub fn bind(cfg: &ServerConfig) -> Self {
+		let observers = cfg
+			.observe_addr
+			.as_deref()
+			.map(|addr| Listener::bind(addr, cfg.socket, false).expect("bind observer port"));
+		let udp = UdpSocket::bind(&cfg.addr)
+			.inspect_err(|e| warn!("udp inputs unavailable: {}", e))
+			.ok();
+		Self { observers, udp }
+	}
+}
+
+// The tick loop keeps this thread to itself, its connections are the network
+// runtime's
+pub fn spawn_server(cfg: ServerConfig) -> queue::Receiver<ServerRender> {
+	let (tx_render, rx_render) = render_queue();
+
+	thread::spawn(move || {
+		let players = bind_players(&cfg.addr, cfg.ws_addr.as_deref(), cfg.socket);
+		let listeners = Listeners::bind(&cfg);
+		transport::runtime().block_on(async {
+			let arrivals = accept_players(players);
+			serve(cfg, arrivals, listeners, 0, tx_render).await;
+		});
+	});
+	rx_render
+}
+
+/// One match, or series of them, for the connections that arrive. Room 0 is
+/// the whole server, numbered rooms are a lobby's. Returns once the series is
+/// over, or when the arrivals end before the players are in.
+pub async fn serve(
+	cfg: ServerConfig,
+	mut arrivals: bounded::Receiver<Arrival>,
+	listeners: Listeners,
+	room: u32,
+	tx_render: queue::Sender<ServerRender>,
+) {
+	let ServerConfig {
+		addr: _,
+		start_delay,
+		lead_ticks,
+		d_max,
+		fairness,
+		save_path,
+		resume,
+		record_path,
+		best_of,
+		time_limit,
+		first_tick,
+		warmup,
+		watch,
+		afk_after,
+		observe_addr: _,
+		reserved,
+		roster,
+		socket: _,
+		max_pair_latency,
+		career_path,
+		ws_addr: _,
+		control_addr,
+		room_status,
+	} = cfg;
+	let (tx_in, mut rx_in) = bounded::channel::<Inbound>(INBOUND_CAPACITY);
+	if let Some(addr) = control_addr {
+		let tx_in = tx_in.clone();
+		if let Err(e) = control::spawn(&addr, move |req| {
+			let _ = tx_in.blocking_send(Inbound::Control(req));
+		}) {
+			error!("no control: {:#}", e);
+		}
+	}
+	let to_ticks = |d: Duration| (d.as_secs_f32() * crate::sim::TPS as f32) as u32;
+	let afk_warn_ticks = afk_after.map(to_ticks);
+	let afk_grace_ticks = to_ticks(AFK_GRACE);
+	let setup = MatchSetup {
+		fingerprint: crate::sim::fingerprint(),
+		rules: Ruleset {
+			best_of,
+			win_score: crate::sim::WIN_SCORE,
+			time_limit_ticks: time_limit.map(to_ticks),
+			warmup,
+		},
+		players: 0,
+	};
+	// Datagrams don't say which room they're for, so a lobby's rooms go without
+	let udp_sessions = UdpSessions::default();
+	let udp_port = listeners.udp.and_then(|socket| {
+		let port = socket.local_addr().map(|a| a.port()).unwrap_or(0);
+		spawn_udp_reader(socket, udp_sessions.clone(), tx_in.clone())
+			.inspect_err(|e| warn!("udp inputs unavailable: {}", e))
+			.ok()?;
+		Some(port)
+	});
+	// A resumed match keeps the teams it was saved with
+	let roster = resume.as_ref().map_or(roster, |s| Roster {
+		teams: s.state.teams.clone(),
+	});
+	let players = roster.players();
+	let setup = MatchSetup {
+		players: players as u8,
+		..setup
+	};
+	let mut recorder = record_path.map(|p| ReplayWriter::create(&p).expect("create replay"));
+	if let Some(r) = recorder.as_mut() {
+		r.write_roster(&roster).expect("write replay roster");
+		r.write_warmup(warmup).expect("write replay warm-up");
+	}
+	let mut career = career_path.map(|p| CareerStore::open(&p).expect("open player stats"));
+	// This match's share of each player's record
+	let mut match_records = vec![PlayerRecord::default(); players];
+
+	let resuming = resume.is_some();
+	let tokens = match &resume {
+		Some(save) => save.tokens.clone(),
+		// A reserved slot's token is the reservation, its player reconnects with it
+		None => (0..players)
+			.map(|pid| {
+				reserved
+					.get(pid)
+					.copied()
+					.flatten()
+					.unwrap_or_else(savegame::new_token)
+			})
+			.collect(),
+	};
+
+	// Matchmaking: players wait here until enough of them are close to each other.
+	// Whoever is left over once the match starts spectates it
+	let max_pair_latency =
+		max_pair_latency.filter(|_| !resuming && reserved.iter().all(Option::is_none));
+	let mut waiting: Vec<Waiting> = Vec::new();
+
+	let mut slots: Vec<Option<Conn>> = (0..players).map(|_| None).collect();
+	let mut player_caps = vec![Capabilities::empty(); players];
+	let mut watchers: Vec<Spectator> = Vec::new();
+	let mut taken = 0;
+	while slots.iter().any(Option::is_none) {
+		if let Some(room_status) = &room_status {
+			room_status.report(taken, slots.iter().flatten().count());
+		}
+		// The lobby gave up on filling the room. Dropping the connections hangs
+		// up on whoever is in it
+		let Some(Arrival {
+			conn,
+			mut reader,
+			hello,
+			standby,
+		}) = arrivals.recv().await
+		else {
+			return;
+		};
+		taken += 1;
+
+		// A standby has a slot to wait for only once the match runs
+		if standby {
+			continue;
+		}
+		let caps = Capabilities::negotiate(hello.capabilities);
+		// A referee or spectator early for the match watches it from the start, no
+		// slot for it. The lobby doesn't count it either
+		if caps.watches_only() {
+			taken -= 1;
+			if welcome(&conn, caps).is_ok() {
+				watchers.push(Spectator {
+					conn,
+					reader: Some(reader),
+					version: None,
+					caps,
+					rejoin: None,
+				});
+			}
+			continue;
+		}
+		if let Some(max_latency) = max_pair_latency {
+			if welcome(&conn, caps).is_err() {
+				continue;
+			}
+			let mut early = Vec::new();
+			let Some(rtt) = measure_rtt(&conn, &mut reader, &mut early).await else {
+				continue;
+			};
+			waiting.push(Waiting {
+				conn,
+				reader,
+				version: hello.version,
+				caps,
+				rtt,
+				early,
+				told_searching: false,
+			});
+			let Some(group) = close_group(&waiting, players, max_latency) else {
+				for w in waiting.iter_mut().filter(|w| !w.told_searching) {
+					w.told_searching = send(&w.conn, &S2C::Searching).is_ok();
+				}
+				continue;
+			};
+			// Highest index first so the others stay put
+			let mut group: Vec<(usize, usize)> = group.into_iter().enumerate().collect();
+			group.sort_by_key(|&(_, i)| std::cmp::Reverse(i));
+			for (pid, i) in group {
+				let w = waiting.remove(i);
+				let mask = protocol::input_mask(protocol::negotiate(w.version));
+				transport::runtime().spawn(read_player(
+					w.reader,
+					pid,
+					mask,
+					w.caps,
+					w.early,
+					tx_in.clone(),
+				));
+				player_caps[pid] = w.caps;
+				slots[pid] = Some(w.conn);
+			}
+			continue;
+		}
+		let pid = if resuming {
+			// Resumed matches only take back their original players
+			let Some(token) = hello.rejoin else {
+				continue;
+			};
+			match tokens.iter().position(|&t| t == token) {
+				Some(pid) if slots[pid].is_none() => pid,
+				_ => continue,
+			}
+		} else {
+			// Reserved slots wait for their token, everyone else takes the first open slot
+			let claimed = hello
+				.reservation
+				.and_then(|t| reserved.iter().position(|&r| r == Some(t)));
+			let open = match claimed {
+				Some(pid) => Some(pid).filter(|&pid| slots[pid].is_none()),
+				None => (0..players).find(|&pid| {
+					slots[pid].is_none() && reserved.get(pid).copied().flatten().is_none()
+				}),
+			};
+			let Some(pid) = open else { continue };
+			pid
+		};
+		// Strip bits the client's protocol version doesn't define
+		let mask = protocol::input_mask(protocol::negotiate(hello.version));
+		if welcome(&conn, caps).is_err() {
+			continue;
+		}
+
+		transport::runtime().spawn(read_player(
+			reader,
+			pid,
+			mask,
+			caps,
+			Vec::new(),
+			tx_in.clone(),
+		));
+
+		player_caps[pid] = caps;
+		slots[pid] = Some(conn);
+	}
+	if let Some(room_status) = &room_status {
+		room_status.report(taken, players);
+	}
+	let mut conns = slots;
+
+	// Anyone connecting after the players is a spectator, unless observers have
+	// their own port. Then the player port closes once the slots are taken.
+	// Whoever came early to watch or was left over from matchmaking goes first
+	let (tx_spec, mut rx_spec) = bounded::channel::<Spectator>(ARRIVAL_QUEUE);
+	let mut joining: VecDeque<Spectator> = watchers.into_iter().collect();
+	joining.extend(waiting.into_iter().map(|w| Spectator {
+		conn: w.conn,
+		reader: Some(w.reader),
+		version: Some(w.version),
+		caps: w.caps,
+		rejoin: None,
+	}));
+	match listeners.observers {
+		Some(observers) => {
+			drop(arrivals);
+			accept(vec![observers], tx_spec, observe);
+		}
+		None => {
+			transport::runtime().spawn(take_spectators(arrivals, tokens.clone(), tx_spec));
+		}
+	}
+	let mut spectators: Vec<Spectator> = Vec::new();
+
+	let (mut tick, mut state, mut last) = match &resume {
+		Some(save) => (save.tick, save.state.clone(), save.last_inputs()),
+		None => (
+			first_tick,
+			SimState::with_teams(roster.teams.clone()).warming_up(warmup),
+			TickInputs::neutral(first_tick, players),
+		),
+	};
+	let mut series = match &resume {
+		Some(save) => save.series.clone(),
+		None => Series::new(best_of, players),
+	};
+	let mut recent: VecDeque<TickInputs> = match resume {
+		Some(save) => save.recent,
+		None => VecDeque::new(),
+	};
+
+	// Where a joining spectator starts, the latest keyframe, and the
+	// authoritative stream since it replayed to catch up
+	let mut spectate_start = SpectateStart {
+		tick,
+		state: state.clone(),
+		roster: roster.clone(),
+	};
+	let mut history: Vec<TickInputs> = Vec::new();
+
+	// Shared start instant, then notify everyone. Ticks are timed from the one
+	// the match starts or resumes at, `base`, which may be anywhere before the
+	// wrap
+	let mut start_at = clock::now() + start_delay;
+	let mut origin = start_at;
+	let mut base = tick;
+	send_start(
+		&conns,
+		&player_caps,
+		setup,
+		&tokens,
+		&roster,
+		start_at,
+		(resuming || first_tick != 0).then_some((tick, &state)),
+	);
+
+	let mut pending: Vec<std::collections::HashMap<u32, (u8, u8)>> =
+		vec![Default::default(); players];
+
+	// Smoothed confirmation lag (server tick minus acked tick) per player
+	let mut lag = vec![0.0f32; players];
+	let mut input_delays = vec![0u8; players];
+
+	// Last tick each player sent something other than a neutral input
+	let mut active_at = vec![tick; players];
+	let mut afk_warned = vec![false; players];
+
+	// Players on the state-sync baseline client
+	let mut snapshot_subs = vec![false; players];
+
+	// Last tick of the newest window granted to each player, inputs stamped past
+	// it are dropped. Clients without INPUT_GRANTS get d_max ticks as before
+	let mut granted_to: Vec<Option<u32>> = vec![None; players];
+
+	let mut state_hashes: VecDeque<StateHash> = VecDeque::new();
+	let stat_desyncs = stats::counter("server.desyncs");
+
+	let stat_tick = stats::gauge("server.tick");
+	let stat_players = stats::gauge("server.players");
+	let stat_spectators = stats::gauge("server.spectators");
+	let stat_late = stats::counter("server.late_inputs");
+	let mut late_count = vec![0u32; players];
+	let mut late_per_sec = vec![0u32; players];
+	// Inputs outside [tick, tick + d_max] or the grant this second, late or early
+	let mut stray_count = vec![0u32; players];
+	let stat_stray_drops = stats::counter("server.stray_disconnects");
+	let stat_early = stats::counter("server.early_inputs");
+	// How far behind its due time each tick ran, the newest and the worst of the
+	// last second. What a shared scheduler would have to keep bounded
+	let stat_lateness = stats::gauge("server.tick_lateness_us");
+	let stat_lateness_max = stats::gauge("server.tick_lateness_max_us");
+	let mut lateness_max = Duration::ZERO;
+
+	let mut last_step = clock::now();
+	let mut acc = 0.0f32;
+
+	// Watch mode only: a player left and no match runs until the slot is taken again
+	let mut idle = false;
+	// When the next match starts, set once the current one is over
+	let mut next_match_at: Option<Instant> = None;
+	// What cut the last wait short, handled before the rest
+	let mut woke: Option<Inbound> = None;
+
+	'ticks: loop {
+		let now = clock::now();
+		if now < start_at {
 			last_step = now;
+			wait_inbound(&mut rx_in, &mut woke, start_at - now).await;
+			continue;
+		}
+		acc += now.duration_since(last_step).as_secs_f32();
+		last_step = now;
 
-			// Keep server tick behind clock by lead_ticks
-			let elapsed = now.saturating_duration_since(start_at);
-			let wall_tick = (elapsed.as_secs_f32() * crate::sim::TPS as f32).floor() as u32;
-			let max_tick = wall_tick.saturating_sub(lead_ticks);
+		// Keep server tick behind clock by lead_ticks
+		let elapsed = now.saturating_duration_since(origin);
+		let wall_ticks = (elapsed.as_secs_f64() * crate::sim::TPS as f64).floor() as u32;
+		let max_ahead = wall_ticks.saturating_sub(lead_ticks);
 
-			while let Ok(msg) = rx_in.try_recv() {
-				let pid = msg.player_id;
-				if msg.tick < tick {
+		while let Some(inbound) = woke.take().or_else(|| rx_in.try_recv().ok()) {
+			let msg = match inbound {
+				Inbound::Input(msg) => msg,
+				Inbound::SigningKey { player_id, key } => {
+					if let Some(r) = recorder.as_mut() {
+						let _ = r.write_signing_key(player_id as u8, key);
+					}
+					continue;
+				}
+				Inbound::Signature { player_id, sig } => {
+					if let Some(r) = recorder.as_mut() {
+						let _ = r.write_signature(player_id as u8, sig);
+					}
 					continue;
 				}
-				if msg.tick > tick.saturating_add(d_max) {
+				Inbound::SubscribeSnapshots { player_id } => {
+					snapshot_subs[player_id] = true;
 					continue;
 				}
-				pending[pid].entry(msg.tick).or_insert(msg.bits);
+				Inbound::UdpUpgrade { player_id, mask } => {
+					if let Some(port) = udp_port
+						&& let Some(s) = &conns[player_id]
+					{
+						let nonce = savegame::new_token();
+						udp_sessions.lock().unwrap().insert(
+							nonce,
+							UdpSession {
+								player_id,
+								mask,
+								last_tick: None,
+							},
+						);
+						let _ = send(s, &S2C::UdpOffer(UdpOffer { port, nonce }));
+					}
+					continue;
+				}
+				Inbound::Ping {
+					player_id,
+					client_us,
+					recv_us,
+					input_latency_us,
+				} => {
+					match_records[player_id].add_latency_samples(&input_latency_us);
+					if let Some(s) = &conns[player_id] {
+						let pong = S2C::Pong(Pong {
+							client_us,
+							server_recv_us: recv_us,
+							server_send_us: clock::wall_us(),
+							input_latency_us,
+						});
+						let _ = send(s, &pong);
+					}
+					continue;
+				}
+				Inbound::StateHash { player_id, hash } => {
+					// Too old or not simulated yet, there's nothing to compare with
+					let Some(ours) = state_hashes.iter().find(|h| h.tick == hash.tick) else {
+						continue;
+					};
+					if *ours != hash {
+						stat_desyncs.inc();
+						warn!("p{player_id} desynced at tick {}", hash.tick);
+						if let Some(s) = &conns[player_id] {
+							let _ = send(s, &S2C::DesyncDetected(hash.tick));
+						}
+					}
+					continue;
+				}
+				Inbound::Control(req) => {
+					let result = match req.command {
+						Command::Dump => Ok(control::dump(tick, &state)),
+						Command::Disconnect(Some(pid)) if pid < players => match &conns[pid] {
+							Some(s) => {
+								s.close();
+								Ok(Value::Null)
+							}
+							None => Err(format!("p{pid} isn't connected")),
+						},
+						Command::Disconnect(_) => {
+							Err(format!("a server drops a player, 0 to {}", players - 1))
+						}
+						Command::SetDelay(_) => Err("a server has no artificial delay".to_string()),
+					};
+					req.reply(result);
+					continue;
+				}
+			};
+			let pid = msg.player_id;
+			if !is_before(tick, msg.ack_tick) {
+				let sample = tick.wrapping_sub(msg.ack_tick) as f32;
+				lag[pid] += (sample - lag[pid]) * 0.05;
+			}
+			if is_before(msg.tick, tick) {
+				stat_late.inc();
+				late_count[pid] += 1;
+				stray_count[pid] += 1;
+				match_records[pid].late_inputs += 1;
+				continue;
+			}
+			if is_before(
+				granted_to[pid].unwrap_or(tick.wrapping_add(d_max)),
+				msg.tick,
+			) {
+				stat_early.inc();
+				stray_count[pid] += 1;
+				continue;
+			}
+			pending[pid].entry(msg.tick).or_insert((msg.bits, msg.aim));
+		}
+
+		while let Some(s) = joining.pop_front().or_else(|| rx_spec.try_recv().ok()) {
+			// The slot's old connection may not have failed a write yet, the token
+			// proves it's the same player
+			if let Some(pid) = s.rejoin {
+				if let Some(old) = conns[pid].take() {
+					old.close();
+				}
+				spectators.push(s);
+				continue;
+			}
+			// Catching up is its writer's, from the keyframe on
+			let ok = s.send_setup(setup)
+				&& s.send(&S2C::SpectateStart(spectate_start.clone()))
+				&& history
+					.chunks(HISTORY_CHUNK_TICKS)
+					.all(|c| s.send(&S2C::History(c.to_vec())))
+				// A referee checks the catch-up too, as far back as we remember
+				&& (!s.caps.contains(Capabilities::REFEREE)
+					|| state_hashes.iter().all(|&h| s.send(&S2C::StateHash(h))));
+			if ok {
+				spectators.push(s);
+			}
+		}
+
+		if watch && !idle && conns.iter().any(Option::is_none) {
+			info!("a player left, waiting for a new opponent");
+			idle = true;
+			broadcast(&mut conns, &S2C::Searching);
+		}
+
+		// A dropped player that reconnected gets its slot back with the live state.
+		// Otherwise the longest waiting spectator takes over from this tick on, it
+		// has been following the stream so its timeline carries on. In watch mode
+		// either fills the slot for the next match instead
+		for pid in 0..players {
+			if conns[pid].is_some() {
+				continue;
+			}
+			let rejoining = spectators.iter().position(|s| s.rejoin == Some(pid));
+			let Some(i) = rejoining.or_else(|| {
+				spectators
+					.iter()
+					.position(|s| s.version.is_some() && s.rejoin.is_none())
+			}) else {
+				continue;
+			};
+			let Spectator {
+				conn,
+				reader,
+				version,
+				caps,
+				rejoin,
+			} = spectators.remove(i);
+			let Some(reader) = reader else {
+				continue;
+			};
+			let handover = match rejoin {
+				Some(_) => S2C::Resume(ResumeState {
+					player_id: pid as u8,
+					token: tokens[pid],
+					tick,
+					start_after_ms: 0,
+					state: state.clone(),
+					roster: roster.clone(),
+				}),
+				None => S2C::Control(ControlChange {
+					player_id: pid as u8,
+					token: tokens[pid],
+					tick,
+				}),
+			};
+			// A rejoiner starts over, whoever takes over already has the setup
+			if !idle
+				&& !((rejoin.is_none() || send_setup(&conn, caps, setup))
+					&& send(&conn, &handover).is_ok())
+			{
+				continue;
+			}
+			let mask = protocol::input_mask(protocol::negotiate(version.unwrap_or(1)));
+			transport::runtime().spawn(read_player(
+				reader,
+				pid,
+				mask,
+				caps,
+				Vec::new(),
+				tx_in.clone(),
+			));
+			// The dropped player's datagrams must not steer the new one
+			udp_sessions
+				.lock()
+				.unwrap()
+				.retain(|_, s| s.player_id != pid);
+			// A rejoiner's inputs that came before it dropped are still its own
+			if rejoin.is_none() {
+				pending[pid].clear();
+			}
+			lag[pid] = 0.0;
+			active_at[pid] = tick;
+			afk_warned[pid] = false;
+			snapshot_subs[pid] = false;
+			granted_to[pid] = None;
+			player_caps[pid] = caps;
+			conns[pid] = Some(conn);
+		}
+
+		if idle {
+			if conns.iter().any(Option::is_none) {
+				wait_inbound(&mut rx_in, &mut woke, TICK).await;
+				continue;
+			}
+			idle = false;
+			series = Series::new(best_of, players);
+			next_match_at = Some(clock::now() + start_delay);
+		}
+		// Everyone starts the next match from a fresh state
+		if let Some(at) = next_match_at.take() {
+			tick = first_tick;
+			base = tick;
+			state = SimState::with_teams(roster.teams.clone()).warming_up(warmup);
+			last = TickInputs::neutral(tick, players);
+			match_records = vec![PlayerRecord::default(); players];
+			active_at = vec![tick; players];
+			afk_warned = vec![false; players];
+			granted_to = vec![None; players];
+			state_hashes.clear();
+			pending.iter_mut().for_each(|p| p.clear());
+			recent.clear();
+			history.clear();
+			spectate_start = SpectateStart {
+				tick,
+				state: state.clone(),
+				roster: roster.clone(),
+			};
+			start_at = at;
+			origin = start_at;
+			acc = 0.0;
+			send_start(
+				&conns,
+				&player_caps,
+				setup,
+				&tokens,
+				&roster,
+				start_at,
+				(first_tick != 0).then_some((tick, &state)),
+			);
+			let s2c = S2C::SpectateStart(spectate_start.clone());
+			spectators.retain(|s| s.send_setup(setup) && s.send(&s2c));
+			continue;
+		}
+
+		while acc >= crate::sim::DT && tick.wrapping_sub(base) <= max_ahead {
+			let ahead = tick.wrapping_sub(base) + lead_ticks;
+			let due = origin + Duration::from_secs_f64(ahead as f64 * crate::sim::DT as f64);
+			let lateness = clock::now().saturating_duration_since(due);
+			stat_lateness.set(lateness.as_micros() as i64);
+			lateness_max = lateness_max.max(lateness);
+			if tick.is_multiple_of(crate::sim::TPS) {
+				stat_lateness_max.set(lateness_max.as_micros() as i64);
+				if let Some(status) = &room_status {
+					status.report_lateness(lateness_max);
+				}
+				lateness_max = Duration::ZERO;
+			}
+
+			if fairness && tick.is_multiple_of(FAIRNESS_INTERVAL_TICKS) {
+				let delays = fairness_delays(&lag);
+				if delays != input_delays {
+					input_delays = delays;
+					let delays = input_delays.clone();
+					broadcast(&mut conns, &S2C::InputDelay(InputDelay { delays }));
+				}
+			}
+
+			for (pid, p) in pending.iter_mut().enumerate() {
+				if let Some((bits, aim)) = p.remove(&tick) {
+					last.inputs[pid] = bits;
+					last.aims[pid] = aim;
+				}
+			}
+			let tick_inputs = TickInputs {
+				tick,
+				..last.clone()
+			};
+			let inputs = &tick_inputs.inputs;
+
+			let mut afk = None;
+			for pid in 0..players {
+				if inputs[pid] != 0 {
+					active_at[pid] = tick;
+					afk_warned[pid] = false;
+				}
+				let Some(warn) = afk_warn_ticks else { continue };
+				let idle = tick.wrapping_sub(active_at[pid]);
+				if idle >= warn + afk_grace_ticks {
+					afk = Some(pid);
+				} else if idle >= warn
+					&& !afk_warned[pid]
+					&& let Some(s) = &conns[pid]
+				{
+					afk_warned[pid] = true;
+					let kick_in_ms = AFK_GRACE.as_millis() as u32;
+					let _ = send(s, &S2C::AfkWarning(AfkWarning { kick_in_ms }));
+				}
+			}
+			// Dropped, it hangs up once the kick went out
+			if let Some(pid) = afk
+				&& let Some(s) = conns[pid].take()
+			{
+				let _ = send(&s, &S2C::Kicked(KickReason::Afk));
 			}
 
-			while acc >= crate::sim::DT && tick <= max_tick {
-				let mut inputs = last;
-				for pid in 0..PLAYER_COUNT {
-					if let Some(b) = pending[pid].remove(&tick) {
-						inputs[pid] = b;
-						last[pid] = b;
+			let s2c = S2C::TickInputs(Stamped {
+				msg: tick_inputs.clone(),
+				sent_us: clock::wall_us(),
+			});
+			broadcast(&mut conns, &s2c);
+			spectators.retain(|s| s.send(&s2c));
+			// A full tail moves the keyframe up to now, catching up never takes more
+			// than one History chunk
+			if history.len() == HISTORY_CHUNK_TICKS {
+				spectate_start.tick = tick;
+				spectate_start.state = state.clone();
+				history.clear();
+			}
+			history.push(tick_inputs.clone());
+
+			if let Some(r) = recorder.as_mut()
+				&& let Err(e) = r.write_tick(&tick_inputs)
+			{
+				error!("replay write failed: {:?}", e);
+				recorder = None;
+			}
+
+			recent.push_back(tick_inputs.clone());
+			if recent.len() > RECENT_INPUTS {
+				recent.pop_front();
+			}
+
+			if tick.is_multiple_of(STATE_HASH_INTERVAL_TICKS) {
+				if state_hashes.len() == STATE_HASHES_KEPT {
+					state_hashes.pop_front();
+				}
+				let hash = StateHash {
+					tick,
+					hash: crate::sim::checksum(&state),
+				};
+				state_hashes.push_back(hash);
+				let s2c = S2C::StateHash(hash);
+				spectators.retain(|s| !s.caps.contains(Capabilities::REFEREE) || s.send(&s2c));
+			}
+			crate::sim::step(&mut state, &tick_inputs.sim_inputs());
+
+			if tick.is_multiple_of(crate::sim::TPS) {
+				late_per_sec = std::mem::replace(&mut late_count, vec![0; players]);
+				let strays = std::mem::replace(&mut stray_count, vec![0; players]);
+				for (pid, stray) in strays.into_iter().enumerate() {
+					if stray > MAX_STRAY_PER_SEC
+						&& let Some(s) = conns[pid].take()
+					{
+						warn!(
+							"dropping p{}, {} inputs outside its window in a second",
+							pid, stray
+						);
+						stat_stray_drops.inc();
+						s.close();
 					}
 				}
+			}
+			// The window never spans more than 64 ticks, d_max is far below that
+			let window_len = (d_max + 1).min(u64::BITS);
+			let input_windows = pending
+				.iter()
+				.map(|p| {
+					(0..window_len)
+						.filter(|&i| p.contains_key(&tick.wrapping_add(1 + i)))
+						.fold(0u64, |w, i| w | 1 << i)
+				})
+				.collect();
+			let _ = tx_render.send(ServerRender {
+				tick,
+				state: state.clone(),
+				input_delays: input_delays.clone(),
+				input_windows,
+				window_len,
+				late_per_sec: late_per_sec.clone(),
+				room,
+			});
 
-				let s2c = S2C::TickInputs(TickInputs { tick, inputs });
-				conns.retain_mut(|s| write_frame(s, &s2c).is_ok());
+			tick = tick.wrapping_add(1);
+			stat_tick.set(tick as i64);
+			stat_players.set(conns.iter().flatten().count() as i64);
+			stat_spectators.set(spectators.len() as i64);
 
-				let sim_inputs = [
-					crate::sim::InputBits::from_u8(inputs[0]),
-					crate::sim::InputBits::from_u8(inputs[1]),
-				];
-				crate::sim::step(&mut state, sim_inputs);
+			if tick.is_multiple_of(SNAPSHOT_INTERVAL_TICKS) {
+				let s2c = S2C::Snapshot(StateSnapshot {
+					tick,
+					state: state.clone(),
+				});
+				let authority = tick.is_multiple_of(AUTHORITY_INTERVAL_TICKS);
+				for pid in 0..players {
+					let wanted = snapshot_subs[pid]
+						|| (authority && player_caps[pid].contains(Capabilities::SNAPSHOTS));
+					if wanted && let Some(c) = &conns[pid] {
+						let _ = send(c, &s2c);
+					}
+				}
+			}
+			if tick.is_multiple_of(GRANT_INTERVAL_TICKS) {
+				let grant = InputGrant {
+					from: tick,
+					to: tick.wrapping_add(d_max),
+				};
+				for pid in 0..players {
+					if player_caps[pid].contains(Capabilities::INPUT_GRANTS)
+						&& let Some(c) = &conns[pid]
+						&& send(c, &S2C::InputGrant(grant)).is_ok()
+					{
+						granted_to[pid] = Some(grant.to);
+					}
+				}
+			}
+			acc -= crate::sim::DT;
 
-				let _ = tx_render.send(ServerRender { tick, state });
+			// An AFK player forfeits the match to the other team
+			let forfeit = afk.and_then(|pid| {
+				let team = roster.teams[pid];
+				roster.teams.iter().copied().find(|&t| t != team)
+			});
+			let time_up = setup
+				.rules
+				.ticks_left(tick.wrapping_sub(first_tick), &state)
+				== Some(0);
+			let winner = crate::sim::winner(&state)
+				.or(forfeit)
+				.or_else(|| time_up.then(|| crate::sim::leader(&state)).flatten());
+			if let Some(winner) = winner {
+				if let Some(career) = career.as_mut() {
+					for (pid, r) in match_records.iter_mut().enumerate() {
+						r.matches = 1;
+						if roster.teams[pid] == winner {
+							r.wins = 1;
+						} else {
+							r.losses = 1;
+						}
+						career.record_match(tokens[pid], r);
+					}
+					if let Err(e) = career.save() {
+						error!("saving player stats failed: {:?}", e);
+					}
+				}
+				let s2c = S2C::Series(series.record_win(winner as usize));
+				broadcast(&mut conns, &s2c);
+				spectators.retain(|s| s.send(&s2c));
+				if series.is_finished() {
+					if !watch {
+						break 'ticks;
+					}
+					series = Series::new(best_of, players);
+				}
 
-				tick = tick.wrapping_add(1);
-				acc -= crate::sim::DT;
+				// Next match after a short intermission
+				next_match_at = Some(clock::now() + INTERMISSION);
+				continue 'ticks;
 			}
 
-			thread::sleep(Duration::from_millis(1));
+			if tick.is_multiple_of(SAVE_INTERVAL_TICKS)
+				&& let Some(r) = recorder.as_mut()
+			{
+				let _ = r.flush();
+			}
+
+			if let Some(path) = &save_path
+				&& tick.is_multiple_of(SAVE_INTERVAL_TICKS)
+			{
+				let save = SaveGame {
+					tick,
+					state: state.clone(),
+					recent: recent.clone(),
+					tokens: tokens.clone(),
+					series: series.clone(),
+				};
+				if let Err(e) = save.write(path) {
+					error!("save failed: {:?}", e);
+				}
+			}
+
+			// Catching up, the other rooms on this worker get their ticks in between
+			tokio::task::yield_now().await;
 		}
-	});
 
-	rx_render
+		// Asleep until the next tick is due, every match keeps its own time
+		let ahead = tick.wrapping_sub(base) + lead_ticks;
+		let due = origin + Duration::from_secs_f64(ahead as f64 * crate::sim::DT as f64);
+		let until_due = due.saturating_duration_since(clock::now()).min(TICK);
+		wait_inbound(&mut rx_in, &mut woke, until_due).await;
+	}
+	// Dropping the connections hangs up once the series result went out, so
+	// clients hear it's over even when the process goes on, like a lobby's
 }
 
+#[derive(Clone)]
 pub enum NetEvent {
 	AssignStart(AssignStart),
-	TickInputs(TickInputs),
+	TickInputs(Stamped<TickInputs>),
+	InputDelay(InputDelay),
+	Resume(ResumeState),
+	SpectateStart(SpectateStart),
+	History(Vec<TickInputs>),
+	Series(SeriesState),
+	AfkWarning(AfkWarning),
+	Kicked(KickReason),
+	Pong(Pong),
+	Snapshot(StateSnapshot),
+	Control(ControlChange),
+	InputGrant(InputGrant),
+	// Tick of a confirmed state the server disagrees with
+	DesyncDetected(u32),
+	// The server's checksum of its state, only a referee gets them
+	StateHash(StateHash),
+	// Comes before every start, needs MATCH_SETUP
+	MatchSetup(MatchSetup),
+	// Capabilities both sides support, first event of a connection
+	Welcome(Capabilities),
+	// The handshake failed, by the server's word or ours. Disconnected follows
+	Rejected(Reject),
+	// Matchmaking is waiting for a closer opponent
+	Searching,
+	Disconnected,
 }
 
-#[derive(Debug, Clone, Copy)]
+// Every tick's inputs are needed, readings and corrections only the newest of
+impl Overflow for NetEvent {
+	fn overflow(&self) -> Policy {
+		match self {
+			Self::TickInputs(_) => Policy::Coalesce,
+			Self::InputDelay(_) | Self::Pong(_) | Self::Snapshot(_) => Policy::DropOldest,
+			_ => Policy::Block,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
 pub enum NetCmd {
-	SendInput { tick: u32, bits: u8 },
+	// The newest input last, see InputWindow
+	SendInputs(Vec<InputMsg>),
+	SendSignature(InputSignature),
+	Ping(Ping),
+	SubscribeSnapshots,
+	// Upgrade handshake and matchmaking probes, issued by the connection's reader on its own
+	UdpUpgrade,
+	UdpOffer(UdpOffer),
+	RttEcho(u32),
+	SendStateHash(StateHash),
+	// Hang up as if the connection had dropped
+	Disconnect,
+	// A standby's claim on the slot, see spawn_standby
+	Failover,
 }
 
-pub fn spawn_client(
-	addr: String,
-) -> anyhow::Result<(mpsc::Receiver<NetEvent>, mpsc::Sender<NetCmd>)> {
-	let (tx_evt, rx_evt) = mpsc::channel::<NetEvent>();
-	let (tx_cmd, rx_cmd) = mpsc::channel::<NetCmd>();
+impl NetCmd {
+	// Size of the UDP datagram it would go out as, none for what only ever goes
+	// over the stream
+	pub fn datagram_len(&self) -> Option<usize> {
+		let NetCmd::SendInputs(inputs) = self else {
+			return None;
+		};
+		let datagram = UdpDatagram {
+			nonce: 0,
+			inputs: inputs.clone(),
+		};
+		bincode::serialized_size(&datagram).ok().map(|n| n as usize)
+	}
+}
 
-	let stream = TcpStream::connect(&addr).context("connect")?;
-	stream.set_nodelay(true).ok();
-	let mut read_stream = stream.try_clone().context("clone read stream")?;
-	let mut write_stream = stream;
+/// The inputs a client sent last. Every message repeats them so one that's
+/// lost or late leaves no hole in the server's, clear it whenever the ticks
+/// start over.
+#[derive(Debug, Default)]
+pub struct InputWindow(VecDeque<InputMsg>);
+
+impl InputWindow {
+	// The command sending `msg` along with the inputs before it
+	pub fn send(&mut self, msg: InputMsg) -> NetCmd {
+		self.0.push_back(msg);
+		if self.0.len() > INPUT_REDUNDANCY {
+			self.0.pop_front();
+		}
+		NetCmd::SendInputs(self.0.iter().copied().collect())
+	}
+
+	// The last command again, for a connection that took over from the one it went out on
+	pub fn resend(&self) -> Option<NetCmd> {
+		(!self.0.is_empty()).then(|| NetCmd::SendInputs(self.0.iter().copied().collect()))
+	}
 
-	// Reader
+	pub fn clear(&mut self) {
+		self.0.clear();
+	}
+}
+
+// The event a server frame is for the client loop, none for what the connection
+// handles itself
+fn event_of(msg: S2C) -> Option<NetEvent> {
+	Some(match msg {
+		S2C::AssignStart(a) => NetEvent::AssignStart(a),
+		S2C::TickInputs(t) => NetEvent::TickInputs(t),
+		S2C::InputDelay(d) => NetEvent::InputDelay(d),
+		S2C::Resume(r) => NetEvent::Resume(r),
+		S2C::SpectateStart(s) => NetEvent::SpectateStart(s),
+		S2C::History(h) => NetEvent::History(h),
+		S2C::Series(s) => NetEvent::Series(s),
+		S2C::AfkWarning(w) => NetEvent::AfkWarning(w),
+		S2C::Kicked(r) => NetEvent::Kicked(r),
+		S2C::Pong(p) => NetEvent::Pong(p),
+		S2C::Snapshot(s) => NetEvent::Snapshot(s),
+		S2C::Control(c) => NetEvent::Control(c),
+		S2C::Welcome(w) => NetEvent::Welcome(w.capabilities()),
+		S2C::UdpOffer(_) | S2C::RttProbe(_) => return None,
+		S2C::Searching => NetEvent::Searching,
+		S2C::InputGrant(g) => NetEvent::InputGrant(g),
+		S2C::DesyncDetected(tick) => NetEvent::DesyncDetected(tick),
+		S2C::StateHash(h) => NetEvent::StateHash(h),
+		S2C::MatchSetup(m) => NetEvent::MatchSetup(m),
+		S2C::Reject(r) => NetEvent::Rejected(r),
+	})
+}
+
+// Forward server frames as events until the connection drops. With `tx_cmd`
+// we said Hello, the reader waits for the answer and also moves inputs to UDP
+// once the server offers it
+fn spawn_reader(
+	mut read_stream: Box<dyn Transport>,
+	tx_evt: queue::Sender<NetEvent>,
+	tx_cmd: Option<mpsc::Sender<NetCmd>>,
+	transport: InputTransport,
+) {
 	thread::spawn(move || {
+		let mut welcomed = tx_cmd.is_none();
+		if !welcomed {
+			read_stream.set_recv_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+		}
 		loop {
-			let msg: anyhow::Result<S2C> = read_frame(&mut read_stream);
-			let Ok(msg) = msg else { break };
-			let ev = match msg {
-				S2C::AssignStart(a) => NetEvent::AssignStart(a),
-				S2C::TickInputs(t) => NetEvent::TickInputs(t),
+			let msg: anyhow::Result<S2C> = read_frame(&mut *read_stream);
+			let msg = match msg {
+				Ok(msg) => msg,
+				// Silence or garbage instead of an answer, unlike a hang-up, means
+				// something other than our server is on the other end
+				Err(e) if !welcomed && !is_hang_up(&e) => {
+					let _ = tx_evt.send(NetEvent::Rejected(Reject::Unreadable));
+					read_stream.close();
+					break;
+				}
+				Err(_) => break,
+			};
+			if let S2C::Welcome(w) = &msg
+				&& !welcomed
+			{
+				welcomed = true;
+				read_stream.set_recv_timeout(None).ok();
+				if w.version < MIN_PROTOCOL_VERSION {
+					let _ = tx_evt.send(NetEvent::Rejected(Reject::Version {
+						peer: w.version,
+						min: MIN_PROTOCOL_VERSION,
+					}));
+					read_stream.close();
+					break;
+				}
+			}
+			if let Some(tx_cmd) = &tx_cmd {
+				match &msg {
+					S2C::Welcome(w) if transport == InputTransport::Udp => {
+						if w.capabilities().contains(Capabilities::UDP_UPGRADE) {
+							let _ = tx_cmd.send(NetCmd::UdpUpgrade);
+						} else {
+							info!("server has no udp inputs, sending them over tcp");
+						}
+					}
+					&S2C::UdpOffer(offer) => {
+						let _ = tx_cmd.send(NetCmd::UdpOffer(offer));
+					}
+					&S2C::RttProbe(seq) => {
+						let _ = tx_cmd.send(NetCmd::RttEcho(seq));
+					}
+					_ => {}
+				}
+			}
+			let Some(ev) = event_of(msg) else {
+				continue;
 			};
 			if tx_evt.send(ev).is_err() {
 				break;
 			}
 		}
+		let _ = tx_evt.send(NetEvent::Disconnected);
 	});
+}
+
+// Whether a read failed because the other side closed the connection
+fn is_hang_up(e: &anyhow::Error) -> bool {
+	e.downcast_ref::<io::Error>().is_some_and(|e| {
+		matches!(
+			e.kind(),
+			io::ErrorKind::UnexpectedEof
+				| io::ErrorKind::ConnectionReset
+				| io::ErrorKind::ConnectionAborted
+		)
+	})
+}
+
+/// How a client sends its inputs. Everything else always goes over TCP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputTransport {
+	// In order with the rest of the stream, a lost segment holds up what follows
+	Tcp,
+	// Datagrams, each repeating the last few inputs with their ticks. Needs the
+	// server to offer UDP_UPGRADE, stays on TCP otherwise
+	Udp,
+}
+
+// `resume` carries the slot token when reconnecting to a resumed server,
+// `reservation` claims a reserved slot, `room` is the lobby room to join,
+// `signing_key` is announced right after the handshake when signing inputs
+pub fn spawn_client(
+	addr: String,
+	resume: Option<u64>,
+	reservation: Option<u64>,
+	room: Option<String>,
+	signing_key: Option<[u8; 32]>,
+	socket: SocketOptions,
+	transport: InputTransport,
+) -> anyhow::Result<(queue::Receiver<NetEvent>, mpsc::Sender<NetCmd>)> {
+	let hello = C2S::Hello(Hello {
+		version: PROTOCOL_VERSION,
+		capabilities: Capabilities::SUPPORTED.bits(),
+		reservation,
+		fingerprint: crate::sim::fingerprint(),
+		rejoin: resume,
+	});
+	connect_client(addr, hello, room, signing_key, socket, transport)
+}
+
+/// A second connection for the slot `token` is ours in, which the server holds
+/// on to until NetCmd::Failover. Then it takes the slot over from our first
+/// connection and answers with Resume like for a reconnect. Only the Welcome
+/// comes before, everything else it would send waits for the claim.
+pub fn spawn_standby(
+	addr: String,
+	token: u64,
+	room: Option<String>,
+	signing_key: Option<[u8; 32]>,
+	socket: SocketOptions,
+	transport: InputTransport,
+) -> anyhow::Result<(queue::Receiver<NetEvent>, mpsc::Sender<NetCmd>)> {
+	let hello = C2S::Standby(Hello {
+		version: PROTOCOL_VERSION,
+		capabilities: Capabilities::SUPPORTED.bits(),
+		reservation: None,
+		fingerprint: crate::sim::fingerprint(),
+		rejoin: Some(token),
+	});
+	connect_client(addr, hello, room, signing_key, socket, transport)
+}
+
+/// A spectator that says it's a WATCHER: it's never handed a player's slot,
+/// not even in a lobby room still waiting for its players. It has nothing to
+/// send, so it stays on TCP.
+pub fn spawn_watcher(
+	addr: String,
+	room: Option<String>,
+	socket: SocketOptions,
+) -> anyhow::Result<(queue::Receiver<NetEvent>, mpsc::Sender<NetCmd>)> {
+	let hello = C2S::Hello(Hello {
+		version: PROTOCOL_VERSION,
+		capabilities: (Capabilities::SUPPORTED | Capabilities::WATCHER).bits(),
+		reservation: None,
+		fingerprint: crate::sim::fingerprint(),
+		rejoin: None,
+	});
+	connect_client(addr, hello, room, None, socket, InputTransport::Tcp)
+}
+
+/// A spectator that says it's a REFEREE: it's sent the server's StateHashes
+/// along with the stream, and is never handed a player's slot. It has no
+/// inputs to send, so it stays on TCP.
+pub fn spawn_referee(
+	addr: String,
+	room: Option<String>,
+	socket: SocketOptions,
+) -> anyhow::Result<(queue::Receiver<NetEvent>, mpsc::Sender<NetCmd>)> {
+	let hello = C2S::Hello(Hello {
+		version: PROTOCOL_VERSION,
+		capabilities: (Capabilities::SUPPORTED | Capabilities::REFEREE).bits(),
+		reservation: None,
+		fingerprint: crate::sim::fingerprint(),
+		rejoin: None,
+	});
+	connect_client(addr, hello, room, None, socket, InputTransport::Tcp)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn connect_client(
+	addr: String,
+	hello: C2S,
+	room: Option<String>,
+	signing_key: Option<[u8; 32]>,
+	socket: SocketOptions,
+	transport: InputTransport,
+) -> anyhow::Result<(queue::Receiver<NetEvent>, mpsc::Sender<NetCmd>)> {
+	let (tx_evt, rx_evt) = queue::bounded(EVENT_CAPACITY, EVENT_QUEUE);
+	let (tx_cmd, rx_cmd) = mpsc::channel::<NetCmd>();
+
+	let stream = transport::connect(&addr, socket).context("connect")?;
+	let read_stream = stream.try_clone().context("clone read stream")?;
+	let mut write_stream = stream;
+
+	let server = write_stream.peer_addr();
+	spawn_reader(read_stream, tx_evt, Some(tx_cmd.clone()), transport);
 
 	// Writer
 	thread::spawn(move || {
+		let stat_udp = stats::gauge("client.udp_inputs");
+		// Socket and nonce once inputs go over UDP, and the newest tick that went
+		// over TCP before. Datagrams don't repeat those, the server would take
+		// them for late
+		let mut udp: Option<(UdpSocket, u64)> = None;
+		let mut tcp_tick: Option<u32> = None;
+		// A standby's key and UDP upgrade go out with its claim, the server
+		// drops what it sends before
+		let mut claimed = !matches!(hello, C2S::Standby(_));
+		let mut upgrade_due = false;
+		let _ = write_frame(&mut *write_stream, &hello);
+		let _ = write_frame(&mut *write_stream, &C2S::JoinRoom(room));
+		if claimed && let Some(key) = signing_key {
+			let _ = write_frame(&mut *write_stream, &C2S::SigningKey(key));
+		}
 		while let Ok(cmd) = rx_cmd.recv() {
 			match cmd {
-				NetCmd::SendInput { tick, bits } => {
-					let _ = write_frame(
-						&mut write_stream,
-						&C2S::Input(crate::protocol::InputMsg { tick, bits }),
-					);
+				NetCmd::SendInputs(mut inputs) => match &udp {
+					Some((socket, nonce)) => {
+						inputs.retain(|i| tcp_tick.is_none_or(|t| i.tick > t));
+						// Empty it would be a probe
+						if inputs.is_empty() {
+							continue;
+						}
+						let datagram = UdpDatagram {
+							nonce: *nonce,
+							inputs,
+						};
+						let started = wirestats::start();
+						if let Ok(bytes) = bincode::serialize(&datagram) {
+							let _ = socket.send(&bytes);
+							wirestats::record(started, Dir::Sent, "UdpDatagram", bytes.len());
+							let s = transport::frame_stats();
+							s.sent.inc();
+							s.sent_bytes.add(bytes.len() as u64);
+						}
+					}
+					None => {
+						tcp_tick = inputs.last().map(|i| i.tick);
+						let _ = write_frame(&mut *write_stream, &C2S::Input(inputs));
+					}
+				},
+				NetCmd::Ping(p) => {
+					let _ = write_frame(&mut *write_stream, &C2S::Ping(p));
+				}
+				NetCmd::SubscribeSnapshots => {
+					let _ = write_frame(&mut *write_stream, &C2S::SubscribeSnapshots);
+				}
+				NetCmd::SendSignature(sig) => {
+					let _ = write_frame(&mut *write_stream, &C2S::InputSignature(sig));
+				}
+				NetCmd::UdpUpgrade if !claimed => upgrade_due = true,
+				NetCmd::UdpUpgrade => {
+					let _ = write_frame(&mut *write_stream, &C2S::UdpUpgrade);
+				}
+				NetCmd::Failover if !claimed => {
+					claimed = true;
+					let _ = write_frame(&mut *write_stream, &C2S::Failover);
+					if let Some(key) = signing_key {
+						let _ = write_frame(&mut *write_stream, &C2S::SigningKey(key));
+					}
+					if upgrade_due {
+						let _ = write_frame(&mut *write_stream, &C2S::UdpUpgrade);
+					}
+				}
+				NetCmd::Failover => {}
+				NetCmd::RttEcho(seq) => {
+					let _ = write_frame(&mut *write_stream, &C2S::RttEcho(seq));
+				}
+				NetCmd::SendStateHash(hash) => {
+					let _ = write_frame(&mut *write_stream, &C2S::StateHash(hash));
+				}
+				NetCmd::Disconnect => write_stream.close(),
+				NetCmd::UdpOffer(offer) => {
+					// Inputs stay on TCP if the probe never comes back
+					udp = server
+						.and_then(|server| udp_connect(server, offer))
+						.map(|s| (s, offer.nonce));
+					if udp.is_none() {
+						warn!(
+							"udp probe to port {} failed, sending inputs over tcp",
+							offer.port
+						);
+					}
+					stat_udp.set(udp.is_some() as i64);
 				}
 			}
 		}
@@ -207,3 +2233,253 @@ pub fn spawn_client(
 
 	Ok((rx_evt, tx_cmd))
 }
+
+// A browser can't block on a socket and has no threads to do it on, so its
+// connections live here and the frame loop moves their traffic, see pump
+#[cfg(target_arch = "wasm32")]
+thread_local! {
+	static BROWSER_CONNS: std::cell::RefCell<Vec<BrowserConn>> =
+		const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(target_arch = "wasm32")]
+struct BrowserConn {
+	socket: crate::browser::Socket,
+	tx_evt: queue::Sender<NetEvent>,
+	rx_cmd: mpsc::Receiver<NetCmd>,
+	signing_key: Option<[u8; 32]>,
+	claimed: bool,
+	welcomed: bool,
+	// When the socket opened, the Welcome is due within HANDSHAKE_TIMEOUT of it
+	opened: Option<Instant>,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl BrowserConn {
+	fn send(&self, msg: &C2S) {
+		let started = wirestats::start();
+		if let Ok(frame) = msg.encode() {
+			self.socket.send(&frame);
+			wirestats::record(started, Dir::Sent, msg.kind(), frame.len());
+		}
+	}
+
+	// Moves what queued up since the last frame both ways, false once the
+	// connection is over and Disconnected went out
+	fn pump(&mut self) -> bool {
+		loop {
+			match self.rx_cmd.try_recv() {
+				Ok(cmd) => self.command(cmd),
+				Err(mpsc::TryRecvError::Empty) => break,
+				Err(mpsc::TryRecvError::Disconnected) => {
+					self.socket.close();
+					return false;
+				}
+			}
+		}
+		if self.opened.is_none() && self.socket.is_open() {
+			self.opened = Some(Instant::now());
+		}
+		// Half the queue a frame, the loop drains it before the next
+		let mut drained = false;
+		for _ in 0..EVENT_CAPACITY / 2 {
+			let Some(frame) = self.socket.recv() else {
+				drained = true;
+				break;
+			};
+			if !self.receive(&frame) {
+				self.socket.close();
+				let _ = self.tx_evt.send(NetEvent::Disconnected);
+				return false;
+			}
+		}
+		let silent = !self.welcomed
+			&& self
+				.opened
+				.is_some_and(|at| at.elapsed() > HANDSHAKE_TIMEOUT);
+		if silent {
+			let _ = self.tx_evt.send(NetEvent::Rejected(Reject::Unreadable));
+			self.socket.close();
+		}
+		// A closed socket still hands out what arrived before
+		if silent || (drained && self.socket.is_closed()) {
+			let _ = self.tx_evt.send(NetEvent::Disconnected);
+			return false;
+		}
+		true
+	}
+
+	// Same as the reader's handling of a frame, false ends the connection
+	fn receive(&mut self, frame: &[u8]) -> bool {
+		let started = wirestats::start();
+		let msg = match S2C::decode(frame) {
+			Ok(msg) => msg,
+			Err(_) if !self.welcomed => {
+				let _ = self.tx_evt.send(NetEvent::Rejected(Reject::Unreadable));
+				return false;
+			}
+			Err(_) => return false,
+		};
+		wirestats::record(started, Dir::Received, msg.kind(), frame.len());
+		match &msg {
+			S2C::Welcome(w) if !self.welcomed => {
+				self.welcomed = true;
+				if w.version < MIN_PROTOCOL_VERSION {
+					let _ = self.tx_evt.send(NetEvent::Rejected(Reject::Version {
+						peer: w.version,
+						min: MIN_PROTOCOL_VERSION,
+					}));
+					return false;
+				}
+			}
+			&S2C::RttProbe(seq) => self.send(&C2S::RttEcho(seq)),
+			_ => {}
+		}
+		event_of(msg).is_none_or(|ev| self.tx_evt.send(ev).is_ok())
+	}
+
+	// The writer thread's commands, everything goes over the WebSocket
+	fn command(&mut self, cmd: NetCmd) {
+		match cmd {
+			NetCmd::SendInputs(inputs) => self.send(&C2S::Input(inputs)),
+			NetCmd::Ping(p) => self.send(&C2S::Ping(p)),
+			NetCmd::SubscribeSnapshots => self.send(&C2S::SubscribeSnapshots),
+			NetCmd::SendSignature(sig) => self.send(&C2S::InputSignature(sig)),
+			NetCmd::Failover if !self.claimed => {
+				self.claimed = true;
+				self.send(&C2S::Failover);
+				if let Some(key) = self.signing_key {
+					self.send(&C2S::SigningKey(key));
+				}
+			}
+			NetCmd::RttEcho(seq) => self.send(&C2S::RttEcho(seq)),
+			NetCmd::SendStateHash(hash) => self.send(&C2S::StateHash(hash)),
+			NetCmd::Disconnect => self.socket.close(),
+			NetCmd::Failover | NetCmd::UdpUpgrade | NetCmd::UdpOffer(_) => {}
+		}
+	}
+}
+
+// In a browser the server is always a WebSocket, `ws://` is implied
+#[cfg(target_arch = "wasm32")]
+fn connect_client(
+	addr: String,
+	hello: C2S,
+	room: Option<String>,
+	signing_key: Option<[u8; 32]>,
+	_socket: SocketOptions,
+	transport: InputTransport,
+) -> anyhow::Result<(queue::Receiver<NetEvent>, mpsc::Sender<NetCmd>)> {
+	let (tx_evt, rx_evt) = queue::bounded(EVENT_CAPACITY, EVENT_QUEUE);
+	let (tx_cmd, rx_cmd) = mpsc::channel::<NetCmd>();
+	if transport == InputTransport::Udp {
+		info!("a browser has no udp, sending inputs over the websocket");
+	}
+	let url = if addr.starts_with("ws://") || addr.starts_with("wss://") {
+		addr
+	} else {
+		format!("ws://{addr}")
+	};
+	let claimed = !matches!(hello, C2S::Standby(_));
+	let conn = BrowserConn {
+		socket: crate::browser::Socket::open(&url),
+		tx_evt,
+		rx_cmd,
+		signing_key,
+		claimed,
+		welcomed: false,
+		opened: None,
+	};
+	// The page holds these until the socket opens
+	conn.send(&hello);
+	conn.send(&C2S::JoinRoom(room));
+	if claimed && let Some(key) = signing_key {
+		conn.send(&C2S::SigningKey(key));
+	}
+	BROWSER_CONNS.with(|conns| conns.borrow_mut().push(conn));
+	Ok((rx_evt, tx_cmd))
+}
+
+/// Moves the traffic of a browser's connections, once a frame before the
+/// client loop reads its events. Natively every connection has its threads and
+/// there's nothing to do.
+pub fn pump() {
+	#[cfg(target_arch = "wasm32")]
+	BROWSER_CONNS.with(|conns| conns.borrow_mut().retain_mut(BrowserConn::pump));
+}
+
+// Probe the offered port, None when nothing comes back and inputs should stay on TCP
+#[cfg(not(target_arch = "wasm32"))]
+fn udp_connect(server: SocketAddr, offer: UdpOffer) -> Option<UdpSocket> {
+	let local = if server.is_ipv4() {
+		"0.0.0.0:0"
+	} else {
+		"[::]:0"
+	};
+	let socket = UdpSocket::bind(local).ok()?;
+	socket.connect((server.ip(), offer.port)).ok()?;
+	socket.set_read_timeout(Some(UDP_PROBE_TIMEOUT)).ok()?;
+	let probe = bincode::serialize(&UdpDatagram {
+		nonce: offer.nonce,
+		inputs: Vec::new(),
+	})
+	.ok()?;
+	let mut buf = [0u8; 64];
+	for _ in 0..UDP_PROBE_TRIES {
+		socket.send(&probe).ok()?;
+		if let Ok(n) = socket.recv(&mut buf)
+			&& buf[..n] == probe[..]
+		{
+			return Some(socket);
+		}
+	}
+	None
+}
+
+// How long to wait on a candidate server, and connections timed per candidate
+const SERVER_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const SERVER_PROBES: u32 = 3;
+
+// Best of a few TCP connects to `addr`, a connect costs one round trip
+fn connect_rtt(addr: &str) -> Option<Duration> {
+	let addrs: Vec<SocketAddr> = addr.to_socket_addrs().ok()?.collect();
+	let target = *addrs.first()?;
+	(0..SERVER_PROBES)
+		.filter_map(|_| {
+			let started = Instant::now();
+			let stream = TcpStream::connect_timeout(&target, SERVER_PROBE_TIMEOUT).ok()?;
+			let rtt = started.elapsed();
+			let _ = stream.shutdown(Shutdown::Both);
+			Some(rtt)
+		})
+		.min()
+}
+
+/// The reachable server with the lowest round trip out of `candidates`.
+pub fn pick_server(candidates: &[String]) -> anyhow::Result<String> {
+	let mut best: Option<(&String, Duration)> = None;
+	for addr in candidates {
+		match connect_rtt(addr) {
+			Some(rtt) => {
+				info!("{addr}: {:.1}ms", rtt.as_secs_f64() * 1000.0);
+				if best.is_none_or(|(_, b)| rtt < b) {
+					best = Some((addr, rtt));
+				}
+			}
+			None => info!("{}: unreachable", addr),
+		}
+	}
+	let (addr, _) = best.context("no server reachable")?;
+	Ok(addr.clone())
+}
+
+// Read-only connection to a server's observer port, nothing is ever sent
+pub fn spawn_observer(
+	addr: String,
+	socket: SocketOptions,
+) -> anyhow::Result<queue::Receiver<NetEvent>> {
+	let (tx_evt, rx_evt) = queue::bounded(EVENT_CAPACITY, EVENT_QUEUE);
+	let stream = transport::connect(&addr, socket).context("connect")?;
+	spawn_reader(stream, tx_evt, None, InputTransport::Tcp);
+	Ok(rx_evt)
+}
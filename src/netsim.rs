@@ -0,0 +1,299 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	time::Duration,
+};
+
+use clap::ValueEnum;
+
+use crate::{env::Rng, net::NetEvent, stats};
+
+use crate::clock::Instant;
+
+// Message types the client's network simulator tells apart, named like
+// `--netsim tick-inputs:loss=0.1`. Inputs and pings are outbound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Kind {
+	AssignStart,
+	Resume,
+	TickInputs,
+	InputDelay,
+	SpectateStart,
+	History,
+	Series,
+	AfkWarning,
+	Kicked,
+	Pong,
+	Snapshot,
+	Control,
+	InputGrant,
+	DesyncDetected,
+	MatchSetup,
+	StateHash,
+	Input,
+	Ping,
+}
+
+impl Kind {
+	// None for what never crosses the simulated link: the handshake, matchmaking
+	// and local drops
+	pub fn of_event(ev: &NetEvent) -> Option<Self> {
+		Some(match ev {
+			NetEvent::AssignStart(_) => Self::AssignStart,
+			NetEvent::Resume(_) => Self::Resume,
+			NetEvent::TickInputs(_) => Self::TickInputs,
+			NetEvent::InputDelay(_) => Self::InputDelay,
+			NetEvent::SpectateStart(_) => Self::SpectateStart,
+			NetEvent::History(_) => Self::History,
+			NetEvent::Series(_) => Self::Series,
+			NetEvent::AfkWarning(_) => Self::AfkWarning,
+			NetEvent::Kicked(_) => Self::Kicked,
+			NetEvent::Pong(_) => Self::Pong,
+			NetEvent::Snapshot(_) => Self::Snapshot,
+			NetEvent::Control(_) => Self::Control,
+			NetEvent::InputGrant(_) => Self::InputGrant,
+			NetEvent::DesyncDetected(_) => Self::DesyncDetected,
+			NetEvent::MatchSetup(_) => Self::MatchSetup,
+			NetEvent::StateHash(_) => Self::StateHash,
+			NetEvent::Welcome(_)
+			| NetEvent::Rejected(_)
+			| NetEvent::Searching
+			| NetEvent::Disconnected => return None,
+		})
+	}
+
+	// Messages that skip the artificial delay unless asked for, see --delay-control
+	pub fn is_control(self) -> bool {
+		matches!(
+			self,
+			Self::AssignStart
+				| Self::Resume
+				| Self::Series
+				| Self::AfkWarning
+				| Self::Kicked
+				| Self::MatchSetup
+		)
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Policy {
+	// Replaces the arrow-key delay when set
+	pub delay_ms: Option<u32>,
+	// Uniform extra delay on top, in [0, jitter_ms]
+	pub jitter_ms: u32,
+	// Chance to drop the message, in [0, 1]
+	pub loss: f32,
+	// Chance to hold the message back so later ones overtake it, in [0, 1]
+	pub reorder: f32,
+	// Chance to deliver the message twice, in [0, 1]
+	pub duplicate: f32,
+	// Largest datagram the path carries. Bigger ones are split into fragments,
+	// each lost with `loss` on its own, and arrive with the last one
+	pub mtu: Option<u32>,
+	// Don't fragment: datagrams over the MTU are dropped instead
+	pub df: bool,
+}
+
+// How much longer than its delay a reordered message is held, before jitter
+const REORDER_HOLD_MS: u32 = 40;
+
+// `kind:key=value,...` with keys delay, jitter, loss, reorder, duplicate, mtu
+// and df, e.g. `tick-inputs:loss=0.1,jitter=20` or `input:mtu=64,df=1`
+pub fn parse_policy(s: &str) -> Result<(Kind, Policy), String> {
+	let (kind, rest) = s.split_once(':').unwrap_or((s, ""));
+	let kind = Kind::from_str(kind, true)?;
+	let mut policy = Policy::default();
+	for pair in rest.split(',').filter(|p| !p.is_empty()) {
+		let (key, value) = pair
+			.split_once('=')
+			.ok_or_else(|| format!("expected key=value, got {pair:?}"))?;
+		let chance = || -> Result<f32, String> {
+			let p: f32 = value.parse().map_err(|e| format!("{key}: {e}"))?;
+			if !(0.0..=1.0).contains(&p) {
+				return Err(format!("{key} must be within 0 and 1"));
+			}
+			Ok(p)
+		};
+		match key {
+			"delay" => policy.delay_ms = Some(value.parse().map_err(|e| format!("delay: {e}"))?),
+			"jitter" => policy.jitter_ms = value.parse().map_err(|e| format!("jitter: {e}"))?,
+			"loss" => policy.loss = chance()?,
+			"reorder" => policy.reorder = chance()?,
+			"duplicate" => policy.duplicate = chance()?,
+			"mtu" => policy.mtu = Some(value.parse().map_err(|e| format!("mtu: {e}"))?),
+			"df" => policy.df = value.parse::<u8>().map_err(|e| format!("df: {e}"))? != 0,
+			_ => {
+				return Err(format!(
+					"unknown key {key:?}, expected delay, jitter, loss, reorder, duplicate, mtu or df"
+				));
+			}
+		}
+	}
+	if policy.mtu == Some(0) {
+		return Err("mtu must be above 0".to_string());
+	}
+	// Only inputs go in datagrams, everything else is a stream's business
+	if (policy.mtu.is_some() || policy.df) && kind != Kind::Input {
+		return Err("mtu and df only apply to input".to_string());
+	}
+	Ok((kind, policy))
+}
+
+/// When one copy of a message arrives: after `delay_ms`, and `in_order` unless
+/// it may be overtaken by messages sent after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delivery {
+	pub delay_ms: u32,
+	pub in_order: bool,
+}
+
+impl Delivery {
+	pub fn in_order(delay_ms: u32) -> Self {
+		Self {
+			delay_ms,
+			in_order: true,
+		}
+	}
+}
+
+/// Loss, delay, jitter, reordering, duplication and an MTU per message type on
+/// top of the client's artificial delay. Types without a policy just get the
+/// artificial delay.
+#[derive(Debug, Clone)]
+pub struct NetSim {
+	policies: HashMap<Kind, Policy>,
+	rng: Rng,
+}
+
+impl NetSim {
+	pub fn new(policies: Vec<(Kind, Policy)>, seed: u32) -> Self {
+		Self {
+			policies: policies.into_iter().collect(),
+			rng: Rng::new(seed),
+		}
+	}
+
+	pub fn has_policy(&self, kind: Kind) -> bool {
+		self.policies.contains_key(&kind)
+	}
+
+	fn chance(&mut self, p: f32) -> bool {
+		p > 0.0 && (self.rng.next_u32() as f64 / u32::MAX as f64) < p as f64
+	}
+
+	fn jitter(&mut self, jitter_ms: u32) -> u32 {
+		match jitter_ms {
+			0 => 0,
+			j => self.rng.next_u32() % (j + 1),
+		}
+	}
+
+	// How many fragments a datagram of `bytes` goes out as, none when it's
+	// dropped for being too big
+	fn fragments(p: &Policy, bytes: Option<usize>) -> Option<u32> {
+		let (Some(mtu), Some(bytes)) = (p.mtu, bytes) else {
+			return Some(1);
+		};
+		let n = bytes.div_ceil(mtu as usize) as u32;
+		if n <= 1 {
+			return Some(1);
+		}
+		if p.df {
+			stats::counter("netsim.oversized_drops").inc();
+			return None;
+		}
+		stats::counter("netsim.fragmented").inc();
+		Some(n)
+	}
+
+	// Every copy of a message of `kind` that arrives given the artificial delay,
+	// none when it's lost. `bytes` is its size as a datagram, when it's sent as one
+	pub fn deliveries(&mut self, kind: Kind, base_ms: u32, bytes: Option<usize>) -> Vec<Delivery> {
+		let Some(&p) = self.policies.get(&kind) else {
+			return vec![Delivery::in_order(base_ms)];
+		};
+		let Some(fragments) = Self::fragments(&p, bytes) else {
+			return Vec::new();
+		};
+		// One lost fragment and the rest can't be put back together
+		if (0..fragments).any(|_| self.chance(p.loss)) {
+			return Vec::new();
+		}
+		let copies = if self.chance(p.duplicate) { 2 } else { 1 };
+		(0..copies)
+			.map(|_| {
+				let jitter = (0..fragments)
+					.map(|_| self.jitter(p.jitter_ms))
+					.max()
+					.unwrap_or(0);
+				let delay_ms = p.delay_ms.unwrap_or(base_ms) + jitter;
+				if self.chance(p.reorder) {
+					Delivery {
+						delay_ms: delay_ms + REORDER_HOLD_MS + self.jitter(p.jitter_ms),
+						in_order: false,
+					}
+				} else {
+					Delivery::in_order(delay_ms)
+				}
+			})
+			.collect()
+	}
+}
+
+/// Messages held until they're due. In-order ones wait for everything
+/// scheduled before them, like a stream delivers; the others are slotted in
+/// by time and may overtake.
+pub struct DelayQueue<T> {
+	queue: VecDeque<(Instant, T)>,
+	last_in_order: Option<Instant>,
+}
+
+impl<T: Clone> DelayQueue<T> {
+	pub fn new() -> Self {
+		Self {
+			queue: VecDeque::new(),
+			last_in_order: None,
+		}
+	}
+
+	// Due now, but still behind whatever is queued
+	pub fn push(&mut self, msg: T) {
+		self.queue.push_back((Instant::now(), msg));
+	}
+
+	pub fn schedule(&mut self, msg: T, deliveries: &[Delivery]) {
+		let Some((last, copies)) = deliveries.split_last() else {
+			return;
+		};
+		for &d in copies {
+			self.schedule_one(msg.clone(), d);
+		}
+		self.schedule_one(msg, *last);
+	}
+
+	fn schedule_one(&mut self, msg: T, d: Delivery) {
+		let mut at = Instant::now() + Duration::from_millis(d.delay_ms as u64);
+		if d.in_order {
+			at = self.last_in_order.map_or(at, |prev| prev.max(at));
+			self.last_in_order = Some(at);
+		}
+		let i = self
+			.queue
+			.iter()
+			.rposition(|(when, _)| *when <= at)
+			.map_or(0, |i| i + 1);
+		self.queue.insert(i, (at, msg));
+	}
+
+	pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+		if self.queue.front()?.0 > now {
+			return None;
+		}
+		self.queue.pop_front().map(|(_, msg)| msg)
+	}
+
+	pub fn clear(&mut self) {
+		self.queue.clear();
+		self.last_in_order = None;
+	}
+}
@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use crate::{
+	clock::Instant,
+	stats::{self, Counter},
+	transport,
+};
+
+// Rates are over this much time, and change that often
+const WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+	bytes_in: u64,
+	bytes_out: u64,
+	rollbacks: u64,
+	resimulated: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rates {
+	// Per second
+	bytes_in: f64,
+	bytes_out: f64,
+	rollbacks: f64,
+	// Mean ticks resimulated per rollback, none without rollbacks
+	depth: Option<f64>,
+}
+
+/// What the HUD line under the tick shows about the connection: round trip
+/// and jitter as of the newest pong, and traffic and rollbacks over the last
+/// second. The counters are the stats registry's, bumped where frames are
+/// read and written and where the client rolls back.
+pub struct NetStats {
+	rollbacks: &'static Counter,
+	resimulated: &'static Counter,
+	since: Instant,
+	last: Totals,
+	// Over the last window, none before the first one ends
+	rate: Option<Rates>,
+}
+
+impl Default for NetStats {
+	fn default() -> Self {
+		let mut s = Self {
+			rollbacks: stats::counter("client.rollbacks"),
+			resimulated: stats::counter("client.resimulated_ticks"),
+			since: Instant::now(),
+			last: Totals::default(),
+			rate: None,
+		};
+		s.last = s.totals();
+		s
+	}
+}
+
+impl NetStats {
+	fn totals(&self) -> Totals {
+		let frames = transport::frame_stats();
+		Totals {
+			bytes_in: frames.received_bytes.get(),
+			bytes_out: frames.sent_bytes.get(),
+			rollbacks: self.rollbacks.get(),
+			resimulated: self.resimulated.get(),
+		}
+	}
+
+	// Called every frame, moves the window on once a second
+	pub fn update(&mut self) {
+		let secs = self.since.elapsed().as_secs_f64();
+		if secs < WINDOW.as_secs_f64() {
+			return;
+		}
+		let now = self.totals();
+		self.rate = Some(Rates {
+			bytes_in: (now.bytes_in - self.last.bytes_in) as f64 / secs,
+			bytes_out: (now.bytes_out - self.last.bytes_out) as f64 / secs,
+			rollbacks: (now.rollbacks - self.last.rollbacks) as f64 / secs,
+			depth: (now.rollbacks > self.last.rollbacks).then(|| {
+				(now.resimulated - self.last.resimulated) as f64
+					/ (now.rollbacks - self.last.rollbacks) as f64
+			}),
+		});
+		self.last = now;
+		self.since = Instant::now();
+	}
+
+	pub fn line(&self, rtt_us: Option<i64>, jitter_us: Option<i64>) -> String {
+		let ms = |us: Option<i64>| {
+			us.map_or("-".to_string(), |us| format!("{:.1}ms", us as f64 / 1000.0))
+		};
+		let Some(r) = self.rate else {
+			return format!("rtt={} jitter={}", ms(rtt_us), ms(jitter_us));
+		};
+		let depth = r.depth.map_or("-".to_string(), |d| format!("{d:.1}"));
+		format!(
+			"rtt={} jitter={} in={:.1}KB/s out={:.1}KB/s rollbacks={}/s depth={depth}",
+			ms(rtt_us),
+			ms(jitter_us),
+			r.bytes_in / 1000.0,
+			r.bytes_out / 1000.0,
+			r.rollbacks.round(),
+		)
+	}
+}
@@ -0,0 +1,124 @@
+use std::ops::{Add, Mul, Sub};
+
+use macroquad::prelude::{Vec2, vec2};
+
+/// The sim's number for positions, velocities and everything they're
+/// computed from. f32 rounds the same on every target the demo ships for,
+/// but not by any guarantee compilers give: `--features fixed-point` makes it
+/// Q16.16, whose arithmetic is integer arithmetic everywhere. Both builds
+/// simulate differently, their fingerprints tell them apart.
+#[cfg(not(feature = "fixed-point"))]
+pub type Num = f32;
+#[cfg(feature = "fixed-point")]
+pub type Num = fixed::types::I16F16;
+
+pub const ZERO: Num = num(0.0);
+pub const ONE: Num = num(1.0);
+
+// Larger than any coordinate or time the sim uses, for "never"
+#[cfg(not(feature = "fixed-point"))]
+pub const MAX: Num = f32::INFINITY;
+#[cfg(feature = "fixed-point")]
+pub const MAX: Num = Num::MAX;
+
+/// Constants and presentation values into the sim's number, rounded to
+/// nearest. Exact for f32.
+#[cfg(not(feature = "fixed-point"))]
+pub const fn num(v: f32) -> Num {
+	v
+}
+
+#[cfg(feature = "fixed-point")]
+pub const fn num(v: f32) -> Num {
+	let scaled = v * (1u32 << Num::FRAC_NBITS) as f32;
+	Num::from_bits(if scaled < 0.0 {
+		(scaled - 0.5) as i32
+	} else {
+		(scaled + 0.5) as i32
+	})
+}
+
+pub fn int(v: i32) -> Num {
+	num(v as f32)
+}
+
+// For drawing and feedback only, nothing simulated comes back from f32
+#[cfg(not(feature = "fixed-point"))]
+pub fn to_f32(v: Num) -> f32 {
+	v
+}
+
+#[cfg(feature = "fixed-point")]
+pub fn to_f32(v: Num) -> f32 {
+	v.to_num()
+}
+
+// `a / b`, clamped to the representable range instead of overflowing
+#[cfg(not(feature = "fixed-point"))]
+pub fn div(a: Num, b: Num) -> Num {
+	a / b
+}
+
+#[cfg(feature = "fixed-point")]
+pub fn div(a: Num, b: Num) -> Num {
+	a.saturating_div(b)
+}
+
+// The bits checksums and fingerprints hash. All NaNs are one pattern, their
+// payloads differ between platforms
+#[cfg(not(feature = "fixed-point"))]
+pub fn to_bits(v: Num) -> u32 {
+	if v.is_nan() {
+		f32::NAN.to_bits()
+	} else {
+		v.to_bits()
+	}
+}
+
+#[cfg(feature = "fixed-point")]
+pub fn to_bits(v: Num) -> u32 {
+	v.to_bits() as u32
+}
+
+/// A point or move in sim numbers, macroquad's Vec2 being f32 only.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Vector {
+	pub x: Num,
+	pub y: Num,
+}
+
+pub const fn vector(x: Num, y: Num) -> Vector {
+	Vector { x, y }
+}
+
+impl Vector {
+	pub const ZERO: Self = vector(ZERO, ZERO);
+
+	pub fn to_vec2(self) -> Vec2 {
+		vec2(to_f32(self.x), to_f32(self.y))
+	}
+}
+
+impl Add for Vector {
+	type Output = Self;
+
+	fn add(self, rhs: Self) -> Self {
+		vector(self.x + rhs.x, self.y + rhs.y)
+	}
+}
+
+impl Sub for Vector {
+	type Output = Self;
+
+	fn sub(self, rhs: Self) -> Self {
+		vector(self.x - rhs.x, self.y - rhs.y)
+	}
+}
+
+impl Mul<Num> for Vector {
+	type Output = Self;
+
+	fn mul(self, rhs: Num) -> Self {
+		vector(self.x * rhs, self.y * rhs)
+	}
+}
@@ -0,0 +1,88 @@
+use clap::ValueEnum;
+use macroquad::prelude::*;
+
+use crate::{num::to_f32, sim::Player};
+
+// Width of the bands and borders players are patterned with, in buffer pixels
+const PATTERN_PX: f32 = 4.0;
+
+/// Colors the arena is drawn in, F6 cycles through them in every window.
+/// Teams also get a pattern each, so hue is never the only difference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Palette {
+	Classic,
+	// Bright on black, every team at least as light as the hill
+	HighContrast,
+	// Okabe-Ito colors, told apart with any of the common color deficiencies
+	Colorblind,
+}
+
+impl Palette {
+	pub fn next(self) -> Self {
+		match self {
+			Self::Classic => Self::HighContrast,
+			Self::HighContrast => Self::Colorblind,
+			Self::Colorblind => Self::Classic,
+		}
+	}
+
+	pub fn label(self) -> &'static str {
+		match self {
+			Self::Classic => "classic",
+			Self::HighContrast => "high contrast",
+			Self::Colorblind => "colorblind",
+		}
+	}
+
+	pub fn team(self, team: u8) -> Color {
+		let colors = match self {
+			Self::Classic => [BLUE, RED, GREEN, ORANGE],
+			Self::HighContrast => [WHITE, YELLOW, SKYBLUE, MAGENTA],
+			Self::Colorblind => [
+				Color::from_rgba(0, 114, 178, 255),
+				Color::from_rgba(230, 159, 0, 255),
+				Color::from_rgba(204, 121, 167, 255),
+				Color::from_rgba(240, 228, 66, 255),
+			],
+		};
+		colors[team as usize % colors.len()]
+	}
+
+	pub fn hill(self) -> Color {
+		match self {
+			Self::Classic | Self::Colorblind => GOLD,
+			Self::HighContrast => LIGHTGRAY,
+		}
+	}
+
+	// Team color with the team's pattern cut out of it in the black of the
+	// background: solid, hollow, striped, then crossed
+	pub fn draw_player(self, x: f32, y: f32, team: u8) {
+		let (w, h) = (to_f32(Player::W), to_f32(Player::H));
+		draw_rectangle(x, y, w, h, self.team(team));
+		match team % 4 {
+			0 => {}
+			1 => draw_rectangle(
+				x + PATTERN_PX,
+				y + PATTERN_PX,
+				w - 2.0 * PATTERN_PX,
+				h - 2.0 * PATTERN_PX,
+				BLACK,
+			),
+			2 => {
+				let mut band = y + PATTERN_PX;
+				while band < y + h {
+					draw_rectangle(x, band, w, PATTERN_PX, BLACK);
+					band += 2.0 * PATTERN_PX;
+				}
+			}
+			_ => {
+				draw_line(x, y, x + w, y + h, PATTERN_PX, BLACK);
+				draw_line(x + w, y, x, y + h, PATTERN_PX, BLACK);
+			}
+		}
+		if self == Self::HighContrast {
+			draw_rectangle_lines(x, y, w, h, 2.0, WHITE);
+		}
+	}
+}
@@ -0,0 +1,140 @@
+use crate::num::{self, Num, ONE, Vector, ZERO, vector};
+
+/// An axis-aligned box, `x` and `y` its top-left corner like everything in
+/// the sim. Only ever moved with plain arithmetic on num::Num, so sweeps are
+/// as deterministic as the rest of the step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+	pub x: Num,
+	pub y: Num,
+	pub w: Num,
+	pub h: Num,
+}
+
+impl Aabb {
+	pub const fn new(x: Num, y: Num, w: Num, h: Num) -> Self {
+		Self { x, y, w, h }
+	}
+
+	// Shares more than an edge with `other`
+	pub fn overlaps(&self, other: &Aabb) -> bool {
+		self.x < other.x + other.w
+			&& other.x < self.x + self.w
+			&& self.y < other.y + other.h
+			&& other.y < self.y + self.h
+	}
+
+	// Resting on top of `other`, touching it along part of its top edge
+	pub fn stands_on(&self, other: &Aabb) -> bool {
+		self.y + self.h == other.y && self.x < other.x + other.w && other.x < self.x + self.w
+	}
+}
+
+/// Where a sweep first touched a solid: how far along the move, 0 to 1, and
+/// which way the touched face points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+	pub time: Num,
+	pub normal: Vector,
+}
+
+// Entry and exit time of [a, a + a_len) moving by `d` through [b, b + b_len)
+fn slab(a: Num, a_len: Num, d: Num, b: Num, b_len: Num) -> (Num, Num) {
+	if d > ZERO {
+		(num::div(b - (a + a_len), d), num::div(b + b_len - a, d))
+	} else if d < ZERO {
+		(num::div(b + b_len - a, d), num::div(b - (a + a_len), d))
+	} else if a < b + b_len && b < a + a_len {
+		(-num::MAX, num::MAX)
+	} else {
+		(num::MAX, -num::MAX)
+	}
+}
+
+/// First contact of `a` moving by `delta` with `b`, None when it never
+/// touches it or already overlaps it. Boxes only touching at the start are
+/// blocked right away if moving into each other, and slide past otherwise.
+pub fn sweep(a: Aabb, delta: Vector, b: Aabb) -> Option<Contact> {
+	let (enter_x, exit_x) = slab(a.x, a.w, delta.x, b.x, b.w);
+	let (enter_y, exit_y) = slab(a.y, a.h, delta.y, b.y, b.h);
+	let enter = enter_x.max(enter_y);
+	let exit = exit_x.min(exit_y);
+	if enter > exit || !(ZERO..=ONE).contains(&enter) || exit <= ZERO {
+		return None;
+	}
+	// The later axis is the face that was hit, x on an exact corner
+	let normal = if enter_x >= enter_y {
+		vector(-delta.x.signum(), ZERO)
+	} else {
+		vector(ZERO, -delta.y.signum())
+	};
+	Some(Contact {
+		time: enter,
+		normal,
+	})
+}
+
+/// How far along its move by `delta` `a` first touches `b`, 0 when it starts
+/// inside. For things too small and fast to check only where they end up.
+pub fn touch_time(a: Aabb, delta: Vector, b: Aabb) -> Option<Num> {
+	if a.overlaps(&b) {
+		return Some(ZERO);
+	}
+	sweep(a, delta, b).map(|c| c.time)
+}
+
+/// What a body bumped into during `move_and_slide`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Blocked {
+	pub x: bool,
+	pub y: bool,
+}
+
+// A move can stop at a wall and then at the floor, never more than once per axis
+const MAX_SLIDES: usize = 2;
+
+/// Moves `body` by `delta`, stopping at the first solid in the way and
+/// sliding along it with what's left of the move. Ties go to the solid
+/// listed first. A body that stops flush against a face is put exactly on
+/// it, so rounding never sinks it in and lets it fall through next time.
+pub fn move_and_slide(body: &mut Aabb, mut delta: Vector, solids: &[Aabb]) -> Blocked {
+	let mut blocked = Blocked::default();
+	for _ in 0..MAX_SLIDES {
+		let hit = solids
+			.iter()
+			.filter_map(|s| sweep(*body, delta, *s).map(|c| (c, s)))
+			.fold(
+				None,
+				|first: Option<(Contact, &Aabb)>, (c, s)| match first {
+					Some((f, _)) if f.time <= c.time => first,
+					_ => Some((c, s)),
+				},
+			);
+		let Some((contact, solid)) = hit else {
+			break;
+		};
+		body.x += delta.x * contact.time;
+		body.y += delta.y * contact.time;
+		let rest = delta * (ONE - contact.time);
+		if contact.normal.x != ZERO {
+			body.x = if contact.normal.x < ZERO {
+				solid.x - body.w
+			} else {
+				solid.x + solid.w
+			};
+			blocked.x = true;
+			delta = vector(ZERO, rest.y);
+		} else {
+			body.y = if contact.normal.y < ZERO {
+				solid.y - body.h
+			} else {
+				solid.y + solid.h
+			};
+			blocked.y = true;
+			delta = vector(rest.x, ZERO);
+		}
+	}
+	body.x += delta.x;
+	body.y += delta.y;
+	blocked
+}
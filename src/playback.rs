@@ -0,0 +1,104 @@
+use crate::{
+	protocol::TickInputs,
+	sim::{self, PlayerInput, SimState},
+};
+
+// Ticks between keyframes, bounds how much a backwards seek resimulates
+pub const KEYFRAME_INTERVAL: u32 = 120;
+
+/// Deterministic playback over a growing authoritative input stream.
+///
+/// Keyframes are taken while stepping forward, so any tick that was
+/// simulated once can be revisited by restoring the closest keyframe.
+/// Ticks wrap, so positions are kept as offsets from the first tick and
+/// only turned back into ticks at the edges.
+pub struct Playback {
+	base_tick: u32,
+	// inputs[i] are the inputs of tick base_tick + i
+	inputs: Vec<Vec<PlayerInput>>,
+	// keyframes[k] is the state right before offset k * KEYFRAME_INTERVAL
+	keyframes: Vec<SimState>,
+	// Offset of the next tick to simulate, `state` is the state right before it
+	at: u32,
+	state: SimState,
+}
+
+impl Playback {
+	pub fn new(base_tick: u32, base_state: SimState) -> Self {
+		Self {
+			base_tick,
+			inputs: Vec::new(),
+			keyframes: vec![base_state.clone()],
+			at: 0,
+			state: base_state,
+		}
+	}
+
+	// Append the next authoritative tick, out of order ticks and ones of
+	// another player count are ignored
+	pub fn push(&mut self, t: &TickInputs) {
+		if t.tick.wrapping_sub(self.base_tick) != self.len()
+			|| t.inputs.len() != self.state.player_count()
+		{
+			return;
+		}
+		self.inputs.push(t.sim_inputs());
+	}
+
+	// Ticks with known inputs
+	fn len(&self) -> u32 {
+		self.inputs.len() as u32
+	}
+
+	// Offset of `tick` within the known range, ones before the first tick are
+	// at its start
+	fn offset(&self, tick: u32) -> u32 {
+		let offset = tick.wrapping_sub(self.base_tick);
+		if (offset as i32) < 0 {
+			0
+		} else {
+			offset.min(self.len())
+		}
+	}
+
+	// One past the last tick with known inputs
+	pub fn end_tick(&self) -> u32 {
+		self.base_tick.wrapping_add(self.len())
+	}
+
+	pub fn tick(&self) -> u32 {
+		self.base_tick.wrapping_add(self.at)
+	}
+
+	pub fn state(&self) -> &SimState {
+		&self.state
+	}
+
+	// Simulate one tick, false when waiting for more inputs
+	pub fn step(&mut self) -> bool {
+		let Some(inputs) = self.inputs.get(self.at as usize) else {
+			return false;
+		};
+		sim::step(&mut self.state, inputs);
+		self.at += 1;
+
+		if self.at.is_multiple_of(KEYFRAME_INTERVAL)
+			&& (self.at / KEYFRAME_INTERVAL) as usize == self.keyframes.len()
+		{
+			self.keyframes.push(self.state.clone());
+		}
+		true
+	}
+
+	// Jump to `target`, clamped to the available range
+	pub fn seek(&mut self, target: u32) {
+		let target = self.offset(target);
+		if target < self.at {
+			let k = (target / KEYFRAME_INTERVAL) as usize;
+			let k = k.min(self.keyframes.len() - 1);
+			self.state = self.keyframes[k].clone();
+			self.at = k as u32 * KEYFRAME_INTERVAL;
+		}
+		while self.at < target && self.step() {}
+	}
+}
@@ -1,32 +1,693 @@
+use std::fmt;
+
+use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
 
-pub const PLAYER_COUNT: usize = 2;
+pub use crate::sim::{DEFAULT_PLAYERS, MAX_PLAYERS};
+use crate::{
+	rollback::is_before,
+	sim::{PlayerInput, SimState},
+};
+
+pub const PROTOCOL_VERSION: u16 = 6;
+
+// Ticks per History message when catching a spectator up
+pub const HISTORY_CHUNK_TICKS: usize = 1024;
+
+// Longest frame either side sends, a History chunk at worst: every tick a
+// five byte varint, its player count and their inputs. Transports refuse a
+// longer length before allocating for it, a peer's word alone never sizes a
+// buffer
+pub const MAX_FRAME_BYTES: usize = 16 + HISTORY_CHUNK_TICKS * (5 + 1 + 2 * MAX_PLAYERS);
+
+// Oldest version a server still serves, clients before it get a Reject.
+// Raise it when a change to the frames leaves older peers unable to read them
+pub const MIN_PROTOCOL_VERSION: u16 = 6;
+
+// Input bits understood by each protocol version, starting at v1.
+// Append a mask and bump PROTOCOL_VERSION when InputBits or the frames change.
+const INPUT_MASKS: [u8; PROTOCOL_VERSION as usize] = [
+	0b0000_0111, // LEFT | RIGHT | JUMP
+	0b0000_1111, // + FIRE
+	0b0000_1111, // compact tick frames, Welcome::version, Reject
+	0b0001_1111, // + READY, a byte per input, Ruleset::warmup
+	0b0011_1111, // + ATTACK, Player::health and the attack's state
+	0b0011_1111, // player counts, tick inputs carry theirs
+];
+
+// Version both sides speak
+pub fn negotiate(client_version: u16) -> u16 {
+	client_version.min(PROTOCOL_VERSION)
+}
+
+// Bits a client on `version` may send, anything else would be misread
+pub fn input_mask(version: u16) -> u8 {
+	let v = version.clamp(1, PROTOCOL_VERSION);
+	INPUT_MASKS[(v - 1) as usize]
+}
+
+bitflags! {
+	// Optional protocol features. Both sides announce theirs in the handshake
+	// and only use the intersection, bits a peer doesn't know are dropped
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct Capabilities: u32 {
+		const SNAPSHOTS   = 1 << 0;
+		const COMPRESSION = 1 << 1;
+		const CHAT        = 1 << 2;
+		const UDP_UPGRADE = 1 << 3;
+		const INPUT_GRANTS = 1 << 4;
+		const STATE_HASHES = 1 << 5;
+		const MATCH_SETUP = 1 << 6;
+		// Not a feature but what the peer is: a spectator that only watches,
+		// gets the server's StateHashes and never takes over a player
+		const REFEREE = 1 << 7;
+		// A spectator that only watches and never takes over a player
+		const WATCHER = 1 << 8;
+	}
+}
+
+impl Capabilities {
+	// Features this build implements
+	pub const SUPPORTED: Self = Self::SNAPSHOTS
+		.union(Self::UDP_UPGRADE)
+		.union(Self::INPUT_GRANTS)
+		.union(Self::STATE_HASHES)
+		.union(Self::MATCH_SETUP);
+
+	// Announced only by the peers that are them
+	pub const ROLES: Self = Self::REFEREE.union(Self::WATCHER);
+
+	// What both we and a peer announcing `peer_bits` support, and its role
+	pub fn negotiate(peer_bits: u32) -> Self {
+		(Self::SUPPORTED | Self::ROLES) & Self::from_bits_truncate(peer_bits)
+	}
+
+	// Whether the peer is there to watch, it gets no player's slot
+	pub fn watches_only(self) -> bool {
+		self.intersects(Self::REFEREE | Self::WATCHER)
+	}
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Hello {
+	pub version: u16,
+	// Capabilities bits of the client
+	pub capabilities: u32,
+	// Token of a slot the server holds for this player
+	pub reservation: Option<u64>,
+	// sim::fingerprint() of the client's build
+	pub fingerprint: u64,
+	// Token of the slot the client had before its connection dropped, from
+	// AssignStart or Resume. The server gives the slot back
+	pub rejoin: Option<u64>,
+}
 
+// Server's answer to Hello, the negotiated Capabilities bits
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Welcome {
+	pub capabilities: u32,
+	pub fingerprint: u64,
+	// The server's PROTOCOL_VERSION
+	pub version: u16,
+}
+
+impl Welcome {
+	pub fn capabilities(&self) -> Capabilities {
+		Capabilities::from_bits_truncate(self.capabilities)
+	}
+}
+
+// Team of every player slot, teammates share a colour. There's one for every
+// player in the match
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Roster {
+	pub teams: Vec<u8>,
+}
+
+impl Roster {
+	// `players` players, everyone on their own team
+	pub fn new(players: usize) -> Self {
+		Self {
+			teams: (0..players as u8).collect(),
+		}
+	}
+
+	pub fn players(&self) -> usize {
+		self.teams.len()
+	}
+
+	// Whether a match of ours can have these teams: a count we play, and team
+	// ids below it
+	pub fn check(&self) -> anyhow::Result<()> {
+		let players = self.players();
+		anyhow::ensure!(
+			(1..=MAX_PLAYERS).contains(&players),
+			"{players} players, we play up to {MAX_PLAYERS}"
+		);
+		anyhow::ensure!(
+			self.teams.iter().all(|&t| (t as usize) < players),
+			"team ids {:?} aren't all below {players}",
+			self.teams
+		);
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AssignStart {
 	pub player_id: u8,
 	pub start_after_ms: u32,
+	// Proves slot ownership when reconnecting, see Hello::rejoin
+	pub token: u64,
+	// Players in the match, the roster has a team for each
+	pub players: u8,
+	pub roster: Roster,
+}
+
+// How a series is played. The score to win is the sim's own, it's here so a
+// client can tell it can't play these rules instead of desyncing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ruleset {
+	pub best_of: u8,
+	pub win_score: u32,
+	// Ticks after which the team ahead wins, played on until one is ahead on a tie
+	pub time_limit_ticks: Option<u32>,
+	// Every match starts with a warm-up, see SimState::warmup
+	pub warmup: bool,
+}
+
+impl Ruleset {
+	// Ticks of the time limit left at `tick`, the warm-up doesn't count
+	pub fn ticks_left(&self, tick: u32, state: &SimState) -> Option<u32> {
+		let limit = self.time_limit_ticks?;
+		Some((limit + state.warmup_ticks).saturating_sub(tick))
+	}
+}
+
+// What a client must agree with before a match starts, sent ahead of every
+// AssignStart, Resume and SpectateStart. The arena and its constants are all
+// in the sim fingerprint. Needs MATCH_SETUP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchSetup {
+	pub fingerprint: u64,
+	pub rules: Ruleset,
+	// Players in the match, at most MAX_PLAYERS
+	pub players: u8,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ResumeRequest {
+	pub token: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeState {
+	pub player_id: u8,
+	pub token: u64,
+	// `state` is the state right before `tick`, which starts after `start_after_ms`
+	pub tick: u32,
+	pub start_after_ms: u32,
+	pub state: SimState,
+	pub roster: Roster,
+}
+
+// Every player's input and aim for a tick, one each
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct TickInputs {
 	pub tick: u32,
-	pub inputs: [u8; PLAYER_COUNT],
+	pub inputs: Vec<u8>,
+	pub aims: Vec<u8>,
+}
+
+impl TickInputs {
+	// Neutral inputs of `players` players
+	pub fn neutral(tick: u32, players: usize) -> Self {
+		Self {
+			tick,
+			inputs: vec![0; players],
+			aims: vec![0; players],
+		}
+	}
+
+	pub fn sim_inputs(&self) -> Vec<PlayerInput> {
+		self.inputs
+			.iter()
+			.zip(&self.aims)
+			.map(|(&bits, &aim)| PlayerInput::new(bits, aim))
+			.collect()
+	}
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct InputMsg {
 	pub tick: u32,
 	pub bits: u8,
+	pub aim: u8,
+	// Latest server tick the client has received, lets the server estimate its lag
+	pub ack_tick: u32,
+	// Client wall clock at send time, µs
+	pub sent_us: u64,
+}
+
+// A message with the sender's wall clock at send time, µs
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Stamped<T> {
+	pub msg: T,
+	pub sent_us: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Ping {
+	pub client_us: u64,
+	// Client's current estimate of server minus client clock, lets the server
+	// turn InputMsg::sent_us into its own time
+	pub offset_us: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pong {
+	pub client_us: u64,
+	pub server_recv_us: u64,
+	pub server_send_us: u64,
+	// One-way Input latencies the server measured since the last Pong
+	pub input_latency_us: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputDelay {
+	pub delays: Vec<u8>,
+}
+
+// Ticks the server accepts this player's inputs for, inclusive. Clients stamp
+// inside the newest grant instead of estimating the server's tick themselves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputGrant {
+	pub from: u32,
+	pub to: u32,
+}
+
+impl InputGrant {
+	// The closest tick inside the grant
+	pub fn clamp(self, tick: u32) -> u32 {
+		if is_before(tick, self.from) {
+			self.from
+		} else if is_before(self.to, tick) {
+			self.to
+		} else {
+			tick
+		}
+	}
+}
+
+// Hands a player slot to a spectator following the match, it controls
+// `player_id` from `tick` on
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ControlChange {
+	pub player_id: u8,
+	pub token: u64,
+	pub tick: u32,
+}
+
+// Sent to spectators: `state` is the state right before `tick`, the first
+// tick of the input stream that follows
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpectateStart {
+	pub tick: u32,
+	pub state: SimState,
+	pub roster: Roster,
+}
+
+// State-sync baseline: `state` is the server's state right before `tick`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateSnapshot {
+	pub tick: u32,
+	pub state: SimState,
+}
+
+// Sent after every match of a series
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeriesState {
+	pub best_of: u8,
+	// Of every team
+	pub wins: Vec<u8>,
+	pub last_winner: u8,
+	pub finished: bool,
+}
+
+// Where a player's inputs may go instead of the TCP stream. Datagrams carry
+// `nonce` so the server can tell whose they are
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct UdpOffer {
+	pub port: u16,
+	pub nonce: u64,
+}
+
+// The only thing sent over UDP. Each carries the last few inputs so a lost
+// datagram costs nothing, one without inputs is a probe the server echoes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UdpDatagram {
+	pub nonce: u64,
+	pub inputs: Vec<InputMsg>,
+}
+
+// Sent to a player who has only sent neutral inputs for a while
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AfkWarning {
+	pub kick_in_ms: u32,
+}
+
+// sim::checksum of a client's confirmed state right before `tick`, for the
+// server to compare with its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateHash {
+	pub tick: u32,
+	pub hash: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum KickReason {
+	Afk,
+	// No longer sent, see Reject::Build. Kept so older servers' kicks still read
+	BuildMismatch,
+}
+
+/// Why a handshake failed. The server sends it in place of Welcome and hangs
+/// up, a client comes to the same conclusions about a server on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reject {
+	// The peer's protocol version is older than `min`, the oldest we speak
+	Version { peer: u16, min: u16 },
+	// The peer's build simulates differently, see sim::fingerprint
+	Build,
+	// The peer's first frame wasn't a handshake we can read, or never came
+	Unreadable,
+	// A Standby whose token is no slot's in the match
+	NoSlot,
+}
+
+impl fmt::Display for Reject {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Version { peer, min } => {
+				write!(f, "protocol version {peer} is too old, {min} at least")
+			}
+			Self::Build => write!(f, "the builds simulate differently"),
+			Self::Unreadable => write!(f, "no handshake we can read, a different build?"),
+			Self::NoSlot => write!(f, "no slot in the match for that token"),
+		}
+	}
+}
+
+// A player's signature over their own authoritative inputs for
+// ticks [start_tick, start_tick + len), ed25519, 64 bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputSignature {
+	pub start_tick: u32,
+	pub len: u32,
+	pub signature: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum C2S {
-	Input(InputMsg),
+	Hello(Hello),
+	// The newest input last, after the few before it again
+	Input(Vec<InputMsg>),
+	// No longer sent, Hello::rejoin carries the token. Kept so the variants after it
+	// keep their tags
+	Resume(ResumeRequest),
+	// ed25519 public key for the InputSignatures that follow
+	SigningKey([u8; 32]),
+	InputSignature(InputSignature),
+	Ping(Ping),
+	// Ask for StateSnapshots, for clients that interpolate instead of rolling back
+	SubscribeSnapshots,
+	// Ask to send inputs over UDP, needs UDP_UPGRADE
+	UdpUpgrade,
+	// Answer to an RttProbe, same sequence number
+	RttEcho(u32),
+	// Needs STATE_HASHES
+	StateHash(StateHash),
+	// Right after Hello: the room to play in on a lobby server, none pairs with
+	// whoever is waiting. Servers without a lobby ignore it
+	JoinRoom(Option<String>),
+	// Instead of Hello: a second connection for the slot Hello::rejoin names,
+	// held open until it sends Failover. Older servers refuse it as unreadable
+	Standby(Hello),
+	// On a standby: the slot's connection is gone, it takes over from here
+	Failover,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum S2C {
 	AssignStart(AssignStart),
-	TickInputs(TickInputs),
+	TickInputs(Stamped<TickInputs>),
+	InputDelay(InputDelay),
+	Resume(ResumeState),
+	SpectateStart(SpectateStart),
+	// Catch-up batch of past ticks for a spectator joining mid-match
+	History(Vec<TickInputs>),
+	Series(SeriesState),
+	AfkWarning(AfkWarning),
+	Kicked(KickReason),
+	Pong(Pong),
+	Snapshot(StateSnapshot),
+	Control(ControlChange),
+	Welcome(Welcome),
+	UdpOffer(UdpOffer),
+	// Round trip measurement while matchmaking, answered with RttEcho
+	RttProbe(u32),
+	// No opponent close enough yet, the match starts once one connects
+	Searching,
+	InputGrant(InputGrant),
+	// A StateHash didn't match the server's state at its tick
+	DesyncDetected(u32),
+	MatchSetup(MatchSetup),
+	// Instead of Welcome, the connection closes after it
+	Reject(Reject),
+	// The server's state right before a tick, every STATE_HASH_INTERVAL_TICKS.
+	// Only to a REFEREE
+	StateHash(StateHash),
+}
+
+// Tick traffic has a compact encoding of its own, everything else is bincode.
+// A bincode frame starts with its variant's u32 tag, so a first byte of
+// COMPACT never is one
+const COMPACT: u8 = 0xFF;
+const COMPACT_TICK_INPUTS: u8 = 0;
+const COMPACT_HISTORY: u8 = 1;
+
+/// How a message goes into a frame and comes back out of one.
+pub trait Frame: Sized {
+	fn encode(&self) -> anyhow::Result<Vec<u8>>;
+	fn decode(frame: &[u8]) -> anyhow::Result<Self>;
+
+	// The variant's name, for telling traffic apart in stats
+	fn kind(&self) -> &'static str;
+}
+
+impl Frame for S2C {
+	fn encode(&self) -> anyhow::Result<Vec<u8>> {
+		let mut out = vec![COMPACT];
+		match self {
+			Self::TickInputs(t) => {
+				out.push(COMPACT_TICK_INPUTS);
+				put_varint(&mut out, t.msg.tick as u64);
+				put_tick_inputs(&mut out, &t.msg);
+				out.extend_from_slice(&t.sent_us.to_le_bytes());
+			}
+			// Ticks as deltas, a byte each when consecutive
+			Self::History(h) => {
+				out.push(COMPACT_HISTORY);
+				put_varint(&mut out, h.len() as u64);
+				let mut prev = 0;
+				for t in h {
+					put_varint(&mut out, zigzag(t.tick as i64 - prev as i64));
+					put_tick_inputs(&mut out, t);
+					prev = t.tick;
+				}
+			}
+			_ => return Ok(bincode::serialize(self)?),
+		}
+		Ok(out)
+	}
+
+	fn decode(frame: &[u8]) -> anyhow::Result<Self> {
+		let Some((&COMPACT, mut r)) = frame.split_first() else {
+			return Ok(bincode::deserialize(frame)?);
+		};
+		let msg = match take(&mut r, 1)?[0] {
+			COMPACT_TICK_INPUTS => {
+				let tick = take_varint(&mut r)? as u32;
+				let msg = take_tick_inputs(&mut r, tick)?;
+				let sent_us = u64::from_le_bytes(take(&mut r, 8)?.try_into()?);
+				Self::TickInputs(Stamped { msg, sent_us })
+			}
+			COMPACT_HISTORY => {
+				let len = take_varint(&mut r)? as usize;
+				// Every tick takes a few bytes, a longer count is a lie
+				anyhow::ensure!(
+					len <= r.len(),
+					"history of {len} ticks in {} bytes",
+					r.len()
+				);
+				let mut prev = 0u32;
+				let mut h = Vec::with_capacity(len);
+				for _ in 0..len {
+					let tick = (prev as i64 + unzigzag(take_varint(&mut r)?)) as u32;
+					h.push(take_tick_inputs(&mut r, tick)?);
+					prev = tick;
+				}
+				Self::History(h)
+			}
+			kind => anyhow::bail!("unknown compact frame {kind}"),
+		};
+		anyhow::ensure!(r.is_empty(), "{} bytes after the message", r.len());
+		Ok(msg)
+	}
+
+	fn kind(&self) -> &'static str {
+		match self {
+			Self::AssignStart(_) => "AssignStart",
+			Self::TickInputs(_) => "TickInputs",
+			Self::InputDelay(_) => "InputDelay",
+			Self::Resume(_) => "Resume",
+			Self::SpectateStart(_) => "SpectateStart",
+			Self::History(_) => "History",
+			Self::Series(_) => "Series",
+			Self::AfkWarning(_) => "AfkWarning",
+			Self::Kicked(_) => "Kicked",
+			Self::Pong(_) => "Pong",
+			Self::Snapshot(_) => "Snapshot",
+			Self::Control(_) => "Control",
+			Self::Welcome(_) => "Welcome",
+			Self::UdpOffer(_) => "UdpOffer",
+			Self::RttProbe(_) => "RttProbe",
+			Self::Searching => "Searching",
+			Self::InputGrant(_) => "InputGrant",
+			Self::DesyncDetected(_) => "DesyncDetected",
+			Self::MatchSetup(_) => "MatchSetup",
+			Self::Reject(_) => "Reject",
+			Self::StateHash(_) => "StateHash",
+		}
+	}
+}
+
+impl Frame for C2S {
+	fn encode(&self) -> anyhow::Result<Vec<u8>> {
+		let Self::Input(inputs) = self else {
+			return Ok(bincode::serialize(self)?);
+		};
+		// Ticks and clocks as deltas from the input before, the first from 0
+		let mut out = vec![COMPACT];
+		out.push(u8::try_from(inputs.len())?);
+		out.extend(inputs.iter().map(|i| i.bits));
+		let (mut tick, mut sent_us) = (0u32, 0u64);
+		for i in inputs {
+			put_varint(&mut out, zigzag(i.tick as i64 - tick as i64));
+			out.push(i.aim);
+			put_varint(&mut out, zigzag(i.tick as i64 - i.ack_tick as i64));
+			put_varint(&mut out, zigzag(i.sent_us.wrapping_sub(sent_us) as i64));
+			(tick, sent_us) = (i.tick, i.sent_us);
+		}
+		Ok(out)
+	}
+
+	fn decode(frame: &[u8]) -> anyhow::Result<Self> {
+		let Some((&COMPACT, mut r)) = frame.split_first() else {
+			return Ok(bincode::deserialize(frame)?);
+		};
+		let len = take(&mut r, 1)?[0] as usize;
+		let bits = take(&mut r, len)?;
+		let (mut tick, mut sent_us) = (0u32, 0u64);
+		let mut inputs = Vec::with_capacity(len);
+		for &bits in bits {
+			tick = (tick as i64 + unzigzag(take_varint(&mut r)?)) as u32;
+			let aim = take(&mut r, 1)?[0];
+			let ack_tick = (tick as i64 - unzigzag(take_varint(&mut r)?)) as u32;
+			sent_us = sent_us.wrapping_add(unzigzag(take_varint(&mut r)?) as u64);
+			inputs.push(InputMsg {
+				tick,
+				bits,
+				aim,
+				ack_tick,
+				sent_us,
+			});
+		}
+		anyhow::ensure!(r.is_empty(), "{} bytes after the inputs", r.len());
+		Ok(Self::Input(inputs))
+	}
+
+	fn kind(&self) -> &'static str {
+		match self {
+			Self::Hello(_) => "Hello",
+			Self::Input(_) => "Input",
+			Self::Resume(_) => "Resume",
+			Self::SigningKey(_) => "SigningKey",
+			Self::InputSignature(_) => "InputSignature",
+			Self::Ping(_) => "Ping",
+			Self::SubscribeSnapshots => "SubscribeSnapshots",
+			Self::UdpUpgrade => "UdpUpgrade",
+			Self::RttEcho(_) => "RttEcho",
+			Self::StateHash(_) => "StateHash",
+			Self::JoinRoom(_) => "JoinRoom",
+			Self::Standby(_) => "Standby",
+			Self::Failover => "Failover",
+		}
+	}
+}
+
+fn zigzag(v: i64) -> u64 {
+	((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+	(v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+// LEB128, seven bits a byte, low first
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+	while v >= 0x80 {
+		out.push(v as u8 | 0x80);
+		v >>= 7;
+	}
+	out.push(v as u8);
+}
+
+fn take_varint(r: &mut &[u8]) -> anyhow::Result<u64> {
+	let mut v = 0u64;
+	for shift in (0..64).step_by(7) {
+		let b = take(r, 1)?[0];
+		v |= u64::from(b & 0x7F) << shift;
+		if b & 0x80 == 0 {
+			return Ok(v);
+		}
+	}
+	anyhow::bail!("varint longer than 64 bits")
+}
+
+fn take<'a>(r: &mut &'a [u8], n: usize) -> anyhow::Result<&'a [u8]> {
+	anyhow::ensure!(r.len() >= n, "frame ends early");
+	let (head, rest) = r.split_at(n);
+	*r = rest;
+	Ok(head)
+}
+
+// The player count first, then every input and every aim
+fn put_tick_inputs(out: &mut Vec<u8>, t: &TickInputs) {
+	out.push(t.inputs.len() as u8);
+	out.extend_from_slice(&t.inputs);
+	out.extend_from_slice(&t.aims);
+}
+
+fn take_tick_inputs(r: &mut &[u8], tick: u32) -> anyhow::Result<TickInputs> {
+	let players = take(r, 1)?[0] as usize;
+	anyhow::ensure!(
+		players <= MAX_PLAYERS,
+		"inputs of {players} players, {MAX_PLAYERS} at most"
+	);
+	Ok(TickInputs {
+		tick,
+		inputs: take(r, players)?.to_vec(),
+		aims: take(r, players)?.to_vec(),
+	})
 }
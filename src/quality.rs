@@ -0,0 +1,164 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::clock::Instant;
+
+// One timeline entry per this much session time
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Names follow what rollback libraries usually report, so sessions can be compared
+#[derive(Serialize)]
+struct Sample {
+	t_secs: f32,
+	// Newest round trip, none before the first pong
+	ping_ms: Option<f32>,
+	rollback_frames: u32,
+	// Local tick minus the estimated server tick at the end of the second
+	frames_ahead: i64,
+	// Mean time past its tick's start that the second's local inputs were
+	// sampled at, see QualityReport::sampled. None while spectating
+	sample_offset_ms: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct Summary {
+	duration_secs: f32,
+	ping_ms_mean: Option<f32>,
+	ping_ms_min: Option<f32>,
+	ping_ms_max: Option<f32>,
+	// Mean difference between consecutive round trips
+	jitter_ms: Option<f32>,
+	rollbacks: u32,
+	rollback_frames_per_sec: f32,
+	max_rollback_frames: u32,
+	frames_ahead_mean: f32,
+	sample_offset_ms_mean: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+	summary: Summary,
+	timeline: &'a [Sample],
+}
+
+/// Network quality over a client session, written as JSON. The file is
+/// rewritten every second, closing the window is how a session ends.
+pub struct QualityReport {
+	path: PathBuf,
+	started: Instant,
+	last_sample: Instant,
+	timeline: Vec<Sample>,
+	pings_ms: Vec<f32>,
+	rollbacks: u32,
+	total_rollback_frames: u32,
+	max_rollback_frames: u32,
+	// Sum and count of input sample offsets over the session
+	sample_offset_total_us: i64,
+	sample_offsets: u64,
+	// Since the last sample
+	rollback_frames: u32,
+	sample_offset_us: i64,
+	samples: u32,
+}
+
+impl QualityReport {
+	pub fn new(path: PathBuf) -> Self {
+		let now = Instant::now();
+		Self {
+			path,
+			started: now,
+			last_sample: now,
+			timeline: Vec::new(),
+			pings_ms: Vec::new(),
+			rollbacks: 0,
+			total_rollback_frames: 0,
+			max_rollback_frames: 0,
+			sample_offset_total_us: 0,
+			sample_offsets: 0,
+			rollback_frames: 0,
+			sample_offset_us: 0,
+			samples: 0,
+		}
+	}
+
+	pub fn ping(&mut self, rtt_us: i64) {
+		self.pings_ms.push(rtt_us as f32 / 1000.0);
+	}
+
+	pub fn rollback(&mut self, frames: u32) {
+		self.rollbacks += 1;
+		self.rollback_frames += frames;
+		self.total_rollback_frames += frames;
+		self.max_rollback_frames = self.max_rollback_frames.max(frames);
+	}
+
+	/// A local input was sampled `offset_us` after the start of the tick it's
+	/// for, on the timeline the client runs ahead of the server on. Clients with
+	/// the same ping that sample later consistently react to fresher input, a
+	/// fairness factor no other number here shows.
+	pub fn sampled(&mut self, offset_us: i32) {
+		self.sample_offset_us += offset_us as i64;
+		self.samples += 1;
+		self.sample_offset_total_us += offset_us as i64;
+		self.sample_offsets += 1;
+	}
+
+	// Called every frame, samples and rewrites the file once a second
+	pub fn update(&mut self, frames_ahead: i64) -> anyhow::Result<()> {
+		if self.last_sample.elapsed() < SAMPLE_INTERVAL {
+			return Ok(());
+		}
+		self.last_sample = Instant::now();
+		self.timeline.push(Sample {
+			t_secs: self.started.elapsed().as_secs_f32(),
+			ping_ms: self.pings_ms.last().copied(),
+			rollback_frames: std::mem::take(&mut self.rollback_frames),
+			frames_ahead,
+			sample_offset_ms: mean_ms(
+				std::mem::take(&mut self.sample_offset_us),
+				std::mem::take(&mut self.samples) as u64,
+			),
+		});
+		self.write()
+	}
+
+	fn summary(&self) -> Summary {
+		let duration_secs = self.started.elapsed().as_secs_f32();
+		let n = self.pings_ms.len() as f32;
+		let jitter_ms = (self.pings_ms.len() > 1).then(|| {
+			let diffs: f32 = self.pings_ms.windows(2).map(|w| (w[1] - w[0]).abs()).sum();
+			diffs / (n - 1.0)
+		});
+		let ahead: i64 = self.timeline.iter().map(|s| s.frames_ahead).sum();
+		Summary {
+			duration_secs,
+			ping_ms_mean: (n > 0.0).then(|| self.pings_ms.iter().sum::<f32>() / n),
+			ping_ms_min: self.pings_ms.iter().copied().reduce(f32::min),
+			ping_ms_max: self.pings_ms.iter().copied().reduce(f32::max),
+			jitter_ms,
+			rollbacks: self.rollbacks,
+			rollback_frames_per_sec: self.total_rollback_frames as f32 / duration_secs.max(1.0),
+			max_rollback_frames: self.max_rollback_frames,
+			frames_ahead_mean: ahead as f32 / self.timeline.len().max(1) as f32,
+			sample_offset_ms_mean: mean_ms(self.sample_offset_total_us, self.sample_offsets),
+		}
+	}
+
+	fn write(&self) -> anyhow::Result<()> {
+		let report = Report {
+			summary: self.summary(),
+			timeline: &self.timeline,
+		};
+		// Write then rename so a window closed mid-write leaves the previous report
+		let tmp = self.path.with_extension("tmp");
+		fs::write(&tmp, serde_json::to_string_pretty(&report)?).context("write quality report")?;
+		fs::rename(&tmp, &self.path).context("replace quality report")?;
+		Ok(())
+	}
+}
+
+fn mean_ms(total_us: i64, n: u64) -> Option<f32> {
+	(n > 0).then(|| total_us as f32 / n as f32 / 1000.0)
+}
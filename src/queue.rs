@@ -0,0 +1,209 @@
+use std::{
+	collections::VecDeque,
+	mem,
+	sync::{
+		Arc, Condvar, Mutex,
+		mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError},
+	},
+	time::Duration,
+};
+
+use crate::stats::{self, Counter, Gauge};
+
+use crate::clock::Instant;
+
+// Messages one slot holds at most, a longer run blocks like any other message
+const COALESCE_MAX: usize = 64;
+
+/// What a full queue does with one more message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+	// Waits for a free slot, for what can't be lost
+	Block,
+	// Shares the last slot if the run queued in it is of the same kind, waits
+	// for a free slot otherwise
+	Coalesce,
+	// Takes the place of the oldest queued message of its kind, or is dropped
+	// when none is queued. Never waits
+	DropOldest,
+}
+
+pub trait Overflow {
+	fn overflow(&self) -> Policy;
+}
+
+/// Where a queue reports in stats.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+	pub depth: &'static str,
+	pub dropped: &'static str,
+	pub coalesced: &'static str,
+}
+
+struct State<T> {
+	// Oldest first, a slot holds one message or a run of one kind
+	slots: VecDeque<VecDeque<T>>,
+	senders: usize,
+	receiver: bool,
+}
+
+struct Shared<T> {
+	state: Mutex<State<T>>,
+	// Signalled on every push and pop, and when either side goes away
+	changed: Condvar,
+	capacity: usize,
+	// Queued messages, summed over every queue reporting under the name
+	depth: &'static Gauge,
+	dropped: &'static Counter,
+	coalesced: &'static Counter,
+}
+
+fn same_kind<T>(a: &T, b: &T) -> bool {
+	mem::discriminant(a) == mem::discriminant(b)
+}
+
+/// A channel between an IO thread and a loop that holds `capacity` slots, for
+/// when the loop stalls. What happens past that is up to each message.
+pub fn bounded<T: Overflow>(capacity: usize, stats: Stats) -> (Sender<T>, Receiver<T>) {
+	let shared = Arc::new(Shared {
+		state: Mutex::new(State {
+			slots: VecDeque::new(),
+			senders: 1,
+			receiver: true,
+		}),
+		changed: Condvar::new(),
+		capacity: capacity.max(1),
+		depth: stats::gauge(stats.depth),
+		dropped: stats::counter(stats.dropped),
+		coalesced: stats::counter(stats.coalesced),
+	});
+	(Sender(shared.clone()), Receiver(shared))
+}
+
+pub struct Sender<T>(Arc<Shared<T>>);
+
+impl<T: Overflow> Sender<T> {
+	pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+		let shared = &*self.0;
+		let mut state = shared.state.lock().unwrap();
+		loop {
+			if !state.receiver {
+				return Err(SendError(msg));
+			}
+			if state.slots.len() < shared.capacity {
+				state.slots.push_back(VecDeque::from([msg]));
+				break;
+			}
+			match msg.overflow() {
+				Policy::Block => {}
+				Policy::Coalesce => {
+					if let Some(run) = state.slots.back_mut()
+						&& run.len() < COALESCE_MAX
+						&& run.front().is_some_and(|m| same_kind(m, &msg))
+					{
+						run.push_back(msg);
+						shared.coalesced.inc();
+						break;
+					}
+				}
+				Policy::DropOldest => {
+					let oldest = state
+						.slots
+						.iter()
+						.position(|s| s.front().is_some_and(|m| same_kind(m, &msg)));
+					if let Some(i) = oldest {
+						state.slots.remove(i);
+						state.slots.push_back(VecDeque::from([msg]));
+						shared.changed.notify_all();
+					}
+					shared.dropped.inc();
+					return Ok(());
+				}
+			}
+			state = shared.changed.wait(state).unwrap();
+		}
+		shared.depth.add(1);
+		shared.changed.notify_all();
+		Ok(())
+	}
+}
+
+impl<T> Clone for Sender<T> {
+	fn clone(&self) -> Self {
+		self.0.state.lock().unwrap().senders += 1;
+		Self(self.0.clone())
+	}
+}
+
+impl<T> Drop for Sender<T> {
+	fn drop(&mut self) {
+		self.0.state.lock().unwrap().senders -= 1;
+		self.0.changed.notify_all();
+	}
+}
+
+pub struct Receiver<T>(Arc<Shared<T>>);
+
+impl<T> Receiver<T> {
+	// The oldest message, None when nothing is queued
+	fn pop(&self, state: &mut State<T>) -> Option<T> {
+		let run = state.slots.front_mut()?;
+		let msg = run.pop_front();
+		if run.is_empty() {
+			state.slots.pop_front();
+		}
+		self.0.depth.add(-1);
+		self.0.changed.notify_all();
+		msg
+	}
+
+	pub fn try_recv(&self) -> Result<T, TryRecvError> {
+		let mut state = self.0.state.lock().unwrap();
+		match self.pop(&mut state) {
+			Some(msg) => Ok(msg),
+			None if state.senders == 0 => Err(TryRecvError::Disconnected),
+			None => Err(TryRecvError::Empty),
+		}
+	}
+
+	pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+		let deadline = Instant::now() + timeout;
+		let mut state = self.0.state.lock().unwrap();
+		loop {
+			if let Some(msg) = self.pop(&mut state) {
+				return Ok(msg);
+			}
+			if state.senders == 0 {
+				return Err(RecvTimeoutError::Disconnected);
+			}
+			let left = deadline.saturating_duration_since(Instant::now());
+			if left.is_zero() {
+				return Err(RecvTimeoutError::Timeout);
+			}
+			state = self.0.changed.wait_timeout(state, left).unwrap().0;
+		}
+	}
+
+	pub fn recv(&self) -> Result<T, RecvError> {
+		let mut state = self.0.state.lock().unwrap();
+		loop {
+			if let Some(msg) = self.pop(&mut state) {
+				return Ok(msg);
+			}
+			if state.senders == 0 {
+				return Err(RecvError);
+			}
+			state = self.0.changed.wait(state).unwrap();
+		}
+	}
+}
+
+impl<T> Drop for Receiver<T> {
+	fn drop(&mut self) {
+		let mut state = self.0.state.lock().unwrap();
+		state.receiver = false;
+		let queued: usize = state.slots.drain(..).map(|s| s.len()).sum();
+		self.0.depth.add(-(queued as i64));
+		self.0.changed.notify_all();
+	}
+}
@@ -0,0 +1,180 @@
+use std::{
+	collections::BTreeMap,
+	sync::mpsc::RecvTimeoutError,
+	time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+use macroquad::prelude::warn;
+
+use crate::{
+	net::{self, NetEvent, STATE_HASH_INTERVAL_TICKS},
+	protocol::{Capabilities, TickInputs},
+	sim::{self, SimState},
+	sockopt::SocketOptions,
+};
+
+// How often the progress line is printed
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Checksums waiting for the other side's of the same tick, the oldest past this
+// many are given up on
+const HASHES_KEPT: usize = 32;
+
+// The current match as the referee simulates it
+struct Match {
+	// Next tick to simulate, `state` is the state right before it
+	tick: u32,
+	state: SimState,
+	// Checksums by tick, ours as the simulation passes them and the server's as
+	// they come in
+	ours: BTreeMap<u32, u64>,
+	theirs: BTreeMap<u32, u64>,
+}
+
+impl Match {
+	fn new(tick: u32, state: SimState) -> Self {
+		Self {
+			tick,
+			state,
+			ours: BTreeMap::new(),
+			theirs: BTreeMap::new(),
+		}
+	}
+
+	// Simulates the next tick of the stream, ticks already simulated are skipped
+	fn push(&mut self, t: &TickInputs) -> anyhow::Result<()> {
+		if t.tick < self.tick {
+			return Ok(());
+		}
+		if t.tick != self.tick {
+			bail!("the stream skipped from tick {} to {}", self.tick, t.tick);
+		}
+		if self.tick.is_multiple_of(STATE_HASH_INTERVAL_TICKS) {
+			self.ours.insert(self.tick, sim::checksum(&self.state));
+		}
+		sim::step(&mut self.state, &t.sim_inputs());
+		self.tick += 1;
+		Ok(())
+	}
+
+	// Compares the checksums both sides have for a tick, returns how many agreed
+	// and the ticks where they didn't
+	fn settle(&mut self) -> (u64, Vec<u32>) {
+		let mut agreed = 0;
+		let mut differed = Vec::new();
+		let both: Vec<u32> = self
+			.ours
+			.keys()
+			.filter(|t| self.theirs.contains_key(t))
+			.copied()
+			.collect();
+		for tick in both {
+			let ours = self.ours.remove(&tick);
+			let theirs = self.theirs.remove(&tick);
+			if ours == theirs {
+				agreed += 1;
+			} else {
+				differed.push(tick);
+			}
+		}
+		for hashes in [&mut self.ours, &mut self.theirs] {
+			while hashes.len() > HASHES_KEPT {
+				hashes.pop_first();
+			}
+		}
+		(agreed, differed)
+	}
+}
+
+/// An observer bot, a third party to a match for tournaments and tests: it
+/// spectates `addr`, in `room` on a lobby server, simulates the match itself
+/// from the authoritative inputs, and checks the checksums the server sends a
+/// referee every STATE_HASH_INTERVAL_TICKS against its own. Runs until the
+/// server hangs up, and fails if any checksum differed or none could be
+/// checked.
+pub fn run_referee(addr: &str, room: Option<String>, socket: SocketOptions) -> anyhow::Result<()> {
+	let (rx_evt, _tx_cmd) =
+		net::spawn_referee(addr.to_string(), room, socket).context("spawn_referee")?;
+
+	let mut game: Option<Match> = None;
+	let mut matches = 0u32;
+	let mut agreed = 0u64;
+	let mut differed: Vec<u32> = Vec::new();
+	let mut reported_at = Instant::now();
+	loop {
+		match rx_evt.recv_timeout(REPORT_INTERVAL) {
+			Ok(NetEvent::Welcome(caps)) if !caps.contains(Capabilities::REFEREE) => {
+				bail!("{addr} doesn't send referees its checksums");
+			}
+			Ok(NetEvent::AssignStart(_) | NetEvent::Control(_) | NetEvent::Resume(_)) => {
+				bail!("{addr} made the referee a player");
+			}
+			Ok(NetEvent::SpectateStart(s)) => {
+				game = Some(Match::new(s.tick, s.state));
+				matches += 1;
+			}
+			Ok(NetEvent::History(h)) => {
+				if let Some(g) = game.as_mut() {
+					h.iter().try_for_each(|t| g.push(t))?;
+				}
+			}
+			Ok(NetEvent::TickInputs(t)) => {
+				if let Some(g) = game.as_mut() {
+					g.push(&t.msg)?;
+				}
+			}
+			Ok(NetEvent::StateHash(h)) => {
+				if let Some(g) = game.as_mut() {
+					g.theirs.insert(h.tick, h.hash);
+				}
+			}
+			Ok(NetEvent::Series(s)) => {
+				let wins: Vec<_> = s.wins.iter().map(ToString::to_string).collect();
+				let wins = wins.join("-");
+				println!(
+					"match {matches} won by team {}, series {wins}",
+					s.last_winner
+				);
+			}
+			Ok(NetEvent::Rejected(r)) => bail!("refused, {r}"),
+			Ok(NetEvent::Disconnected) | Err(RecvTimeoutError::Disconnected) => break,
+			Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+		}
+
+		if let Some(g) = game.as_mut() {
+			let (a, d) = g.settle();
+			agreed += a;
+			for tick in d {
+				warn!(
+					"match {}: the server's state at tick {} differs from ours",
+					matches, tick
+				);
+				differed.push(tick);
+			}
+		}
+		if reported_at.elapsed() >= REPORT_INTERVAL {
+			reported_at = Instant::now();
+			println!(
+				"match {matches} tick {}, {agreed} checksums agree, {} differ",
+				game.as_ref().map_or(0, |g| g.tick),
+				differed.len()
+			);
+		}
+	}
+
+	println!(
+		"referee: {matches} matches, {agreed} checksums agree, {} differ",
+		differed.len()
+	);
+	if let Some(first) = differed.first() {
+		bail!(
+			"the server's state differed {} times, first at tick {first}",
+			differed.len()
+		);
+	}
+	if agreed == 0 {
+		bail!("no checksum of the server's could be checked");
+	}
+	Ok(())
+}
@@ -0,0 +1,307 @@
+use std::{
+	collections::HashMap,
+	fs::File,
+	io::{BufWriter, Cursor, Write},
+	path::Path,
+};
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	protocol::{DEFAULT_PLAYERS, InputSignature, Roster, TickInputs},
+	sim::SimState,
+};
+
+const MAGIC: [u8; 4] = *b"RPLY";
+
+/// Current replay format version.
+///
+/// Bump it whenever the recorded tick format or input meaning changes, and
+/// teach `upgrade` how to turn the previous version into the new one so
+/// old files stay playable.
+///
+/// v1: bare TickInputs records
+/// v2: tagged records, adds players' signing keys and input signatures
+/// v3: TickInputs carry each player's aim, signatures cover it (v2 ones no longer verify)
+/// v4: adds the team roster, older matches had everyone on their own team
+/// v5: adds whether matches start with a warm-up, older ones never did
+/// v6: ticks and the roster carry the match's player count, older matches had two
+pub const REPLAY_VERSION: u16 = 6;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Record {
+	Tick(TickInputs),
+	SigningKey { player: u8, key: [u8; 32] },
+	Signature { player: u8, sig: InputSignature },
+	Roster(Roster),
+	Warmup(bool),
+}
+
+/// Authoritative input stream of a match, in tick order.
+#[derive(Debug, Clone)]
+pub struct Replay {
+	pub ticks: Vec<TickInputs>,
+	pub keys: HashMap<u8, [u8; 32]>,
+	pub signatures: Vec<(u8, InputSignature)>,
+	pub roster: Roster,
+	// Every match starts with a warm-up, see SimState::warmup
+	pub warmup: bool,
+}
+
+impl Replay {
+	/// Read a replay of any known version, upgrading it to the current one.
+	/// Also returns the version found in the file.
+	pub fn read(path: &Path) -> anyhow::Result<(Self, u16)> {
+		let bytes = std::fs::read(path).context("read replay")?;
+		if bytes.len() < 6 || bytes[..4] != MAGIC {
+			bail!("not a replay file");
+		}
+		let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+		let mut body = Cursor::new(&bytes[6..]);
+		let mut replay = Self {
+			ticks: Vec::new(),
+			keys: HashMap::new(),
+			signatures: Vec::new(),
+			roster: Roster::new(DEFAULT_PLAYERS),
+			warmup: false,
+		};
+		while (body.position() as usize) < body.get_ref().len() {
+			match upgrade(version, &mut body)? {
+				Record::Tick(t) => replay.ticks.push(t),
+				Record::SigningKey { player, key } => {
+					replay.keys.insert(player, key);
+				}
+				Record::Signature { player, sig } => replay.signatures.push((player, sig)),
+				Record::Roster(r) => replay.roster = r,
+				Record::Warmup(w) => replay.warmup = w,
+			}
+		}
+		Ok((replay, version))
+	}
+
+	// The state every match starts from
+	pub fn start_state(&self) -> SimState {
+		SimState::with_teams(self.roster.teams.clone()).warming_up(self.warmup)
+	}
+
+	/// Ticks of each match in the file. Every match of a series restarts at
+	/// tick 0, whatever comes before the first start (a resumed match) is left out.
+	pub fn matches(&self) -> Vec<&[TickInputs]> {
+		let starts: Vec<usize> = self
+			.ticks
+			.iter()
+			.enumerate()
+			.filter(|(_, t)| t.tick == 0)
+			.map(|(i, _)| i)
+			.collect();
+		starts
+			.iter()
+			.enumerate()
+			.map(|(n, &s)| {
+				let end = starts.get(n + 1).copied().unwrap_or(self.ticks.len());
+				&self.ticks[s..end]
+			})
+			.collect()
+	}
+
+	pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+		let mut w = ReplayWriter::create(path)?;
+		w.write_roster(&self.roster)?;
+		w.write_warmup(self.warmup)?;
+		for (&player, &key) in &self.keys {
+			w.write_signing_key(player, key)?;
+		}
+		for t in &self.ticks {
+			w.write_tick(t)?;
+		}
+		for (player, sig) in &self.signatures {
+			w.write_signature(*player, sig.clone())?;
+		}
+		w.flush()
+	}
+}
+
+// Record layouts of older versions, only ever read
+mod old {
+	use serde::Deserialize;
+
+	use super::Record;
+	use crate::protocol::{InputSignature, Roster, TickInputs};
+
+	// Every match had two players before v6
+	const PLAYERS: usize = 2;
+
+	#[derive(Deserialize)]
+	pub struct TickInputsV2 {
+		tick: u32,
+		inputs: [u8; PLAYERS],
+	}
+
+	impl From<TickInputsV2> for TickInputs {
+		fn from(t: TickInputsV2) -> Self {
+			Self {
+				tick: t.tick,
+				inputs: t.inputs.to_vec(),
+				aims: vec![0; PLAYERS],
+			}
+		}
+	}
+
+	#[derive(Deserialize)]
+	pub struct TickInputsV5 {
+		tick: u32,
+		inputs: [u8; PLAYERS],
+		aims: [u8; PLAYERS],
+	}
+
+	#[derive(Deserialize)]
+	pub enum RecordV5 {
+		Tick(TickInputsV5),
+		SigningKey { player: u8, key: [u8; 32] },
+		Signature { player: u8, sig: InputSignature },
+		Roster { teams: [u8; PLAYERS] },
+		Warmup(bool),
+	}
+
+	impl From<RecordV5> for Record {
+		fn from(r: RecordV5) -> Self {
+			match r {
+				RecordV5::Tick(t) => Self::Tick(TickInputs {
+					tick: t.tick,
+					inputs: t.inputs.to_vec(),
+					aims: t.aims.to_vec(),
+				}),
+				RecordV5::SigningKey { player, key } => Self::SigningKey { player, key },
+				RecordV5::Signature { player, sig } => Self::Signature { player, sig },
+				RecordV5::Roster { teams } => Self::Roster(Roster {
+					teams: teams.to_vec(),
+				}),
+				RecordV5::Warmup(w) => Self::Warmup(w),
+			}
+		}
+	}
+
+	#[derive(Deserialize)]
+	pub enum RecordV2 {
+		Tick(TickInputsV2),
+		SigningKey { player: u8, key: [u8; 32] },
+		Signature { player: u8, sig: InputSignature },
+	}
+}
+
+// Decode one record stored in `version` into the current format
+fn upgrade(version: u16, body: &mut Cursor<&[u8]>) -> anyhow::Result<Record> {
+	match version {
+		1 => {
+			let t: old::TickInputsV2 = bincode::deserialize_from(body)?;
+			Ok(Record::Tick(t.into()))
+		}
+		2 => Ok(match bincode::deserialize_from(body)? {
+			old::RecordV2::Tick(t) => Record::Tick(t.into()),
+			old::RecordV2::SigningKey { player, key } => Record::SigningKey { player, key },
+			old::RecordV2::Signature { player, sig } => Record::Signature { player, sig },
+		}),
+		// v4 and v5 only appended a record kind each
+		3..=5 => Ok(bincode::deserialize_from::<_, old::RecordV5>(body)?.into()),
+		REPLAY_VERSION => Ok(bincode::deserialize_from(body)?),
+		v => bail!("unsupported replay version {v} (newest known is {REPLAY_VERSION})"),
+	}
+}
+
+/// Streams records to disk as they happen, so a crash only loses the tail.
+pub struct ReplayWriter {
+	out: BufWriter<File>,
+}
+
+impl ReplayWriter {
+	pub fn create(path: &Path) -> anyhow::Result<Self> {
+		let mut out = BufWriter::new(File::create(path).context("create replay")?);
+		out.write_all(&MAGIC)?;
+		out.write_all(&REPLAY_VERSION.to_le_bytes())?;
+		Ok(Self { out })
+	}
+
+	fn write_record(&mut self, r: &Record) -> anyhow::Result<()> {
+		bincode::serialize_into(&mut self.out, r)?;
+		Ok(())
+	}
+
+	pub fn write_tick(&mut self, t: &TickInputs) -> anyhow::Result<()> {
+		self.write_record(&Record::Tick(t.clone()))
+	}
+
+	pub fn write_roster(&mut self, roster: &Roster) -> anyhow::Result<()> {
+		self.write_record(&Record::Roster(roster.clone()))
+	}
+
+	pub fn write_warmup(&mut self, warmup: bool) -> anyhow::Result<()> {
+		self.write_record(&Record::Warmup(warmup))
+	}
+
+	pub fn write_signing_key(&mut self, player: u8, key: [u8; 32]) -> anyhow::Result<()> {
+		self.write_record(&Record::SigningKey { player, key })
+	}
+
+	pub fn write_signature(&mut self, player: u8, sig: InputSignature) -> anyhow::Result<()> {
+		self.write_record(&Record::Signature { player, sig })
+	}
+
+	pub fn flush(&mut self) -> anyhow::Result<()> {
+		self.out.flush()?;
+		Ok(())
+	}
+}
+
+pub fn run_migrate(input: &Path, output: &Path) -> anyhow::Result<()> {
+	let (replay, version) = Replay::read(input)?;
+	replay.write(output)?;
+	println!(
+		"migrated {} ticks from v{version} to v{REPLAY_VERSION}",
+		replay.ticks.len()
+	);
+	Ok(())
+}
+
+pub fn run_verify(input: &Path) -> anyhow::Result<()> {
+	let (replay, _) = Replay::read(input)?;
+	let by_tick: HashMap<u32, &TickInputs> = replay.ticks.iter().map(|t| (t.tick, t)).collect();
+
+	let mut bad = 0;
+	for player in 0..replay.roster.players() as u8 {
+		let Some(key) = replay.keys.get(&player) else {
+			println!("P{player}: unsigned");
+			continue;
+		};
+		let mut valid = 0;
+		let mut total = 0;
+		for (_, sig) in replay.signatures.iter().filter(|(p, _)| *p == player) {
+			total += 1;
+			let p = player as usize;
+			let inputs: Option<Vec<[u8; 2]>> = (sig.start_tick..sig.start_tick + sig.len)
+				.map(|t| by_tick.get(&t).map(|i| [i.inputs[p], i.aims[p]]))
+				.collect();
+			match inputs {
+				Some(inputs) if crate::signing::verify(key, player, sig, inputs.as_flattened()) => {
+					valid += 1
+				}
+				_ => println!(
+					"P{player}: ticks {}..{} don't match the signature",
+					sig.start_tick,
+					sig.start_tick + sig.len
+				),
+			}
+		}
+		bad += total - valid;
+		println!(
+			"P{player}: {valid}/{total} chunks verified ({} of {} ticks)",
+			valid * crate::signing::SIGN_CHUNK_TICKS as usize,
+			replay.ticks.len()
+		);
+	}
+
+	if bad > 0 {
+		bail!("{bad} chunks failed verification");
+	}
+	Ok(())
+}
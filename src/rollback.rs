@@ -0,0 +1,261 @@
+/// A deterministic simulation the rollback session can drive. It keeps its
+/// own state history, so it decides how saved states are stored.
+pub trait Game {
+	type State: Clone;
+	// Every player's input for one tick
+	type Inputs: Clone + PartialEq;
+
+	fn step(&self, state: &mut Self::State, inputs: &Self::Inputs);
+
+	// State after each of `inputs` in turn, games with a faster way to replay override it
+	fn resimulate(&self, start: &Self::State, inputs: &[Self::Inputs]) -> Vec<Self::State> {
+		let mut state = start.clone();
+		inputs
+			.iter()
+			.map(|i| {
+				self.step(&mut state, i);
+				state.clone()
+			})
+			.collect()
+	}
+
+	// State before `tick` ran
+	fn save(&mut self, tick: u32, state: &Self::State);
+	fn load(&self, tick: u32) -> Option<Self::State>;
+	// Drops the state saved before `tick`, if it's still there
+	fn forget(&mut self, tick: u32);
+	fn clear(&mut self);
+}
+
+/// A rollback that ran: `states[i]` is the state after tick `from + i`.
+pub struct Rollback<G: Game> {
+	pub from: u32,
+	pub start: G::State,
+	pub states: Vec<G::State>,
+}
+
+/// Prediction bookkeeping of a rollback client: the inputs each tick ran with,
+/// the server's inputs as they arrive, and the earliest tick where the two
+/// disagree. Rings of `capacity` ticks, anything older is forgotten, except
+/// for a checkpoint kept at the newest state all of whose inputs were confirmed.
+/// Compacting, ticks behind the checkpoint are thinned out as well.
+pub struct Session<G: Game> {
+	game: G,
+	auth: Vec<Option<(u32, G::Inputs)>>,
+	used: Vec<Option<(u32, G::Inputs)>>,
+	pending: Option<u32>,
+	// State before the tick, stepped with the server's inputs only as they arrive
+	checkpoint: Option<(u32, G::State)>,
+	// First tick not simulated yet
+	next: Option<u32>,
+	// Confirmed ticks keep their state and inputs only this often, none keeps all
+	keep_every: Option<u32>,
+}
+
+impl<G: Game> Session<G> {
+	pub fn new(game: G, capacity: usize) -> Self {
+		Self {
+			game,
+			auth: vec![None; capacity],
+			used: vec![None; capacity],
+			pending: None,
+			checkpoint: None,
+			next: None,
+			keep_every: None,
+		}
+	}
+
+	/// Keeps the state and inputs of a tick behind the checkpoint only every
+	/// `keep_every` ticks, for states too big to keep them all. Unconfirmed
+	/// ticks stay whole, they may still roll back. The kept states are the
+	/// checkpoint's, run on the server's inputs alone. The rest no longer
+	/// load, and a rollback to one starts from the checkpoint instead.
+	pub fn compacting(self, keep_every: u32) -> Self {
+		Self {
+			keep_every: Some(keep_every.max(1)),
+			..self
+		}
+	}
+
+	// Whether compaction leaves `tick` in the history
+	fn kept(&self, tick: u32) -> bool {
+		let Some(every) = self.keep_every else {
+			return true;
+		};
+		tick.is_multiple_of(every)
+			|| self
+				.checkpoint
+				.as_ref()
+				.is_none_or(|&(c, _)| !is_before(tick, c))
+	}
+
+	fn slot(ring: &[Option<(u32, G::Inputs)>], tick: u32) -> Option<G::Inputs> {
+		ring[tick as usize % ring.len()]
+			.as_ref()
+			.filter(|(t, _)| *t == tick)
+			.map(|(_, i)| i.clone())
+	}
+
+	// A new timeline, nothing carries over
+	pub fn clear(&mut self) {
+		self.auth.iter_mut().for_each(|s| *s = None);
+		self.used.iter_mut().for_each(|s| *s = None);
+		self.game.clear();
+		self.pending = None;
+		self.checkpoint = None;
+		self.next = None;
+	}
+
+	pub fn authoritative(&self, tick: u32) -> Option<G::Inputs> {
+		Self::slot(&self.auth, tick)
+	}
+
+	// What `tick` was last simulated with
+	pub fn used(&self, tick: u32) -> Option<G::Inputs> {
+		Self::slot(&self.used, tick)
+	}
+
+	pub fn load(&self, tick: u32) -> Option<G::State> {
+		self.game.load(tick)
+	}
+
+	pub fn pending_rollback(&self) -> Option<u32> {
+		self.pending
+	}
+
+	// The last known good state and the tick it's from
+	pub fn checkpoint(&self) -> Option<(u32, &G::State)> {
+		self.checkpoint.as_ref().map(|(t, s)| (*t, s))
+	}
+
+	// Keeps the earlier of the two, ticks compare across the wrap
+	fn roll_back_to(&mut self, tick: u32) {
+		self.pending = Some(match self.pending {
+			Some(t) if !is_before(tick, t) => t,
+			_ => tick,
+		});
+	}
+
+	// Steps the checkpoint over every tick the server has confirmed since
+	fn advance_checkpoint(&mut self) {
+		let Some((tick, state)) = self.checkpoint.as_mut() else {
+			return;
+		};
+		while let Some(inputs) = Self::slot(&self.auth, *tick) {
+			match self.keep_every {
+				None => {}
+				Some(every) if !tick.is_multiple_of(every) => {
+					self.game.forget(*tick);
+					let i = *tick as usize % self.used.len();
+					if self.used[i].as_ref().is_some_and(|(t, _)| t == tick) {
+						self.used[i] = None;
+					}
+				}
+				// Replaces what we predicted, unless the ring moved past the tick
+				Some(_) if self.game.load(*tick).is_some() => self.game.save(*tick, state),
+				Some(_) => {}
+			}
+			self.game.step(state, &inputs);
+			*tick = tick.wrapping_add(1);
+		}
+	}
+
+	// The server's inputs for `tick`, a rollback is due if we ran it with others
+	pub fn confirm(&mut self, tick: u32, inputs: G::Inputs) {
+		let i = tick as usize % self.auth.len();
+		let mispredicted = match self.used(tick) {
+			Some(used) => used != inputs,
+			// Simulated so long ago the ring forgot how, only a resimulation can tell.
+			// Compacted away it was confirmed before, the server says the same twice
+			None => self.kept(tick) && self.next.is_some_and(|next| is_before(tick, next)),
+		};
+		self.auth[i] = Some((tick, inputs));
+		if mispredicted {
+			self.roll_back_to(tick);
+		}
+		self.advance_checkpoint();
+	}
+
+	// Replaces our state before `tick`, with the server's say, and replays from there
+	pub fn reseed(&mut self, tick: u32, state: &G::State) {
+		self.game.save(tick, state);
+		self.roll_back_to(tick);
+		if self
+			.checkpoint
+			.as_ref()
+			.is_none_or(|&(t, _)| !is_before(tick, t))
+		{
+			self.checkpoint = Some((tick, state.clone()));
+			self.advance_checkpoint();
+		}
+	}
+
+	// Runs `tick` on `state`, remembering the state before it and the inputs used.
+	// The first state after a clear is where the server started us, the checkpoint
+	pub fn step(&mut self, tick: u32, state: &mut G::State, inputs: G::Inputs) {
+		if self.checkpoint.is_none() {
+			self.checkpoint = Some((tick, state.clone()));
+			self.advance_checkpoint();
+		}
+		self.game.save(tick, state);
+		self.game.step(state, &inputs);
+		let i = tick as usize % self.used.len();
+		self.used[i] = Some((tick, inputs));
+		self.next = Some(tick.wrapping_add(1));
+	}
+
+	// Replays the pending rollback up to `now`, the first tick not simulated yet.
+	// Ticks without authoritative inputs get `predict(tick, used)`. A tick no
+	// longer saved replays from the checkpoint instead. Returns nothing when
+	// there's no rollback or neither is available
+	pub fn rollback(
+		&mut self,
+		now: u32,
+		predict: impl Fn(u32, Option<G::Inputs>) -> G::Inputs,
+	) -> Option<Rollback<G>> {
+		let pending = self.pending?;
+		let (from, start) = match self.game.load(pending) {
+			Some(start) => (pending, start),
+			None => self
+				.checkpoint
+				.clone()
+				.filter(|&(t, _)| !is_before(now, t))?,
+		};
+		let inputs: Vec<G::Inputs> = (0..now.wrapping_sub(from))
+			.map(|k| {
+				let t = from.wrapping_add(k);
+				self.authoritative(t)
+					.unwrap_or_else(|| predict(t, self.used(t)))
+			})
+			.collect();
+		let states = self.game.resimulate(&start, &inputs);
+		let mut before = start.clone();
+		for (k, (inputs, after)) in inputs.into_iter().zip(&states).enumerate() {
+			let t = from.wrapping_add(k as u32);
+			if !self.kept(t) {
+				before = after.clone();
+				continue;
+			}
+			self.game.save(t, &before);
+			let i = t as usize % self.used.len();
+			self.used[i] = Some((t, inputs));
+			before = after.clone();
+		}
+		self.pending = None;
+		Some(Rollback {
+			from,
+			start,
+			states,
+		})
+	}
+}
+
+// Whether tick `a` comes before `b`, across the wrap
+pub fn is_before(a: u32, b: u32) -> bool {
+	(a.wrapping_sub(b) as i32) < 0
+}
+
+// The later of two ticks, across the wrap
+pub fn later(a: u32, b: u32) -> u32 {
+	if is_before(a, b) { b } else { a }
+}
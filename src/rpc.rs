@@ -0,0 +1,85 @@
+use std::{
+	io::{BufRead, Write},
+	sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+
+// JSON-RPC error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+
+/// Code and message of a failed call.
+pub type Error = (i64, String);
+
+/// Where answers and notifications go, shared by everyone sending them.
+pub type Out = Arc<Mutex<Box<dyn Write + Send>>>;
+
+#[derive(Deserialize)]
+struct Request {
+	method: String,
+	#[serde(default)]
+	params: Value,
+	// Absent on notifications, which get no answer
+	id: Option<Value>,
+}
+
+fn send(out: &Out, msg: &Value) {
+	let mut out = out.lock().unwrap();
+	let _ = writeln!(out, "{msg}");
+	let _ = out.flush();
+}
+
+pub fn notify(out: &Out, method: &str, params: Value) {
+	send(
+		out,
+		&json!({"jsonrpc": "2.0", "method": method, "params": params}),
+	);
+}
+
+// A call's params as `T`
+pub fn params<T: DeserializeOwned>(params: Value) -> Result<T, Error> {
+	serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+pub fn no_method(method: &str) -> Error {
+	(METHOD_NOT_FOUND, format!("no method {method}"))
+}
+
+/// JSON-RPC 2.0, one message per line: answers every call read from `read`
+/// with `handle` until it closes.
+pub fn serve(
+	read: impl BufRead,
+	out: &Out,
+	mut handle: impl FnMut(&str, Value) -> Result<Value, Error>,
+) {
+	for line in read.lines() {
+		let Ok(line) = line else { break };
+		if line.trim().is_empty() {
+			continue;
+		}
+		let answer = match serde_json::from_str::<Request>(&line) {
+			Ok(req) => {
+				let result = handle(&req.method, req.params);
+				req.id.map(|id| match result {
+					Ok(v) => json!({"jsonrpc": "2.0", "result": v, "id": id}),
+					Err((code, msg)) => json!({
+						"jsonrpc": "2.0",
+						"error": {"code": code, "message": msg},
+						"id": id,
+					}),
+				})
+			}
+			Err(e) => Some(json!({
+				"jsonrpc": "2.0",
+				"error": {"code": PARSE_ERROR, "message": e.to_string()},
+				"id": null,
+			})),
+		};
+		if let Some(answer) = answer {
+			send(out, &answer);
+		}
+	}
+}
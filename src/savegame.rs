@@ -0,0 +1,68 @@
+use std::{collections::VecDeque, fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{protocol::TickInputs, series::Series, sim::SimState};
+
+// Server ticks between two saves
+pub const SAVE_INTERVAL_TICKS: u32 = 60;
+
+// Authoritative ticks kept in a save, newest last
+pub const RECENT_INPUTS: usize = 120;
+
+/// Everything a restarted server needs to pick the match back up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaveGame {
+	// Next tick to simulate, `state` is the state right before it
+	pub tick: u32,
+	pub state: SimState,
+	pub recent: VecDeque<TickInputs>,
+	// Every player's, as many as the state has players
+	pub tokens: Vec<u64>,
+	pub series: Series,
+}
+
+impl SaveGame {
+	// Inputs held by each player as of the last saved tick
+	pub fn last_inputs(&self) -> TickInputs {
+		self.recent
+			.back()
+			.cloned()
+			.unwrap_or_else(|| TickInputs::neutral(self.tick, self.state.player_count()))
+	}
+
+	pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+		// Write then rename so a crash mid-write never leaves a torn save
+		let tmp = path.with_extension("tmp");
+		fs::write(&tmp, bincode::serialize(self)?).context("write save")?;
+		fs::rename(&tmp, path).context("replace save")?;
+		Ok(())
+	}
+
+	pub fn read(path: &Path) -> anyhow::Result<Self> {
+		let bytes = fs::read(path).context("read save")?;
+		let save: Self = bincode::deserialize(&bytes)?;
+		save.state.check().context("saved state")?;
+		anyhow::ensure!(
+			save.tokens.len() == save.state.player_count(),
+			"a save of {} players with {} tokens",
+			save.state.player_count(),
+			save.tokens.len()
+		);
+		Ok(save)
+	}
+}
+
+// Connection tokens only need to be unguessable by other players, the std hasher keys are random
+pub fn new_token() -> u64 {
+	use std::hash::{BuildHasher, Hasher};
+	let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+	h.write_u128(
+		std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_nanos(),
+	);
+	h.finish()
+}
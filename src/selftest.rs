@@ -0,0 +1,921 @@
+use std::{
+	collections::VecDeque,
+	sync::mpsc::RecvTimeoutError,
+	thread,
+	time::{Duration, Instant},
+};
+
+use crate::{
+	bot, clock,
+	env::Rng,
+	interp::SnapshotView,
+	net::{
+		self, InputTransport, InputWindow, Listeners, NetCmd, NetEvent, ServerConfig, ServerRender,
+	},
+	netsim::{self, NetSim},
+	num::{ZERO, num, vector},
+	physics::{self, Aabb},
+	playback::{self, Playback},
+	protocol::{
+		C2S, Capabilities, Frame, HISTORY_CHUNK_TICKS, InputMsg, MAX_FRAME_BYTES, ResumeState,
+		Roster, S2C, Stamped, TickInputs,
+	},
+	queue,
+	rollback::Session,
+	sim::{self, Arena, DEFAULT_PLAYERS, InputBits, MAX_PLAYERS, PlayerInput, Shot, SimState},
+	sockopt::SocketOptions,
+	stats,
+	transport::{self, Listener},
+};
+use anyhow::{Context, bail};
+
+// Same lead and input window as the real server
+const LEAD_TICKS: u32 = 4;
+const D_MAX: u32 = 32;
+
+// Rollback history of a bot client, deep enough for the worst jitter below
+const HISTORY: usize = 256;
+
+// Confirmed states the odd bots keep, the rest of theirs is compacted away
+const KEEP_EVERY: u32 = 6;
+
+// Length of the session
+const TICKS: u32 = 5000;
+
+// Progress line interval of a stress run, in session time
+const STRESS_REPORT_TICKS: u32 = 3600 * sim::TPS;
+
+// One-way latency of each bot in ticks (up, down), jitter is added on top.
+// Bots past the table reuse it from the start
+const LATENCY: [(u32, u32); 4] = [(3, 5), (6, 2), (4, 7), (5, 4)];
+const JITTER: u32 = 4;
+
+// A message in flight, delivered once the mock clock reaches `at`
+struct Wire<T> {
+	at: u32,
+	msg: T,
+}
+
+// The real client's rollback session: predict remotes with their last input,
+// roll back on mismatch
+struct Bot {
+	id: usize,
+	// Tick of the first step, checksums are indexed from it
+	start: u32,
+	rng: Rng,
+	held: PlayerInput,
+	tick: u32,
+	state: SimState,
+	session: Session<Arena>,
+	last_remote: Vec<PlayerInput>,
+	// checksums[t] is the checksum of the state after tick t
+	checksums: Vec<u64>,
+	rollbacks: u32,
+	max_depth: u32,
+}
+
+impl Bot {
+	fn new(id: usize, players: usize, seed: u32, start: u32) -> Self {
+		Self {
+			id,
+			start,
+			rng: Rng::new(seed),
+			held: InputBits::empty().into(),
+			tick: start,
+			state: SimState::new(players).warming_up(true),
+			session: match id % 2 {
+				0 => Session::new(Arena::new(HISTORY), HISTORY),
+				// The same checksums prove compaction loses nothing
+				_ => Session::new(Arena::new(HISTORY), HISTORY).compacting(KEEP_EVERY),
+			},
+			last_remote: vec![InputBits::empty().into(); players],
+			checksums: Vec::new(),
+			rollbacks: 0,
+			max_depth: 0,
+		}
+	}
+
+	// Mash buttons and spray shots, holding each combination for a while
+	fn input(&mut self) -> PlayerInput {
+		if self.rng.next_u32().is_multiple_of(12) {
+			let r = self.rng.next_u32();
+			self.held = PlayerInput::new(r as u8, (r >> 8) as u8);
+		}
+		self.held
+	}
+
+	// Index into the checksums, ticks wrap but the session doesn't
+	fn index(&self, tick: u32) -> usize {
+		tick.wrapping_sub(self.start) as usize
+	}
+
+	fn receive(&mut self, t: TickInputs) {
+		let inputs = t.sim_inputs();
+		self.session.confirm(t.tick, inputs.clone());
+		self.last_remote = inputs;
+		if self.session.pending_rollback().is_none() {
+			return;
+		}
+		// Mispredicted, resimulate everything from there
+		let (id, last_remote) = (self.id, &self.last_remote);
+		let rb = self
+			.session
+			.rollback(self.tick, |_, used| {
+				let mut inputs = last_remote.clone();
+				if let Some(used) = used {
+					inputs[id] = used[id];
+				}
+				inputs
+			})
+			.expect("rollback deeper than history");
+		let from = self.index(rb.from);
+		// Compacted, it starts from the checkpoint: the state after the tick
+		// before, which nothing else recomputed
+		if let Some(before) = from.checked_sub(1) {
+			self.checksums[before] = sim::checksum(&rb.start);
+		}
+		for (k, after) in rb.states.iter().enumerate() {
+			self.checksums[from + k] = sim::checksum(after);
+		}
+		if let Some(last) = rb.states.last() {
+			self.state = last.clone();
+		}
+		self.rollbacks += 1;
+		self.max_depth = self.max_depth.max(rb.states.len() as u32);
+	}
+
+	// Simulate the next tick, returns our input for it
+	fn step(&mut self) -> PlayerInput {
+		let local = self.input();
+		let inputs = self.session.authoritative(self.tick).unwrap_or_else(|| {
+			let mut inputs = self.last_remote.clone();
+			inputs[self.id] = local;
+			inputs
+		});
+		let ours = inputs[self.id];
+		self.session.step(self.tick, &mut self.state, inputs);
+		self.checksums.push(sim::checksum(&self.state));
+		self.tick = self.tick.wrapping_add(1);
+		ours
+	}
+}
+
+/// Soak variant of the self-test: hours of session time. The loopback runs
+/// them as fast as it can with the tick counter wrapping halfway, then the
+/// real server and bots play them at `rate` times real time, every match
+/// crossing the wrap.
+#[derive(Debug, Clone, Copy)]
+pub struct Stress {
+	pub hours: f32,
+	pub rate: f32,
+}
+
+// Checksums of the fresh and played states below, pinned so every platform
+// and build agrees on them. Only a deliberate change of the canonical form may
+// update these
+#[cfg(not(feature = "fixed-point"))]
+const PINNED: [u64; 2] = [0x36b4_bf84_a678_e1e3, 0xdbaa_7802_3a88_62b9];
+#[cfg(feature = "fixed-point")]
+const PINNED: [u64; 2] = [0x5f55_3f4e_9b5f_278e, 0x1d28_99ec_6746_70bc];
+
+fn check_checksums() -> anyhow::Result<()> {
+	let mut played = SimState::new(DEFAULT_PLAYERS);
+	played.team_scores[0] = 9;
+	(played.warmup, played.warmup_ticks) = (true, 11);
+	let p = &mut played.players[1];
+	(p.x, p.y, p.vx, p.vy) = (num(100.5), num(64.0), num(-0.0), num(-123.25));
+	(p.score, p.cooldown, p.hits, p.fired, p.ready) = (7, 3, 2, 5, true);
+	(p.health, p.hitstun, p.swing, p.swing_left, p.swing_hit) = (40, 6, 9, true, true);
+	// A NaN with a payload hashes like any other NaN
+	#[cfg(not(feature = "fixed-point"))]
+	let y = f32::from_bits(0x7fc0_0001);
+	#[cfg(feature = "fixed-point")]
+	let y = num(-3.5);
+	p.shots[0] = Shot {
+		x: num(10.25),
+		y,
+		vx: num(-240.0),
+		vy: ZERO,
+		ttl: 17,
+		id: 4,
+	};
+	let pinned = [
+		("fresh", SimState::new(DEFAULT_PLAYERS), PINNED[0]),
+		("played", played, PINNED[1]),
+	];
+	for (name, state, want) in pinned {
+		let got = sim::checksum(&state);
+		if got != want {
+			bail!("checksum of the {name} state is {got:016x}, pinned {want:016x}");
+		}
+		let back = SimState::from_bytes(&state.to_bytes())?;
+		if sim::checksum(&back) != want {
+			bail!("the {name} state changed going through its bytes");
+		}
+	}
+	Ok(())
+}
+
+// Moves far past what anything does today must still collide, or faster
+// projectiles would tunnel through whatever is in their way
+fn check_physics() -> anyhow::Result<()> {
+	// A box falling ten times its height in one step lands flush on a thin ledge
+	let ledge = Aabb::new(num(0.0), num(100.0), num(50.0), num(1.0));
+	let mut body = Aabb::new(num(10.0), num(60.0), num(8.0), num(8.0));
+	let blocked = physics::move_and_slide(&mut body, vector(num(5.0), num(80.0)), &[ledge]);
+	if !blocked.y || !body.stands_on(&ledge) || body.x != num(15.0) {
+		bail!("a fast fall went through a ledge, ended at {body:?}");
+	}
+
+	// A shot covering the whole arena in one tick still hits who's in its way
+	let mut state = SimState::new(DEFAULT_PLAYERS);
+	let target = state.players[1].center();
+	state.players[0].shots[0] = Shot {
+		x: num(1.0),
+		y: target.y - Shot::SIZE / num(2.0),
+		vx: num((sim::BUFFER_W * sim::TPS) as f32),
+		vy: ZERO,
+		ttl: 5,
+		id: 0,
+	};
+	let idle = vec![InputBits::empty().into(); DEFAULT_PLAYERS];
+	sim::step(&mut state, &idle);
+	if state.players[1].hits != 1 {
+		bail!("a fast shot tunneled through the other player");
+	}
+
+	// Players walking into each other end up side by side, and a rollback deep
+	// enough to run on threads comes out the same as stepping did
+	let walk: Vec<PlayerInput> = (0..DEFAULT_PLAYERS)
+		.map(|i| match i {
+			0 => InputBits::RIGHT.into(),
+			_ => InputBits::LEFT.into(),
+		})
+		.collect();
+	let inputs = vec![walk; sim::PARALLEL_RESIM_MIN_TICKS * 2];
+	let start = SimState::new(DEFAULT_PLAYERS);
+	let mut stepped = start.clone();
+	for i in &inputs {
+		sim::step(&mut stepped, i);
+	}
+	let (a, b) = (stepped.players[0].body(), stepped.players[1].body());
+	if a.overlaps(&b) || a.x >= b.x {
+		bail!("players walking into each other ended up at {a:?} and {b:?}");
+	}
+	let resimulated = sim::resimulate(&start, &inputs);
+	if resimulated.last().map(sim::checksum) != Some(sim::checksum(&stepped)) {
+		bail!("a deep rollback over players colliding came out different");
+	}
+
+	// An attack lands on the player next to it once, however long it's out
+	let mut state = stepped;
+	let mut attack = idle.clone();
+	attack[0] = InputBits::ATTACK.into();
+	sim::step(&mut state, &attack);
+	for _ in 0..sim::TPS {
+		sim::step(&mut state, &idle);
+	}
+	let hit = state.players[1];
+	if hit.hits != 1 || hit.health >= sim::Player::MAX_HEALTH {
+		bail!("an attack next to a player hit it {} times", hit.hits);
+	}
+	Ok(())
+}
+
+// The most a datagram may carry without being fragmented on any IPv4 path:
+// the 576 bytes every host reassembles, less the IP and UDP headers
+const SAFE_DATAGRAM: usize = 508;
+
+// A full window of inputs has to fit in one datagram, and the netsim has to
+// treat bigger ones like a path with that MTU would
+fn check_datagrams() -> anyhow::Result<()> {
+	let mut window = InputWindow::default();
+	let mut cmd = None;
+	for tick in 0..8 {
+		cmd = Some(window.send(InputMsg {
+			tick: u32::MAX - tick,
+			bits: u8::MAX,
+			aim: u8::MAX,
+			ack_tick: u32::MAX,
+			sent_us: u64::MAX,
+		}));
+	}
+	let len = cmd
+		.and_then(|c| c.datagram_len())
+		.context("inputs don't go in datagrams")?;
+	if len > SAFE_DATAGRAM {
+		bail!("a full input window takes {len} bytes, over the {SAFE_DATAGRAM} any path carries");
+	}
+	let policy = |p: String| netsim::parse_policy(&p).map_err(anyhow::Error::msg);
+	let mut dropping = NetSim::new(vec![policy(format!("input:mtu={},df=1", len - 1))?], 1);
+	if !dropping
+		.deliveries(netsim::Kind::Input, 0, Some(len))
+		.is_empty()
+	{
+		bail!("a {len} byte datagram went through an MTU below it with df");
+	}
+	let mut splitting = NetSim::new(vec![policy(format!("input:mtu={}", len / 4))?], 1);
+	if splitting
+		.deliveries(netsim::Kind::Input, 0, Some(len))
+		.len() != 1
+	{
+		bail!("a {len} byte datagram didn't arrive once fragmented on a lossless path");
+	}
+	Ok(())
+}
+
+// The longest frames the protocol sends have to fit in what transports accept:
+// a History chunk with ticks far apart, and a Resume with every shot in flight,
+// both of as many players as a match can have
+fn check_frames() -> anyhow::Result<()> {
+	let history: Vec<TickInputs> = (0..HISTORY_CHUNK_TICKS as u32)
+		.map(|i| TickInputs {
+			tick: i.wrapping_mul(0x9e37_79b9),
+			inputs: vec![u8::MAX; MAX_PLAYERS],
+			aims: vec![u8::MAX; MAX_PLAYERS],
+		})
+		.collect();
+	let mut state = SimState::new(MAX_PLAYERS);
+	for p in &mut state.players {
+		p.shots = [Shot {
+			ttl: u16::MAX,
+			id: u32::MAX,
+			..Default::default()
+		}; sim::MAX_SHOTS];
+	}
+	let resume = S2C::Resume(ResumeState {
+		player_id: 0,
+		token: u64::MAX,
+		tick: u32::MAX,
+		start_after_ms: u32::MAX,
+		state,
+		roster: Roster::new(MAX_PLAYERS),
+	});
+	for frame in [S2C::History(history), resume] {
+		let len = frame.encode()?.len();
+		if len > MAX_FRAME_BYTES {
+			bail!(
+				"a {} frame takes {len} bytes, over MAX_FRAME_BYTES",
+				frame.kind()
+			);
+		}
+	}
+	Ok(())
+}
+
+// A spectator's playback of a match that starts shortly before the tick
+// counter wraps. Stepping up to the stream's end, seeking back to keyframes
+// and forward across the wrap all have to land on the states a straight run
+// of the same inputs goes through
+fn check_playback(seed: u32) -> anyhow::Result<()> {
+	let ticks = 4 * playback::KEYFRAME_INTERVAL;
+	let first = 0u32.wrapping_sub(ticks / 2);
+	let mut rng = Rng::new(seed);
+	let history: Vec<TickInputs> = (0..ticks)
+		.map(|i| TickInputs {
+			tick: first.wrapping_add(i),
+			inputs: (0..DEFAULT_PLAYERS).map(|_| rng.next_u32() as u8).collect(),
+			aims: (0..DEFAULT_PLAYERS).map(|_| rng.next_u32() as u8).collect(),
+		})
+		.collect();
+	// sums[i] is the checksum of the state right before tick first + i
+	let mut state = SimState::new(DEFAULT_PLAYERS);
+	let mut sums = vec![sim::checksum(&state)];
+	for t in &history {
+		sim::step(&mut state, &t.sim_inputs());
+		sums.push(sim::checksum(&state));
+	}
+
+	let mut pb = Playback::new(first, SimState::new(DEFAULT_PLAYERS));
+	let check = |pb: &Playback, want: u32, what: &str| {
+		let at = pb.tick().wrapping_sub(first);
+		if at != want || sim::checksum(pb.state()) != sums[at as usize] {
+			bail!(
+				"playback {what} is at tick {} ({at} in), expected {want} in, or off its state",
+				pb.tick()
+			);
+		}
+		Ok(())
+	};
+	// The stream comes in up to a little past the wrap, playback waits there
+	let split = ticks as usize / 2 + 10;
+	history[..split].iter().for_each(|t| pb.push(t));
+	while pb.step() {}
+	check(&pb, split as u32, "stepping up to the stream's end")?;
+	pb.push(&history[0]);
+	if pb.end_tick() != history[split].tick {
+		bail!("playback took a tick it already had");
+	}
+	history[split..].iter().for_each(|t| pb.push(t));
+
+	let seeks = [
+		(first.wrapping_sub(5), 0, "seeking to before the first tick"),
+		(
+			u32::MAX,
+			ticks / 2 - 1,
+			"seeking to the last tick before the wrap",
+		),
+		(3, ticks / 2 + 3, "seeking across the wrap"),
+		(
+			u32::MAX - 130,
+			ticks / 2 - 131,
+			"seeking back across the wrap",
+		),
+		(
+			pb.end_tick().wrapping_add(100),
+			ticks,
+			"seeking past the end",
+		),
+	];
+	for (tick, want, what) in seeks {
+		pb.seek(tick);
+		check(&pb, want, what)?;
+	}
+	pb.seek(u32::MAX - 1);
+	for want in ticks / 2 - 2..ticks / 2 + 2 {
+		check(&pb, want, "stepping across the wrap")?;
+		pb.step();
+	}
+	Ok(())
+}
+
+// Length of the match the real server plays below, in real time
+const SERVE_SECS: u64 = 3;
+
+// Served stress matches start this long before the tick counter wraps, so
+// every one that lasts crosses it
+const WRAP_AFTER_SECS: u32 = 10;
+
+// Ticks either side of the wrap whose late inputs are counted against it. Inputs
+// it loses come in right around it, hiccups of the process anywhere
+const WRAP_WINDOW_TICKS: u32 = sim::TPS / 4;
+
+// Longest a served stress run may go without a tick, in real time
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The real server's config for a served run of `players` on `addr`, without
+// anything that needs files or ports
+fn served_config(addr: String, players: usize) -> ServerConfig {
+	ServerConfig {
+		addr,
+		start_delay: Duration::from_millis(200),
+		lead_ticks: LEAD_TICKS,
+		d_max: D_MAX,
+		fairness: false,
+		save_path: None,
+		resume: None,
+		record_path: None,
+		best_of: 1,
+		time_limit: None,
+		first_tick: 0,
+		warmup: false,
+		watch: false,
+		afk_after: None,
+		observe_addr: None,
+		reserved: vec![None; players],
+		roster: Roster::new(players),
+		socket: SocketOptions::default(),
+		max_pair_latency: None,
+		career_path: None,
+		ws_addr: None,
+		control_addr: None,
+		room_status: None,
+	}
+}
+
+// net::serve on an in-memory listener and `bots` bot clients playing on it,
+// the ones past the config's players spectate
+struct Served {
+	rx_render: queue::Receiver<ServerRender>,
+	server: thread::JoinHandle<()>,
+	bots: thread::JoinHandle<anyhow::Result<()>>,
+}
+
+fn serve_bots(cfg: ServerConfig, bots: u32, seed: u32) -> anyhow::Result<Served> {
+	let name = cfg
+		.addr
+		.strip_prefix("mem://")
+		.context("not an in-memory address")?;
+	let listener = Listener::memory(name).context("listen in memory")?;
+	let addr = cfg.addr.clone();
+	let (tx_render, rx_render) = net::render_queue();
+	let server = thread::spawn(move || {
+		transport::runtime().block_on(async {
+			let arrivals = net::accept_players(vec![listener]);
+			net::serve(cfg, arrivals, Listeners::default(), 0, tx_render).await;
+		});
+	});
+	let bots = thread::spawn(move || {
+		bot::run_bots(
+			&addr,
+			None,
+			bots,
+			None,
+			seed,
+			SocketOptions::default(),
+			InputTransport::Tcp,
+		)
+	});
+	Ok(Served {
+		rx_render,
+		server,
+		bots,
+	})
+}
+
+// The snapshot client without its window, in a served match's last slot: from
+// the start on, what it shows has to have the match's players and its own.
+// Returns how many snapshots it was sent
+fn view_snapshots(addr: String, players: usize) -> anyhow::Result<u32> {
+	let (rx_evt, tx_cmd) = net::spawn_client(
+		addr,
+		None,
+		None,
+		None,
+		None,
+		SocketOptions::default(),
+		InputTransport::Tcp,
+	)?;
+	let mut view = SnapshotView::default();
+	let mut snapshots = 0;
+	let check = |view: &SnapshotView, when: &str| {
+		let Some(shown) = &view.shown else {
+			bail!("the snapshot client shows nothing {when}");
+		};
+		if shown.player_count() != players || shown.players.get(view.my_id).is_none() {
+			bail!(
+				"the snapshot client, p{}, shows {} players {when}, the match has {players}",
+				view.my_id,
+				shown.player_count()
+			);
+		}
+		Ok(())
+	};
+	loop {
+		let ev = match rx_evt.recv_timeout(Duration::from_secs(SERVE_SECS * 10)) {
+			Ok(ev) => ev,
+			Err(_) => bail!("the snapshot client stopped hearing from the server"),
+		};
+		crate::check_start(&ev)?;
+		match ev {
+			NetEvent::Welcome(caps) if caps.contains(Capabilities::SNAPSHOTS) => {
+				let _ = tx_cmd.send(NetCmd::SubscribeSnapshots);
+			}
+			NetEvent::AssignStart(a) => {
+				let state = SimState::with_teams(a.roster.teams.clone());
+				view.start(a.player_id, a.roster, state);
+				check(&view, "at the start")?;
+			}
+			NetEvent::Snapshot(s) => {
+				let tick = s.tick;
+				view.push(s);
+				view.sample(tick as f64);
+				check(&view, "from a snapshot")?;
+				snapshots += 1;
+			}
+			NetEvent::Disconnected => return Ok(snapshots),
+			_ => {}
+		}
+	}
+}
+
+// The real server and bot clients over in-memory connections: a timed match
+// of `players` has to run to its end with every bot playing it. With
+// `snapshot_client` it takes the last slot instead of a bot
+fn check_serve(seed: u32, players: usize, snapshot_client: bool) -> anyhow::Result<()> {
+	let cfg = ServerConfig {
+		time_limit: Some(Duration::from_secs(SERVE_SECS)),
+		..served_config(format!("mem://self-test-{seed:08x}-{players}"), players)
+	};
+	let addr = cfg.addr.clone();
+	let bots = players - snapshot_client as usize;
+	let received_before = stats::counter("bot.ticks_received").get();
+	let served = serve_bots(cfg, bots as u32, seed)?;
+	let viewer = snapshot_client.then(|| thread::spawn(move || view_snapshots(addr, players)));
+
+	// The server hangs up on the bots once the match is over
+	let deadline = Instant::now() + Duration::from_secs(SERVE_SECS * 10);
+	let mut last_tick = 0;
+	loop {
+		let left = deadline.saturating_duration_since(Instant::now());
+		match served.rx_render.recv_timeout(left) {
+			Ok(r) => last_tick = r.tick,
+			Err(RecvTimeoutError::Disconnected) => break,
+			Err(RecvTimeoutError::Timeout) => {
+				bail!("the served match didn't end, at tick {last_tick}")
+			}
+		}
+	}
+	if served.server.join().is_err() {
+		bail!("the server panicked");
+	}
+	match served.bots.join() {
+		Ok(result) => result?,
+		Err(_) => bail!("the bots panicked"),
+	}
+	let snapshots = match viewer.map(thread::JoinHandle::join) {
+		Some(Ok(result)) => Some(result?),
+		Some(Err(_)) => bail!("the snapshot client panicked"),
+		None => None,
+	};
+	let ticks = SERVE_SECS as u32 * sim::TPS;
+	let received = stats::counter("bot.ticks_received").get() - received_before;
+	print!("served {players} players: {last_tick} ticks, {received} received by the bots");
+	match snapshots {
+		Some(n) => println!(", {n} snapshots by the snapshot client"),
+		None => println!(),
+	}
+	if last_tick + 1 < ticks || received < (bots as u32 * ticks) as u64 {
+		bail!("the served match ended early");
+	}
+	if snapshots.is_some_and(|n| n < ticks / net::SNAPSHOT_INTERVAL_TICKS) {
+		bail!("the snapshot client missed snapshots");
+	}
+	Ok(())
+}
+
+// Hours of matches between the real server and bots, plus a spectating one,
+// on a clock running `rate` times real time. Every match starts shortly
+// before the tick counter wraps. Fails when the server stalls, drops a bot,
+// the bots stop sending, or inputs around the wrap come in late more than
+// elsewhere
+fn run_served_stress(seed: u32, stress: Stress) -> anyhow::Result<()> {
+	if stress.rate <= 0.0 {
+		bail!("the served stress run needs a rate above 0");
+	}
+	clock::set_rate(stress.rate as f64);
+	let wrap_at = WRAP_AFTER_SECS * sim::TPS;
+	let cfg = ServerConfig {
+		first_tick: 0u32.wrapping_sub(wrap_at),
+		watch: true,
+		..served_config(format!("mem://stress-{seed:08x}"), DEFAULT_PLAYERS)
+	};
+	let first_tick = cfg.first_tick;
+	// Left running, the process ends with the run
+	let served = serve_bots(cfg, DEFAULT_PLAYERS as u32 + 1, seed)?;
+
+	let session = Duration::from_secs_f64(stress.hours as f64 * 3600.0);
+	let started = clock::now();
+	let real_started = Instant::now();
+	let late = stats::counter("server.late_inputs");
+	let (mut matches, mut wraps, mut played) = (0u32, 0u32, 0u64);
+	// Late inputs in the window around each wrap, and the count as it opened
+	let (mut late_at_wraps, mut late_before_wrap) = (0u64, None);
+	let (window_from, window_to) = (wrap_at - WRAP_WINDOW_TICKS, wrap_at + WRAP_WINDOW_TICKS);
+	let mut offset: Option<u32> = None;
+	let mut reported_hours = 0;
+	loop {
+		let elapsed = clock::now().saturating_duration_since(started);
+		if elapsed >= session {
+			break;
+		}
+		let hours = (elapsed.as_secs() / 3600) as u32;
+		if hours > reported_hours {
+			reported_hours = hours;
+			println!(
+				"{hours}h of session time in {:.0?}, {matches} matches, {wraps} across the wrap",
+				real_started.elapsed()
+			);
+		}
+		let r = match served.rx_render.recv_timeout(STALL_TIMEOUT) {
+			Ok(r) => r,
+			Err(RecvTimeoutError::Timeout) => bail!("the server stalled at tick {:?}", offset),
+			Err(RecvTimeoutError::Disconnected) => bail!("the server stopped"),
+		};
+		let now = r.tick.wrapping_sub(first_tick);
+		match offset {
+			Some(prev) if now >= prev => played += (now - prev) as u64,
+			_ => {
+				matches += 1;
+				played += now as u64;
+			}
+		}
+		let before = offset.filter(|&prev| prev <= now).unwrap_or(0);
+		if before < window_from && now >= window_from {
+			late_before_wrap = Some(late.get());
+		}
+		if before < wrap_at && now >= wrap_at {
+			wraps += 1;
+		}
+		if now >= window_to
+			&& let Some(at) = late_before_wrap.take()
+		{
+			late_at_wraps += late.get() - at;
+		}
+		offset = Some(now);
+	}
+	if served.server.is_finished() || served.bots.is_finished() {
+		bail!("the served run ended before its time");
+	}
+
+	let playing = stats::gauge("bot.playing").get();
+	let sent = stats::counter("bot.inputs_sent").get();
+	let dropped = stats::counter("server.stray_disconnects").get();
+	let late = late.get();
+	let expected = late as f64 * (2 * WRAP_WINDOW_TICKS * wraps) as f64 / played as f64;
+	println!(
+		"served: {matches} matches, {played} ticks, {wraps} across the wrap, {late} late inputs, \
+		 {late_at_wraps} around the wrap ({expected:.1} expected), {:.0?} real time",
+		real_started.elapsed()
+	);
+	if wraps == 0 {
+		bail!("no served match reached the wrap");
+	}
+	if playing < DEFAULT_PLAYERS as i64 || dropped > 0 {
+		bail!("the server dropped a bot, {playing} still playing");
+	}
+	// Every player sends an input a tick, give or take the ones in flight
+	if sent < played * DEFAULT_PLAYERS as u64 * 9 / 10 {
+		bail!("the bots sent {sent} inputs for {played} ticks");
+	}
+	if late_at_wraps as f64 > expected * 2.0 + wraps as f64 {
+		bail!("inputs around the wrap came in late");
+	}
+	Ok(())
+}
+
+/// Loopback session on a mock clock: an in-process server and two bot
+/// clients exchanging inputs over lossy-latency queues. Fails when a bot's
+/// confirmed states don't match the server's.
+pub fn run_self_test(seed: u32, stress: Option<Stress>) -> anyhow::Result<()> {
+	check_checksums()?;
+	check_physics()?;
+	check_datagrams()?;
+	check_frames()?;
+	check_playback(seed)?;
+	// Two players, and as many as a match takes
+	check_serve(seed, DEFAULT_PLAYERS, false)?;
+	check_serve(seed, MAX_PLAYERS, true)?;
+	let ticks = match stress {
+		Some(s) => (s.hours * 3600.0 * sim::TPS as f32) as u32,
+		None => TICKS,
+	};
+	// Mock clock and queues count from zero, only the ticks on the wire wrap
+	let start = match stress {
+		Some(_) => 0u32.wrapping_sub(ticks / 2),
+		None => 0,
+	};
+	let tick_at = |t: u32| start.wrapping_add(t);
+	let started = Instant::now();
+
+	let mut jitter = Rng::new(seed ^ 0x5eed);
+	let players = DEFAULT_PLAYERS;
+	let mut bots: Vec<Bot> = (0..players)
+		.map(|id| Bot::new(id, players, seed.wrapping_add(id as u32 * 7919), start))
+		.collect();
+	// Messages travel encoded like real frames, wrapping ticks and all
+	let mut up: Vec<VecDeque<Wire<Vec<u8>>>> = (0..players).map(|_| VecDeque::new()).collect();
+	let mut down: Vec<VecDeque<Wire<Vec<u8>>>> = (0..players).map(|_| VecDeque::new()).collect();
+
+	// Random READY presses end the warm-up a few ticks in, mispredicted ones
+	// move where the rules switch under the bots' rollbacks
+	let mut server = SimState::new(players).warming_up(true);
+	let mut server_tick = 0u32;
+	let mut server_checksums = Vec::new();
+	let mut pending: Vec<Vec<(u32, PlayerInput)>> = vec![Vec::new(); players];
+	let mut last = vec![PlayerInput::from(InputBits::empty()); players];
+	let mut late = 0u32;
+
+	// Run past `ticks` so everything in flight lands
+	let drain = LEAD_TICKS + D_MAX + 2 * JITTER + 16;
+	for now in 0..ticks + drain {
+		if stress.is_some() && now > 0 && now.is_multiple_of(STRESS_REPORT_TICKS) {
+			println!(
+				"{}h of session time in {:.0?}, at tick {}",
+				now / STRESS_REPORT_TICKS,
+				started.elapsed(),
+				tick_at(now)
+			);
+		}
+
+		// Bots
+		for (id, bot) in bots.iter_mut().enumerate() {
+			while down[id].front().is_some_and(|w| w.at <= now) {
+				let w = down[id].pop_front().unwrap();
+				let S2C::TickInputs(t) = S2C::decode(&w.msg).context("decode tick")? else {
+					bail!("tick frame decoded to another message");
+				};
+				bot.receive(t.msg);
+			}
+			if now < ticks {
+				let bits = bot.step();
+				// Stamped so the worst jitter arrives late, exercising own-input rollbacks
+				let (lat_up, _) = LATENCY[id % LATENCY.len()];
+				let stamp = bot.tick.wrapping_add(lat_up - 3);
+				let at = now + lat_up + jitter.next_u32() % (JITTER + 1);
+				// TCP keeps order
+				let at = up[id].back().map_or(at, |w: &Wire<_>| w.at.max(at));
+				let msg = C2S::Input(vec![InputMsg {
+					tick: stamp,
+					bits: bits.bits.as_u8(),
+					aim: bits.aim,
+					ack_tick: bot.tick,
+					sent_us: 0,
+				}]);
+				up[id].push_back(Wire {
+					at,
+					msg: msg.encode()?,
+				});
+			}
+		}
+
+		// Server
+		for (id, q) in up.iter_mut().enumerate() {
+			while q.front().is_some_and(|w| w.at <= now) {
+				let frame = q.pop_front().unwrap().msg;
+				let C2S::Input(msg) = C2S::decode(&frame).context("decode input")? else {
+					bail!("input frame decoded to another message");
+				};
+				let i = msg[0];
+				let (stamp, bits) = (i.tick, PlayerInput::new(i.bits, i.aim));
+				let tick = stamp.wrapping_sub(start);
+				if tick < server_tick {
+					late += 1;
+					continue;
+				}
+				if tick <= server_tick + D_MAX {
+					pending[id].push((tick, bits));
+				}
+			}
+		}
+		let max_tick = now.saturating_sub(LEAD_TICKS).min(ticks);
+		while server_tick < max_tick {
+			let mut inputs = last.clone();
+			for (id, p) in pending.iter_mut().enumerate() {
+				if let Some(&(_, bits)) = p.iter().rev().find(|(t, _)| *t == server_tick) {
+					inputs[id] = bits;
+				}
+				p.retain(|(t, _)| *t > server_tick);
+			}
+			sim::step(&mut server, &inputs);
+			server_checksums.push(sim::checksum(&server));
+
+			let msg = S2C::TickInputs(Stamped {
+				msg: TickInputs {
+					tick: tick_at(server_tick),
+					inputs: inputs.iter().map(|i| i.bits.as_u8()).collect(),
+					aims: inputs.iter().map(|i| i.aim).collect(),
+				},
+				sent_us: 0,
+			})
+			.encode()?;
+			last = inputs;
+			for (id, q) in down.iter_mut().enumerate() {
+				let (_, lat_down) = LATENCY[id % LATENCY.len()];
+				let at = now + lat_down + jitter.next_u32() % (JITTER + 1);
+				let at = q.back().map_or(at, |w: &Wire<_>| w.at.max(at));
+				q.push_back(Wire {
+					at,
+					msg: msg.clone(),
+				});
+			}
+			server_tick += 1;
+		}
+	}
+
+	let mut failed = false;
+	for bot in &bots {
+		let confirmed = server_checksums.len().min(bot.checksums.len());
+		let mismatch = (0..confirmed).find(|&t| bot.checksums[t] != server_checksums[t]);
+		// The checkpoint only ever ran the server's inputs, it must agree with it too
+		let checkpoint_ok = bot.session.checkpoint().is_some_and(|(t, cp)| {
+			let i = bot.index(t);
+			i > 0 && server_checksums.get(i - 1) == Some(&sim::checksum(cp))
+		});
+		println!(
+			"bot {}: {} ticks, {} rollbacks (max depth {}), {}",
+			bot.id,
+			confirmed,
+			bot.rollbacks,
+			bot.max_depth,
+			match mismatch {
+				Some(t) => format!("DESYNC at tick {t}"),
+				None if !checkpoint_ok => "checkpoint DESYNC".to_string(),
+				None => "in sync".to_string(),
+			}
+		);
+		failed |= mismatch.is_some() || !checkpoint_ok || confirmed < ticks as usize;
+	}
+	println!(
+		"server: {server_tick} ticks, {late} late inputs dropped, warm-up over after {}",
+		server.warmup_ticks
+	);
+	failed |= server.warmup;
+	if stress.is_some() {
+		println!(
+			"ticks {} to {}, {:.0?} real time",
+			start,
+			tick_at(server_tick),
+			started.elapsed()
+		);
+	}
+
+	if failed {
+		bail!("self-test failed");
+	}
+	if let Some(stress) = stress {
+		run_served_stress(seed, stress)?;
+	}
+	println!("self-test passed");
+	Ok(())
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::SeriesState;
+
+/// Best-of-N series controller. Lives on the server next to the match loop,
+/// the score carries over between matches while SimState is reset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Series {
+	best_of: u8,
+	// Of every team, there are as many as players at most
+	wins: Vec<u8>,
+}
+
+impl Series {
+	pub fn new(best_of: u8, players: usize) -> Self {
+		Self {
+			best_of: best_of.max(1),
+			wins: vec![0; players],
+		}
+	}
+
+	pub fn record_win(&mut self, winner: usize) -> SeriesState {
+		self.wins[winner] = self.wins[winner].saturating_add(1);
+		SeriesState {
+			best_of: self.best_of,
+			wins: self.wins.clone(),
+			last_winner: winner as u8,
+			finished: self.is_finished(),
+		}
+	}
+
+	pub fn is_finished(&self) -> bool {
+		let needed = self.best_of / 2 + 1;
+		self.wins.iter().any(|&w| w >= needed)
+	}
+}
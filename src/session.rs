@@ -0,0 +1,97 @@
+use std::{
+	collections::VecDeque,
+	fs::{self, File},
+	io::{BufWriter, Write},
+	path::Path,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Instant;
+
+/// Something the person running a test client did to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+	// Command line the client was started with, the netsim policies and chaos
+	// options are all in there
+	Start { args: Vec<String> },
+	// Artificial delay set with the arrow keys
+	Delay { ms: u32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+	// Since the recording started
+	pub at_ms: u64,
+	// Local tick when it happened, what a replayed session keys on
+	pub tick: u32,
+	pub action: Action,
+}
+
+/// Timeline of operator actions, one JSON object per line. Flushed on every
+/// entry, the session worth keeping is usually the one that crashed.
+pub struct SessionRecorder {
+	out: BufWriter<File>,
+	started: Instant,
+}
+
+impl SessionRecorder {
+	pub fn create(path: &Path) -> anyhow::Result<Self> {
+		let out = BufWriter::new(File::create(path).context("create session recording")?);
+		let mut rec = Self {
+			out,
+			started: Instant::now(),
+		};
+		rec.record(
+			0,
+			Action::Start {
+				args: std::env::args().collect(),
+			},
+		)?;
+		Ok(rec)
+	}
+
+	pub fn record(&mut self, tick: u32, action: Action) -> anyhow::Result<()> {
+		let entry = Entry {
+			at_ms: self.started.elapsed().as_millis() as u64,
+			tick,
+			action,
+		};
+		serde_json::to_writer(&mut self.out, &entry)?;
+		self.out.write_all(b"\n")?;
+		self.out.flush()?;
+		Ok(())
+	}
+}
+
+/// A recorded session played back: its actions come due at the ticks they
+/// were recorded at.
+pub struct SessionScript {
+	entries: VecDeque<Entry>,
+}
+
+impl SessionScript {
+	pub fn load(path: &Path) -> anyhow::Result<Self> {
+		let text = fs::read_to_string(path).context("read session recording")?;
+		let entries = text
+			.lines()
+			.enumerate()
+			.filter(|(_, l)| !l.trim().is_empty())
+			.map(|(n, l)| serde_json::from_str(l).with_context(|| format!("line {}", n + 1)))
+			.collect::<anyhow::Result<_>>()?;
+		Ok(Self { entries })
+	}
+
+	// Actions recorded at or before `tick` that haven't been handed out yet
+	pub fn due(&mut self, tick: u32) -> Vec<Action> {
+		let mut out = Vec::new();
+		while let Some(e) = self.entries.front()
+			&& e.tick <= tick
+		{
+			out.push(self.entries.pop_front().unwrap().action);
+		}
+		out
+	}
+}
@@ -0,0 +1,83 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::protocol::InputSignature;
+
+// Authoritative ticks covered by one signature
+pub const SIGN_CHUNK_TICKS: u32 = 60;
+
+// v2 signs the aim next to the buttons, v1 signatures don't verify anymore
+const DOMAIN: &[u8] = b"repl-net-rs inputs v2";
+
+// Bytes a player signs for their inputs of ticks from `start_tick` on,
+// `inputs` holds a (bits, aim) byte pair per tick
+fn chunk_message(player: u8, start_tick: u32, inputs: &[u8]) -> Vec<u8> {
+	let mut msg = Vec::with_capacity(DOMAIN.len() + 5 + inputs.len());
+	msg.extend_from_slice(DOMAIN);
+	msg.push(player);
+	msg.extend_from_slice(&start_tick.to_le_bytes());
+	msg.extend_from_slice(inputs);
+	msg
+}
+
+/// Signs a player's confirmed inputs chunk by chunk as authoritative ticks arrive.
+pub struct InputSigner {
+	key: SigningKey,
+	chunk_start: Option<u32>,
+	chunk: Vec<u8>,
+}
+
+impl InputSigner {
+	pub fn generate() -> anyhow::Result<Self> {
+		let mut seed = [0u8; 32];
+		getrandom::fill(&mut seed).map_err(|e| anyhow::anyhow!("getrandom: {e}"))?;
+		Ok(Self {
+			key: SigningKey::from_bytes(&seed),
+			chunk_start: None,
+			chunk: Vec::new(),
+		})
+	}
+
+	pub fn public_key(&self) -> [u8; 32] {
+		self.key.verifying_key().to_bytes()
+	}
+
+	pub fn reset(&mut self) {
+		self.chunk_start = None;
+		self.chunk.clear();
+	}
+
+	// Feed our authoritative input for `tick`, returns a signature once a chunk is complete.
+	// Chunks are aligned to SIGN_CHUNK_TICKS and a gap in the stream drops the partial chunk.
+	pub fn push(&mut self, player: u8, tick: u32, bits: u8, aim: u8) -> Option<InputSignature> {
+		let expected = self.chunk_start.map(|s| s + self.chunk.len() as u32 / 2);
+		if expected != Some(tick) {
+			self.chunk.clear();
+			self.chunk_start = tick.is_multiple_of(SIGN_CHUNK_TICKS).then_some(tick);
+		}
+		let start = self.chunk_start?;
+		self.chunk.extend_from_slice(&[bits, aim]);
+		if self.chunk.len() < 2 * SIGN_CHUNK_TICKS as usize {
+			return None;
+		}
+
+		let sig = self.key.sign(&chunk_message(player, start, &self.chunk));
+		self.chunk.clear();
+		self.chunk_start = Some(start + SIGN_CHUNK_TICKS);
+		Some(InputSignature {
+			start_tick: start,
+			len: SIGN_CHUNK_TICKS,
+			signature: sig.to_bytes().to_vec(),
+		})
+	}
+}
+
+pub fn verify(key: &[u8; 32], player: u8, sig: &InputSignature, inputs: &[u8]) -> bool {
+	let Ok(key) = VerifyingKey::from_bytes(key) else {
+		return false;
+	};
+	let Ok(sig_bytes) = <[u8; 64]>::try_from(sig.signature.as_slice()) else {
+		return false;
+	};
+	let msg = chunk_message(player, sig.start_tick, inputs);
+	key.verify(&msg, &Signature::from_bytes(&sig_bytes)).is_ok()
+}
@@ -1,14 +1,61 @@
-use macroquad::prelude::{KeyCode, vec2};
+use macroquad::prelude::{KeyCode, MouseButton, Vec2, vec2};
 
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	num::{self, Num, Vector, ZERO, num, vector},
+	physics::{self, Aabb},
+};
 
 pub const BUFFER_W: u32 = 240;
 pub const BUFFER_H: u32 = 140;
 
 pub const TPS: u32 = 60;
 pub const DT: f32 = 1.0 / TPS as f32;
+// DT in the sim's numbers
+const SIM_DT: Num = num(DT);
+
+// Players in a match unless the server asks for more, and the most it may
+// ask for. A match's count is its state's, protocol and netcode follow it
+pub const DEFAULT_PLAYERS: usize = 2;
+pub const MAX_PLAYERS: usize = 4;
 
-pub const PLAYER_COUNT: usize = 2;
+// King of the hill: a team with the hill to itself scores a point per tick
+const HILL_PX: f32 = 48.0;
+pub const HILL_W: Num = num(HILL_PX);
+pub const HILL_X: Num = num((BUFFER_W as f32 - HILL_PX) / 2.0);
+pub const WIN_SCORE: u32 = 5 * TPS;
+
+// The arena's floor, walls and ceiling, just outside the buffer. Sweeps stop
+// at them however thin, the thickness only keeps them easy to read
+const WALL: f32 = 64.0;
+pub const SOLIDS: [Aabb; 4] = [
+	Aabb::new(
+		num(-WALL),
+		num(BUFFER_H as f32),
+		num(BUFFER_W as f32 + 2.0 * WALL),
+		num(WALL),
+	),
+	Aabb::new(
+		num(-WALL),
+		num(-WALL),
+		num(BUFFER_W as f32 + 2.0 * WALL),
+		num(WALL),
+	),
+	Aabb::new(
+		num(-WALL),
+		num(-WALL),
+		num(WALL),
+		num(BUFFER_H as f32 + 2.0 * WALL),
+	),
+	Aabb::new(
+		num(BUFFER_W as f32),
+		num(-WALL),
+		num(WALL),
+		num(BUFFER_H as f32 + 2.0 * WALL),
+	),
+];
 
 bitflags! {
 	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -16,24 +63,122 @@ bitflags! {
 		const LEFT  = 1 << 0;
 		const RIGHT = 1 << 1;
 		const JUMP  = 1 << 2;
+		const FIRE  = 1 << 3;
+		// Done warming up, means nothing once the match is on
+		const READY = 1 << 4;
+		// Swing at whoever is in reach, toward where the player aims
+		const ATTACK = 1 << 5;
 	}
 }
 
-impl InputBits {
-	pub fn from_keyboard() -> Self {
+/// Which keys drive a player. Several players can share one keyboard, each
+/// with their own map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMap {
+	left: KeyCode,
+	right: KeyCode,
+	jump: KeyCode,
+	// None fires with the left mouse button
+	fire: Option<KeyCode>,
+	ready: KeyCode,
+	// None attacks with the right mouse button
+	attack: Option<KeyCode>,
+}
+
+impl KeyMap {
+	// A and D to move, Space to jump, left click to fire, right click to
+	// attack, R when ready
+	pub const MOUSE: Self = Self {
+		left: KeyCode::A,
+		right: KeyCode::D,
+		jump: KeyCode::Space,
+		fire: None,
+		ready: KeyCode::R,
+		attack: None,
+	};
+	// The two halves of a shared keyboard
+	pub const WASD: Self = Self {
+		left: KeyCode::A,
+		right: KeyCode::D,
+		jump: KeyCode::W,
+		fire: Some(KeyCode::Space),
+		ready: KeyCode::Q,
+		attack: Some(KeyCode::E),
+	};
+	pub const ARROWS: Self = Self {
+		left: KeyCode::Left,
+		right: KeyCode::Right,
+		jump: KeyCode::Up,
+		fire: Some(KeyCode::Enter),
+		ready: KeyCode::RightShift,
+		attack: Some(KeyCode::RightControl),
+	};
+}
+
+/// Where a player's buttons come from: keys, or a gamepad::Gamepad.
+pub trait InputSource {
+	fn held(&self) -> InputBits;
+
+	// Went down since the last call, once a frame
+	fn pressed(&mut self) -> InputBits;
+
+	// Its own aim, None to aim with the mouse
+	fn aim(&self) -> Option<u8> {
+		None
+	}
+}
+
+impl InputSource for KeyMap {
+	fn held(&self) -> InputBits {
+		use macroquad::prelude::{is_key_down, is_mouse_button_down};
 		let mut b = InputBits::empty();
-		if macroquad::prelude::is_key_down(KeyCode::A) {
-			b |= InputBits::LEFT;
-		}
-		if macroquad::prelude::is_key_down(KeyCode::D) {
-			b |= InputBits::RIGHT;
-		}
-		if macroquad::prelude::is_key_down(KeyCode::Space) {
-			b |= InputBits::JUMP;
-		}
+		b.set(InputBits::LEFT, is_key_down(self.left));
+		b.set(InputBits::RIGHT, is_key_down(self.right));
+		b.set(InputBits::JUMP, is_key_down(self.jump));
+		b.set(InputBits::READY, is_key_down(self.ready));
+		b.set(
+			InputBits::FIRE,
+			match self.fire {
+				Some(key) => is_key_down(key),
+				None => is_mouse_button_down(MouseButton::Left),
+			},
+		);
+		b.set(
+			InputBits::ATTACK,
+			match self.attack {
+				Some(key) => is_key_down(key),
+				None => is_mouse_button_down(MouseButton::Right),
+			},
+		);
 		b
 	}
 
+	fn pressed(&mut self) -> InputBits {
+		use macroquad::prelude::{is_key_pressed, is_mouse_button_pressed};
+		let mut b = InputBits::empty();
+		b.set(InputBits::LEFT, is_key_pressed(self.left));
+		b.set(InputBits::RIGHT, is_key_pressed(self.right));
+		b.set(InputBits::JUMP, is_key_pressed(self.jump));
+		b.set(InputBits::READY, is_key_pressed(self.ready));
+		b.set(
+			InputBits::FIRE,
+			match self.fire {
+				Some(key) => is_key_pressed(key),
+				None => is_mouse_button_pressed(MouseButton::Left),
+			},
+		);
+		b.set(
+			InputBits::ATTACK,
+			match self.attack {
+				Some(key) => is_key_pressed(key),
+				None => is_mouse_button_pressed(MouseButton::Right),
+			},
+		);
+		b
+	}
+}
+
+impl InputBits {
 	pub fn as_u8(self) -> u8 {
 		self.bits()
 	}
@@ -43,93 +188,1037 @@ impl InputBits {
 	}
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Input sampling for a tick loop that runs at a different rate than the
+/// frames. A button pressed and released between two samples still counts
+/// for the next one, so a short tap holds for exactly one tick. Only the
+/// resulting bits reach the sim, replays and the server see them as usual.
+pub struct TapLatch {
+	keys: Box<dyn InputSource>,
+	taps: InputBits,
+}
+
+impl TapLatch {
+	pub fn with_keys(keys: KeyMap) -> Self {
+		Self::with_source(Box::new(keys))
+	}
+
+	pub fn with_source(keys: Box<dyn InputSource>) -> Self {
+		Self {
+			keys,
+			taps: InputBits::empty(),
+		}
+	}
+
+	// Once per frame, before any tick samples
+	pub fn poll(&mut self) {
+		// Pressed this frame but already up again
+		self.taps |= self.keys.pressed() - self.keys.held();
+	}
+
+	// Keys for one tick, consuming any pending taps
+	pub fn sample(&mut self) -> InputBits {
+		let bits = self.keys.held() | self.taps;
+		self.taps = InputBits::empty();
+		bits
+	}
+
+	// What's down right now, taps left out
+	pub fn held(&self) -> InputBits {
+		self.keys.held()
+	}
+
+	pub fn aim(&self) -> Option<u8> {
+		self.keys.aim()
+	}
+}
+
+/// Everything a player controls in one tick: buttons plus an aim direction,
+/// 256 steps clockwise from +x (screen y points down).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerInput {
+	pub bits: InputBits,
+	pub aim: u8,
+}
+
+impl PlayerInput {
+	pub fn new(bits: u8, aim: u8) -> Self {
+		Self {
+			bits: InputBits::from_u8(bits),
+			aim,
+		}
+	}
+}
+
+impl From<InputBits> for PlayerInput {
+	fn from(bits: InputBits) -> Self {
+		Self { bits, aim: 0 }
+	}
+}
+
+// sin(i * PI / 128) for a quarter turn. Hardcoded because libm sin/cos may
+// round differently across platforms and shot velocities must not.
+const QUARTER_SINE: [f32; 65] = [
+	0.0,
+	0.0245412,
+	0.0490677,
+	0.0735646,
+	0.0980171,
+	0.1224107,
+	0.1467305,
+	0.1709619,
+	0.1950903,
+	0.2191012,
+	0.2429802,
+	0.2667128,
+	0.2902847,
+	0.3136817,
+	0.3368899,
+	0.359895,
+	0.3826834,
+	0.4052413,
+	0.4275551,
+	0.4496113,
+	0.4713967,
+	0.4928982,
+	0.5141027,
+	0.5349976,
+	0.5555702,
+	0.5758082,
+	0.5956993,
+	0.6152316,
+	0.6343933,
+	0.6531728,
+	0.671559,
+	0.6895405,
+	std::f32::consts::FRAC_1_SQRT_2,
+	0.7242471,
+	0.7409511,
+	0.7572088,
+	0.7730105,
+	0.7883464,
+	0.8032075,
+	0.8175848,
+	0.8314696,
+	0.8448536,
+	0.8577286,
+	0.870087,
+	0.8819213,
+	0.8932243,
+	0.9039893,
+	0.9142098,
+	0.9238795,
+	0.9329928,
+	0.9415441,
+	0.9495282,
+	0.9569403,
+	0.9637761,
+	0.9700313,
+	0.9757021,
+	0.9807853,
+	0.9852776,
+	0.9891765,
+	0.9924795,
+	0.9951847,
+	0.9972905,
+	0.9987955,
+	0.9996988,
+	1.0,
+];
+
+// Unit vector for an aim step, exact table lookups only
+pub fn aim_dir(aim: u8) -> Vector {
+	let sin = |a: u8| {
+		let (quadrant, r) = (a / 64, (a % 64) as usize);
+		match quadrant {
+			0 => QUARTER_SINE[r],
+			1 => QUARTER_SINE[64 - r],
+			2 => -QUARTER_SINE[r],
+			_ => -QUARTER_SINE[64 - r],
+		}
+	};
+	vector(num(sin(aim.wrapping_add(64))), num(sin(aim)))
+}
+
+// Client side only, the sim never sees anything but the quantized step
+pub fn quantize_aim(dir: Vec2) -> u8 {
+	let turns = dir.y.atan2(dir.x) / std::f32::consts::TAU;
+	(turns * 256.0).round().rem_euclid(256.0) as u8
+}
+
+// Shots a player can have in flight at once
+pub const MAX_SHOTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Shot {
+	pub x: Num,
+	pub y: Num,
+	pub vx: Num,
+	pub vy: Num,
+	// Ticks left to live, 0 means the slot is free
+	pub ttl: u16,
+	// The owner's `fired` count when it spawned. The sim is deterministic, so a
+	// shot predicted locally has the same id once the server's inputs replay it
+	pub id: u32,
+}
+
+impl Shot {
+	pub const SIZE: Num = num(3.0);
+	const SPEED: Num = num(240.0);
+	const TTL: u16 = TPS as u16;
+	const COOLDOWN: u8 = 20;
+	const KNOCKBACK_UP: Num = num(160.0);
+	const KNOCKBACK_SIDE: Num = num(8.0);
+	const DAMAGE: u8 = 10;
+	const HITSTUN: u8 = 6;
+
+	pub fn center(&self) -> Vector {
+		vector(
+			self.x + Shot::SIZE / num(2.0),
+			self.y + Shot::SIZE / num(2.0),
+		)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
 pub struct Player {
-	pub x: f32,
-	pub y: f32,
-	pub vx: f32,
-	pub vy: f32,
+	pub x: Num,
+	pub y: Num,
+	pub vx: Num,
+	pub vy: Num,
+	pub score: u32,
+	// Own shots live with the player so a player stays one snapshot chunk
+	pub shots: [Shot; MAX_SHOTS],
+	pub cooldown: u8,
+	// Times hit by the other player's shots and attacks
+	pub hits: u16,
+	// Shots spawned so far, the next one's id
+	pub fired: u32,
+	// Pressed READY during the warm-up
+	pub ready: bool,
+	// Knocked out at 0, back at the spawn with all of it
+	pub health: u8,
+	// Ticks left without control after a hit, knockback carries on meanwhile
+	pub hitstun: u8,
+	// Ticks left of the current attack, 0 when not attacking. It reaches to the
+	// left or right, and hits a player only once
+	pub swing: u8,
+	pub swing_left: bool,
+	pub swing_hit: bool,
 }
 
 impl Player {
-	pub const W: f32 = 32.0;
-	pub const H: f32 = 32.0;
+	pub const W: Num = num(32.0);
+	pub const H: Num = num(32.0);
+	const GRAVITY: Num = num(600.0);
+	const MOVE_SPEED: Num = num(90.0);
+	const JUMP_SPEED: Num = num(220.0);
+	// Spawn columns, the first two are where a two player match always started
+	const SPAWN_X: [Num; 4] = [num(20.0), num(100.0), num(180.0), num(60.0)];
+	pub const MAX_HEALTH: u8 = 100;
+	// An attack winds up, hits for a few ticks and recovers, then it can go again
+	const SWING_WINDUP: u8 = 4;
+	const SWING_ACTIVE: u8 = 4;
+	const SWING_RECOVERY: u8 = 12;
+	const REACH: Num = num(14.0);
+	const ATTACK_DAMAGE: u8 = 20;
+	const ATTACK_HITSTUN: u8 = 18;
+	// Speed of the knockback, sideways for as long as the hitstun lasts
+	const ATTACK_KNOCKBACK_SIDE: Num = num(140.0);
+	const ATTACK_KNOCKBACK_UP: Num = num(120.0);
+
+	// Player `i` fresh at its spawn
+	fn spawn(i: usize) -> Self {
+		Self {
+			x: Player::SPAWN_X[i % Player::SPAWN_X.len()],
+			y: num(20.0),
+			health: Player::MAX_HEALTH,
+			..Default::default()
+		}
+	}
+
+	pub fn body(&self) -> Aabb {
+		Aabb::new(self.x, self.y, Player::W, Player::H)
+	}
+
+	pub fn on_ground(&self) -> bool {
+		SOLIDS.iter().any(|s| self.body().stands_on(s))
+	}
+
+	pub fn center(&self) -> Vector {
+		vector(self.x + Player::W / num(2.0), self.y + Player::H / num(2.0))
+	}
+
+	pub fn on_hill(&self) -> bool {
+		let cx = self.x + Player::W / num(2.0);
+		self.on_ground() && (HILL_X..HILL_X + HILL_W).contains(&cx)
+	}
+
+	// The box an attack hits with, none outside the ticks it hits on
+	pub fn swing_box(&self) -> Option<Aabb> {
+		let active = (Player::SWING_RECOVERY + 1..=Player::SWING_RECOVERY + Player::SWING_ACTIVE)
+			.contains(&self.swing);
+		let x = if self.swing_left {
+			self.x - Player::REACH
+		} else {
+			self.x + Player::W
+		};
+		active.then(|| Aabb::new(x, self.y, Player::REACH, Player::H))
+	}
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimState {
-	pub players: [Player; PLAYER_COUNT],
+	pub players: Vec<Player>,
+	// Team of each player, team ids are below the player count
+	pub teams: Vec<u8>,
+	pub team_scores: Vec<u32>,
+	// Free play before the match: nothing scores, and it starts over from the
+	// spawns once every player is ready
+	pub warmup: bool,
+	// Ticks the warm-up took, time limits start after them
+	pub warmup_ticks: u32,
 }
 
 impl SimState {
-	pub fn new() -> Self {
-		let p0 = Player {
-			x: 20.0,
-			y: 20.0,
-			vx: 0.0,
-			vy: 0.0,
+	// `players` players, everyone on their own team
+	pub fn new(players: usize) -> Self {
+		Self::with_teams((0..players as u8).collect())
+	}
+
+	// A player for every team in `teams`
+	pub fn with_teams(teams: Vec<u8>) -> Self {
+		Self {
+			players: (0..teams.len()).map(Player::spawn).collect(),
+			team_scores: vec![0; teams.len()],
+			teams,
+			warmup: false,
+			warmup_ticks: 0,
+		}
+	}
+
+	pub fn player_count(&self) -> usize {
+		self.players.len()
+	}
+
+	// Whether a state from elsewhere can be stepped: a count we play, and a
+	// team below it and a team score for every player
+	pub fn check(&self) -> anyhow::Result<()> {
+		let players = self.player_count();
+		anyhow::ensure!(
+			(1..=MAX_PLAYERS).contains(&players),
+			"{players} players, we play up to {MAX_PLAYERS}"
+		);
+		anyhow::ensure!(
+			self.teams.len() == players && self.team_scores.len() == players,
+			"{players} players in {} teams with {} scores",
+			self.teams.len(),
+			self.team_scores.len()
+		);
+		anyhow::ensure!(
+			self.teams.iter().all(|&t| (t as usize) < players),
+			"team ids {:?} aren't all below {players}",
+			self.teams
+		);
+		Ok(())
+	}
+
+	pub fn warming_up(self, warmup: bool) -> Self {
+		Self { warmup, ..self }
+	}
+
+	/// The state on its own as bincode, for keyframes, saves and debugging.
+	/// Only the same build reads it back, checksum() is what builds compare.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		bincode::serialize(self).expect("a state always serializes")
+	}
+
+	// What to_bytes made, nothing more or less
+	pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+		let state: Self = bincode::deserialize(bytes)?;
+		let len = bincode::serialized_size(&state)? as usize;
+		anyhow::ensure!(
+			len == bytes.len(),
+			"{} bytes after the state",
+			bytes.len() - len
+		);
+		state.check()?;
+		Ok(state)
+	}
+}
+
+// Each player is a snapshot chunk, so idle players are shared between snapshots
+impl crate::snapshot::Chunked for SimState {
+	type Chunk = Player;
+	type Extra = (Vec<u8>, Vec<u32>, bool, u32);
+
+	fn chunk_count(&self) -> usize {
+		self.players.len()
+	}
+
+	fn chunk(&self, i: usize) -> &Player {
+		&self.players[i]
+	}
+
+	fn extra(&self) -> Self::Extra {
+		(
+			self.teams.clone(),
+			self.team_scores.clone(),
+			self.warmup,
+			self.warmup_ticks,
+		)
+	}
+
+	fn assemble(
+		players: Vec<Player>,
+		(teams, team_scores, warmup, warmup_ticks): Self::Extra,
+	) -> Self {
+		Self {
+			players,
+			teams,
+			team_scores,
+			warmup,
+			warmup_ticks,
+		}
+	}
+}
+
+// Rollbacks at least this deep resimulate each player on its own thread
+pub const PARALLEL_RESIM_MIN_TICKS: usize = 64;
+
+// `inputs` has one for every player in `state`
+pub fn step(state: &mut SimState, inputs: &[PlayerInput]) {
+	for (p, &input) in state.players.iter_mut().zip(inputs) {
+		step_player(p, input);
+	}
+	resolve_bodies(state);
+	resolve_hits(state);
+	resolve_attacks(state);
+	respawn_knocked_out(state);
+	if state.warmup {
+		warm_up(state, inputs);
+	} else {
+		score_hill(state);
+	}
+}
+
+// Nothing scores yet, hits and all are forgotten when the match starts on the
+// tick the last player gets ready
+fn warm_up(state: &mut SimState, inputs: &[PlayerInput]) {
+	state.warmup_ticks += 1;
+	for (p, input) in state.players.iter_mut().zip(inputs) {
+		p.ready |= input.bits.contains(InputBits::READY);
+	}
+	if state.players.iter().all(|p| p.ready) {
+		*state = SimState {
+			warmup_ticks: state.warmup_ticks,
+			..SimState::with_teams(std::mem::take(&mut state.teams))
 		};
-		let p1 = Player {
-			x: 100.0,
-			y: 20.0,
-			vx: 0.0,
-			vy: 0.0,
+	}
+}
+
+// Players are solid to each other: once everyone moved, two that overlap are
+// pushed apart along the axis they overlap least on, never into a wall. Pairs
+// go in index order, so a pile-up comes apart the same way everywhere
+fn resolve_bodies(state: &mut SimState) {
+	let count = state.players.len();
+	for a in 0..count {
+		for b in a + 1..count {
+			let (body_a, body_b) = (state.players[a].body(), state.players[b].body());
+			if !body_a.overlaps(&body_b) {
+				continue;
+			}
+			let dx = (body_a.x + body_a.w).min(body_b.x + body_b.w) - body_a.x.max(body_b.x);
+			let dy = (body_a.y + body_a.h).min(body_b.y + body_b.h) - body_a.y.max(body_b.y);
+			if dx <= dy {
+				// Side by side, each gives way by half. What a wall keeps one from
+				// moving the other makes up for
+				let (left, right) = if body_a.x <= body_b.x { (a, b) } else { (b, a) };
+				let moved = push(&mut state.players[left], vector(-dx / num(2.0), ZERO));
+				push(&mut state.players[right], vector(dx + moved.x, ZERO));
+			} else {
+				// One on top of the other: the upper stands on the lower, which
+				// bumps its head
+				let (upper, lower) = if body_a.y <= body_b.y { (a, b) } else { (b, a) };
+				let moved = push(&mut state.players[upper], vector(ZERO, -dy));
+				push(&mut state.players[lower], vector(ZERO, dy + moved.y));
+				state.players[upper].vy = state.players[upper].vy.min(ZERO);
+				state.players[lower].vy = state.players[lower].vy.max(ZERO);
+			}
+		}
+	}
+}
+
+// Moves a player by `delta` or up to the first wall, returns how far it got
+fn push(p: &mut Player, delta: Vector) -> Vector {
+	let mut body = p.body();
+	physics::move_and_slide(&mut body, delta, &SOLIDS);
+	let moved = vector(body.x - p.x, body.y - p.y);
+	(p.x, p.y) = (body.x, body.y);
+	moved
+}
+
+// Where a shot's centre went this tick, a point and its move. Shots fired
+// this tick haven't moved yet
+fn shot_path(shot: &Shot) -> (Aabb, Vector) {
+	let delta = if shot.ttl < Shot::TTL {
+		vector(shot.vx, shot.vy) * SIM_DT
+	} else {
+		Vector::ZERO
+	};
+	let from = shot.center() - delta;
+	(Aabb::new(from.x, from.y, ZERO, ZERO), delta)
+}
+
+// Shots knock the first other player along their path this tick up and away,
+// however fast they fly. A shot that reaches a wall first is gone
+fn resolve_hits(state: &mut SimState) {
+	let count = state.players.len();
+	for shooter in 0..count {
+		for i in 0..MAX_SHOTS {
+			let shot = state.players[shooter].shots[i];
+			if shot.ttl == 0 {
+				continue;
+			}
+			let (from, delta) = shot_path(&shot);
+			let wall = SOLIDS
+				.iter()
+				.filter_map(|s| physics::touch_time(from, delta, *s))
+				.fold(num::MAX, Num::min);
+			let mut hit: Option<(Num, usize)> = None;
+			for target in (0..count).filter(|&t| t != shooter) {
+				let body = state.players[target].body();
+				if let Some(time) = physics::touch_time(from, delta, body)
+					&& time < wall && hit.is_none_or(|(first, _)| time < first)
+				{
+					hit = Some((time, target));
+				}
+			}
+			let Some((_, target)) = hit else {
+				if wall <= num::ONE {
+					state.players[shooter].shots[i].ttl = 0;
+				}
+				continue;
+			};
+			state.players[shooter].shots[i].ttl = 0;
+			let target = &mut state.players[target];
+			take_hit(target, Shot::DAMAGE, Shot::HITSTUN);
+			target.vx = ZERO;
+			target.vy = -Shot::KNOCKBACK_UP;
+			let side = if shot.vx < ZERO {
+				-Shot::KNOCKBACK_SIDE
+			} else {
+				Shot::KNOCKBACK_SIDE
+			};
+			let mut body = target.body();
+			physics::move_and_slide(&mut body, vector(side, ZERO), &SOLIDS);
+			target.x = body.x;
+		}
+	}
+}
+
+// Attacks hit every other player their box overlaps, once per swing. The boxes
+// are taken before anyone is hit, so two players trading blows both land
+fn resolve_attacks(state: &mut SimState) {
+	let boxes: Vec<Option<Aabb>> = state
+		.players
+		.iter()
+		.map(|p| p.swing_box().filter(|_| !p.swing_hit))
+		.collect();
+	for (attacker, hitbox) in boxes.iter().enumerate() {
+		let Some(hitbox) = hitbox else {
+			continue;
+		};
+		let side = if state.players[attacker].swing_left {
+			-Player::ATTACK_KNOCKBACK_SIDE
+		} else {
+			Player::ATTACK_KNOCKBACK_SIDE
 		};
-		Self { players: [p0, p1] }
+		for target in (0..boxes.len()).filter(|&t| t != attacker) {
+			if !hitbox.overlaps(&state.players[target].body()) {
+				continue;
+			}
+			state.players[attacker].swing_hit = true;
+			let target = &mut state.players[target];
+			take_hit(target, Player::ATTACK_DAMAGE, Player::ATTACK_HITSTUN);
+			target.vx = side;
+			target.vy = -Player::ATTACK_KNOCKBACK_UP;
+		}
 	}
 }
 
-pub fn step(state: &mut SimState, inputs: [InputBits; PLAYER_COUNT]) {
-	const GRAVITY: f32 = 600.0;
-	const MOVE_SPEED: f32 = 90.0;
-	const JUMP_SPEED: f32 = 220.0;
+// Costs health and control, and ends the swing the player was in
+fn take_hit(p: &mut Player, damage: u8, hitstun: u8) {
+	p.hits = p.hits.saturating_add(1);
+	p.health = p.health.saturating_sub(damage);
+	p.hitstun = hitstun;
+	p.swing = 0;
+}
 
+// Players out of health start over at their spawn. What they scored, fired
+// and took stays theirs, and so do their shots still in flight
+fn respawn_knocked_out(state: &mut SimState) {
 	for (i, p) in state.players.iter_mut().enumerate() {
-		let input = inputs[i];
-		let mut dx = 0i32;
-		if input.contains(InputBits::LEFT) {
-			dx -= 1;
+		if p.health > 0 {
+			continue;
 		}
-		if input.contains(InputBits::RIGHT) {
-			dx += 1;
+		*p = Player {
+			score: p.score,
+			shots: p.shots,
+			cooldown: p.cooldown,
+			hits: p.hits,
+			fired: p.fired,
+			ready: p.ready,
+			..Player::spawn(i)
+		};
+	}
+}
+
+// Team whose players are the only ones on the hill, none when empty or contested
+fn hill_team(state: &SimState) -> Option<u8> {
+	let mut on_hill = state
+		.players
+		.iter()
+		.zip(&state.teams)
+		.filter(|(p, _)| p.on_hill())
+		.map(|(_, &team)| team);
+	let team = on_hill.next()?;
+	on_hill.all(|t| t == team).then_some(team)
+}
+
+// The holding team scores, its players on the hill keep their own tally too
+fn score_hill(state: &mut SimState) {
+	let Some(team) = hill_team(state) else {
+		return;
+	};
+	state.team_scores[team as usize] += 1;
+	for (p, &t) in state.players.iter_mut().zip(&state.teams) {
+		if t == team && p.on_hill() {
+			p.score += 1;
 		}
-		p.vx = dx as f32 * MOVE_SPEED;
+	}
+}
 
-		let on_ground = p.y + Player::H >= BUFFER_H as f32;
-		if input.contains(InputBits::JUMP) && on_ground {
-			p.vy = -JUMP_SPEED;
+// FNV-1a, stable across runs and platforms unlike std's hashers
+fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+	bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+		(h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+	})
+}
+
+// Every field of the state in a fixed order, little-endian, so the bytes don't
+// depend on the platform, the compiler's layout or a serializer's format
+fn canonical_bytes(state: &SimState) -> Vec<u8> {
+	fn num_bytes(out: &mut Vec<u8>, v: Num) {
+		out.extend_from_slice(&num::to_bits(v).to_le_bytes());
+	}
+	let SimState {
+		players,
+		teams,
+		team_scores,
+		warmup,
+		warmup_ticks,
+	} = state;
+	let mut out = vec![players.len() as u8];
+	for p in players {
+		// Destructured so a new field doesn't compile until it's hashed too
+		let Player {
+			x,
+			y,
+			vx,
+			vy,
+			score,
+			shots,
+			cooldown,
+			hits,
+			fired,
+			ready,
+			health,
+			hitstun,
+			swing,
+			swing_left,
+			swing_hit,
+		} = p;
+		for v in [x, y, vx, vy] {
+			num_bytes(&mut out, *v);
 		}
+		out.extend_from_slice(&score.to_le_bytes());
+		for shot in shots {
+			let Shot {
+				x,
+				y,
+				vx,
+				vy,
+				ttl,
+				id,
+			} = shot;
+			for v in [x, y, vx, vy] {
+				num_bytes(&mut out, *v);
+			}
+			out.extend_from_slice(&ttl.to_le_bytes());
+			out.extend_from_slice(&id.to_le_bytes());
+		}
+		out.push(*cooldown);
+		out.extend_from_slice(&hits.to_le_bytes());
+		out.extend_from_slice(&fired.to_le_bytes());
+		out.push(*ready as u8);
+		out.extend_from_slice(&[
+			*health,
+			*hitstun,
+			*swing,
+			*swing_left as u8,
+			*swing_hit as u8,
+		]);
+	}
+	out.extend_from_slice(teams);
+	for s in team_scores {
+		out.extend_from_slice(&s.to_le_bytes());
+	}
+	out.push(*warmup as u8);
+	out.extend_from_slice(&warmup_ticks.to_le_bytes());
+	out
+}
+
+pub fn checksum(state: &SimState) -> u64 {
+	fnv1a(canonical_bytes(state))
+}
+
+// Bump when the rules or the state's layout change in a way none of the
+// constants in `fingerprint` show. v2: shots carry ids. v3: checksums hash
+// the canonical form. v4: warm-ups. v5: swept collisions, shots stop at walls.
+// v6: players collide with each other. v7: attacks, health and hitstun.
+// v8: the player count is the match's, checksums start with it
+pub const SIM_VERSION: u32 = 8;
+
+/// What this build simulates: the sim version and every constant outcomes
+/// depend on. Peers with different fingerprints are bound to desync.
+pub fn fingerprint() -> u64 {
+	let ints = [
+		SIM_VERSION,
+		TPS,
+		BUFFER_W,
+		BUFFER_H,
+		WIN_SCORE,
+		MAX_PLAYERS as u32,
+		MAX_SHOTS as u32,
+		Shot::TTL as u32,
+		Shot::COOLDOWN as u32,
+		Shot::DAMAGE as u32,
+		Shot::HITSTUN as u32,
+		Player::MAX_HEALTH as u32,
+		Player::SWING_WINDUP as u32,
+		Player::SWING_ACTIVE as u32,
+		Player::SWING_RECOVERY as u32,
+		Player::ATTACK_DAMAGE as u32,
+		Player::ATTACK_HITSTUN as u32,
+	];
+	// Q16.16 constants' bits differ from the f32 ones, so the fixed-point
+	// build never matches an f32 one
+	let nums = [
+		HILL_W,
+		Shot::SIZE,
+		Shot::SPEED,
+		Shot::KNOCKBACK_UP,
+		Shot::KNOCKBACK_SIDE,
+		Player::W,
+		Player::H,
+		Player::GRAVITY,
+		Player::MOVE_SPEED,
+		Player::JUMP_SPEED,
+		Player::REACH,
+		Player::ATTACK_KNOCKBACK_SIDE,
+		Player::ATTACK_KNOCKBACK_UP,
+	]
+	.into_iter()
+	.chain(Player::SPAWN_X)
+	.map(num::to_bits)
+	.chain(QUARTER_SINE.map(f32::to_bits));
+	fnv1a(ints.into_iter().chain(nums).flat_map(u32::to_le_bytes))
+}
 
-		p.vy += GRAVITY * DT;
+// Team with the most points, none on a tie
+pub fn leader(state: &SimState) -> Option<u8> {
+	let best = *state.team_scores.iter().max()?;
+	let mut leaders = (0..state.team_scores.len()).filter(|&t| state.team_scores[t] == best);
+	let team = leaders.next()?;
+	leaders.next().is_none().then_some(team as u8)
+}
+
+// First team to reach WIN_SCORE
+pub fn winner(state: &SimState) -> Option<u8> {
+	state
+		.team_scores
+		.iter()
+		.position(|&s| s >= WIN_SCORE)
+		.map(|t| t as u8)
+}
 
-		p.x += p.vx * DT;
-		p.y += p.vy * DT;
+/// Something worth reacting to in presentation, derived from two consecutive states.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimEvent {
+	// Player touched down, `speed` is the fall speed right before impact
+	Land { player: u8, speed: f32 },
+	// Player became the only one on the hill
+	HillTaken { player: u8 },
+	// Player was hit by a shot
+	Hit { player: u8 },
+	// Player spawned shot `shot`
+	ShotFired { player: u8, shot: u32 },
+}
+
+fn hill_holder(state: &SimState) -> Option<usize> {
+	let mut on_hill = state
+		.players
+		.iter()
+		.enumerate()
+		.filter(|(_, p)| p.on_hill());
+	match (on_hill.next(), on_hill.next()) {
+		(Some((i, _)), None) => Some(i),
+		_ => None,
+	}
+}
+
+pub fn events(prev: &SimState, next: &SimState) -> Vec<SimEvent> {
+	let mut out = Vec::new();
+	for (i, (a, b)) in prev.players.iter().zip(&next.players).enumerate() {
+		if !a.on_ground() && b.on_ground() {
+			out.push(SimEvent::Land {
+				player: i as u8,
+				speed: num::to_f32(a.vy),
+			});
+		}
+		if b.hits > a.hits {
+			out.push(SimEvent::Hit { player: i as u8 });
+		}
+		if b.fired != a.fired {
+			out.push(SimEvent::ShotFired {
+				player: i as u8,
+				shot: a.fired,
+			});
+		}
+	}
+	if let Some(holder) = hill_holder(next)
+		&& hill_holder(prev) != Some(holder)
+	{
+		out.push(SimEvent::HillTaken {
+			player: holder as u8,
+		});
+	}
+	out
+}
 
-		if p.x < 0.0 {
-			p.x = 0.0;
+fn step_player(p: &mut Player, input: PlayerInput) {
+	// Hitstun takes the controls away for a while
+	let stunned = p.hitstun > 0;
+	p.hitstun = p.hitstun.saturating_sub(1);
+	let input = if stunned {
+		PlayerInput {
+			bits: InputBits::empty(),
+			..input
 		}
-		let max_x = BUFFER_W as f32 - Player::W;
-		if p.x > max_x {
-			p.x = max_x;
+	} else {
+		input
+	};
+	step_shots(p, input);
+	step_swing(p, input);
+	let input = input.bits;
+
+	let mut dx = 0i32;
+	if input.contains(InputBits::LEFT) {
+		dx -= 1;
+	}
+	if input.contains(InputBits::RIGHT) {
+		dx += 1;
+	}
+	// A stunned player keeps the knockback's speed
+	if !stunned {
+		p.vx = num::int(dx) * Player::MOVE_SPEED;
+	}
+
+	if input.contains(InputBits::JUMP) && p.on_ground() {
+		p.vy = -Player::JUMP_SPEED;
+	}
+
+	p.vy += Player::GRAVITY * SIM_DT;
+
+	let mut body = p.body();
+	let blocked = physics::move_and_slide(&mut body, vector(p.vx, p.vy) * SIM_DT, &SOLIDS);
+	(p.x, p.y) = (body.x, body.y);
+	if blocked.x {
+		p.vx = ZERO;
+	}
+	if blocked.y {
+		p.vy = ZERO;
+	}
+}
+
+// Counts the current swing down, or starts one toward where the player aims
+fn step_swing(p: &mut Player, input: PlayerInput) {
+	if p.swing > 0 {
+		p.swing -= 1;
+		return;
+	}
+	if input.bits.contains(InputBits::ATTACK) {
+		p.swing = Player::SWING_WINDUP + Player::SWING_ACTIVE + Player::SWING_RECOVERY;
+		p.swing_left = aim_dir(input.aim).x < ZERO;
+		p.swing_hit = false;
+	}
+}
+
+// Move the player's shots, then fire a new one from the player's centre
+fn step_shots(p: &mut Player, input: PlayerInput) {
+	// Walls and players stop them in resolve_hits, which sees the whole path
+	for shot in p.shots.iter_mut().filter(|s| s.ttl > 0) {
+		shot.x += shot.vx * SIM_DT;
+		shot.y += shot.vy * SIM_DT;
+		shot.ttl -= 1;
+	}
+
+	p.cooldown = p.cooldown.saturating_sub(1);
+	if !input.bits.contains(InputBits::FIRE) || p.cooldown > 0 {
+		return;
+	}
+	let dir = aim_dir(input.aim);
+	let c = p.center();
+	let Some(slot) = p.shots.iter_mut().find(|s| s.ttl == 0) else {
+		return;
+	};
+	*slot = Shot {
+		x: c.x - Shot::SIZE / num(2.0),
+		y: c.y - Shot::SIZE / num(2.0),
+		vx: dir.x * Shot::SPEED,
+		vy: dir.y * Shot::SPEED,
+		ttl: Shot::TTL,
+		id: p.fired,
+	};
+	p.fired = p.fired.wrapping_add(1);
+	p.cooldown = Shot::COOLDOWN;
+}
+
+/// The arena as a rollback game, its saved states share unchanged chunks.
+pub struct Arena {
+	history: crate::snapshot::SnapshotRing<SimState>,
+}
+
+impl Arena {
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			history: crate::snapshot::SnapshotRing::new(capacity),
 		}
+	}
+}
 
-		if p.y < 0.0 {
-			p.y = 0.0;
-			if p.vy < 0.0 {
-				p.vy = 0.0;
-			}
+impl crate::rollback::Game for Arena {
+	type State = SimState;
+	type Inputs = Vec<PlayerInput>;
+
+	fn step(&self, state: &mut SimState, inputs: &Self::Inputs) {
+		step(state, inputs);
+	}
+
+	fn resimulate(&self, start: &SimState, inputs: &[Self::Inputs]) -> Vec<SimState> {
+		resimulate(start, inputs)
+	}
+
+	fn save(&mut self, tick: u32, state: &SimState) {
+		self.history.save(tick, state);
+	}
+
+	fn load(&self, tick: u32) -> Option<SimState> {
+		self.history.load(tick)
+	}
+
+	fn forget(&mut self, tick: u32) {
+		self.history.forget(tick);
+	}
+
+	fn clear(&mut self) {
+		self.history.clear();
+	}
+}
+
+/// Re-run `inputs` from `start`, returning the state after each tick.
+///
+/// Movement only interacts between players that touch, so deep rollbacks move
+/// each player on its own thread and only the hill scoring runs in tick order.
+/// From the first tick two of them overlap it goes on sequentially. Shots and
+/// attacks do interact, any in progress or started keeps it sequential, and so
+/// does a warm-up, which ends for everyone at once. Shallow rollbacks aren't worth
+/// the spawn cost.
+pub fn resimulate(start: &SimState, inputs: &[Vec<PlayerInput>]) -> Vec<SimState> {
+	let shots_involved = start
+		.players
+		.iter()
+		.any(|p| p.swing > 0 || p.shots.iter().any(|s| s.ttl > 0))
+		|| inputs
+			.iter()
+			.flatten()
+			.any(|i| i.bits.intersects(InputBits::FIRE | InputBits::ATTACK));
+	if inputs.len() < PARALLEL_RESIM_MIN_TICKS || shots_involved || start.warmup {
+		return resimulate_sequential(start, inputs);
+	}
+
+	let trajectories: Vec<Vec<Player>> = std::thread::scope(|scope| {
+		let handles: Vec<_> = (0..start.players.len())
+			.map(|pid| {
+				scope.spawn(move || {
+					let mut p = start.players[pid];
+					inputs
+						.iter()
+						.map(|i| {
+							step_player(&mut p, i[pid]);
+							p
+						})
+						.collect::<Vec<_>>()
+				})
+			})
+			.collect();
+		handles
+			.into_iter()
+			.map(|h| h.join().expect("resim thread"))
+			.collect()
+	});
+
+	let mut state = start.clone();
+	let mut states = Vec::with_capacity(inputs.len());
+	for t in 0..inputs.len() {
+		let before = state.clone();
+		for (p, traj) in state.players.iter_mut().zip(&trajectories) {
+			*p = Player {
+				score: p.score,
+				..traj[t]
+			};
 		}
-		let max_y = BUFFER_H as f32 - Player::H;
-		if p.y > max_y {
-			p.y = max_y;
-			if p.vy > 0.0 {
-				p.vy = 0.0;
-			}
+		if touching(&state) {
+			states.extend(resimulate_sequential(&before, &inputs[t..]));
+			break;
 		}
+		score_hill(&mut state);
+		states.push(state.clone());
 	}
+	states
+}
+
+fn resimulate_sequential(start: &SimState, inputs: &[Vec<PlayerInput>]) -> Vec<SimState> {
+	let mut state = start.clone();
+	inputs
+		.iter()
+		.map(|i| {
+			step(&mut state, i);
+			state.clone()
+		})
+		.collect()
+}
+
+// Whether any two players overlap, which resolve_bodies would have undone
+fn touching(state: &SimState) -> bool {
+	let players = &state.players;
+	(0..players.len())
+		.any(|a| (a + 1..players.len()).any(|b| players[a].body().overlaps(&players[b].body())))
 }
 
-pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
+// Drawing only, between two of the sim's numbers
+pub fn lerp(a: Num, b: Num, t: f32) -> f32 {
+	let (a, b) = (num::to_f32(a), num::to_f32(b));
 	a + (b - a) * t
 }
 
@@ -0,0 +1,95 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, bail};
+use serde::Serialize;
+
+use crate::sim::{self, InputBits, PlayerInput, SimState};
+
+#[derive(Serialize)]
+struct Outcome {
+	ticks: u32,
+	// Hex, JSON numbers lose precision past 2^53
+	checksum: String,
+	winner: Option<u8>,
+	state: SimState,
+}
+
+// One line per tick: held keys out of L, R, J, F, A ("-" for none), then an
+// optional aim byte and an optional `*n` to hold the line for n ticks.
+// `#` starts a comment, e.g. `RJ 64 *30`
+pub fn parse_script(text: &str) -> anyhow::Result<Vec<PlayerInput>> {
+	let mut out = Vec::new();
+	for (n, line) in text.lines().enumerate() {
+		let line = line.split('#').next().unwrap_or("").trim();
+		if line.is_empty() {
+			continue;
+		}
+		let ctx = || format!("line {}", n + 1);
+		let mut words = line.split_whitespace();
+		let mut bits = InputBits::empty();
+		for c in words.next().unwrap_or("-").chars() {
+			bits |= match c.to_ascii_uppercase() {
+				'L' => InputBits::LEFT,
+				'R' => InputBits::RIGHT,
+				'J' => InputBits::JUMP,
+				'F' => InputBits::FIRE,
+				'A' => InputBits::ATTACK,
+				'-' => InputBits::empty(),
+				c => bail!("{}: unknown key {c:?}", ctx()),
+			};
+		}
+		let mut aim = 0u8;
+		let mut repeat = 1usize;
+		for w in words {
+			match w.strip_prefix('*') {
+				Some(r) => repeat = r.parse().with_context(ctx)?,
+				None => aim = w.parse().with_context(ctx)?,
+			}
+		}
+		out.extend(std::iter::repeat_n(PlayerInput { bits, aim }, repeat));
+	}
+	Ok(out)
+}
+
+/// Run `ticks` ticks from a fresh match with scripted inputs, players hold
+/// their last scripted input once their script runs out, like the server does.
+/// Players past the last script stay idle.
+pub fn run_simulate(
+	players: usize,
+	ticks: u32,
+	scripts: &[&Path],
+	out: Option<&Path>,
+) -> anyhow::Result<()> {
+	if scripts.len() > players {
+		bail!("{} input scripts for {players} players", scripts.len());
+	}
+	let mut inputs = Vec::new();
+	for path in scripts {
+		let text = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+		inputs.push(parse_script(&text).with_context(|| path.display().to_string())?);
+	}
+
+	let mut state = SimState::new(players);
+	let mut held = vec![PlayerInput::from(InputBits::empty()); players];
+	for t in 0..ticks as usize {
+		for (h, script) in held.iter_mut().zip(&inputs) {
+			if let Some(&i) = script.get(t) {
+				*h = i;
+			}
+		}
+		sim::step(&mut state, &held);
+	}
+
+	let outcome = Outcome {
+		ticks,
+		checksum: format!("{:016x}", sim::checksum(&state)),
+		winner: sim::winner(&state),
+		state,
+	};
+	let json = serde_json::to_string_pretty(&outcome)?;
+	match out {
+		Some(path) => fs::write(path, json).with_context(|| format!("write {}", path.display()))?,
+		None => println!("{json}"),
+	}
+	Ok(())
+}
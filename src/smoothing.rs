@@ -0,0 +1,68 @@
+use macroquad::prelude::Vec2;
+
+use crate::sim::SimState;
+
+// Time for a visual offset to shrink to half, short enough to read as a glide
+const HALF_LIFE_SECS: f32 = 0.05;
+
+// Offsets below this are dropped, in buffer pixels
+const EPSILON_PX: f32 = 0.1;
+
+// Rollback jumps shorter than this hide in the render interpolation
+const SIGNIFICANT_PX: f32 = 1.0;
+
+// A rollback jump further than this snaps, gliding across the level reads as a bug
+pub const MAX_SMOOTH_PX: f32 = 64.0;
+
+/// Presentation-only offsets that hide a rollback or correction: the sim
+/// jumps to the corrected state at once, while what's drawn starts where
+/// each player was and glides over.
+#[derive(Debug, Default)]
+pub struct VisualOffsets {
+	// One per player of the match, none before the first jump
+	offsets: Vec<Vec2>,
+}
+
+impl VisualOffsets {
+	pub fn clear(&mut self) {
+		self.offsets.clear();
+	}
+
+	// The sim moved from `old` to `new` in one go, keep drawing players where they
+	// were when the jump is at most `max_px`. Returns how many jumped further and snap.
+	pub fn absorb(&mut self, old: &SimState, new: &SimState, max_px: f32) -> usize {
+		let mut snapped = 0;
+		self.offsets.resize(new.players.len(), Vec2::ZERO);
+		for (offset, (a, b)) in self
+			.offsets
+			.iter_mut()
+			.zip(old.players.iter().zip(&new.players))
+		{
+			let delta = (a.center() - b.center()).to_vec2();
+			if delta.length() < SIGNIFICANT_PX {
+				continue;
+			}
+			if delta.length() <= max_px {
+				*offset += delta;
+			} else {
+				*offset = Vec2::ZERO;
+				snapped += 1;
+			}
+		}
+		snapped
+	}
+
+	pub fn decay(&mut self, dt: f32) {
+		let keep = 0.5f32.powf(dt / HALF_LIFE_SECS);
+		for o in self.offsets.iter_mut() {
+			*o *= keep;
+			if o.length() < EPSILON_PX {
+				*o = Vec2::ZERO;
+			}
+		}
+	}
+
+	pub fn get(&self, player: usize) -> Vec2 {
+		self.offsets.get(player).copied().unwrap_or_default()
+	}
+}
@@ -0,0 +1,92 @@
+use std::rc::Rc;
+
+/// State that can be split into independently shareable chunks.
+pub trait Chunked {
+	type Chunk: Clone + PartialEq;
+	// Small state outside the chunks, copied whole into every snapshot
+	type Extra: Clone;
+
+	fn chunk_count(&self) -> usize;
+	fn chunk(&self, i: usize) -> &Self::Chunk;
+	fn extra(&self) -> Self::Extra;
+	// The state back from its chunks, as many as it had, and its extra
+	fn assemble(chunks: Vec<Self::Chunk>, extra: Self::Extra) -> Self;
+}
+
+struct Snapshot<T: Chunked> {
+	tick: u32,
+	chunks: Vec<Rc<T::Chunk>>,
+	extra: T::Extra,
+}
+
+/// Rolling history of states where a snapshot shares every chunk that didn't
+/// change since the previous save, so unchanged data is stored once.
+pub struct SnapshotRing<T: Chunked> {
+	slots: Vec<Option<Snapshot<T>>>,
+	last: Option<usize>,
+}
+
+impl<T: Chunked> SnapshotRing<T> {
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			slots: (0..capacity).map(|_| None).collect(),
+			last: None,
+		}
+	}
+
+	pub fn save(&mut self, tick: u32, state: &T) {
+		let idx = (tick as usize) % self.slots.len();
+		let prev = self.last.and_then(|i| self.slots[i].as_ref());
+		let chunks = (0..state.chunk_count())
+			.map(|i| {
+				let cur = state.chunk(i);
+				match prev.and_then(|p| p.chunks.get(i)) {
+					Some(shared) if **shared == *cur => Rc::clone(shared),
+					_ => Rc::new(cur.clone()),
+				}
+			})
+			.collect();
+		self.slots[idx] = Some(Snapshot {
+			tick,
+			chunks,
+			extra: state.extra(),
+		});
+		self.last = Some(idx);
+	}
+
+	pub fn load(&self, tick: u32) -> Option<T> {
+		let idx = (tick as usize) % self.slots.len();
+		let snap = self.slots[idx].as_ref().filter(|s| s.tick == tick)?;
+		let chunks = snap.chunks.iter().map(|c| (**c).clone()).collect();
+		Some(T::assemble(chunks, snap.extra.clone()))
+	}
+
+	// Drops the snapshot of `tick`, if the ring still has it
+	pub fn forget(&mut self, tick: u32) {
+		let idx = (tick as usize) % self.slots.len();
+		if self.slots[idx].as_ref().is_some_and(|s| s.tick == tick) {
+			self.slots[idx] = None;
+			if self.last == Some(idx) {
+				self.last = None;
+			}
+		}
+	}
+
+	pub fn clear(&mut self) {
+		for s in self.slots.iter_mut() {
+			*s = None;
+		}
+		self.last = None;
+	}
+
+	// Bytes of chunk data actually retained, counting shared chunks once
+	pub fn retained_bytes(&self) -> usize {
+		let mut seen = std::collections::HashSet::new();
+		for snap in self.slots.iter().flatten() {
+			for c in &snap.chunks {
+				seen.insert(Rc::as_ptr(c));
+			}
+		}
+		seen.len() * std::mem::size_of::<T::Chunk>()
+	}
+}
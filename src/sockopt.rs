@@ -0,0 +1,109 @@
+use std::{io, net::TcpStream, time::Duration};
+
+use macroquad::prelude::warn;
+
+/// TCP tuning applied to every connection, server and client side. The
+/// defaults are what the netcode was tuned with: Nagle off, OS buffers.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+	// TCP_NODELAY, with it off small frames wait to be batched
+	pub nodelay: bool,
+	// SO_SNDBUF / SO_RCVBUF in bytes, the OS may round or double them
+	pub send_buffer: Option<usize>,
+	pub recv_buffer: Option<usize>,
+	// Idle time before keepalive probes, and between them
+	pub keepalive: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+	fn default() -> Self {
+		Self {
+			nodelay: true,
+			send_buffer: None,
+			recv_buffer: None,
+			keepalive: None,
+		}
+	}
+}
+
+impl SocketOptions {
+	// Failures are logged, a connection still works without its tuning
+	pub fn apply(&self, stream: &TcpStream) {
+		if let Err(e) = self.try_apply(stream) {
+			warn!("socket options: {}", e);
+		}
+	}
+
+	fn try_apply(&self, stream: &TcpStream) -> io::Result<()> {
+		stream.set_nodelay(self.nodelay)?;
+		if self.send_buffer.is_none() && self.recv_buffer.is_none() && self.keepalive.is_none() {
+			return Ok(());
+		}
+		platform::apply(self, stream)
+	}
+}
+
+#[cfg(unix)]
+mod platform {
+	use std::{io, net::TcpStream, os::fd::AsRawFd};
+
+	use super::SocketOptions;
+
+	fn set(
+		stream: &TcpStream,
+		level: libc::c_int,
+		name: libc::c_int,
+		value: usize,
+	) -> io::Result<()> {
+		let value = libc::c_int::try_from(value)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "socket option too large"))?;
+		// SAFETY: the fd is open for the lifetime of `stream` and `value` outlives the call
+		let rc = unsafe {
+			libc::setsockopt(
+				stream.as_raw_fd(),
+				level,
+				name,
+				(&value as *const libc::c_int).cast(),
+				size_of::<libc::c_int>() as libc::socklen_t,
+			)
+		};
+		if rc == 0 {
+			Ok(())
+		} else {
+			Err(io::Error::last_os_error())
+		}
+	}
+
+	pub fn apply(opts: &SocketOptions, stream: &TcpStream) -> io::Result<()> {
+		if let Some(bytes) = opts.send_buffer {
+			set(stream, libc::SOL_SOCKET, libc::SO_SNDBUF, bytes)?;
+		}
+		if let Some(bytes) = opts.recv_buffer {
+			set(stream, libc::SOL_SOCKET, libc::SO_RCVBUF, bytes)?;
+		}
+		if let Some(interval) = opts.keepalive {
+			let secs = interval.as_secs().max(1) as usize;
+			set(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+			#[cfg(any(target_os = "linux", target_os = "android"))]
+			set(stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+			#[cfg(target_vendor = "apple")]
+			set(stream, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, secs)?;
+			set(stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(not(unix))]
+mod platform {
+	use std::{io, net::TcpStream};
+
+	use super::SocketOptions;
+
+	pub fn apply(_: &SocketOptions, _: &TcpStream) -> io::Result<()> {
+		Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"buffer sizes and keepalive need a unix socket API",
+		))
+	}
+}
@@ -0,0 +1,94 @@
+use std::{
+	collections::BTreeMap,
+	sync::{
+		Mutex, OnceLock,
+		atomic::{AtomicI64, AtomicU64, Ordering},
+	},
+};
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+	pub fn inc(&self) {
+		self.add(1);
+	}
+
+	pub fn add(&self, n: u64) {
+		self.0.fetch_add(n, Ordering::Relaxed);
+	}
+
+	pub fn get(&self) -> u64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+	pub fn set(&self, v: i64) {
+		self.0.store(v, Ordering::Relaxed);
+	}
+
+	pub fn add(&self, n: i64) {
+		self.0.fetch_add(n, Ordering::Relaxed);
+	}
+
+	pub fn get(&self) -> i64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+#[derive(Clone, Copy)]
+enum Metric {
+	Counter(&'static Counter),
+	Gauge(&'static Gauge),
+}
+
+// Metrics live for the whole process, so handles are plain &'static and
+// updating one never takes the lock
+fn registry() -> &'static Mutex<BTreeMap<&'static str, Metric>> {
+	static REGISTRY: OnceLock<Mutex<BTreeMap<&'static str, Metric>>> = OnceLock::new();
+	REGISTRY.get_or_init(Default::default)
+}
+
+/// The counter registered as `name`, registering it on first use. Names are
+/// dotted by owner, e.g. `net.frames_sent`.
+pub fn counter(name: &'static str) -> &'static Counter {
+	let mut reg = registry().lock().unwrap();
+	match *reg
+		.entry(name)
+		.or_insert_with(|| Metric::Counter(Box::leak(Box::default())))
+	{
+		Metric::Counter(c) => c,
+		Metric::Gauge(_) => panic!("stat {name} is a gauge"),
+	}
+}
+
+pub fn gauge(name: &'static str) -> &'static Gauge {
+	let mut reg = registry().lock().unwrap();
+	match *reg
+		.entry(name)
+		.or_insert_with(|| Metric::Gauge(Box::leak(Box::default())))
+	{
+		Metric::Gauge(g) => g,
+		Metric::Counter(_) => panic!("stat {name} is a counter"),
+	}
+}
+
+// Every registered metric and its current value, sorted by name
+pub fn snapshot() -> Vec<(&'static str, i64)> {
+	registry()
+		.lock()
+		.unwrap()
+		.iter()
+		.map(|(&name, m)| {
+			let v = match m {
+				Metric::Counter(c) => c.get() as i64,
+				Metric::Gauge(g) => g.get(),
+			};
+			(name, v)
+		})
+		.collect()
+}
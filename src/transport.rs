@@ -0,0 +1,694 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	io::{self, Read, Write},
+	net::{Shutdown, SocketAddr, TcpStream},
+	sync::{Arc, Condvar, Mutex, OnceLock},
+	time::{Duration, Instant},
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt, BufReader},
+	net::{
+		TcpListener,
+		tcp::{OwnedReadHalf, OwnedWriteHalf},
+	},
+};
+use tokio::{
+	runtime::{Builder, Runtime},
+	sync::{
+		Notify,
+		mpsc::{self, error::TrySendError},
+		watch,
+	},
+};
+
+use crate::{
+	protocol::MAX_FRAME_BYTES,
+	sockopt::SocketOptions,
+	stats::{self, Counter},
+	websocket,
+};
+
+/// A connection carrying whole frames, what the server and client loops are
+/// written against. Frames arrive complete and in order, once each.
+pub trait Transport: Send {
+	fn send_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+
+	// Blocks until a frame arrives, the timeout runs out or the connection closes
+	fn recv_frame(&mut self) -> io::Result<Vec<u8>>;
+
+	// Second handle on the same connection, readers and writers run on different threads
+	fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+	// None waits forever
+	fn set_recv_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+	// Ends the connection for every handle, blocked reads return an error
+	fn close(&self);
+
+	// Address of the other end for side channels like UDP inputs, none when the
+	// backend has no such thing
+	fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+// First and longest wait after a failed accept
+const BACKOFF_MIN: Duration = Duration::from_millis(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Waits between failed accepts, twice as long each time in a row. Out of file
+/// descriptors every accept fails at once, retrying right away would spin.
+#[derive(Debug, Default)]
+pub struct Backoff(u32);
+
+impl Backoff {
+	pub async fn failed(&mut self) {
+		tokio::time::sleep(BACKOFF_MIN.saturating_mul(1 << self.0).min(BACKOFF_MAX)).await;
+		self.0 = (self.0 + 1).min(16);
+	}
+
+	pub fn succeeded(&mut self) {
+		self.0 = 0;
+	}
+}
+
+// Traffic of every connection in the process, TCP length prefixes and UDP
+// datagrams included
+pub struct FrameStats {
+	pub sent: &'static Counter,
+	pub sent_bytes: &'static Counter,
+	pub received: &'static Counter,
+	pub received_bytes: &'static Counter,
+}
+
+pub fn frame_stats() -> &'static FrameStats {
+	static STATS: OnceLock<FrameStats> = OnceLock::new();
+	STATS.get_or_init(|| FrameStats {
+		sent: stats::counter("net.frames_sent"),
+		sent_bytes: stats::counter("net.bytes_sent"),
+		received: stats::counter("net.frames_received"),
+		received_bytes: stats::counter("net.bytes_received"),
+	})
+}
+
+pub fn count_sent(bytes: usize) {
+	let s = frame_stats();
+	s.sent.inc();
+	s.sent_bytes.add(bytes as u64);
+}
+
+pub fn count_received(bytes: usize) {
+	let s = frame_stats();
+	s.received.inc();
+	s.received_bytes.add(bytes as u64);
+}
+
+fn invalid(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Frames are their length, seven bits a byte low first with the top bit set
+// on all but the last, followed by the payload. A tick's frame takes one byte
+// of length
+fn length_prefixed(frame: &[u8]) -> Vec<u8> {
+	let mut buf = Vec::with_capacity(5 + frame.len());
+	let mut len = frame.len() as u32;
+	while len >= 0x80 {
+		buf.push(len as u8 | 0x80);
+		len >>= 7;
+	}
+	buf.push(len as u8);
+	buf.extend_from_slice(frame);
+	buf
+}
+
+// A length prefix as it's read, a byte at a time
+#[derive(Default)]
+struct LengthPrefix {
+	len: usize,
+	bytes: usize,
+}
+
+impl LengthPrefix {
+	// The length once `b` was its last byte
+	fn push(&mut self, b: u8) -> io::Result<Option<usize>> {
+		self.len |= ((b & 0x7F) as usize) << (7 * self.bytes);
+		self.bytes += 1;
+		if b & 0x80 != 0 {
+			return match self.bytes {
+				5 => Err(invalid("frame length over 5 bytes")),
+				_ => Ok(None),
+			};
+		}
+		if self.len > MAX_FRAME_BYTES {
+			return Err(invalid("frame longer than the protocol allows"));
+		}
+		Ok(Some(self.len))
+	}
+}
+
+impl Transport for TcpStream {
+	fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+		let buf = length_prefixed(frame);
+		self.write_all(&buf)?;
+		count_sent(buf.len());
+		Ok(())
+	}
+
+	fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+		let mut prefix = LengthPrefix::default();
+		let len = loop {
+			let mut b = [0u8; 1];
+			self.read_exact(&mut b)?;
+			if let Some(len) = prefix.push(b[0])? {
+				break len;
+			}
+		};
+		let mut buf = vec![0u8; len];
+		self.read_exact(&mut buf)?;
+		count_received(prefix.bytes + len);
+		Ok(buf)
+	}
+
+	fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+		Ok(Box::new(TcpStream::try_clone(self)?))
+	}
+
+	fn set_recv_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		self.set_read_timeout(timeout)
+	}
+
+	fn close(&self) {
+		let _ = self.shutdown(Shutdown::Both);
+	}
+
+	fn peer_addr(&self) -> Option<SocketAddr> {
+		TcpStream::peer_addr(self).ok()
+	}
+}
+
+// Frames a server connection's writer may fall behind by before it's hung up
+// on, a spectator's catch-up and several seconds of ticks
+const SEND_QUEUE: usize = 8 * crate::sim::TPS as usize;
+
+// Connections made to a mem:// name that its server hasn't accepted yet
+const MEMORY_BACKLOG: usize = 64;
+
+/// Where the server's connections are read and written, one task each way per
+/// connection on a few worker threads however many clients come. Started by
+/// the first server in the process. Its tasks stay here wherever the match
+/// they're for runs, a lobby's rooms tick on a pool of their own.
+pub fn runtime() -> &'static Runtime {
+	static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+	RUNTIME.get_or_init(|| {
+		#[cfg(not(target_arch = "wasm32"))]
+		let mut builder = Builder::new_multi_thread();
+		// A page can't take connections, it never gets to run a server
+		#[cfg(target_arch = "wasm32")]
+		let mut builder = Builder::new_current_thread();
+		builder
+			.thread_name("net-io")
+			.enable_all()
+			.build()
+			.expect("start the network runtime")
+	})
+}
+
+// Set once a connection is over, by whichever end of it noticed first
+type Hangup = Arc<watch::Sender<bool>>;
+
+async fn hung_up(hangup: &Hangup) {
+	let _ = hangup.subscribe().wait_for(|&over| over).await;
+}
+
+/// The server's side of a connection. Frames queue for its writer task, so a
+/// slow client never holds up whoever sends, one that falls SEND_QUEUE frames
+/// behind is hung up on. Dropping it hangs up once what's queued went out.
+pub struct Conn {
+	tx: mpsc::Sender<Vec<u8>>,
+	hangup: Hangup,
+	peer: Option<SocketAddr>,
+}
+
+impl Conn {
+	// Starts the writer task, the reader is for whoever reads the connection
+	fn open(
+		reader: FrameReader,
+		writer: FrameWriter,
+		peer: Option<SocketAddr>,
+	) -> (Self, ConnReader) {
+		let hangup = Hangup::new(watch::Sender::new(false));
+		let (tx, rx) = mpsc::channel(SEND_QUEUE);
+		runtime().spawn(write_frames(writer, rx, hangup.clone()));
+		let conn = Self {
+			tx,
+			hangup: hangup.clone(),
+			peer,
+		};
+		(
+			conn,
+			ConnReader {
+				inner: reader,
+				hangup,
+			},
+		)
+	}
+
+	/// Queues a frame, an error once the connection is over or fell too far
+	/// behind. It's closed then and should be dropped.
+	pub fn send(&self, frame: Vec<u8>) -> io::Result<()> {
+		if *self.hangup.borrow() {
+			return Err(io::ErrorKind::BrokenPipe.into());
+		}
+		self.tx.try_send(frame).map_err(|e| {
+			self.close();
+			match e {
+				TrySendError::Full(_) => io::Error::other("client fell too far behind"),
+				TrySendError::Closed(_) => io::ErrorKind::BrokenPipe.into(),
+			}
+		})
+	}
+
+	// Hangs up right away, what's still queued doesn't go out
+	pub fn close(&self) {
+		self.hangup.send_replace(true);
+	}
+
+	pub fn peer_addr(&self) -> Option<SocketAddr> {
+		self.peer
+	}
+}
+
+// Until the connection's Conn is gone and everything it queued went out, or
+// either end hung up
+async fn write_frames(mut writer: FrameWriter, mut rx: mpsc::Receiver<Vec<u8>>, hangup: Hangup) {
+	loop {
+		let frame = tokio::select! {
+			frame = rx.recv() => frame,
+			_ = hung_up(&hangup) => None,
+		};
+		let Some(frame) = frame else { break };
+		// A client that stopped reading leaves the write pending until it's closed
+		let sent = tokio::select! {
+			sent = writer.send(&frame) => sent.is_ok(),
+			_ = hung_up(&hangup) => false,
+		};
+		if !sent {
+			break;
+		}
+	}
+	hangup.send_replace(true);
+	writer.shutdown().await;
+}
+
+/// What the client on a server connection sends, for the one task reading it.
+/// Once either end hangs up reads fail, and a failed read hangs up. Giving up
+/// on a read part way, a timeout, leaves the frames after it unreadable.
+pub struct ConnReader {
+	inner: FrameReader,
+	hangup: Hangup,
+}
+
+impl ConnReader {
+	pub async fn recv(&mut self) -> io::Result<Vec<u8>> {
+		let frame = tokio::select! {
+			frame = self.inner.recv() => frame,
+			_ = hung_up(&self.hangup) => Err(io::ErrorKind::ConnectionAborted.into()),
+		};
+		if frame.is_err() {
+			self.close();
+		}
+		frame
+	}
+
+	pub fn close(&self) {
+		self.hangup.send_replace(true);
+	}
+}
+
+// The two directions of a server connection, one kind per backend
+enum FrameReader {
+	#[cfg(not(target_arch = "wasm32"))]
+	Tcp(BufReader<OwnedReadHalf>),
+	#[cfg(not(target_arch = "wasm32"))]
+	Ws(websocket::WsReader),
+	Memory(MemoryConn),
+}
+
+impl FrameReader {
+	async fn recv(&mut self) -> io::Result<Vec<u8>> {
+		match self {
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Tcp(stream) => {
+				let mut prefix = LengthPrefix::default();
+				let len = loop {
+					if let Some(len) = prefix.push(stream.read_u8().await?)? {
+						break len;
+					}
+				};
+				let mut buf = vec![0u8; len];
+				stream.read_exact(&mut buf).await?;
+				count_received(prefix.bytes + len);
+				Ok(buf)
+			}
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Ws(ws) => ws.recv().await,
+			Self::Memory(pipe) => pipe.recv_async().await,
+		}
+	}
+}
+
+enum FrameWriter {
+	#[cfg(not(target_arch = "wasm32"))]
+	Tcp(OwnedWriteHalf),
+	#[cfg(not(target_arch = "wasm32"))]
+	Ws(websocket::WsWriter),
+	Memory(MemoryConn),
+}
+
+impl FrameWriter {
+	async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+		match self {
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Tcp(stream) => {
+				let buf = length_prefixed(frame);
+				stream.write_all(&buf).await?;
+				count_sent(buf.len());
+				Ok(())
+			}
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Ws(ws) => ws.send(frame).await,
+			Self::Memory(pipe) => pipe.send_frame(frame),
+		}
+	}
+
+	async fn shutdown(&mut self) {
+		match self {
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Tcp(stream) => {
+				let _ = stream.shutdown().await;
+			}
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Ws(ws) => ws.shutdown().await,
+			Self::Memory(pipe) => Transport::close(pipe),
+		}
+	}
+}
+
+/// A port the server takes connections on. Accepting only takes the
+/// connection, `Incoming::open` finishes its handshake so that runs on the
+/// connection's own task.
+pub enum Listener {
+	#[cfg(not(target_arch = "wasm32"))]
+	Tcp(TcpListener, SocketOptions),
+	#[cfg(not(target_arch = "wasm32"))]
+	Ws(TcpListener, SocketOptions),
+	Memory(MemoryListener),
+}
+
+impl Listener {
+	// Raw TCP on `addr`, or WebSocket with `ws`
+	#[cfg(not(target_arch = "wasm32"))]
+	pub fn bind(addr: &str, socket: SocketOptions, ws: bool) -> io::Result<Self> {
+		let listener = std::net::TcpListener::bind(addr)?;
+		listener.set_nonblocking(true)?;
+		let _runtime = runtime().enter();
+		let listener = TcpListener::from_std(listener)?;
+		Ok(match ws {
+			true => Self::Ws(listener, socket),
+			false => Self::Tcp(listener, socket),
+		})
+	}
+
+	#[cfg(target_arch = "wasm32")]
+	pub fn bind(_addr: &str, _socket: SocketOptions, _ws: bool) -> io::Result<Self> {
+		Err(io::ErrorKind::Unsupported.into())
+	}
+
+	/// Takes the connections made to `mem://name` in this process, for running a
+	/// server and its clients without sockets. The name is free again once this
+	/// is dropped.
+	pub fn memory(name: &str) -> io::Result<Self> {
+		let mut listeners = memory_listeners().lock().unwrap();
+		if listeners.contains_key(name) {
+			return Err(io::ErrorKind::AddrInUse.into());
+		}
+		let (tx, rx) = mpsc::channel(MEMORY_BACKLOG);
+		listeners.insert(name.to_string(), tx);
+		Ok(Self::Memory(MemoryListener {
+			name: name.to_string(),
+			rx,
+		}))
+	}
+
+	pub async fn accept(&mut self) -> io::Result<Incoming> {
+		match self {
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Tcp(listener, socket) => Ok(Incoming::Tcp(accept_tcp(listener, *socket).await?)),
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Ws(listener, socket) => Ok(Incoming::Ws(accept_tcp(listener, *socket).await?)),
+			Self::Memory(listener) => listener
+				.rx
+				.recv()
+				.await
+				.map(Incoming::Memory)
+				.ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "memory listener gone")),
+		}
+	}
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn accept_tcp(
+	listener: &TcpListener,
+	socket: SocketOptions,
+) -> io::Result<tokio::net::TcpStream> {
+	let (stream, _) = listener.accept().await?;
+	// Tuned through the std socket, the options are set on the descriptor
+	let stream = stream.into_std()?;
+	socket.apply(&stream);
+	tokio::net::TcpStream::from_std(stream)
+}
+
+/// A connection just accepted, not yet read or written.
+pub enum Incoming {
+	#[cfg(not(target_arch = "wasm32"))]
+	Tcp(tokio::net::TcpStream),
+	#[cfg(not(target_arch = "wasm32"))]
+	Ws(tokio::net::TcpStream),
+	Memory(MemoryConn),
+}
+
+impl Incoming {
+	// The WebSocket upgrade for those that need one, then the connection's
+	// writer starts
+	pub async fn open(self) -> io::Result<(Conn, ConnReader)> {
+		Ok(match self {
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Tcp(stream) => {
+				let peer = stream.peer_addr().ok();
+				let (reader, writer) = stream.into_split();
+				Conn::open(
+					FrameReader::Tcp(BufReader::new(reader)),
+					FrameWriter::Tcp(writer),
+					peer,
+				)
+			}
+			#[cfg(not(target_arch = "wasm32"))]
+			Self::Ws(stream) => {
+				let peer = stream.peer_addr().ok();
+				let (reader, writer) = websocket::upgrade(stream).await?;
+				Conn::open(FrameReader::Ws(reader), FrameWriter::Ws(writer), peer)
+			}
+			Self::Memory(pipe) => {
+				let writer = pipe.handle();
+				Conn::open(FrameReader::Memory(pipe), FrameWriter::Memory(writer), None)
+			}
+		})
+	}
+}
+
+// Both ends of an in-memory connection. Side 0 reads what side 1 sent and
+// the other way around
+#[derive(Default)]
+struct Pipes {
+	frames: [VecDeque<Vec<u8>>; 2],
+	timeouts: [Option<Duration>; 2],
+	// Handles still open on each side, the connection ends with the last one
+	handles: [usize; 2],
+	closed: bool,
+}
+
+// Blocked readers wait on the condvar, the server's tasks on the notify
+#[derive(Default)]
+struct Pipe {
+	pipes: Mutex<Pipes>,
+	ready: Condvar,
+	ready_async: Notify,
+}
+
+impl Pipe {
+	fn notify(&self) {
+		self.ready.notify_all();
+		self.ready_async.notify_waiters();
+	}
+}
+
+pub struct MemoryConn {
+	pipe: Arc<Pipe>,
+	side: usize,
+}
+
+impl MemoryConn {
+	fn pair() -> (Self, Self) {
+		let pipe = Arc::new(Pipe {
+			pipes: Mutex::new(Pipes {
+				handles: [1, 1],
+				..Default::default()
+			}),
+			..Default::default()
+		});
+		let end = |side| Self {
+			pipe: pipe.clone(),
+			side,
+		};
+		(end(0), end(1))
+	}
+
+	// Another handle on this side
+	fn handle(&self) -> Self {
+		self.pipe.pipes.lock().unwrap().handles[self.side] += 1;
+		Self {
+			pipe: self.pipe.clone(),
+			side: self.side,
+		}
+	}
+
+	async fn recv_async(&mut self) -> io::Result<Vec<u8>> {
+		loop {
+			// Registered before looking, a frame sent in between still wakes it
+			let ready = self.pipe.ready_async.notified();
+			let mut ready = std::pin::pin!(ready);
+			ready.as_mut().enable();
+			{
+				let mut p = self.pipe.pipes.lock().unwrap();
+				if let Some(frame) = p.frames[self.side].pop_front() {
+					return Ok(frame);
+				}
+				if p.closed {
+					return Err(io::ErrorKind::UnexpectedEof.into());
+				}
+			}
+			ready.await;
+		}
+	}
+}
+
+impl Transport for MemoryConn {
+	fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+		let mut p = self.pipe.pipes.lock().unwrap();
+		if p.closed {
+			return Err(io::ErrorKind::BrokenPipe.into());
+		}
+		p.frames[1 - self.side].push_back(frame.to_vec());
+		self.pipe.notify();
+		Ok(())
+	}
+
+	// What was sent before the connection closed still arrives
+	fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+		let mut p = self.pipe.pipes.lock().unwrap();
+		let deadline = p.timeouts[self.side].map(|t| Instant::now() + t);
+		loop {
+			if let Some(frame) = p.frames[self.side].pop_front() {
+				return Ok(frame);
+			}
+			if p.closed {
+				return Err(io::ErrorKind::UnexpectedEof.into());
+			}
+			p = match deadline {
+				Some(deadline) => {
+					let left = deadline.saturating_duration_since(Instant::now());
+					if left.is_zero() {
+						return Err(io::ErrorKind::TimedOut.into());
+					}
+					self.pipe.ready.wait_timeout(p, left).unwrap().0
+				}
+				None => self.pipe.ready.wait(p).unwrap(),
+			};
+		}
+	}
+
+	fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+		Ok(Box::new(self.handle()))
+	}
+
+	fn set_recv_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		self.pipe.pipes.lock().unwrap().timeouts[self.side] = timeout;
+		Ok(())
+	}
+
+	fn close(&self) {
+		self.pipe.pipes.lock().unwrap().closed = true;
+		self.pipe.notify();
+	}
+
+	fn peer_addr(&self) -> Option<SocketAddr> {
+		None
+	}
+}
+
+impl Drop for MemoryConn {
+	fn drop(&mut self) {
+		let mut p = self.pipe.pipes.lock().unwrap();
+		p.handles[self.side] -= 1;
+		if p.handles[self.side] == 0 {
+			p.closed = true;
+			self.pipe.notify();
+		}
+	}
+}
+
+// Servers listening in this process by name, what mem:// addresses connect to
+type MemoryListeners = Mutex<HashMap<String, mpsc::Sender<MemoryConn>>>;
+
+fn memory_listeners() -> &'static MemoryListeners {
+	static LISTENERS: OnceLock<MemoryListeners> = OnceLock::new();
+	LISTENERS.get_or_init(Default::default)
+}
+
+pub struct MemoryListener {
+	name: String,
+	rx: mpsc::Receiver<MemoryConn>,
+}
+
+impl Drop for MemoryListener {
+	fn drop(&mut self) {
+		memory_listeners().lock().unwrap().remove(&self.name);
+	}
+}
+
+// Refused like a full backlog when the server is that far behind
+fn connect_memory(name: &str) -> io::Result<Box<dyn Transport>> {
+	let listeners = memory_listeners().lock().unwrap();
+	let tx = listeners
+		.get(name)
+		.ok_or(io::ErrorKind::ConnectionRefused)?;
+	let (client, server) = MemoryConn::pair();
+	tx.try_send(server)
+		.map_err(|_| io::ErrorKind::ConnectionRefused)?;
+	Ok(Box::new(client))
+}
+
+// Plain host:port is raw TCP, ws://host:port a WebSocket, mem://name a server
+// in this process
+pub fn connect(addr: &str, socket: SocketOptions) -> io::Result<Box<dyn Transport>> {
+	if addr.starts_with("ws://") {
+		return websocket::connect(addr, socket);
+	}
+	if let Some(name) = addr.strip_prefix("mem://") {
+		return connect_memory(name);
+	}
+	let stream = TcpStream::connect(addr)?;
+	socket.apply(&stream);
+	Ok(Box::new(stream))
+}
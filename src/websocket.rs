@@ -0,0 +1,362 @@
+use std::{
+	io::{self, Read, Write},
+	net::{SocketAddr, TcpStream},
+	time::Duration,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt, BufReader},
+	net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+};
+
+use crate::{
+	protocol::MAX_FRAME_BYTES,
+	sockopt::SocketOptions,
+	transport::{self, Transport},
+};
+
+// Appended to the client's key before hashing, fixed by RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// An HTTP upgrade request or response longer than this isn't one of ours
+const MAX_HANDSHAKE_BYTES: usize = 8192;
+
+// How long a client waits for the server to answer its upgrade
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+fn invalid(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// One unfragmented message, masked with `key` the way clients must
+fn encode(opcode: u8, payload: &[u8], key: Option<[u8; 4]>) -> Vec<u8> {
+	let mut frame = Vec::with_capacity(payload.len() + 14);
+	frame.push(0x80 | opcode);
+	let mask_bit = if key.is_some() { 0x80 } else { 0 };
+	match payload.len() {
+		n if n < 126 => frame.push(mask_bit | n as u8),
+		n if n <= u16::MAX as usize => {
+			frame.push(mask_bit | 126);
+			frame.extend_from_slice(&(n as u16).to_be_bytes());
+		}
+		n => {
+			frame.push(mask_bit | 127);
+			frame.extend_from_slice(&(n as u64).to_be_bytes());
+		}
+	}
+	match key {
+		Some(key) => {
+			frame.extend_from_slice(&key);
+			frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+		}
+		None => frame.extend_from_slice(payload),
+	}
+	frame
+}
+
+// A frame's length, checked before allocating for it and the message it continues
+fn payload_len(len: u64, message: &[u8]) -> io::Result<usize> {
+	usize::try_from(len)
+		.ok()
+		.filter(|&n| n <= MAX_FRAME_BYTES - message.len())
+		.ok_or_else(|| invalid("websocket message longer than the protocol allows"))
+}
+
+// Adds a frame's payload to its message, true once the message is whole
+fn add_payload(
+	head: u8,
+	mut payload: Vec<u8>,
+	key: Option<[u8; 4]>,
+	message: &mut Vec<u8>,
+) -> io::Result<bool> {
+	if let Some(key) = key {
+		for (i, b) in payload.iter_mut().enumerate() {
+			*b ^= key[i % 4];
+		}
+	}
+	match head & 0x0f {
+		OP_BINARY | OP_CONTINUATION => message.extend_from_slice(&payload),
+		OP_CLOSE => {
+			return Err(io::Error::new(
+				io::ErrorKind::ConnectionAborted,
+				"websocket closed",
+			));
+		}
+		// Nobody here sends pings, and answering would race the writer
+		OP_PING | OP_PONG => return Ok(false),
+		_ => return Err(invalid("unexpected websocket opcode")),
+	}
+	Ok(head & 0x80 != 0)
+}
+
+/// A client's WebSocket, each binary message is one frame of the game
+/// protocol. Browsers can only open these, native clients use them with a
+/// ws:// address.
+pub struct WsConn {
+	stream: TcpStream,
+}
+
+impl Transport for WsConn {
+	fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+		let mut key = [0u8; 4];
+		getrandom::fill(&mut key).map_err(|e| io::Error::other(e.to_string()))?;
+		self.stream
+			.write_all(&encode(OP_BINARY, frame, Some(key)))?;
+		transport::count_sent(frame.len());
+		Ok(())
+	}
+
+	// Servers must not mask what they send
+	fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+		let mut message = Vec::new();
+		loop {
+			let mut head = [0u8; 2];
+			self.stream.read_exact(&mut head)?;
+			if head[1] & 0x80 != 0 {
+				return Err(invalid("websocket masking from the wrong side"));
+			}
+			let len = match head[1] & 0x7f {
+				126 => {
+					let mut b = [0u8; 2];
+					self.stream.read_exact(&mut b)?;
+					u16::from_be_bytes(b) as u64
+				}
+				127 => {
+					let mut b = [0u8; 8];
+					self.stream.read_exact(&mut b)?;
+					u64::from_be_bytes(b)
+				}
+				n => n as u64,
+			};
+			let mut payload = vec![0u8; payload_len(len, &message)?];
+			self.stream.read_exact(&mut payload)?;
+			if add_payload(head[0], payload, None, &mut message)? {
+				break;
+			}
+		}
+		transport::count_received(message.len());
+		Ok(message)
+	}
+
+	fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+		Ok(Box::new(WsConn {
+			stream: self.stream.try_clone()?,
+		}))
+	}
+
+	fn set_recv_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		self.stream.set_read_timeout(timeout)
+	}
+
+	fn close(&self) {
+		Transport::close(&self.stream);
+	}
+
+	fn peer_addr(&self) -> Option<SocketAddr> {
+		self.stream.peer_addr().ok()
+	}
+}
+
+/// What a client sends on the server's end of a WebSocket.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct WsReader(BufReader<OwnedReadHalf>);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WsReader {
+	// Clients must mask what they send
+	pub async fn recv(&mut self) -> io::Result<Vec<u8>> {
+		let mut message = Vec::new();
+		loop {
+			let mut head = [0u8; 2];
+			self.0.read_exact(&mut head).await?;
+			if head[1] & 0x80 == 0 {
+				return Err(invalid("websocket masking from the wrong side"));
+			}
+			let len = match head[1] & 0x7f {
+				126 => self.0.read_u16().await? as u64,
+				127 => self.0.read_u64().await?,
+				n => n as u64,
+			};
+			let mut key = [0u8; 4];
+			self.0.read_exact(&mut key).await?;
+			let mut payload = vec![0u8; payload_len(len, &message)?];
+			self.0.read_exact(&mut payload).await?;
+			if add_payload(head[0], payload, Some(key), &mut message)? {
+				break;
+			}
+		}
+		transport::count_received(message.len());
+		Ok(message)
+	}
+}
+
+/// The server's end of a WebSocket for sending.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct WsWriter(OwnedWriteHalf);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WsWriter {
+	pub async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+		self.0.write_all(&encode(OP_BINARY, frame, None)).await?;
+		transport::count_sent(frame.len());
+		Ok(())
+	}
+
+	pub async fn shutdown(&mut self) {
+		let _ = self.0.shutdown().await;
+	}
+}
+
+// Up to and including the blank line ending the headers. Read a byte at a time
+// so nothing of the first frame is consumed
+fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+	let mut head = Vec::new();
+	let mut byte = [0u8; 1];
+	while !head.ends_with(b"\r\n\r\n") {
+		if head.len() > MAX_HANDSHAKE_BYTES {
+			return Err(invalid("handshake too long"));
+		}
+		stream.read_exact(&mut byte)?;
+		head.push(byte[0]);
+	}
+	String::from_utf8(head).map_err(|_| invalid("handshake is not utf-8"))
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+	head.lines().find_map(|line| {
+		let (k, v) = line.split_once(':')?;
+		k.trim().eq_ignore_ascii_case(name).then(|| v.trim())
+	})
+}
+
+fn accept_key(key: &str) -> String {
+	base64(&sha1(format!("{key}{ACCEPT_GUID}").as_bytes()))
+}
+
+/// Answers a client's HTTP upgrade on a connection the server accepted, the
+/// caller bounds how long that may take.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn upgrade(stream: tokio::net::TcpStream) -> io::Result<(WsReader, WsWriter)> {
+	let (reader, mut writer) = stream.into_split();
+	// Frames right behind the request stay buffered for the reader
+	let mut reader = BufReader::new(reader);
+	let mut head = Vec::new();
+	while !head.ends_with(b"\r\n\r\n") {
+		if head.len() > MAX_HANDSHAKE_BYTES {
+			return Err(invalid("handshake too long"));
+		}
+		head.push(reader.read_u8().await?);
+	}
+	let head = String::from_utf8(head).map_err(|_| invalid("handshake is not utf-8"))?;
+	let key =
+		header(&head, "Sec-WebSocket-Key").ok_or_else(|| invalid("not a websocket upgrade"))?;
+	let response = format!(
+		"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+		accept_key(key)
+	);
+	writer.write_all(response.as_bytes()).await?;
+	Ok((WsReader(reader), WsWriter(writer)))
+}
+
+/// `url` is ws://host:port with an optional path, TLS isn't supported.
+pub fn connect(url: &str, socket: SocketOptions) -> io::Result<Box<dyn Transport>> {
+	let rest = url
+		.strip_prefix("ws://")
+		.ok_or_else(|| invalid("websocket address must start with ws://"))?;
+	let (host, path) = match rest.find('/') {
+		Some(i) => rest.split_at(i),
+		None => (rest, "/"),
+	};
+	let mut stream = TcpStream::connect(host)?;
+	socket.apply(&stream);
+
+	let mut nonce = [0u8; 16];
+	getrandom::fill(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+	let key = base64(&nonce);
+	let request = format!(
+		"GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+	);
+	stream.write_all(request.as_bytes())?;
+	stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+	let head = read_head(&mut stream)?;
+	stream.set_read_timeout(None)?;
+	let switched = head.lines().next().is_some_and(|l| l.contains(" 101 "));
+	if !switched || header(&head, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+		return Err(invalid("server refused the websocket upgrade"));
+	}
+	Ok(Box::new(WsConn { stream }))
+}
+
+// Only the handshake hashes anything, with SHA-1 because the RFC says so
+fn sha1(data: &[u8]) -> [u8; 20] {
+	let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+	let mut msg = data.to_vec();
+	msg.push(0x80);
+	while msg.len() % 64 != 56 {
+		msg.push(0);
+	}
+	msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+	for block in msg.chunks(64) {
+		let mut w = [0u32; 80];
+		for (i, word) in block.chunks(4).enumerate() {
+			w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+		}
+		for i in 16..80 {
+			w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+		}
+		let [mut a, mut b, mut c, mut d, mut e] = h;
+		for (i, &wi) in w.iter().enumerate() {
+			let (f, k) = match i {
+				0..20 => ((b & c) | (!b & d), 0x5a827999),
+				20..40 => (b ^ c ^ d, 0x6ed9eba1),
+				40..60 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+				_ => (b ^ c ^ d, 0xca62c1d6),
+			};
+			let t = a
+				.rotate_left(5)
+				.wrapping_add(f)
+				.wrapping_add(e)
+				.wrapping_add(k)
+				.wrapping_add(wi);
+			e = d;
+			d = c;
+			c = b.rotate_left(30);
+			b = a;
+			a = t;
+		}
+		for (x, y) in h.iter_mut().zip([a, b, c, d, e]) {
+			*x = x.wrapping_add(y);
+		}
+	}
+	let mut out = [0u8; 20];
+	for (chunk, x) in out.chunks_mut(4).zip(h) {
+		chunk.copy_from_slice(&x.to_be_bytes());
+	}
+	out
+}
+
+fn base64(data: &[u8]) -> String {
+	const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+	let mut out = String::new();
+	for chunk in data.chunks(3) {
+		let n = chunk
+			.iter()
+			.enumerate()
+			.fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+		for i in 0..4 {
+			if i <= chunk.len() {
+				out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+			} else {
+				out.push('=');
+			}
+		}
+	}
+	out
+}
@@ -0,0 +1,85 @@
+use std::{
+	collections::BTreeMap,
+	sync::{
+		Mutex,
+		atomic::{AtomicBool, Ordering},
+	},
+	thread,
+	time::Duration,
+};
+
+use macroquad::prelude::info;
+
+use crate::clock::Instant;
+
+// Off unless asked for, the clock reads would cost every frame otherwise
+static ON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Dir {
+	Sent,
+	Received,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+	frames: u64,
+	bytes: u64,
+	nanos: u64,
+}
+
+static TOTALS: Mutex<BTreeMap<(Dir, &'static str), Totals>> = Mutex::new(BTreeMap::new());
+
+pub fn enabled() -> bool {
+	ON.load(Ordering::Relaxed)
+}
+
+// When measuring, the instant to time a frame from
+pub fn start() -> Option<Instant> {
+	enabled().then(Instant::now)
+}
+
+// Counts a frame of `kind` with `bytes` of payload, timed from `started`
+pub fn record(started: Option<Instant>, dir: Dir, kind: &'static str, bytes: usize) {
+	let Some(started) = started else {
+		return;
+	};
+	let took = started.elapsed();
+	let mut totals = TOTALS.lock().unwrap();
+	let t = totals.entry((dir, kind)).or_default();
+	t.frames += 1;
+	t.bytes += bytes as u64;
+	t.nanos += took.as_nanos() as u64;
+}
+
+/// Prints once a second what each kind of message cost in the second before,
+/// both ways: frames, payload bytes, and time. Sending counts encoding and
+/// the write, receiving only decoding, as a read mostly waits for the peer.
+pub fn report_every_second() {
+	ON.store(true, Ordering::Relaxed);
+	thread::spawn(|| {
+		loop {
+			thread::sleep(Duration::from_secs(1));
+			let totals = std::mem::take(&mut *TOTALS.lock().unwrap());
+			if totals.is_empty() {
+				continue;
+			}
+			let mut rows: Vec<_> = totals.into_iter().collect();
+			rows.sort_by_key(|&((dir, _), t)| (dir, std::cmp::Reverse(t.bytes)));
+			let mut out = String::from("wire, last second:");
+			for ((dir, kind), t) in rows {
+				let dir = match dir {
+					Dir::Sent => "sent",
+					Dir::Received => "recv",
+				};
+				out += &format!(
+					"\n  {dir} {kind:<18} {:>6} frames {:>9} B {:>8.3} ms",
+					t.frames,
+					t.bytes,
+					t.nanos as f64 / 1e6
+				);
+			}
+			info!("{}", out);
+		}
+	});
+}
@@ -0,0 +1,100 @@
+// What src/browser.rs imports: WebSockets whose messages wait here until the
+// frame loop takes them, the server address and random bytes
+"use strict";
+
+(function () {
+	var sockets = [];
+	var encoder = new TextEncoder();
+	var decoder = new TextDecoder();
+
+	function bytes(ptr, len) {
+		return new Uint8Array(wasm_memory.buffer, ptr, len);
+	}
+
+	// ws://<page host>:4001 unless the page says ?server=
+	function serverAddr() {
+		var given = new URLSearchParams(window.location.search).get("server");
+		return given || "ws://" + window.location.hostname + ":4001";
+	}
+
+	miniquad_add_plugin({
+		name: "repl_net",
+		version: 1,
+		register_plugin: function (importObject) {
+			var env = importObject.env;
+
+			env.repl_ws_open = function (ptr, len) {
+				var s = { ws: null, state: 0, pending: [], inbox: [] };
+				try {
+					s.ws = new WebSocket(decoder.decode(bytes(ptr, len).slice()));
+				} catch (e) {
+					console.error(e);
+					s.state = 2;
+					sockets.push(s);
+					return sockets.length - 1;
+				}
+				s.ws.binaryType = "arraybuffer";
+				s.ws.onopen = function () {
+					s.state = 1;
+					s.pending.forEach(function (m) { s.ws.send(m); });
+					s.pending = [];
+				};
+				s.ws.onmessage = function (e) {
+					if (e.data instanceof ArrayBuffer) {
+						s.inbox.push(new Uint8Array(e.data));
+					}
+				};
+				s.ws.onclose = function () { s.state = 2; };
+				s.ws.onerror = function () { s.state = 2; };
+				sockets.push(s);
+				return sockets.length - 1;
+			};
+
+			env.repl_ws_state = function (id) {
+				return sockets[id].state;
+			};
+
+			env.repl_ws_send = function (id, ptr, len) {
+				var s = sockets[id];
+				var msg = bytes(ptr, len).slice();
+				if (s.state == 0) {
+					s.pending.push(msg);
+				} else if (s.state == 1) {
+					s.ws.send(msg);
+				}
+			};
+
+			env.repl_ws_recv_len = function (id) {
+				var s = sockets[id];
+				return s.inbox.length ? s.inbox[0].length : -1;
+			};
+
+			env.repl_ws_recv = function (id, ptr, len) {
+				bytes(ptr, len).set(sockets[id].inbox.shift());
+			};
+
+			env.repl_ws_close = function (id) {
+				var s = sockets[id];
+				if (s.ws && s.state != 2) {
+					s.ws.close();
+				}
+				s.state = 2;
+			};
+
+			env.repl_server_addr = function (ptr, len) {
+				var addr = encoder.encode(serverAddr());
+				if (addr.length <= len) {
+					bytes(ptr, addr.length).set(addr);
+				}
+				return addr.length;
+			};
+
+			env.repl_random = function (ptr, len) {
+				// getRandomValues takes at most 64k at once
+				for (var at = 0; at < len; at += 65536) {
+					crypto.getRandomValues(bytes(ptr + at, Math.min(65536, len - at)));
+				}
+			};
+		},
+	});
+})();